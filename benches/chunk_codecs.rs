@@ -0,0 +1,67 @@
+//! Throughput of the temporary chunk codecs
+//!
+//! Run with `cargo bench --bench chunk_codecs`. Writes and reads back a few
+//! million synthetic metadata records per codec and reports time and size.
+
+use rmduprs::Metadata;
+use rmduprs::io::{ChunkCodec, open_chunk_reader, save_chunk_parallel};
+use std::time::Instant;
+
+const RECORDS: u64 = 4_000_000;
+
+fn synthetic_chunk() -> Vec<Metadata> {
+    let mut rng = fastrand::Rng::with_seed(7);
+    (0..RECORDS)
+        .map(|i| {
+            let pos = rng.i64(0..50_000_000);
+            if i % 3 == 0 {
+                Metadata::new_se(0, rng.i32(0..24), pos, rng.u8(0..2), rng.u32(0..6000), i)
+            } else {
+                let ref_id = rng.i32(0..24);
+                let pos2 = pos + rng.i64(100..600);
+                Metadata::new_pe(
+                    0,
+                    ref_id,
+                    pos,
+                    0,
+                    ref_id,
+                    pos2,
+                    1,
+                    rng.u32(0..6000),
+                    i,
+                    i + 1,
+                )
+            }
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let codecs = [ChunkCodec::Lz4, ChunkCodec::Zstd, ChunkCodec::None];
+    for (seq, codec) in codecs.into_iter().enumerate() {
+        let chunk = synthetic_chunk();
+        let start = Instant::now();
+        let path = save_chunk_parallel(chunk, dir.path(), seq, codec)?;
+        let write = start.elapsed();
+
+        let start = Instant::now();
+        let mut reader = open_chunk_reader(&path)?;
+        let mut n = 0u64;
+        while Metadata::read_from(&mut reader)?.is_some() {
+            n += 1;
+        }
+        let read = start.elapsed();
+        assert_eq!(n, RECORDS);
+
+        let size = std::fs::metadata(&path)?.len();
+        println!(
+            "{:<5} write {:>7.1} ms  read {:>7.1} ms  size {:>6.1} MiB",
+            format!("{:?}", codec).to_lowercase(),
+            write.as_secs_f64() * 1e3,
+            read.as_secs_f64() * 1e3,
+            size as f64 / (1 << 20) as f64
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Write-pass duplicate lookup: a bitmap `contains` per record against a
+//! cursor over the sorted indices
+//!
+//! Run with `cargo bench --bench mark_cursor`. Marks 10% of 100 million
+//! record indices at random and counts the duplicates with `contains`, with a
+//! cursor over a sorted `Vec` and with a cursor over the bitmap's own
+//! iterator, which is what the write pass uses.
+
+use roaring::RoaringBitmap;
+use std::time::Instant;
+
+const RECORDS: u32 = 100_000_000;
+
+fn main() {
+    let mut rng = fastrand::Rng::with_seed(7);
+    let mask: RoaringBitmap = (0..RECORDS).filter(|_| rng.u8(0..10) == 0).collect();
+
+    let start = Instant::now();
+    let mut found = 0u64;
+    for idx in 0..RECORDS {
+        found += std::hint::black_box(mask.contains(idx)) as u64;
+    }
+    println!(
+        "contains  {:>7.1} ms  {} duplicates",
+        start.elapsed().as_secs_f64() * 1e3,
+        found
+    );
+
+    let start = Instant::now();
+    let sorted: Vec<u32> = mask.iter().collect();
+    let built = start.elapsed();
+    let mut cursor = 0;
+    let mut found_cursor = 0u64;
+    for idx in 0..RECORDS {
+        let hit = cursor < sorted.len() && sorted[cursor] == idx;
+        cursor += hit as usize;
+        found_cursor += std::hint::black_box(hit) as u64;
+    }
+    println!(
+        "cursor    {:>7.1} ms  {} duplicates (vec built in {:.1} ms)",
+        start.elapsed().as_secs_f64() * 1e3,
+        found_cursor,
+        built.as_secs_f64() * 1e3
+    );
+
+    let start = Instant::now();
+    let mut iter = mask.iter().peekable();
+    let mut found_iter = 0u64;
+    for idx in 0..RECORDS {
+        let hit = iter.next_if_eq(&idx).is_some();
+        found_iter += std::hint::black_box(hit) as u64;
+    }
+    println!(
+        "iterator  {:>7.1} ms  {} duplicates",
+        start.elapsed().as_secs_f64() * 1e3,
+        found_iter
+    );
+    assert_eq!(found, found_cursor);
+    assert_eq!(found, found_iter);
+}
@@ -0,0 +1,85 @@
+//! Merge-and-dedup time with and without per-library shards
+//!
+//! Run with `cargo bench --bench sharded_merge`. Spills the first-pass
+//! metadata of a synthetic eight-library input as plain chunks and as
+//! per-library chunks, then times the merge of each layout.
+
+use rmduprs::Metadata;
+use rmduprs::args::Args;
+use rmduprs::io::{ChunkCodec, save_chunk_by_library, save_chunk_parallel};
+use rmduprs::pipeline::merge_shards;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Instant;
+
+const RECORDS: u64 = 8_000_000;
+const LIBRARIES: i32 = 8;
+const CHUNK: usize = 1_000_000;
+
+fn synthetic_reads() -> Vec<Metadata> {
+    let mut rng = fastrand::Rng::with_seed(7);
+    (0..RECORDS)
+        .map(|i| {
+            let lib = rng.i32(0..LIBRARIES);
+            let ref_id = rng.i32(0..24);
+            let pos = rng.i64(0..5_000_000);
+            if i % 3 == 0 {
+                Metadata::new_se(lib, ref_id, pos, rng.u8(0..2), rng.u32(0..6000), 2 * i)
+            } else {
+                let pos2 = pos + rng.i64(100..600);
+                Metadata::new_pe(
+                    lib,
+                    ref_id,
+                    pos,
+                    0,
+                    ref_id,
+                    pos2,
+                    1,
+                    rng.u32(0..6000),
+                    2 * i,
+                    2 * i + 1,
+                )
+            }
+        })
+        .collect()
+}
+
+fn main() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let reads = synthetic_reads();
+    let mut single = Vec::new();
+    let mut split: BTreeMap<i32, Vec<PathBuf>> = BTreeMap::new();
+    for (seq, part) in reads.chunks(CHUNK).enumerate() {
+        single.push(save_chunk_parallel(
+            part.to_vec(),
+            dir.path(),
+            seq,
+            ChunkCodec::Lz4,
+        )?);
+        let split_seq = seq + reads.len() / CHUNK + 1;
+        for (lib, path) in
+            save_chunk_by_library(part.to_vec(), dir.path(), split_seq, ChunkCodec::Lz4)?
+        {
+            split.entry(lib).or_default().push(path);
+        }
+    }
+    let split: Vec<_> = split.into_values().collect();
+
+    let args = Args::default();
+    let pe_second_ends = HashMap::new();
+    let mut dups = Vec::new();
+    for (name, shards) in [("single", vec![single]), ("sharded", split)] {
+        let start = Instant::now();
+        let outcome = merge_shards(&shards, &pe_second_ends, &args)?;
+        println!(
+            "{:<8} {:>2} shards  merge {:>7.1} ms  {} duplicates",
+            name,
+            shards.len(),
+            start.elapsed().as_secs_f64() * 1e3,
+            outcome.dup_mask.len()
+        );
+        dups.push(outcome.dup_mask);
+    }
+    assert_eq!(dups[0], dups[1]);
+    Ok(())
+}
@@ -0,0 +1,12 @@
+// Record the resolved noodles version for `rmduprs info`
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock
+        .split("[[package]]")
+        .find(|pkg| pkg.contains("\nname = \"noodles\"\n"))
+        .and_then(|pkg| pkg.lines().find_map(|l| l.strip_prefix("version = ")))
+        .map(|v| v.trim_matches('"').to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=RMDUPRS_NOODLES_VERSION={}", version);
+}
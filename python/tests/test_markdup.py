@@ -0,0 +1,49 @@
+"""Smoke test of the `rmduprs` Python module on test/golden.bam
+
+Build and install the module first, e.g. `maturin develop --release`, then
+run `python -m unittest discover python/tests`.
+"""
+
+import os
+import tempfile
+import unittest
+
+import rmduprs
+
+FIXTURE = os.path.join(os.path.dirname(__file__), "..", "..", "test", "golden.bam")
+
+
+class MarkdupTest(unittest.TestCase):
+    def test_counts(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            summary = rmduprs.markdup(FIXTURE, os.path.join(tmp, "out.bam"), threads=1)
+
+        self.assertEqual(summary["pe"], 3)
+        self.assertEqual(summary["se"], 5)
+        self.assertEqual(summary["duplicates"], 5)
+        self.assertEqual(summary["orphan_duplicates"], 1)
+        self.assertEqual(summary["pe_duplicates"], 2)
+        self.assertEqual(summary["se_only_duplicates"], 2)
+
+        lib = summary["libraries"]["lib1"]
+        self.assertEqual(lib["read_pairs_examined"], 3)
+        self.assertEqual(lib["unpaired_reads_examined"], 5)
+        self.assertEqual(lib["unmapped_reads"], 1)
+        self.assertEqual(lib["read_pair_duplicates"], 1)
+        self.assertEqual(lib["unpaired_read_duplicates"], 3)
+
+    def test_remove_duplicates(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            out = os.path.join(tmp, "out.bam")
+            summary = rmduprs.markdup(FIXTURE, out, remove_duplicates=True)
+            self.assertEqual(summary["duplicates"], 5)
+            self.assertTrue(os.path.getsize(out) > 0)
+
+    def test_errors_raise(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            with self.assertRaises(RuntimeError):
+                rmduprs.markdup(os.path.join(tmp, "missing.bam"), os.path.join(tmp, "out.bam"))
+
+
+if __name__ == "__main__":
+    unittest.main()
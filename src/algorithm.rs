@@ -3,73 +3,611 @@
 //! This module implements the core duplicate detection logic that matches
 // Sambamba's markdup algorithm behavior.
 
+use crate::histogram::DupHistogram;
+use crate::metadata::Metadata;
 use anyhow::Result;
+use clap::ValueEnum;
 use noodles::bam;
+use noodles::sam::alignment::record::cigar::Op;
 use noodles::sam::alignment::record::cigar::op::Kind;
 use roaring::RoaringBitmap;
-use std::collections::HashSet;
+use std::collections::HashMap;
 
 /// Calculate the 5' position of a read
 ///
 /// For forward reads, this is the alignment start minus soft-clipped bases.
 /// For reverse reads, this is the alignment end plus soft-clipped bases.
-pub fn get_5p_pos(record: &bam::Record) -> Result<i32> {
+/// Hard clips count as well: the 5' end is where the unclipped read would
+/// start, as in Sambamba (BioD's `is_clipping` covers `S` and `H`) and
+/// Picard's unclipped start. A supplementary alignment that an aligner
+/// hard-clips therefore lands where the same alignment would with soft
+/// clips. Only the read's own strand matters: a fragment
+/// whose mate is unmapped, or a read with MAPQ 0, is handled like any other
+/// read, as in Sambamba.
+///
+/// Positions are `i64` so that references longer than 2^31 bp (some plant
+/// and amphibian chromosomes) cannot overflow.
+///
+/// Reads with more than 65535 CIGAR operations (long reads, graph
+/// alignments) carry a `kSmN` placeholder and the real CIGAR in the `CG`
+/// tag; `bam::Record::cigar` returns the real one, so clips are found as
+/// for any other read.
+///
+/// Returns -1 for reads without an alignment start, and also for reads whose
+/// CIGAR has no reference-consuming operation (e.g. `50S` or `10I40S`, as
+/// emitted by some malformed aligners): such reads have no meaningful 5' end
+/// and are treated as unmapped for marking purposes.
+#[inline]
+pub fn get_5p_pos(record: &bam::Record) -> Result<i64> {
+    get_5p_pos_with(record, true)
+}
+
+/// [`get_5p_pos`] counting only soft clips unless `hard_clips` is set
+///
+/// Some aligner and tool combinations place reads by soft clips alone
+/// (`--ignore-hard-clips`); a hard-clipped supplementary alignment then
+/// starts where its aligned bases do.
+#[inline]
+pub fn get_5p_pos_with(record: &bam::Record, hard_clips: bool) -> Result<i64> {
+    clipped_end_pos(record, record.flags().is_reverse_complemented(), hard_clips)
+}
+
+/// Calculate the 3' position of a read, clips included: the 5' position the
+/// same alignment would have on the other strand (see [`get_5p_pos_with`])
+///
+/// Keys fragments on both ends with `--use-3p-end`.
+#[inline]
+pub fn get_3p_pos_with(record: &bam::Record, hard_clips: bool) -> Result<i64> {
+    clipped_end_pos(
+        record,
+        !record.flags().is_reverse_complemented(),
+        hard_clips,
+    )
+}
+
+/// Unclipped start of `record`, or its unclipped end when `reverse`
+fn clipped_end_pos(record: &bam::Record, reverse: bool, hard_clips: bool) -> Result<i64> {
     let start = record
         .alignment_start()
         .transpose()?
-        .map(|p| p.get() as i32 - 1)
+        .map(|p| p.get() as i64 - 1)
         .unwrap_or(-1);
     if start < 0 {
         return Ok(-1);
     }
-    let cigar = record.cigar();
+    let ops: Vec<Op> = record.cigar().iter().collect::<Result<_, _>>()?;
+    Ok(five_prime_pos(start, reverse, &ops, hard_clips))
+}
 
-    if !record.flags().is_reverse_complemented() {
-        let mut clipped = 0;
-        for op in cigar.iter() {
-            let op = op?;
-            match op.kind() {
-                Kind::SoftClip | Kind::HardClip => clipped += op.len() as i32,
-                _ => break,
-            }
+/// Reference and 5' position of a read's mate, from RNEXT, PNEXT, the
+/// mate's strand flag and its CIGAR in the `MC` tag
+///
+/// `None` when the mate is unmapped or the record has no position or `MC`
+/// tag for it. The position is -1 when the mate CIGAR consumes no reference,
+/// as in [`get_5p_pos`]; hard clips count with `hard_clips`, as in
+/// [`get_5p_pos_with`].
+pub fn get_mate_5p_pos(record: &bam::Record, hard_clips: bool) -> Option<(i32, i64)> {
+    use noodles::sam::alignment::record::data::field::Value;
+
+    let flags = record.flags();
+    if !flags.is_segmented() || flags.is_mate_unmapped() {
+        return None;
+    }
+    let ref_id = record.mate_reference_sequence_id()?.ok()?;
+    let start = record.mate_alignment_start()?.ok()?.get() as i64 - 1;
+    let Value::String(cigar) = record.data().get(b"MC")?.ok()? else {
+        return None;
+    };
+    let ops: Vec<Op> = noodles::sam::record::Cigar::new(cigar)
+        .iter()
+        .collect::<Result<_, _>>()
+        .ok()?;
+    Some((
+        ref_id as i32,
+        five_prime_pos(
+            start,
+            flags.is_mate_reverse_complemented(),
+            &ops,
+            hard_clips,
+        ),
+    ))
+}
+
+/// 5' position from a 0-based alignment start and the CIGAR operations
+///
+/// -1 when no operation consumes reference, see [`get_5p_pos`]. Hard clips
+/// move the 5' end only with `hard_clips`.
+pub(crate) fn five_prime_pos(start: i64, reverse: bool, ops: &[Op], hard_clips: bool) -> i64 {
+    if !ops.iter().any(|op| op.kind().consumes_reference()) {
+        return -1;
+    }
+    // Hard-clipped bases are missing from SEQ but were part of the read
+    let is_clip = |op: &&Op| matches!(op.kind(), Kind::SoftClip | Kind::HardClip);
+    let clip_len = |op: &Op| {
+        if hard_clips || op.kind() == Kind::SoftClip {
+            op.len() as i64
+        } else {
+            0
         }
-        Ok(start - clipped)
+    };
+    if !reverse {
+        let clipped: i64 = ops.iter().take_while(is_clip).map(clip_len).sum();
+        start - clipped
     } else {
-        let mut ref_span = 0;
-        for op in cigar.iter() {
-            let op = op?;
-            if op.kind().consumes_reference() {
-                ref_span += op.len() as i32;
-            }
-        }
-        let ops: Vec<_> = cigar.iter().collect::<Result<_, _>>()?;
-        let mut clipped_end = 0;
-        for op in ops.iter().rev() {
-            match op.kind() {
-                Kind::SoftClip | Kind::HardClip => clipped_end += op.len() as i32,
-                _ => break,
-            }
-        }
-        Ok(start + ref_span + clipped_end)
+        let ref_span: i64 = ops
+            .iter()
+            .filter(|op| op.kind().consumes_reference())
+            .map(|op| op.len() as i64)
+            .sum();
+        let clipped_end: i64 = ops.iter().rev().take_while(is_clip).map(clip_len).sum();
+        start + ref_span + clipped_end
     }
 }
 
+/// Lowest base quality counted by [`get_score`], as in Sambamba
+pub const DEFAULT_MIN_BASE_QUALITY: u8 = 15;
+
 /// Calculate the duplicate scoring metric
 ///
 /// Sum of quality scores >= 15. This is used to select the best copy
 /// when multiple duplicates exist.
 #[inline]
 pub fn get_score(record: &bam::Record) -> u32 {
+    get_score_with(record, DEFAULT_MIN_BASE_QUALITY)
+}
+
+/// [`get_score`] counting the qualities >= `min_quality` instead of >= 15
+#[inline]
+pub fn get_score_with(record: &bam::Record, min_quality: u8) -> u32 {
     record
         .quality_scores()
         .as_ref()
         .iter()
-        .map(|&q| u8::from(q))
-        .filter(|&q| q >= 15)
+        .copied()
+        .filter(|&q| q >= min_quality)
         .map(|q| q as u32)
         .sum()
 }
 
+/// How a read's score, which picks the copy to keep, is computed (`--score`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScoreStrategy {
+    /// Sum of the base qualities >= `--min-base-quality`, as Sambamba
+    #[default]
+    #[value(alias = "sum_qual")]
+    SumQual,
+    /// Reference bases covered by the alignment (Picard's
+    /// `TOTAL_MAPPED_REFERENCE_LENGTH`)
+    #[value(alias = "total_mapped_ref_length")]
+    TotalMappedRefLength,
+    /// Mapping quality; a missing MAPQ (255) scores 0
+    Mapq,
+    /// Sum of all base qualities
+    #[value(alias = "sum_all_qual")]
+    SumAllQual,
+}
+
+impl ScoreStrategy {
+    /// Score of `record`; `min_quality` only applies to [`ScoreStrategy::SumQual`]
+    pub fn score(self, record: &bam::Record, min_quality: u8) -> u32 {
+        match self {
+            ScoreStrategy::SumQual => get_score_with(record, min_quality),
+            ScoreStrategy::SumAllQual => get_score_with(record, 0),
+            ScoreStrategy::Mapq => record.mapping_quality().map_or(0, |q| u32::from(q.get())),
+            ScoreStrategy::TotalMappedRefLength => record
+                .cigar()
+                .iter()
+                .filter_map(|op| op.ok())
+                .filter(|op| op.kind().consumes_reference())
+                .map(|op| op.len() as u32)
+                .sum(),
+        }
+    }
+}
+
+/// Count the base qualities of up to `max_reads` records, indexed by quality
+///
+/// Used to check whether `--min-base-quality` separates the quality bins of
+/// a dataset; returns the counts and the number of reads sampled.
+pub fn count_base_qualities(
+    records: impl IntoIterator<Item = std::io::Result<bam::Record>>,
+    max_reads: usize,
+) -> std::io::Result<([u64; 256], usize)> {
+    let mut counts = [0u64; 256];
+    let mut reads = 0;
+    for record in records.into_iter().take(max_reads) {
+        for &q in record?.quality_scores().as_ref() {
+            counts[q as usize] += 1;
+        }
+        reads += 1;
+    }
+    Ok((counts, reads))
+}
+
+/// Key identifying the position of a PE second end: (lib_id, barcode, ref_id,
+/// pos, rev, umi)
+///
+/// `ref_id` and `pos` are the second end's own coordinates, not those of the
+/// pair's first end, so the far mate of an inter-chromosomal pair only
+/// matches fragments on its own chromosome. The map of second ends stores
+/// the best score of a second end at each key.
+pub type SecondEndKey = (i32, u64, i32, i64, u8, u64);
+
+/// Best score of the PE second ends at each [`SecondEndKey`]
+///
+/// The first pass collects second ends in a map; second ends of pairs that
+/// are only joined while merging are kept apart and looked up together
+/// with it as a pair of maps.
+pub trait SecondEnds {
+    /// Best score of a second end at `key`, if there is one
+    fn best(&self, key: &SecondEndKey) -> Option<u32>;
+}
+
+impl SecondEnds for HashMap<SecondEndKey, u32> {
+    #[inline]
+    fn best(&self, key: &SecondEndKey) -> Option<u32> {
+        self.get(key).copied()
+    }
+}
+
+impl<A: SecondEnds + ?Sized, B: SecondEnds + ?Sized> SecondEnds for (&A, &B) {
+    #[inline]
+    fn best(&self, key: &SecondEndKey) -> Option<u32> {
+        self.0.best(key).max(self.1.best(key))
+    }
+}
+
+/// What happens to fragments that share a position with paired reads
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    /// Mark every such fragment, as Sambamba does
+    #[default]
+    Sambamba,
+    /// Keep the best fragment when it outscores every PE end at the position
+    KeepBest,
+}
+
+/// What happens to pairs whose mates lie on different references
+/// (`--inter-chrom-policy`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterChromPolicy {
+    /// Deduplicate them as pairs, by both ends, as Sambamba does
+    #[default]
+    Pair,
+    /// Deduplicate each end on its own, as a fragment (single-end read)
+    Single,
+    /// Leave both ends out of detection and write them unchanged
+    Skip,
+}
+
+/// Read group field whose value separates duplicate groups (`--group-by`)
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Library (`LB`), as Sambamba and Picard
+    #[default]
+    Library,
+    /// Sample (`SM`), so that samples multiplexed in one file are never
+    /// collapsed together, even when they share a library name
+    Sample,
+}
+
+impl GroupBy {
+    /// Read group header tag holding the group name
+    pub fn tag(self) -> [u8; 2] {
+        match self {
+            GroupBy::Library => *b"LB",
+            GroupBy::Sample => *b"SM",
+        }
+    }
+}
+
+/// Tool whose duplicate calls are reproduced (`--compat`)
+///
+/// Picard's `MarkDuplicates` differs from Sambamba only in corner cases:
+/// each read's score is capped at `i16::MAX / 2` so that long reads cannot
+/// overflow Picard's 16-bit scores, and when both ends of a pair share a 5'
+/// coordinate the later read in the input becomes the first end (its index
+/// breaks ties, as Picard's `read1IndexInFile`) and an RF pair is counted as
+/// FR. Scores, the tie-break on the first end's index and the marking of
+/// fragments at pair positions are otherwise the same.
+///
+/// samtools `markdup` (after `fixmate -m`, in its default template mode)
+/// scores a pair by one read's score plus the mate score in its `ms` tag,
+/// keeps the copy whose name sorts first when scores tie (see
+/// [`SamtoolsPolicy`]) and leaves QC-failed reads alone.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compat {
+    /// Sambamba's `markdup`
+    #[default]
+    Sambamba,
+    /// Picard's `MarkDuplicates` with `SUM_OF_BASE_QUALITIES` scoring
+    Picard,
+    /// samtools `markdup`
+    Samtools,
+}
+
+/// Highest score Picard gives one read
+pub const PICARD_MAX_READ_SCORE: u32 = i16::MAX as u32 / 2;
+
+impl Compat {
+    /// Name of the tool for messages, as given to `--compat`
+    pub fn name(self) -> &'static str {
+        match self {
+            Compat::Sambamba => "sambamba",
+            Compat::Picard => "picard",
+            Compat::Samtools => "samtools",
+        }
+    }
+
+    /// The score of one read under this tool, from its sum of qualities
+    #[inline]
+    pub fn read_score(self, score: u32) -> u32 {
+        match self {
+            Compat::Sambamba | Compat::Samtools => score,
+            Compat::Picard => score.min(PICARD_MAX_READ_SCORE),
+        }
+    }
+
+    /// Whether the read at `pos` is the first end of a pair whose mate,
+    /// earlier in the input, is at `mate_pos`; positions are (ref_id, 5' pos)
+    #[inline]
+    pub fn is_first_end(self, pos: (i32, i64), mate_pos: (i32, i64)) -> bool {
+        match self {
+            Compat::Sambamba | Compat::Samtools => pos < mate_pos,
+            Compat::Picard => pos <= mate_pos,
+        }
+    }
+
+    /// Strands of the first and second end of a pair as grouped by this tool
+    ///
+    /// Picard counts a pair whose ends share a coordinate as FR whichever
+    /// end is first.
+    #[inline]
+    pub fn pair_strands(self, same_pos: bool, rev1: u8, rev2: u8) -> (u8, u8) {
+        match self {
+            Compat::Picard if same_pos && (rev1, rev2) == (1, 0) => (0, 1),
+            _ => (rev1, rev2),
+        }
+    }
+}
+
+/// Options controlling duplicate detection within a positional group
+#[derive(Debug, Clone, Default)]
+pub struct DedupOptions {
+    /// Maximum Hamming distance at which UMIs are clustered into one family
+    /// (0 = exact UMI match only)
+    pub umi_edit_distance: u32,
+    /// Treatment of fragments in groups that also hold paired reads
+    pub orphan_policy: OrphanPolicy,
+    /// Group reads by 5' position regardless of strand, so forward and
+    /// reverse fragments at one position are duplicates of each other
+    /// (pairs still only match pairs on the same strands)
+    pub strand_agnostic: bool,
+    /// Largest distance in bases between 5' positions that still counts as
+    /// the same position (0 = exact), for noisy long-read ends
+    pub position_tolerance: u32,
+}
+
+/// Choice of the read (or pair) kept from a set of duplicate copies
+///
+/// `candidates` holds the copies of one molecule within a positional group:
+/// the fragments of a group, or the pairs sharing both ends. The returned
+/// index, which must be below `candidates.len()`, picks the one left
+/// unmarked; every other candidate is marked. The provided method is
+/// Sambamba's rule, so a policy only overrides it to choose differently.
+pub trait DuplicatePolicy {
+    fn choose_representative(&self, candidates: &[&Metadata]) -> usize {
+        let mut best = 0;
+        for i in 1..candidates.len() {
+            if is_better(candidates[i], candidates[best]) {
+                best = i;
+            }
+        }
+        best
+    }
+}
+
+/// Sambamba's policy: keep the highest score, then the first in the input
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SambambaPolicy;
+
+impl DuplicatePolicy for SambambaPolicy {}
+
+/// samtools' policy: keep the highest score, then the smallest read name,
+/// then the first in the input
+///
+/// Names are only carried in [`Metadata::name`] under `--compat samtools`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SamtoolsPolicy;
+
+impl DuplicatePolicy for SamtoolsPolicy {
+    fn choose_representative(&self, candidates: &[&Metadata]) -> usize {
+        (0..candidates.len())
+            .min_by_key(|&i| {
+                let m = candidates[i];
+                (std::cmp::Reverse(m.score), m.name.as_slice(), m.idx1)
+            })
+            .unwrap_or(0)
+    }
+}
+
+/// Duplicate sets found during detection, for the `DI`, `DS` and `MI`
+/// output tags
+///
+/// Maps the record index of every member of a set (representative and
+/// duplicates, both ends of a pair) to the set id and the record index of the
+/// representative, and each set id to the number of copies in the set (a
+/// pair counts once). Set ids are handed out in merge order, so they are the
+/// same on every run over the same input. Orphan fragments, which are marked
+/// because pairs share their position rather than because of a copy of
+/// their own, belong to no set unless `keep-best` kept one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DupSets {
+    sizes: Vec<u32>,
+    members: HashMap<u32, (u32, u64)>,
+}
+
+impl DupSets {
+    /// Record a set of `size` copies whose representative is the read at
+    /// index `rep`
+    pub fn add(&mut self, rep: u64, size: usize, members: impl IntoIterator<Item = u64>) -> u32 {
+        let id = self.sizes.len() as u32;
+        self.sizes.push(size as u32);
+        for idx in members {
+            self.members.insert(idx as u32, (id, rep));
+        }
+        id
+    }
+
+    /// Append the sets of `other`, found after all sets of `self` in merge
+    /// order; their ids are shifted so ids stay unique and in merge order
+    pub fn append(&mut self, other: DupSets) {
+        let offset = self.sizes.len() as u32;
+        self.sizes.extend(other.sizes);
+        self.members.extend(
+            other
+                .members
+                .into_iter()
+                .map(|(idx, (id, rep))| (idx, (id + offset, rep))),
+        );
+    }
+
+    /// Set id and representative index of the read at index `idx`
+    #[inline]
+    pub fn get(&self, idx: u32) -> Option<(u32, u64)> {
+        self.members.get(&idx).copied()
+    }
+
+    /// Number of copies in the set `id`
+    #[inline]
+    pub fn size(&self, id: u32) -> u32 {
+        self.sizes[id as usize]
+    }
+
+    /// Record indices of the representatives of all sets, in no particular
+    /// order and each once per member
+    pub fn representatives(&self) -> impl Iterator<Item = u64> + '_ {
+        self.members.values().map(|&(_, rep)| rep)
+    }
+
+    /// Record indices of all set members, in no particular order
+    pub fn members(&self) -> impl Iterator<Item = u32> + '_ {
+        self.members.keys().copied()
+    }
+
+    /// Number of sets recorded
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+}
+
+/// Call `f` with every positional group of `sorted`
+///
+/// `sorted` must be in `Metadata` order. A group is a maximal run of reads
+/// sharing `lib_id`, `barcode`, `ref_id1`, `pos1` and `rev1`, the unit that
+/// [`identify_dups`] works on.
+pub fn for_each_group(sorted: impl IntoIterator<Item = Metadata>, f: impl FnMut(&[Metadata])) {
+    for_each_group_with(sorted, &DedupOptions::default(), f)
+}
+
+/// [`for_each_group`] with the grouping of `opts`
+///
+/// With `opts.strand_agnostic` a group ignores `rev1`. The two strands of a
+/// position are adjacent in `Metadata` order, so the same sorted stream works
+/// for both groupings.
+pub fn for_each_group_with(
+    sorted: impl IntoIterator<Item = Metadata>,
+    opts: &DedupOptions,
+    mut f: impl FnMut(&[Metadata]),
+) {
+    let strand = |m: &Metadata| if opts.strand_agnostic { 0 } else { m.rev1 };
+    if opts.position_tolerance > 0 {
+        return for_each_window(sorted, strand, opts.position_tolerance as i64, f);
+    }
+    let mut group: Vec<Metadata> = Vec::with_capacity(1000);
+    for m in sorted {
+        if let Some(first) = group.first()
+            && (m.lib_id, m.barcode, m.ref_id1, m.pos1, strand(&m))
+                != (
+                    first.lib_id,
+                    first.barcode,
+                    first.ref_id1,
+                    first.pos1,
+                    strand(first),
+                )
+        {
+            f(&group);
+            group.clear();
+        }
+        group.push(m);
+    }
+    if !group.is_empty() {
+        f(&group);
+    }
+}
+
+/// [`for_each_group_with`] with `opts.position_tolerance`
+///
+/// A group is a window of reads on one strand (of `strand`) whose 5'
+/// positions lie within `tolerance` bases of the first one. Windows are
+/// anchored greedily, so reads 1 apart can still fall into two windows when
+/// the first window started just before them. The reads of a window are
+/// moved onto its first position, and the second ends of its pairs onto the
+/// first of each run within `tolerance`, so the group looks exact to
+/// [`identify_dups`]. PE second ends of other pairs are still only found at
+/// the exact position.
+fn for_each_window(
+    sorted: impl IntoIterator<Item = Metadata>,
+    strand: impl Fn(&Metadata) -> u8,
+    tolerance: i64,
+    mut f: impl FnMut(&[Metadata]),
+) {
+    let mut windows: [Vec<Metadata>; 2] = Default::default();
+    let mut flush = |window: &mut Vec<Metadata>| {
+        if window.is_empty() {
+            return;
+        }
+        let anchor = window[0].pos1;
+        for m in window.iter_mut() {
+            m.pos1 = anchor;
+        }
+        window.sort_unstable();
+        let mut start = 0;
+        for i in 1..window.len() {
+            let (a, m) = (&window[start], &window[i]);
+            if (a.rev1, a.rev2, a.umi, a.ref_id2) != (m.rev1, m.rev2, m.umi, m.ref_id2)
+                || m.pos2 - a.pos2 > tolerance
+            {
+                start = i;
+            }
+            window[i].pos2 = window[start].pos2;
+        }
+        window.sort_unstable();
+        f(window);
+        window.clear();
+    };
+    for m in sorted {
+        // A window ends once a read is past its reach, on either strand
+        for window in windows.iter_mut() {
+            if let Some(first) = window.first()
+                && ((m.lib_id, m.barcode, m.ref_id1)
+                    != (first.lib_id, first.barcode, first.ref_id1)
+                    || m.pos1 - first.pos1 > tolerance)
+            {
+                flush(window);
+            }
+        }
+        windows[strand(&m) as usize].push(m);
+    }
+    for window in windows.iter_mut() {
+        flush(window);
+    }
+}
+
 /// Identify duplicates within a group of reads with the same position
 ///
 /// Returns a tuple of (orphan_count, pe_count, se_only_count) for the group.
@@ -77,15 +615,265 @@ pub fn get_score(record: &bam::Record) -> u32 {
 /// - **orphan**: SE read in a group that also has PE reads
 /// - **pe**: PE read where both reads have another duplicate pair
 /// - **se_only**: SE read where no PE reads exist in the group
-pub fn identify_dups(
-    group: &[super::metadata::Metadata],
+///
+/// Reads only compete with reads carrying the same `umi`; when UMIs are in
+/// use the group is split by UMI and each subgroup is processed on its own.
+pub fn identify_dups<S: SecondEnds + ?Sized>(
+    group: &[Metadata],
+    mask: &mut RoaringBitmap,
+    pe_second_ends: &S,
+) -> (usize, usize, usize) {
+    identify_dups_with(
+        group,
+        mask,
+        pe_second_ends,
+        &DedupOptions::default(),
+        None,
+        None,
+    )
+}
+
+/// Identify duplicates within a group, honouring `opts`
+///
+/// See [`identify_dups`] for the meaning of the returned counts. When `hist`
+/// is given, the size of every duplicate set in the group is recorded in it;
+/// when `sets` is given, every set with at least two copies is added to it.
+pub fn identify_dups_with<S: SecondEnds + ?Sized>(
+    group: &[Metadata],
+    mask: &mut RoaringBitmap,
+    pe_second_ends: &S,
+    opts: &DedupOptions,
+    hist: Option<&mut DupHistogram>,
+    sets: Option<&mut DupSets>,
+) -> (usize, usize, usize) {
+    identify_dups_with_policy(
+        group,
+        mask,
+        pe_second_ends,
+        opts,
+        hist,
+        sets,
+        &SambambaPolicy,
+    )
+}
+
+/// [`identify_dups_with`] choosing representatives with `policy`
+///
+/// The policy is a type parameter, so the default [`SambambaPolicy`] is
+/// compiled in directly; pass a `&dyn DuplicatePolicy` to pick one at run
+/// time.
+pub fn identify_dups_with_policy<S: SecondEnds + ?Sized, P: DuplicatePolicy + ?Sized>(
+    group: &[Metadata],
     mask: &mut RoaringBitmap,
-    pe_second_ends: &HashSet<(i32, i32, i32, u8)>,
+    pe_second_ends: &S,
+    opts: &DedupOptions,
+    mut hist: Option<&mut DupHistogram>,
+    mut sets: Option<&mut DupSets>,
+    policy: &P,
 ) -> (usize, usize, usize) {
     if group.is_empty() {
         return (0, 0, 0);
     }
+    if opts.umi_edit_distance > 0 {
+        return identify_dups_clustered(group, mask, pe_second_ends, opts, hist, sets, policy);
+    }
+    if group.iter().all(|m| m.umi == group[0].umi) {
+        let second_end = best_second_end(pe_second_ends, &group[0], group[0].umi, opts);
+        return identify_dups_exact(
+            group,
+            mask,
+            second_end,
+            opts.orphan_policy,
+            hist,
+            sets,
+            policy,
+        );
+    }
+
+    // Stable sort keeps the comparator order within each UMI
+    let mut by_umi = group.to_vec();
+    by_umi.sort_by_key(|m| m.umi);
+    let mut totals = (0, 0, 0);
+    for sub in by_umi.chunk_by(|a, b| a.umi == b.umi) {
+        let second_end = best_second_end(pe_second_ends, &sub[0], sub[0].umi, opts);
+        let (o, p, s) = identify_dups_exact(
+            sub,
+            mask,
+            second_end,
+            opts.orphan_policy,
+            hist.as_deref_mut(),
+            sets.as_deref_mut(),
+            policy,
+        );
+        totals.0 += o;
+        totals.1 += p;
+        totals.2 += s;
+    }
+    totals
+}
+
+/// Best score of a PE second end at the position of the group headed by
+/// `m`, on either strand when `opts.strand_agnostic` is set
+#[inline]
+fn best_second_end<S: SecondEnds + ?Sized>(
+    pe_second_ends: &S,
+    m: &Metadata,
+    umi: u64,
+    opts: &DedupOptions,
+) -> Option<u32> {
+    let strands: &[u8] = if opts.strand_agnostic {
+        &[0, 1]
+    } else {
+        std::slice::from_ref(&m.rev1)
+    };
+    strands
+        .iter()
+        .filter_map(|&rev| pe_second_ends.best(&(m.lib_id, m.barcode, m.ref_id1, m.pos1, rev, umi)))
+        .max()
+}
+
+/// Duplicate detection with UMI families formed by directional adjacency
+///
+/// Reads are grouped by their raw UMI, the UMIs are clustered with
+/// [`cluster_umis`], and every family is then deduplicated as if its members
+/// shared one UMI. A PE second end only counts towards a family when its UMI
+/// matches one observed among the family's reads.
+fn identify_dups_clustered<S: SecondEnds + ?Sized, P: DuplicatePolicy + ?Sized>(
+    group: &[Metadata],
+    mask: &mut RoaringBitmap,
+    pe_second_ends: &S,
+    opts: &DedupOptions,
+    mut hist: Option<&mut DupHistogram>,
+    mut sets: Option<&mut DupSets>,
+    policy: &P,
+) -> (usize, usize, usize) {
+    let family_of = umi_families(group, opts.umi_edit_distance);
+    let umi_of: HashMap<&[u8], u64> = group
+        .iter()
+        .map(|m| (m.umi_seq.as_slice(), m.umi))
+        .collect();
+
+    // The comparator puts `umi` before the second end, so pairs of one family
+    // that share a second end need not be adjacent; sort them back together.
+    // Stable sort keeps the comparator order within each second end.
+    let mut by_family: Vec<(usize, &Metadata)> = group
+        .iter()
+        .map(|m| (family_of[m.umi_seq.as_slice()], m))
+        .collect();
+    by_family.sort_by_key(|(f, m)| (*f, m.rev1, m.rev2, m.ref_id2, m.pos2, m.tlen));
+
+    let mut totals = (0, 0, 0);
+    for sub in by_family.chunk_by(|a, b| a.0 == b.0) {
+        let members: Vec<Metadata> = sub.iter().map(|(_, m)| (*m).clone()).collect();
+        let second_end = umi_of
+            .iter()
+            .filter(|(seq, _)| family_of[*seq] == sub[0].0)
+            .filter_map(|(_, &umi)| best_second_end(pe_second_ends, &members[0], umi, opts))
+            .max();
+        let (o, p, s) = identify_dups_exact(
+            &members,
+            mask,
+            second_end,
+            opts.orphan_policy,
+            hist.as_deref_mut(),
+            sets.as_deref_mut(),
+            policy,
+        );
+        totals.0 += o;
+        totals.1 += p;
+        totals.2 += s;
+    }
+    totals
+}
+
+/// Family of every UMI sequence in `group` under [`cluster_umis`] with
+/// `max_dist` mismatches, counting each read once
+pub(crate) fn umi_families(group: &[Metadata], max_dist: u32) -> HashMap<&[u8], usize> {
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for m in group {
+        *counts.entry(m.umi_seq.as_slice()).or_default() += 1;
+    }
+    let mut umis: Vec<(&[u8], usize)> = counts.into_iter().collect();
+    umis.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    let families = cluster_umis(&umis, max_dist);
+    umis.iter().map(|u| u.0).zip(families).collect()
+}
+
+/// Cluster UMIs into families using UMI-tools' directional adjacency method
+///
+/// `umis` holds each distinct UMI with its read count, sorted by descending
+/// count. A UMI `a` absorbs `b` when they differ by at most `max_dist`
+/// mismatches and `count(a) >= 2 * count(b) - 1`; families are the nodes
+/// reachable from each still-unassigned UMI, taken in count order. UMIs of
+/// different lengths are never adjacent.
+///
+/// Returns the family index of each UMI, parallel to `umis`.
+pub fn cluster_umis(umis: &[(&[u8], usize)], max_dist: u32) -> Vec<usize> {
+    let mut family = vec![usize::MAX; umis.len()];
+    let mut next_family = 0;
+    for root in 0..umis.len() {
+        if family[root] != usize::MAX {
+            continue;
+        }
+        family[root] = next_family;
+        let mut stack = vec![root];
+        while let Some(a) = stack.pop() {
+            for b in 0..umis.len() {
+                if family[b] == usize::MAX
+                    && umis[a].1 + 1 >= 2 * umis[b].1
+                    && hamming_within(umis[a].0, umis[b].0, max_dist)
+                {
+                    family[b] = next_family;
+                    stack.push(b);
+                }
+            }
+        }
+        next_family += 1;
+    }
+    family
+}
+
+/// Whether two equal-length sequences differ in at most `max_dist` positions
+#[inline]
+fn hamming_within(a: &[u8], b: &[u8], max_dist: u32) -> bool {
+    a.len() == b.len() && a.iter().zip(b).filter(|(x, y)| x != y).count() as u32 <= max_dist
+}
+
+/// Whether `a` should be kept in preference to `b`
+///
+/// The higher score wins; on equal scores the read that comes first in the
+/// input (smaller `idx1`) is kept, for fragments and pairs alike, so the
+/// representative never depends on the order of the group.
+#[inline]
+fn is_better(a: &Metadata, b: &Metadata) -> bool {
+    a.score > b.score || (a.score == b.score && a.idx1 < b.idx1)
+}
+
+/// The fragments of a group split by their 3' end, which is kept in `pos2`
+/// with `--use-3p-end` (otherwise 0, so they all stay together)
+fn fragment_copies<'a>(fragments: &[&'a Metadata]) -> Vec<Vec<&'a Metadata>> {
+    let mut by_end = fragments.to_vec();
+    by_end.sort_by_key(|m| m.pos2);
+    by_end
+        .chunk_by(|a, b| a.pos2 == b.pos2)
+        .map(<[_]>::to_vec)
+        .collect()
+}
 
+/// Duplicate detection for a group whose reads all share one UMI
+///
+/// `second_end` is the best score of a PE second end at this position, if
+/// any sits there.
+#[allow(clippy::too_many_arguments)]
+fn identify_dups_exact<P: DuplicatePolicy + ?Sized>(
+    group: &[Metadata],
+    mask: &mut RoaringBitmap,
+    second_end: Option<u32>,
+    orphan_policy: OrphanPolicy,
+    mut hist: Option<&mut DupHistogram>,
+    mut sets: Option<&mut DupSets>,
+    policy: &P,
+) -> (usize, usize, usize) {
     let mut orphan_marked = 0;
     let mut pe_marked = 0;
     let mut se_only_marked = 0;
@@ -94,21 +882,15 @@ pub fn identify_dups(
 
     // paired_end == 0: fragment (read with unmapped mate or SE read)
     // paired_end == 1: PE second end (mate is also in this group)
-    let paired_0: Vec<_> = ses.iter().filter(|se| se.paired_end == 0).collect();
+    let paired_0: Vec<&Metadata> = ses
+        .iter()
+        .filter(|se| se.paired_end == 0)
+        .copied()
+        .collect();
     let paired_1: Vec<_> = ses.iter().filter(|se| se.paired_end == 1).collect();
 
     let k_pe = pes.len();
-    let group_pos = (
-        group[0].lib_id,
-        group[0].ref_id1,
-        group[0].pos1,
-        group[0].rev1,
-    );
-    let k_pos = if pe_second_ends.contains(&group_pos) {
-        1
-    } else {
-        0
-    };
+    let k_pos = if second_end.is_some() { 1 } else { 0 };
 
     let k = paired_0.len() + paired_1.len();
     let total = k + k_pe + k_pos;
@@ -116,53 +898,109 @@ pub fn identify_dups(
     let seen_fragment = !paired_0.is_empty();
     let seen_paired_read = !paired_1.is_empty() || k_pe > 0 || k_pos > 0;
 
+    let best_fragment = || policy.choose_representative(&paired_0);
+
     // SE-only deduplication logic
     if total >= 2 && seen_fragment {
         if seen_paired_read {
-            // Orphan handling: mark fragments when paired reads exist
-            for se in &paired_0 {
-                mask.insert(se.idx1 as u32);
-                orphan_marked += 1;
+            // Orphan handling: mark fragments when paired reads exist, unless
+            // keep-best lets a fragment outscoring every PE end survive
+            let mut kept = None;
+            if orphan_policy == OrphanPolicy::KeepBest {
+                let best_idx = best_fragment();
+                let best_end = pes
+                    .iter()
+                    .map(|pe| pe.score1)
+                    .chain(paired_1.iter().map(|se| se.score))
+                    .chain(second_end)
+                    .max()
+                    .unwrap_or(0);
+                if paired_0[best_idx].score > best_end {
+                    kept = Some(best_idx);
+                    if paired_0.len() >= 2
+                        && let Some(sets) = sets.as_deref_mut()
+                    {
+                        sets.add(
+                            paired_0[best_idx].idx1,
+                            paired_0.len(),
+                            paired_0.iter().map(|se| se.idx1),
+                        );
+                    }
+                }
             }
-        } else if paired_0.len() >= 2 {
-            // Fragment deduplication: keep highest scoring
-            let mut best_idx = 0;
-            for i in 1..paired_0.len() {
-                if paired_0[i].score > paired_0[best_idx].score {
-                    best_idx = i;
+            for (i, se) in paired_0.iter().enumerate() {
+                if kept != Some(i) {
+                    mask.insert(se.idx1 as u32);
+                    orphan_marked += 1;
                 }
             }
-            for i in 0..paired_0.len() {
-                if i != best_idx {
-                    mask.insert(paired_0[i].idx1 as u32);
-                    se_only_marked += 1;
+        } else if paired_0.len() >= 2 {
+            // Fragment deduplication: keep highest scoring of each 3' end
+            for copies in fragment_copies(&paired_0)
+                .iter()
+                .filter(|copies| copies.len() >= 2)
+            {
+                let best_idx = policy.choose_representative(copies);
+                for (i, se) in copies.iter().enumerate() {
+                    if i != best_idx {
+                        mask.insert(se.idx1 as u32);
+                        se_only_marked += 1;
+                    }
+                }
+                if let Some(sets) = sets.as_deref_mut() {
+                    sets.add(
+                        copies[best_idx].idx1,
+                        copies.len(),
+                        copies.iter().map(|se| se.idx1),
+                    );
                 }
             }
         }
     }
 
-    // PE internal deduplication
-    if pes.len() >= 2 {
+    if let Some(h) = hist.as_deref_mut() {
+        for copies in fragment_copies(&paired_0) {
+            h.add_se(copies.len());
+        }
+    }
+
+    // PE internal deduplication (single pairs only feed the histogram)
+    if pes.len() >= 2 || hist.is_some() {
         let mut i = 0;
         while i < pes.len() {
             let mut j = i + 1;
-            let mut best_idx = i;
             // Find reads with same mate position/orientation
             while j < pes.len()
+                && pes[i].rev1 == pes[j].rev1
                 && pes[i].rev2 == pes[j].rev2
                 && pes[i].ref_id2 == pes[j].ref_id2
                 && pes[i].pos2 == pes[j].pos2
+                && pes[i].tlen == pes[j].tlen
             {
-                if pes[j].score >= pes[best_idx].score {
-                    best_idx = j;
-                }
                 j += 1;
             }
+            let best_idx = if j - i >= 2 {
+                i + policy.choose_representative(&pes[i..j])
+            } else {
+                i
+            };
+            if let Some(h) = hist.as_deref_mut() {
+                h.add_pe(j - i);
+            }
+            if j - i >= 2
+                && let Some(sets) = sets.as_deref_mut()
+            {
+                sets.add(
+                    pes[best_idx].idx1,
+                    j - i,
+                    pes[i..j].iter().flat_map(|pe| [pe.idx1, pe.idx2]),
+                );
+            }
             // Mark all except best scoring
-            for k in i..j {
+            for (k, pe) in pes.iter().enumerate().take(j).skip(i) {
                 if k != best_idx {
-                    mask.insert(pes[k].idx1 as u32);
-                    mask.insert(pes[k].idx2 as u32);
+                    mask.insert(pe.idx1 as u32);
+                    mask.insert(pe.idx2 as u32);
                     pe_marked += 2;
                 }
             }
@@ -176,15 +1014,13 @@ pub fn identify_dups(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::metadata::Metadata;
     use roaring::RoaringBitmap;
-    use std::collections::HashSet;
 
     /// Create a test metadata for SE read
     fn make_se(
         lib_id: i32,
         ref_id: i32,
-        pos: i32,
+        pos: i64,
         rev: u8,
         score: u32,
         idx: u64,
@@ -192,27 +1028,35 @@ mod tests {
     ) -> Metadata {
         Metadata {
             lib_id,
+            barcode: 0,
             ref_id1: ref_id,
             pos1: pos,
             rev1: rev,
             rev2: 0,
+            umi: 0,
             ref_id2: -1,
             pos2: 0,
+            tlen: 0,
             score,
+            score1: score,
             idx1: idx,
             idx2: 0,
             paired_end,
+            umi_seq: Vec::new(),
+            location: None,
+            name: Vec::new(),
         }
     }
 
     /// Create a test metadata for PE read
+    #[allow(clippy::too_many_arguments)]
     fn make_pe(
         lib_id: i32,
         ref_id1: i32,
-        pos1: i32,
+        pos1: i64,
         rev1: u8,
         ref_id2: i32,
-        pos2: i32,
+        pos2: i64,
         rev2: u8,
         score: u32,
         idx1: u64,
@@ -220,23 +1064,142 @@ mod tests {
     ) -> Metadata {
         Metadata {
             lib_id,
+            barcode: 0,
             ref_id1,
             pos1,
             rev1,
             rev2,
+            umi: 0,
             ref_id2,
             pos2,
+            tlen: 0,
             score,
+            score1: score / 2,
             idx1,
             idx2,
             paired_end: 1,
+            umi_seq: Vec::new(),
+            location: None,
+            name: Vec::new(),
         }
     }
 
+    #[test]
+    fn test_for_each_group_boundaries() {
+        let mut sorted = vec![
+            make_se(0, 0, 100, 0, 50, 0, 0),
+            make_pe(0, 0, 100, 0, 0, 300, 1, 60, 1, 2),
+            make_se(0, 0, 100, 1, 50, 3, 0), // other strand
+            make_se(0, 0, 101, 1, 50, 4, 0), // next position
+            make_se(0, 1, 101, 1, 50, 5, 0), // next reference
+            make_se(0, 1, 101, 1, 40, 6, 0),
+            make_se(1, 1, 101, 1, 50, 7, 0), // next library
+            make_se(1, 1, 101, 1, 60, 8, 0).with_barcode(9), // next cell
+        ];
+        sorted.sort();
+        let mut groups = Vec::new();
+        for_each_group(sorted, |group| {
+            groups.push(group.iter().map(|m| m.idx1).collect::<Vec<_>>())
+        });
+        assert_eq!(
+            groups,
+            vec![vec![0, 1], vec![3], vec![4], vec![6, 5], vec![7], vec![8]]
+        );
+
+        let mut calls = 0;
+        for_each_group(Vec::new(), |_| calls += 1);
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_strand_agnostic_groups_fragments_across_strands() {
+        let mut sorted = vec![
+            make_se(0, 0, 100, 0, 50, 0, 0),
+            make_se(0, 0, 100, 1, 60, 1, 0), // same 5' position, reverse strand
+            make_pe(0, 0, 200, 0, 0, 400, 1, 60, 2, 3),
+            make_pe(0, 0, 200, 1, 0, 400, 1, 70, 4, 5), // same ends but rev1
+        ];
+        sorted.sort();
+        let run = |opts: &DedupOptions| {
+            let mask = &mut RoaringBitmap::new();
+            let mut groups = 0;
+            for_each_group_with(sorted.clone(), opts, |group| {
+                identify_dups_with(group, mask, &HashMap::new(), opts, None, None);
+                groups += 1;
+            });
+            (groups, mask.iter().collect::<Vec<_>>())
+        };
+
+        // Strand-aware (Sambamba): four groups, nothing marked
+        assert_eq!(run(&DedupOptions::default()), (4, vec![]));
+
+        // Strand-agnostic: the fragments compete and the lower score is
+        // marked; the pairs share a group but differ in their first strand
+        let opts = DedupOptions {
+            strand_agnostic: true,
+            ..Default::default()
+        };
+        assert_eq!(run(&opts), (2, vec![0]));
+    }
+
+    #[test]
+    fn test_position_tolerance_groups_nearby_ends() {
+        let mut sorted = vec![
+            make_se(0, 0, 100, 0, 50, 0, 0),
+            make_se(0, 0, 103, 0, 60, 1, 0),
+            make_se(0, 0, 101, 1, 40, 2, 0), // reverse strand, its own window
+            make_se(0, 0, 106, 0, 70, 3, 0), // past the window anchored at 100
+            make_se(0, 0, 108, 0, 30, 4, 0),
+            make_pe(0, 0, 200, 0, 0, 400, 1, 60, 5, 6),
+            make_pe(0, 0, 202, 0, 0, 398, 1, 70, 7, 8),
+            make_pe(0, 0, 201, 0, 0, 450, 1, 80, 9, 10), // far second end
+        ];
+        sorted.sort();
+        let run = |position_tolerance| {
+            let opts = DedupOptions {
+                position_tolerance,
+                ..Default::default()
+            };
+            let mask = &mut RoaringBitmap::new();
+            let mut groups = Vec::new();
+            for_each_group_with(sorted.clone(), &opts, |group| {
+                identify_dups_with(group, mask, &HashMap::new(), &opts, None, None);
+                groups.push(group.len());
+            });
+            groups.sort();
+            (groups, mask.iter().collect::<Vec<_>>())
+        };
+
+        assert_eq!(run(0), (vec![1, 1, 1, 1, 1, 1, 1, 1], vec![]));
+        assert_eq!(run(5), (vec![1, 2, 2, 3], vec![0, 4, 5, 6]));
+    }
+
+    #[test]
+    fn test_tlen_splits_pairs_with_same_ends() {
+        // Same 5' positions on both ends; an indel changes the insert size
+        let mut group = vec![
+            make_pe(0, 0, 100, 0, 0, 400, 1, 60, 0, 1).with_tlen(350),
+            make_pe(0, 0, 100, 0, 0, 400, 1, 50, 2, 3).with_tlen(338),
+            make_pe(0, 0, 100, 0, 0, 400, 1, 40, 4, 5).with_tlen(350),
+        ];
+        group.sort();
+        let mask = &mut RoaringBitmap::new();
+        let (_, pe, _) = identify_dups(&group, mask, &HashMap::new());
+        assert_eq!(pe, 2);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [4, 5]);
+
+        // Without template lengths (tlen 0) all three collapse
+        let mut group: Vec<_> = group.into_iter().map(|m| m.with_tlen(0)).collect();
+        group.sort();
+        let mask = &mut RoaringBitmap::new();
+        identify_dups(&group, mask, &HashMap::new());
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [2, 3, 4, 5]);
+    }
+
     #[test]
     fn test_empty_group() {
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&[], mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (0, 0, 0));
     }
@@ -245,7 +1208,7 @@ mod tests {
     fn test_single_read_not_marked() {
         let group = vec![make_se(0, 0, 100, 0, 50, 0, 0)];
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (0, 0, 0));
         assert!(mask.is_empty());
@@ -260,7 +1223,7 @@ mod tests {
             make_se(0, 0, 100, 0, 40, 2, 0),
         ];
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (0, 0, 2));
         assert_eq!(mask.len(), 2);
@@ -275,7 +1238,7 @@ mod tests {
             make_pe(0, 0, 100, 0, 1, 200, 1, 60, 1, 2), // PE
         ];
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (1, 0, 0));
         assert!(mask.contains(0));
@@ -289,7 +1252,7 @@ mod tests {
             make_pe(0, 0, 100, 0, 1, 200, 1, 50, 2, 3), // lower score, marked
         ];
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (0, 2, 0));
         assert_eq!(mask.len(), 2);
@@ -297,6 +1260,194 @@ mod tests {
         assert!(!mask.contains(1));
     }
 
+    #[test]
+    fn test_compat_pairing_and_scores() {
+        assert_eq!(Compat::Sambamba.read_score(20_000), 20_000);
+        assert_eq!(Compat::Picard.read_score(20_000), 16_383);
+        assert_eq!(Compat::Picard.read_score(900), 900);
+
+        // Ties on the coordinate go to the mate for Sambamba, the later read for Picard
+        assert!(!Compat::Sambamba.is_first_end((0, 100), (0, 100)));
+        assert!(Compat::Picard.is_first_end((0, 100), (0, 100)));
+        assert!(Compat::Picard.is_first_end((0, 500), (1, 100)));
+        assert!(!Compat::Picard.is_first_end((1, 100), (0, 500)));
+
+        assert_eq!(Compat::Sambamba.pair_strands(true, 1, 0), (1, 0));
+        assert_eq!(Compat::Picard.pair_strands(true, 1, 0), (0, 1));
+        assert_eq!(Compat::Picard.pair_strands(false, 1, 0), (1, 0));
+        assert_eq!(Compat::Picard.pair_strands(true, 1, 1), (1, 1));
+    }
+
+    #[test]
+    fn test_samtools_policy_breaks_ties_on_names() {
+        let named =
+            |score, idx, name: &[u8]| Metadata::new_se(0, 0, 100, 0, score, idx).with_name(name);
+        let fragments = [
+            named(50, 0, b"r9"),
+            named(60, 1, b"r5"),
+            named(60, 2, b"r10"),
+            named(60, 3, b"r10"),
+        ];
+        let candidates: Vec<&Metadata> = fragments.iter().collect();
+        assert_eq!(SamtoolsPolicy.choose_representative(&candidates), 2);
+        assert_eq!(SambambaPolicy.choose_representative(&candidates), 1);
+    }
+
+    #[test]
+    fn test_dup_sets_link_copies_to_representative() {
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+        let opts = DedupOptions::default();
+        let mut sets = DupSets::default();
+
+        // Two pairs sharing both ends, plus a lone pair that forms no set
+        let pairs = vec![
+            make_pe(0, 0, 100, 0, 1, 200, 1, 50, 0, 1),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 70, 2, 3), // kept
+            make_pe(0, 0, 100, 0, 1, 300, 1, 70, 4, 5),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        identify_dups_with(&pairs, mask, &pe_second_ends, &opts, None, Some(&mut sets));
+        // Fragments only: two copies, the second one kept
+        let fragments = vec![
+            make_se(0, 0, 500, 0, 10, 6, 0),
+            make_se(0, 0, 500, 0, 20, 7, 0),
+        ];
+        identify_dups_with(
+            &fragments,
+            mask,
+            &pe_second_ends,
+            &opts,
+            None,
+            Some(&mut sets),
+        );
+
+        assert_eq!(sets.len(), 2);
+        for idx in 0..4 {
+            assert_eq!(sets.get(idx), Some((0, 2)));
+        }
+        assert_eq!(sets.get(4), None);
+        assert_eq!(sets.get(6), Some((1, 7)));
+        assert_eq!(sets.get(7), Some((1, 7)));
+        assert_eq!((sets.size(0), sets.size(1)), (2, 2));
+    }
+
+    #[test]
+    fn test_custom_policy_keeps_lowest_index() {
+        struct LowestIndex;
+        impl DuplicatePolicy for LowestIndex {
+            fn choose_representative(&self, candidates: &[&Metadata]) -> usize {
+                (0..candidates.len())
+                    .min_by_key(|&i| candidates[i].idx1)
+                    .unwrap()
+            }
+        }
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+        let opts = DedupOptions::default();
+
+        // The best-scoring copies come last and are marked in favour of the
+        // lowest-index ones
+        let fragments = vec![
+            make_se(0, 0, 100, 0, 10, 4, 0),
+            make_se(0, 0, 100, 0, 50, 2, 0),
+            make_se(0, 0, 100, 0, 90, 7, 0),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        identify_dups_with_policy(
+            &fragments,
+            mask,
+            &pe_second_ends,
+            &opts,
+            None,
+            None,
+            &LowestIndex,
+        );
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [4, 7]);
+
+        let pairs = vec![
+            make_pe(0, 0, 100, 0, 1, 200, 1, 90, 6, 7),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 10, 0, 1),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 60, 2, 3),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        let policy: &dyn DuplicatePolicy = &LowestIndex;
+        identify_dups_with_policy(&pairs, mask, &pe_second_ends, &opts, None, None, policy);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [2, 3, 6, 7]);
+
+        // The default policy keeps the best score instead
+        let mask = &mut RoaringBitmap::new();
+        identify_dups_with_policy(
+            &pairs,
+            mask,
+            &pe_second_ends,
+            &opts,
+            None,
+            None,
+            &SambambaPolicy,
+        );
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_equal_scores_keep_lower_index() {
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+
+        // Listed out of index order, so group order alone cannot decide
+        let fragments = vec![
+            make_se(0, 0, 100, 0, 50, 5, 0),
+            make_se(0, 0, 100, 0, 50, 3, 0),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        identify_dups(&fragments, mask, &pe_second_ends);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [5]);
+
+        let pairs = vec![
+            make_pe(0, 0, 100, 0, 1, 200, 1, 60, 2, 3),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 60, 8, 9),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 60, 0, 1),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        identify_dups(&pairs, mask, &pe_second_ends);
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [2, 3, 8, 9]);
+    }
+
+    #[test]
+    fn test_orphan_policy_on_mixed_group() {
+        // Two fragments, a pair whose first end scores 40, and a second end
+        // scoring 45 from a pair that starts further left
+        let group = vec![
+            make_se(0, 0, 100, 0, 70, 0, 0),
+            make_se(0, 0, 100, 0, 30, 1, 0),
+            make_pe(0, 0, 100, 0, 0, 300, 1, 100, 2, 3).with_score1(40),
+        ];
+        let mut pe_second_ends = HashMap::new();
+        pe_second_ends.insert((0, 0, 0, 100, 0, 0), 45);
+
+        let mask = &mut RoaringBitmap::new();
+        let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
+        assert_eq!((orphan, pe, se_only), (2, 0, 0));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [0, 1]);
+
+        // keep-best keeps the 70 fragment: it beats both PE ends (40 and 45)
+        // though not the pair's total of 100
+        let opts = DedupOptions {
+            orphan_policy: OrphanPolicy::KeepBest,
+            ..Default::default()
+        };
+        let mask = &mut RoaringBitmap::new();
+        let mut sets = DupSets::default();
+        let (orphan, pe, se_only) =
+            identify_dups_with(&group, mask, &pe_second_ends, &opts, None, Some(&mut sets));
+        assert_eq!((orphan, pe, se_only), (1, 0, 0));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), [1]);
+        assert_eq!(sets.get(1), Some((0, 0)));
+
+        // A second end scoring 70 ties the fragment, so the fragment is marked
+        pe_second_ends.insert((0, 0, 0, 100, 0, 0), 70);
+        let mask = &mut RoaringBitmap::new();
+        let (orphan, _, _) = identify_dups_with(&group, mask, &pe_second_ends, &opts, None, None);
+        assert_eq!(orphan, 2);
+    }
+
     #[test]
     fn test_different_library_separate() {
         // Note: identify_dups assumes all reads in the group have the same
@@ -308,7 +1459,7 @@ mod tests {
             make_se(0, 0, 100, 0, 60, 1, 0), // same lib_id
         ];
         let mask = &mut RoaringBitmap::new();
-        let pe_second_ends: HashSet<(i32, i32, i32, u8)> = HashSet::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         // Same position, same library, should mark one as duplicate
         assert_eq!((orphan, pe, se_only), (0, 0, 1));
@@ -321,11 +1472,393 @@ mod tests {
         let group = vec![
             make_se(0, 0, 100, 0, 50, 0, 0), // SE fragment
         ];
-        let mut pe_second_ends = HashSet::new();
-        pe_second_ends.insert((0, 0, 100, 0)); // This read IS a PE second end
+        let mut pe_second_ends = HashMap::new();
+        pe_second_ends.insert((0, 0, 0, 100, 0, 0), 30); // This read IS a PE second end
 
         let mask = &mut RoaringBitmap::new();
         let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
         assert_eq!((orphan, pe, se_only), (1, 0, 0));
     }
+
+    #[test]
+    fn test_umi_separates_duplicates() {
+        // Same position, two UMIs: only reads sharing a UMI collapse
+        let group = vec![
+            make_se(0, 0, 100, 0, 50, 0, 0).with_umi(7),
+            make_se(0, 0, 100, 0, 60, 1, 0).with_umi(9),
+            make_se(0, 0, 100, 0, 40, 2, 0).with_umi(7),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+        let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
+        assert_eq!((orphan, pe, se_only), (0, 0, 1));
+        assert!(mask.contains(2));
+        assert!(!mask.contains(1));
+    }
+
+    #[test]
+    fn test_umi_orphan_requires_matching_umi() {
+        // A fragment is only an orphan if a paired read shares its UMI
+        let group = vec![
+            make_se(0, 0, 100, 0, 50, 0, 0).with_umi(7),
+            make_pe(0, 0, 100, 0, 1, 200, 1, 60, 1, 2).with_umi(9),
+        ];
+        let mask = &mut RoaringBitmap::new();
+        let mut pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+        pe_second_ends.insert((0, 0, 0, 100, 0, 9), 30);
+        let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
+        assert_eq!((orphan, pe, se_only), (0, 0, 0));
+        assert!(mask.is_empty());
+    }
+
+    #[test]
+    fn test_umi_edit_distance_clusters_one_mismatch() {
+        // AAAA and AAAT are one mismatch apart and form one family; GGGG stands alone
+        let umi = |m: Metadata, seq: &[u8]| {
+            m.with_umi(crate::utils::hash_barcode(seq))
+                .with_umi_seq(seq)
+        };
+        let group = vec![
+            umi(make_se(0, 0, 100, 0, 50, 0, 0), b"AAAA"),
+            umi(make_se(0, 0, 100, 0, 60, 1, 0), b"AAAT"),
+            umi(make_se(0, 0, 100, 0, 40, 2, 0), b"GGGG"),
+        ];
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+
+        let mask = &mut RoaringBitmap::new();
+        let (orphan, pe, se_only) = identify_dups(&group, mask, &pe_second_ends);
+        assert_eq!((orphan, pe, se_only), (0, 0, 0));
+
+        let opts = DedupOptions {
+            umi_edit_distance: 1,
+            ..Default::default()
+        };
+        let mask = &mut RoaringBitmap::new();
+        let (orphan, pe, se_only) =
+            identify_dups_with(&group, mask, &pe_second_ends, &opts, None, None);
+        assert_eq!((orphan, pe, se_only), (0, 0, 1));
+        assert!(mask.contains(0));
+        assert!(!mask.contains(1));
+        assert!(!mask.contains(2));
+    }
+
+    #[test]
+    fn test_umi_edit_distance_collapses_interleaved_pairs() {
+        // UMI A's pairs at 300 and 500 sort before UMI B's pair at 300, so the
+        // two 300 pairs of the family are not adjacent in comparator order
+        let umi = |m: Metadata, seq: &[u8]| {
+            m.with_umi(crate::utils::hash_barcode(seq))
+                .with_umi_seq(seq)
+        };
+        let group = vec![
+            umi(make_pe(0, 0, 100, 0, 0, 300, 1, 60, 0, 1), b"AAAA"),
+            umi(make_pe(0, 0, 100, 0, 0, 300, 1, 50, 2, 3), b"AAAA"),
+            umi(make_pe(0, 0, 100, 0, 0, 300, 1, 50, 4, 5), b"AAAA"),
+            umi(make_pe(0, 0, 100, 0, 0, 500, 1, 50, 6, 7), b"AAAA"),
+            umi(make_pe(0, 0, 100, 0, 0, 300, 1, 40, 8, 9), b"AAAT"),
+        ];
+        let pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+        let opts = DedupOptions {
+            umi_edit_distance: 1,
+            ..Default::default()
+        };
+        let mask = &mut RoaringBitmap::new();
+        let (orphan, pe, se_only) =
+            identify_dups_with(&group, mask, &pe_second_ends, &opts, None, None);
+        assert_eq!((orphan, pe, se_only), (0, 6, 0));
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![2, 3, 4, 5, 8, 9]);
+    }
+
+    #[test]
+    fn test_cluster_umis_directional() {
+        // A rare UMI is absorbed by an abundant neighbour, but two equally
+        // abundant UMIs above one read each are not merged
+        let umis: Vec<(&[u8], usize)> = vec![(b"AAAA", 10), (b"AAAT", 2), (b"GGGG", 1)];
+        assert_eq!(cluster_umis(&umis, 1), vec![0, 0, 1]);
+
+        let umis: Vec<(&[u8], usize)> = vec![(b"AAAA", 3), (b"AAAT", 3)];
+        assert_eq!(cluster_umis(&umis, 1), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_five_prime_pos_beyond_i32() {
+        // 3 Gbp into a chromosome: 5S90M5S
+        let start = 3_000_000_000i64;
+        let ops = [
+            Op::new(Kind::SoftClip, 5),
+            Op::new(Kind::Match, 90),
+            Op::new(Kind::SoftClip, 5),
+        ];
+        assert_eq!(five_prime_pos(start, false, &ops, true), 2_999_999_995);
+        assert_eq!(five_prime_pos(start, true, &ops, true), 3_000_000_095);
+
+        // A reverse read starting just below i32::MAX must not wrap
+        let start = i32::MAX as i64 - 10;
+        assert_eq!(
+            five_prime_pos(start, true, &ops, true),
+            i32::MAX as i64 + 85
+        );
+    }
+
+    /// Encode a mapped read with the given CIGAR as a BAM record
+    fn encoded_read(reverse: bool, ops: Vec<Op>) -> bam::Record {
+        use noodles::sam::alignment::record::Flags;
+        let flags = if reverse {
+            Flags::REVERSE_COMPLEMENTED
+        } else {
+            Flags::empty()
+        };
+        encoded_read_with(flags, None, ops)
+    }
+
+    fn encoded_read_with(
+        flags: noodles::sam::alignment::record::Flags,
+        mapq: Option<u8>,
+        ops: Vec<Op>,
+    ) -> bam::Record {
+        use noodles::core::Position;
+        use noodles::sam::alignment::io::Write as _;
+        use noodles::sam::alignment::record::MappingQuality;
+        use noodles::sam::alignment::record_buf::{Cigar, RecordBuf, Sequence};
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+
+        let len = std::num::NonZeroUsize::new(10_000).unwrap();
+        let header = noodles::sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .build();
+        let read_len = ops
+            .iter()
+            .filter(|op| op.kind().consumes_read())
+            .map(|op| op.len())
+            .sum();
+        let mut builder = RecordBuf::builder().set_flags(flags);
+        if let Some(mapq) = mapq {
+            builder = builder.set_mapping_quality(MappingQuality::new(mapq).unwrap());
+        }
+        let record = builder
+            .set_reference_sequence_id(0)
+            .set_alignment_start(Position::try_from(1001).unwrap())
+            .set_cigar(Cigar::from(ops))
+            .set_sequence(Sequence::from(vec![b'A'; read_len]))
+            .build();
+        let mut data = Vec::new();
+        bam::io::Writer::from(&mut data)
+            .write_alignment_record(&header, &record)
+            .unwrap();
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&data[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    /// Unmapped read with the given base qualities
+    fn read_with_qualities(qualities: &[u8]) -> bam::Record {
+        use noodles::sam::alignment::io::Write as _;
+        use noodles::sam::alignment::record_buf::{QualityScores, RecordBuf, Sequence};
+
+        let header = noodles::sam::Header::default();
+        let record = RecordBuf::builder()
+            .set_sequence(Sequence::from(vec![b'A'; qualities.len()]))
+            .set_quality_scores(QualityScores::from(qualities.to_vec()))
+            .build();
+        let mut data = Vec::new();
+        bam::io::Writer::from(&mut data)
+            .write_alignment_record(&header, &record)
+            .unwrap();
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&data[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn test_get_score_min_base_quality() {
+        // NovaSeq-style bins: 2, 12, 23 and 37
+        let record = read_with_qualities(&[2, 12, 23, 37, 37, 14, 15]);
+        assert_eq!(get_score(&record), 23 + 37 + 37 + 15);
+        assert_eq!(get_score_with(&record, 15), get_score(&record));
+        assert_eq!(get_score_with(&record, 12), 12 + 23 + 37 + 37 + 14 + 15);
+        assert_eq!(get_score_with(&record, 30), 37 + 37);
+        assert_eq!(get_score_with(&record, 0), 2 + 12 + 23 + 37 + 37 + 14 + 15);
+
+        assert_eq!(
+            ScoreStrategy::SumQual.score(&record, 15),
+            get_score(&record)
+        );
+        assert_eq!(
+            ScoreStrategy::SumAllQual.score(&record, 15),
+            2 + 12 + 23 + 37 + 37 + 14 + 15
+        );
+
+        let records = (0..5).map(|_| Ok(read_with_qualities(&[2, 37, 37])));
+        let (counts, reads) = count_base_qualities(records, 3).unwrap();
+        assert_eq!(reads, 3);
+        assert_eq!(
+            (counts[2], counts[37], counts.iter().sum::<u64>()),
+            (3, 6, 9)
+        );
+    }
+
+    #[test]
+    fn test_get_5p_pos_hard_clips_match_soft_clips() {
+        let m = |n| Op::new(Kind::Match, n);
+        let s = |n| Op::new(Kind::SoftClip, n);
+        let h = |n| Op::new(Kind::HardClip, n);
+
+        // Reverse: 1000 + 50 aligned + 20 trailing clipped bases either way
+        for ops in [
+            vec![m(50), s(20)],
+            vec![m(50), h(20)],
+            vec![m(50), s(5), h(15)],
+        ] {
+            assert_eq!(get_5p_pos(&encoded_read(true, ops)).unwrap(), 1070);
+        }
+        // Leading clips do not move a reverse read's 5' end
+        assert_eq!(
+            get_5p_pos(&encoded_read(true, vec![h(30), m(50)])).unwrap(),
+            1050
+        );
+
+        // Forward: 1000 - 20 leading clipped bases either way
+        for ops in [
+            vec![s(20), m(50)],
+            vec![h(20), m(50)],
+            vec![h(15), s(5), m(50)],
+        ] {
+            assert_eq!(get_5p_pos(&encoded_read(false, ops)).unwrap(), 980);
+        }
+        assert_eq!(
+            get_5p_pos(&encoded_read(false, vec![m(50), h(30)])).unwrap(),
+            1000
+        );
+    }
+
+    #[test]
+    fn test_get_5p_pos_ignoring_hard_clips() {
+        let m = |n| Op::new(Kind::Match, n);
+        let s = |n| Op::new(Kind::SoftClip, n);
+        let h = |n| Op::new(Kind::HardClip, n);
+        let pos = |reverse, ops| get_5p_pos_with(&encoded_read(reverse, ops), false).unwrap();
+
+        // Only the soft-clipped bases move the 5' end
+        assert_eq!(pos(true, vec![m(50), s(20)]), 1070);
+        assert_eq!(pos(true, vec![m(50), h(20)]), 1050);
+        assert_eq!(pos(true, vec![m(50), s(5), h(15)]), 1055);
+        assert_eq!(pos(false, vec![h(20), m(50)]), 1000);
+        assert_eq!(pos(false, vec![h(15), s(5), m(50)]), 995);
+    }
+
+    #[test]
+    fn test_get_3p_pos_is_the_other_end() {
+        let m = |n| Op::new(Kind::Match, n);
+        let s = |n| Op::new(Kind::SoftClip, n);
+        let end = |reverse, ops| get_3p_pos_with(&encoded_read(reverse, ops), true).unwrap();
+
+        assert_eq!(end(false, vec![s(5), m(50), s(20)]), 1070);
+        assert_eq!(end(true, vec![s(5), m(50), s(20)]), 995);
+    }
+
+    #[test]
+    fn test_get_5p_pos_rejects_cigar_without_reference_ops() {
+        let clipped_only = vec![Op::new(Kind::SoftClip, 50)];
+        let insert_and_clip = vec![Op::new(Kind::Insertion, 10), Op::new(Kind::SoftClip, 40)];
+        for ops in [clipped_only, insert_and_clip] {
+            assert_eq!(get_5p_pos(&encoded_read(false, ops.clone())).unwrap(), -1);
+            assert_eq!(get_5p_pos(&encoded_read(true, ops)).unwrap(), -1);
+        }
+
+        // Sanity check on a well-formed 5S45M read
+        let ops = vec![Op::new(Kind::SoftClip, 5), Op::new(Kind::Match, 45)];
+        assert_eq!(get_5p_pos(&encoded_read(false, ops.clone())).unwrap(), 995);
+        assert_eq!(get_5p_pos(&encoded_read(true, ops)).unwrap(), 1045);
+    }
+
+    #[test]
+    fn test_get_5p_pos_long_cigar_in_cg_tag() {
+        // 5S, 35000 x 1M1I, 7S: more than 65535 operations, so BAM stores a
+        // kSmN placeholder and the real CIGAR in the CG tag
+        let mut ops = vec![Op::new(Kind::SoftClip, 5)];
+        for _ in 0..35_000 {
+            ops.extend([Op::new(Kind::Match, 1), Op::new(Kind::Insertion, 1)]);
+        }
+        ops.push(Op::new(Kind::SoftClip, 7));
+
+        // Read from the placeholder (35012S35000N), the 5' ends would be
+        // 1000 - 35012 forward and 36000 reverse
+        let read = encoded_read(false, ops.clone());
+        assert!(read.data().get(b"CG").is_some());
+        assert_eq!(read.cigar().len(), ops.len());
+        assert_eq!(get_5p_pos(&read).unwrap(), 995);
+        assert_eq!(
+            get_5p_pos(&encoded_read(true, ops.clone())).unwrap(),
+            1000 + 35_000 + 7
+        );
+
+        // Re-encoding for the output keeps the CG tag
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+        let len = std::num::NonZeroUsize::new(100_000).unwrap();
+        let header = noodles::sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .build();
+        let bytes = crate::io::record_to_bytes(&header, &read).unwrap();
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&bytes[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded.cigar().len(), ops.len());
+    }
+
+    #[test]
+    fn test_get_5p_pos_mate_unmapped_fragments() {
+        use noodles::sam::alignment::record::Flags;
+        // 2H5S40M3S1H aligned at 1001 (0-based start 1000)
+        let ops = vec![
+            Op::new(Kind::HardClip, 2),
+            Op::new(Kind::SoftClip, 5),
+            Op::new(Kind::Match, 40),
+            Op::new(Kind::SoftClip, 3),
+            Op::new(Kind::HardClip, 1),
+        ];
+        let fragment = Flags::SEGMENTED | Flags::MATE_UNMAPPED;
+        let mate_rev = Flags::MATE_REVERSE_COMPLEMENTED;
+        for mapq in [None, Some(0)] {
+            // Forward: start minus the leading clips, whatever the mate's strand flag says
+            for flags in [fragment, fragment | mate_rev] {
+                let read = encoded_read_with(flags, mapq, ops.clone());
+                assert_eq!(get_5p_pos(&read).unwrap(), 993);
+            }
+            // Reverse: end plus the trailing clips
+            for flags in [fragment, fragment | mate_rev] {
+                let read =
+                    encoded_read_with(flags | Flags::REVERSE_COMPLEMENTED, mapq, ops.clone());
+                assert_eq!(get_5p_pos(&read).unwrap(), 1044);
+            }
+        }
+        // Same positions as a plain single-end read
+        assert_eq!(get_5p_pos(&encoded_read(false, ops.clone())).unwrap(), 993);
+        assert_eq!(get_5p_pos(&encoded_read(true, ops)).unwrap(), 1044);
+    }
+
+    #[test]
+    fn test_score_strategies() {
+        use noodles::sam::alignment::record::Flags;
+        // 5S30M2I10M4D20N8M3S: 30 + 10 + 4 + 20 + 8 reference bases
+        let ops = vec![
+            Op::new(Kind::SoftClip, 5),
+            Op::new(Kind::Match, 30),
+            Op::new(Kind::Insertion, 2),
+            Op::new(Kind::Match, 10),
+            Op::new(Kind::Deletion, 4),
+            Op::new(Kind::Skip, 20),
+            Op::new(Kind::Match, 8),
+            Op::new(Kind::SoftClip, 3),
+        ];
+        let read = encoded_read_with(Flags::empty(), Some(42), ops.clone());
+        assert_eq!(ScoreStrategy::TotalMappedRefLength.score(&read, 15), 72);
+        assert_eq!(ScoreStrategy::Mapq.score(&read, 15), 42);
+        // No MAPQ (255) scores like MAPQ 0
+        assert_eq!(ScoreStrategy::Mapq.score(&encoded_read(false, ops), 15), 0);
+    }
 }
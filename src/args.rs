@@ -1,39 +1,629 @@
 // Command-line argument parsing
-use clap::Parser;
+use crate::algorithm::{
+    Compat, DEFAULT_MIN_BASE_QUALITY, GroupBy, InterChromPolicy, OrphanPolicy, ScoreStrategy,
+};
+use crate::io::{ChunkCodec, IndexFormat, InputFormat, OutputFormat, TaggingPolicy};
+use crate::optical::{DEFAULT_READ_NAME_REGEX, NameParser};
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
 
+/// Top-level command line: a subcommand, or markdup options directly
+///
+/// `rmduprs -i in.bam -o out.bam` is shorthand for
+/// `rmduprs markdup -i in.bam -o out.bam`, so existing command lines keep
+/// working; use [`Cli::markdup_args`] to get the markdup options either way.
 #[derive(Parser, Debug)]
-#[command(name = "rmduprs", about = "Sambamba-consistent MarkDuplicates (Rust)")]
-pub struct Args {
+#[command(
+    name = "rmduprs",
+    about = "Sambamba-consistent MarkDuplicates (Rust)",
+    long_about = None,
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub markdup: Option<Args>,
+}
+
+impl Cli {
+    /// The markdup options, whether given after `markdup` or without a
+    /// subcommand, or the write options of `apply`
+    pub fn markdup_args(self) -> Option<Args> {
+        match self.command {
+            Some(Command::Markdup(args)) => Some(*args),
+            Some(Command::Apply(apply)) => Some(apply.markdup),
+            Some(_) => None,
+            None => self.markdup,
+        }
+    }
+
+    /// The mask file of `apply`
+    pub fn apply_mask(&self) -> Option<&Path> {
+        match &self.command {
+            Some(Command::Apply(apply)) => Some(&apply.mask),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Mark or remove duplicates (the default when no subcommand is given)
+    Markdup(Box<Args>),
+    /// Write the input marked with a mask saved by --write-mask, skipping detection
+    Apply(Box<ApplyArgs>),
+    /// Remove reads with identical sequences from single or paired FASTQ
+    Fastq(FastqArgs),
+    /// Compare DUPLICATE flags of two BAMs holding the same records in the same order
+    Verify(VerifyArgs),
+    /// Print version, allocator and build details for performance reports
+    Info,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ApplyArgs {
+    /// Mask saved by --write-mask from the same input
+    #[arg(long, value_name = "PATH")]
+    pub mask: PathBuf,
+    #[command(flatten)]
+    pub markdup: Args,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct FastqArgs {
+    /// FASTQ of the first ends, plain or gzip-compressed
+    #[arg(short = 'i', long = "in1", value_name = "PATH")]
+    pub in1: PathBuf,
+    /// FASTQ of the second ends, in the same order as --in1
+    #[arg(short = 'I', long = "in2", value_name = "PATH", requires = "out2")]
+    pub in2: Option<PathBuf>,
+    /// Output of the first ends (BGZF-compressed when it ends in .gz)
+    #[arg(short = 'o', long = "out1", value_name = "PATH")]
+    pub out1: PathBuf,
+    /// Output of the second ends
+    #[arg(short = 'O', long = "out2", value_name = "PATH", requires = "in2")]
+    pub out2: Option<PathBuf>,
+    /// Compare only the first N bases of each end
+    #[arg(long, value_name = "N")]
+    pub sequence_prefix: Option<usize>,
+    /// Templates per sorted chunk
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+    /// Directory for the sorted chunks (default: the system temp directory)
+    #[arg(long)]
+    pub tmp_dir: Option<PathBuf>,
+    /// Compression of the sorted chunks
+    #[arg(long, value_enum, default_value_t = ChunkCodec::Lz4)]
+    pub tmp_compression: ChunkCodec,
+    /// Most chunks merged at once
+    #[arg(long, default_value_t = 256)]
+    pub max_merge_fanin: usize,
+    /// Overwrite existing outputs
     #[arg(short, long)]
-    pub input: String,
+    pub force: bool,
+    /// Suppress progress messages; errors are still reported
     #[arg(short, long)]
+    pub quiet: bool,
+}
+
+impl Default for FastqArgs {
+    fn default() -> Self {
+        Self {
+            in1: PathBuf::new(),
+            in2: None,
+            out1: PathBuf::new(),
+            out2: None,
+            sequence_prefix: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            tmp_dir: None,
+            tmp_compression: ChunkCodec::Lz4,
+            max_merge_fanin: 256,
+            force: false,
+            quiet: false,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// BAM marked by rmduprs
+    #[arg(long)]
+    pub ours: PathBuf,
+    /// Reference BAM (e.g. marked by Sambamba)
+    #[arg(long)]
+    pub theirs: PathBuf,
+    /// Number of discordant reads to print
+    #[arg(long, default_value_t = 10)]
+    pub max_report: usize,
+    /// Write the report to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "rmduprs", about = "Sambamba-consistent MarkDuplicates (Rust)")]
+pub struct Args {
+    /// Input BAM (`-` for standard input; pipes are copied to the temp
+    /// directory first); repeat or comma-separate to merge several
+    /// coordinate-sorted BAMs
+    #[arg(short, long, required = true, value_delimiter = ',')]
+    pub input: Vec<String>,
+    /// Output file (`-` for standard output)
+    #[arg(
+        short,
+        long,
+        required_unless_present = "shard_by_chromosome",
+        default_value = "",
+        hide_default_value = true
+    )]
     pub output: String,
+    /// Overwrite the output file if it exists (never allowed when it is an input)
+    #[arg(long)]
+    pub force: bool,
+    /// Output format: BGZF-compressed BAM, plain-text SAM or BGZF-compressed
+    /// SAM; by default taken from the output extension (`.sam`, `.sam.gz`),
+    /// otherwise BAM
+    #[arg(long, visible_alias = "output-fmt", value_enum)]
+    pub output_format: Option<OutputFormat>,
+    /// Input format of every input: BAM, SAM (plain or gzip-compressed) or
+    /// CRAM; by default detected from the first bytes of each file
+    #[arg(long, visible_alias = "input-fmt", value_enum)]
+    pub input_format: Option<InputFormat>,
+    /// Write BGZF output without compression (level 0), for piping into
+    /// another tool
+    #[arg(short = 'u', long)]
+    pub uncompressed: bool,
+    /// BGZF compression level of the output, from 0 (none) to 12 (smallest)
+    #[arg(
+        short = 'l',
+        long,
+        value_name = "LEVEL",
+        value_parser = clap::value_parser!(u8).range(0..=12),
+        conflicts_with = "uncompressed"
+    )]
+    pub compression_level: Option<u8>,
+    /// Write an index of the output to <OUTPUT>.bai or <OUTPUT>.csi
+    /// (coordinate-sorted BAM output to a file only)
+    #[arg(long, conflicts_with = "name_sorted")]
+    pub write_index: bool,
+    /// Index format for --write-index [default: bai, or csi when a reference
+    /// is longer than 2^29 bp]
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "write_index")]
+    pub index_format: Option<IndexFormat>,
     #[arg(short = 'r', long)]
     pub remove_duplicates: bool,
+    /// Write the duplicates to this path (format from its extension) and
+    /// leave only the kept reads in the output
+    #[arg(long, value_name = "PATH")]
+    pub duplicates_output: Option<PathBuf>,
+    /// Write only the duplicates, e.g. to inspect duplicate calls
+    #[arg(long, conflicts_with_all = ["remove_duplicates", "duplicates_output"])]
+    pub only_duplicates: bool,
+    /// Write one output per reference sequence to this directory instead of
+    /// --output: <DIR>/<reference>.bam, and <DIR>/unmapped.bam for reads
+    /// without one
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    pub shard_by_chromosome: Option<PathBuf>,
+    /// Do not add a @PG line for this run to the output header
+    #[arg(long = "no-PG", alias = "no-pg")]
+    pub no_pg: bool,
+    /// Number of threads; 0 means one per CPU
     #[arg(short = 't', long, default_value_t = num_cpus())]
     pub threads: usize,
-    #[arg(long, default_value_t = 2_000_000)]
-    pub batch_size: usize,
+    /// Records per sorted chunk [default: 2000000]; overrides --max-mem
     #[arg(long)]
-    pub tmp_dir: Option<std::path::PathBuf>,
+    pub batch_size: Option<usize>,
+    #[arg(long)]
+    pub tmp_dir: Option<PathBuf>,
     /// Force single-threaded mode (useful for Windows or I/O-bound workloads)
     #[arg(long)]
     pub single_threaded: bool,
+    /// Aux tag holding the UMI (RX when given without a value); reads only
+    /// collapse when their UMIs match exactly. `--barcode-tag` is the former
+    /// name of this option
+    #[arg(
+        long,
+        alias = "barcode-tag",
+        value_name = "TAG",
+        num_args = 0..=1,
+        default_missing_value = "RX",
+        value_parser = parse_tag
+    )]
+    pub umi_tag: Option<[u8; 2]>,
+    /// Aux tag holding the cell barcode (CB when given without a value);
+    /// reads of different cells are never duplicates
+    #[arg(
+        long,
+        value_name = "TAG",
+        num_args = 0..=1,
+        default_missing_value = "CB",
+        value_parser = parse_tag,
+        conflicts_with = "by_sequence"
+    )]
+    pub cell_barcode_tag: Option<[u8; 2]>,
+    /// Aux tag holding another barcode to key duplicates on (e.g. BC for
+    /// inline sample barcodes); reads whose values differ are never duplicates
+    #[arg(long, value_name = "TAG", value_parser = parse_tag, conflicts_with = "by_sequence")]
+    pub key_tag: Option<[u8; 2]>,
+    /// Collapse UMIs differing by up to this many mismatches (directional adjacency)
+    #[arg(long, default_value_t = 0, requires = "umi_tag")]
+    pub umi_edit_distance: u32,
+    /// Most chunk files merged at once; more are pre-merged in groups first
+    #[arg(long, value_name = "N", default_value_t = 256, value_parser = parse_fanin)]
+    pub max_merge_fanin: usize,
+    /// Compression codec for temporary chunk files: none for fast local
+    /// disks, zstd for smaller spill files on slow shared scratch
+    #[arg(long, visible_alias = "tmp-codec", value_enum, default_value_t = ChunkCodec::Lz4)]
+    pub tmp_compression: ChunkCodec,
+    /// Memory budget for the first pass (e.g. 4G); chunks are sized adaptively
+    #[arg(long, value_name = "SIZE", value_parser = parse_mem_size)]
+    pub max_mem: Option<u64>,
+    /// Write a TSV histogram of duplicate set sizes (PE and SE) to this path
+    #[arg(long, value_name = "PATH")]
+    pub histogram: Option<PathBuf>,
+    /// Write a bedGraph of duplicates per --bin-size window to this path
+    #[arg(long, value_name = "PATH")]
+    pub dup_bed: Option<PathBuf>,
+    /// Find duplicates by read sequence instead of position, e.g. in unaligned
+    /// BAM: adjacent mates match on both sequences, other reads on their own
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "tag_duplicate_set", "tag_duplicate_set_size", "tag_molecule_id", "tag_original_name", "histogram", "dup_bed",
+            "dup_loci_bed", "metrics_file", "umi_edit_distance"
+        ]
+    )]
+    pub by_sequence: bool,
+    /// Compare only the first N bases of each read with --by-sequence
+    #[arg(long, value_name = "N", requires = "by_sequence")]
+    pub sequence_prefix: Option<usize>,
+    /// Write a BED of the positional groups holding duplicates (5' position,
+    /// strand, group size, duplicates) to this path
+    #[arg(long, value_name = "PATH")]
+    pub dup_loci_bed: Option<PathBuf>,
+    /// Save the indices of the duplicate records to this path, so the write
+    /// pass can be redone later without detection
+    #[arg(long, value_name = "PATH")]
+    pub write_mask: Option<PathBuf>,
+    /// Write the name of every record marked duplicate, one per line, to this path
+    #[arg(long, value_name = "PATH")]
+    pub dup_names_out: Option<PathBuf>,
+    /// Window size in bases for --dup-bed
+    #[arg(long, value_name = "BASES", default_value_t = 1000, value_parser = parse_bin_size)]
+    pub bin_size: u64,
+    /// Write Picard-style duplication metrics (one row per library) to this path
+    #[arg(long, value_name = "PATH")]
+    pub metrics_file: Option<PathBuf>,
+    /// Write the wall-clock time of each phase (read, sort, merge, write) as JSON to this path
+    #[arg(long, value_name = "PATH")]
+    pub timing_json: Option<PathBuf>,
+    /// Tag every member of a duplicate set with a DI tag holding the set id
+    #[arg(long)]
+    pub tag_duplicate_set: bool,
+    /// Tag every member of a duplicate set with a DS tag holding the number
+    /// of copies in the set
+    #[arg(long)]
+    pub tag_duplicate_set_size: bool,
+    /// Tag every member of a duplicate set with an MI tag holding the set id,
+    /// for consensus callers such as fgbio
+    #[arg(long)]
+    pub tag_molecule_id: bool,
+    /// Tag every duplicate in a duplicate set with a do tag holding the name
+    /// of the read kept, as samtools markdup -t does; takes one more pass
+    /// over the input
+    #[arg(long)]
+    pub tag_original_name: bool,
+    /// Only add DUPLICATE flags; never clear flags already set in the input
+    #[arg(long)]
+    pub additive: bool,
+    /// Give secondary and supplementary alignments the DUPLICATE flag of
+    /// their read's primary alignment, as samtools markdup -S does; takes
+    /// one more pass over the input
+    #[arg(short = 'S', long)]
+    pub mark_supplementary: bool,
+    /// Give an unmapped mate placed by its mapped read the DUPLICATE flag of
+    /// that read, as Picard does (always on with --compat picard); takes one
+    /// more pass over the input
+    #[arg(long)]
+    pub mark_unmapped_mates: bool,
+    /// Ignore a trailing /1 or /2 on read names when pairing mates
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    pub strip_mate_suffix: bool,
+    /// Leave QC-failed reads (0x200) out of duplicate detection and their flags untouched
+    #[arg(long)]
+    pub exclude_qcfail: bool,
+    /// Keep QC-failed reads (0x200) in duplicate detection but score them
+    /// below every read that passed QC, so a passing copy is kept over them
+    /// (by default they are treated like any other read, as Sambamba does)
+    #[arg(long, conflicts_with_all = ["exclude_qcfail", "by_sequence"])]
+    pub include_qcfail: bool,
+    /// Reference sequences (@SQ names) whose reads are neither marked nor
+    /// considered; repeat or comma-separate
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub exclude_ref: Vec<String>,
+    /// Leave reads with a mapping quality below N out of duplicate detection
+    /// and their flags untouched, e.g. MAPQ 0 multi-mapped reads in repeats
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "by_sequence"
+    )]
+    pub min_mapq: u8,
+    /// Records queued between reading and the output writer thread
+    #[arg(long, value_name = "N", default_value_t = 1024)]
+    pub write_queue_depth: usize,
+    /// Library name for read groups without an LB tag (and headers without read groups)
+    #[arg(long, visible_alias = "library-name", value_name = "NAME", default_value = DEFAULT_LIBRARY)]
+    pub default_library: String,
+    /// Treat all reads as one library (named by --default-library) whatever
+    /// their read groups say, e.g. for lanes of one library with per-lane LB
+    #[arg(long)]
+    pub ignore_rg: bool,
+    /// Read group field that separates duplicate groups: library (LB) or
+    /// sample (SM), for multiplexed files whose samples must never be collapsed
+    #[arg(long, value_enum, default_value_t = GroupBy::Library, conflicts_with = "ignore_rg")]
+    pub group_by: GroupBy,
+    /// Pair reads by name only, ignoring the mate CIGAR (MC) tags that
+    /// otherwise let pairs be joined while merging rather than held in memory
+    #[arg(long)]
+    pub no_mate_cigar: bool,
+    /// Input is name-sorted (detected from @HD SO:queryname): pair adjacent
+    /// mates; output keeps the input order
+    #[arg(long)]
+    pub name_sorted: bool,
+    /// Abort when a read name occurs twice for the same end of a pair, or a
+    /// paired read has no name
+    #[arg(long)]
+    pub strict: bool,
+    /// Fragments sharing a position with paired reads: always mark them
+    /// (sambamba) or keep the best one if it outscores every PE end there
+    #[arg(long, value_enum, default_value_t = OrphanPolicy::Sambamba)]
+    pub orphan_policy: OrphanPolicy,
+    /// Pairs with their mates on different references: deduplicate them as
+    /// pairs (the default), each end as a fragment, or skip them
+    #[arg(long, value_enum, default_value_t = InterChromPolicy::Pair, conflicts_with = "by_sequence")]
+    pub inter_chrom_policy: InterChromPolicy,
+    /// Reproduce the duplicate calls of another tool: sambamba (the default),
+    /// picard (MarkDuplicates with SUM_OF_BASE_QUALITIES scoring) or samtools
+    /// (markdup after fixmate -m)
+    #[arg(long, value_enum, default_value_t = Compat::Sambamba, conflicts_with = "by_sequence")]
+    pub compat: Compat,
+    /// How the copy to keep is chosen: sum-qual (qualities >= --min-base-quality),
+    /// total-mapped-ref-length, mapq or sum-all-qual; pairs add both reads' scores
+    #[arg(long, value_enum, default_value_t = ScoreStrategy::SumQual, conflicts_with = "by_sequence")]
+    pub score: ScoreStrategy,
+    /// Lowest base quality counted in a read's score when choosing the copy to keep
+    #[arg(long, value_name = "Q", default_value_t = DEFAULT_MIN_BASE_QUALITY)]
+    pub min_base_quality: u8,
+    /// Log the base quality distribution of the first N reads before the run
+    #[arg(long, value_name = "N")]
+    pub sample_qualities: Option<usize>,
+    /// Only pairs with the same signed template length (TLEN) are duplicates
+    #[arg(long)]
+    pub use_tlen: bool,
+    /// Group fragments by 5' position regardless of strand (Sambamba keeps
+    /// the strands apart)
+    #[arg(long)]
+    pub strand_agnostic: bool,
+    /// Count 5' positions up to N bases apart as the same position, for
+    /// long reads whose ends rarely agree exactly (0 = exact positions)
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "by_sequence"
+    )]
+    pub position_tolerance: u32,
+    /// Place reads by their soft clips alone: hard-clipped bases do not move
+    /// the 5' position (Sambamba, Picard and samtools count both)
+    #[arg(long, conflicts_with = "by_sequence")]
+    pub ignore_hard_clips: bool,
+    /// Tell pairs apart by which read of the template is at each end, so
+    /// F1R2 and F2R1 (or F1F2 and F2F1) pairs are never duplicates
+    #[arg(long, conflicts_with = "by_sequence")]
+    pub orientation_class: bool,
+    /// Key fragments on both ends: single-end reads are duplicates only when
+    /// their 3' ends match too, for long reads that differ in length
+    #[arg(long, conflicts_with = "by_sequence")]
+    pub use_3p_end: bool,
+    /// Count a duplicate as optical when it lies within N pixels of another
+    /// copy on the same tile, by the tile and x/y in the read names (Picard
+    /// uses 100, or 2500 for patterned flowcells)
+    #[arg(long, value_name = "N", conflicts_with = "by_sequence")]
+    pub optical_duplicate_pixel_distance: Option<u32>,
+    /// Regex matching whole read names, with capture groups for the tile, x
+    /// and y; Picard's default, given as is, uses a fast parse of
+    /// Illumina-style names
+    #[arg(long, value_name = "REGEX", default_value = DEFAULT_READ_NAME_REGEX, value_parser = parse_read_name_regex)]
+    pub read_name_regex: String,
+    /// Write reads, duplicates and optical duplicates per flowcell tile as TSV to this path
+    #[arg(long, value_name = "PATH")]
+    pub tile_metrics: Option<PathBuf>,
+    /// Tag duplicates with their type in a DT tag: SQ for optical duplicates
+    /// (needs --optical-duplicate-pixel-distance), LB for the others with `all`
+    #[arg(long, value_enum, default_value_t = TaggingPolicy::DontTag, conflicts_with = "by_sequence")]
+    pub tagging_policy: TaggingPolicy,
+    /// Write progress messages to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Suppress progress messages; errors are still reported
+    #[arg(short, long)]
+    pub quiet: bool,
 }
 
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            input: Vec::new(),
+            output: String::new(),
+            force: false,
+            output_format: None,
+            input_format: None,
+            uncompressed: false,
+            compression_level: None,
+            write_index: false,
+            index_format: None,
+            remove_duplicates: false,
+            duplicates_output: None,
+            only_duplicates: false,
+            shard_by_chromosome: None,
+            no_pg: false,
+            threads: num_cpus(),
+            batch_size: None,
+            tmp_dir: None,
+            single_threaded: false,
+            umi_tag: None,
+            cell_barcode_tag: None,
+            key_tag: None,
+            umi_edit_distance: 0,
+            max_merge_fanin: 256,
+            tmp_compression: ChunkCodec::Lz4,
+            max_mem: None,
+            histogram: None,
+            dup_bed: None,
+            dup_names_out: None,
+            dup_loci_bed: None,
+            write_mask: None,
+            by_sequence: false,
+            sequence_prefix: None,
+            bin_size: 1000,
+            metrics_file: None,
+            timing_json: None,
+            tag_duplicate_set: false,
+            tag_duplicate_set_size: false,
+            tag_molecule_id: false,
+            tag_original_name: false,
+            additive: false,
+            mark_supplementary: false,
+            mark_unmapped_mates: false,
+            strip_mate_suffix: true,
+            exclude_qcfail: false,
+            include_qcfail: false,
+            min_mapq: 0,
+            exclude_ref: Vec::new(),
+            write_queue_depth: 1024,
+            default_library: DEFAULT_LIBRARY.to_string(),
+            ignore_rg: false,
+            group_by: GroupBy::Library,
+            no_mate_cigar: false,
+            name_sorted: false,
+            strict: false,
+            orphan_policy: OrphanPolicy::Sambamba,
+            inter_chrom_policy: InterChromPolicy::Pair,
+            compat: Compat::Sambamba,
+            score: ScoreStrategy::SumQual,
+            min_base_quality: DEFAULT_MIN_BASE_QUALITY,
+            strand_agnostic: false,
+            position_tolerance: 0,
+            ignore_hard_clips: false,
+            orientation_class: false,
+            use_3p_end: false,
+            optical_duplicate_pixel_distance: None,
+            read_name_regex: DEFAULT_READ_NAME_REGEX.to_string(),
+            tile_metrics: None,
+            tagging_policy: TaggingPolicy::DontTag,
+            use_tlen: false,
+            sample_qualities: None,
+            log_file: None,
+            quiet: false,
+        }
+    }
+}
+
+/// Records per chunk when neither `--batch-size` nor `--max-mem` is given
+pub const DEFAULT_BATCH_SIZE: usize = 2_000_000;
+
+/// Library name of reads without an LB unless `--library-name` gives one
+pub const DEFAULT_LIBRARY: &str = "unknown";
+
 pub fn num_cpus() -> usize {
     std::thread::available_parallelism()
         .map(|n| n.get())
         .unwrap_or(4)
 }
 
+/// Most threads used per available CPU; larger `--threads` values are capped
+pub const MAX_THREADS_PER_CPU: usize = 4;
+
 /// Get effective thread count based on args and platform
+///
+/// `--threads 0` means one per CPU, and more than [`MAX_THREADS_PER_CPU`]
+/// per CPU only oversubscribes, so the count is capped there. The result is
+/// always at least 1, and exactly 1 with `--single-threaded`.
 #[inline]
 pub fn effective_threads(args: &Args) -> usize {
     if args.single_threaded {
         1
+    } else if args.threads == 0 {
+        num_cpus()
     } else {
-        args.threads
+        args.threads.min(MAX_THREADS_PER_CPU * num_cpus()).max(1)
+    }
+}
+
+/// Parse a two-character SAM aux tag such as `RX`
+pub fn parse_tag(s: &str) -> Result<[u8; 2], String> {
+    match s.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([*a, *b]),
+        _ => Err(format!(
+            "invalid tag '{}': expected two characters like RX",
+            s
+        )),
+    }
+}
+
+/// Check a `--read-name-regex` pattern: it must compile and have three
+/// capture groups
+pub fn parse_read_name_regex(s: &str) -> Result<String, String> {
+    NameParser::new(s)
+        .map(|_| s.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Parse a merge fan-in, which must be at least 2
+pub fn parse_fanin(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n >= 2 => Ok(n),
+        _ => Err(format!(
+            "invalid fan-in '{}': expected a whole number of at least 2",
+            s
+        )),
+    }
+}
+
+/// Parse a `--bin-size` window width, which must be at least one base
+pub fn parse_bin_size(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(n) if n >= 1 => Ok(n),
+        _ => Err(format!(
+            "invalid bin size '{}': expected a whole number of bases, at least 1",
+            s
+        )),
+    }
+}
+
+/// Parse a memory size such as `4G`, `512M`, `1.5GiB` or `1000000` (bytes)
+///
+/// Suffixes K, M, G and T are binary multiples (1K = 1024 bytes).
+pub fn parse_mem_size(s: &str) -> Result<u64, String> {
+    let t = s.trim();
+    let upper = t.to_ascii_uppercase();
+    let digits = upper.trim_end_matches("IB").trim_end_matches('B');
+    let (num, shift) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 10),
+        Some('M') => (&digits[..digits.len() - 1], 20),
+        Some('G') => (&digits[..digits.len() - 1], 30),
+        Some('T') => (&digits[..digits.len() - 1], 40),
+        _ => (digits, 0),
+    };
+    match num.trim().parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok((n * (1u64 << shift) as f64) as u64),
+        _ => Err(format!(
+            "invalid memory size '{}': expected e.g. 4G or 512M",
+            s
+        )),
     }
 }
 
@@ -44,29 +634,335 @@ mod tests {
     #[test]
     fn test_args_default_threads() {
         let args = Args {
-            input: "test.bam".to_string(),
+            input: vec!["test.bam".to_string()],
             output: "out.bam".to_string(),
-            remove_duplicates: false,
             threads: 4,
-            batch_size: 2_000_000,
-            tmp_dir: None,
-            single_threaded: false,
+            ..Default::default()
         };
-        assert_eq!(args.input, "test.bam");
+        assert_eq!(args.input, ["test.bam"]);
         assert_eq!(effective_threads(&args), 4);
     }
 
     #[test]
     fn test_single_threaded_flag() {
         let args = Args {
-            input: "test.bam".to_string(),
+            input: vec!["test.bam".to_string()],
             output: "out.bam".to_string(),
-            remove_duplicates: false,
             threads: 8,
-            batch_size: 2_000_000,
-            tmp_dir: None,
             single_threaded: true,
+            ..Default::default()
         };
         assert_eq!(effective_threads(&args), 1);
+
+        for threads in [0, usize::MAX] {
+            let args = Args {
+                threads,
+                single_threaded: true,
+                ..Default::default()
+            };
+            assert_eq!(effective_threads(&args), 1);
+        }
+    }
+
+    #[test]
+    fn test_effective_threads_auto_and_cap() {
+        let threads = |threads: usize| {
+            effective_threads(&Args {
+                threads,
+                ..Default::default()
+            })
+        };
+        assert_eq!(threads(0), num_cpus());
+        assert_eq!(threads(1), 1);
+        assert_eq!(threads(usize::MAX), MAX_THREADS_PER_CPU * num_cpus());
+        assert_eq!(threads(100_000), MAX_THREADS_PER_CPU * num_cpus());
+        let args = Args::parse_from(["rmduprs", "-i", "a.bam", "-o", "b.bam", "-t", "0"]);
+        assert_eq!(effective_threads(&args), num_cpus());
+    }
+
+    #[test]
+    fn test_compression_level_parsing() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert_eq!(parse(&[]).unwrap().compression_level, None);
+        assert_eq!(parse(&["-l", "0"]).unwrap().compression_level, Some(0));
+        assert_eq!(
+            parse(&["--compression-level", "12"])
+                .unwrap()
+                .compression_level,
+            Some(12)
+        );
+        assert!(parse(&["-l", "13"]).is_err());
+        assert!(parse(&["-l", "1", "-u"]).is_err());
+    }
+
+    #[test]
+    fn test_write_index_conflicts_with_name_sorted() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(parse(&["--write-index"]).unwrap().write_index);
+        assert!(parse(&["--write-index", "--name-sorted"]).is_err());
+    }
+
+    #[test]
+    fn test_shard_by_chromosome_replaces_output() {
+        let parse =
+            |args: &[&str]| Args::try_parse_from(["rmduprs", "-i", "a.bam"].iter().chain(args));
+        let args = parse(&["--shard-by-chromosome", "shards"]).unwrap();
+        assert_eq!(args.shard_by_chromosome, Some(PathBuf::from("shards")));
+        assert_eq!(args.output, "");
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-o", "b.bam", "--shard-by-chromosome", "shards"]).is_err());
+    }
+
+    #[test]
+    fn test_no_pg_spellings() {
+        let parse = |extra: &[&str]| {
+            Args::parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(!parse(&[]).no_pg);
+        assert!(parse(&["--no-PG"]).no_pg);
+        assert!(parse(&["--no-pg"]).no_pg);
+    }
+
+    #[test]
+    fn test_by_sequence_options() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        let args = parse(&["--by-sequence", "--sequence-prefix", "50"]).unwrap();
+        assert!(args.by_sequence);
+        assert_eq!(args.sequence_prefix, Some(50));
+        assert!(parse(&["--sequence-prefix", "50"]).is_err());
+        assert!(parse(&["--by-sequence", "--tag-duplicate-set"]).is_err());
+        assert!(parse(&["--by-sequence", "--dup-bed", "d.bed"]).is_err());
+    }
+
+    #[test]
+    fn test_fastq_subcommand() {
+        let cli = Cli::try_parse_from([
+            "rmduprs", "fastq", "-i", "r1.fq", "-I", "r2.fq", "-o", "o1.fq", "-O", "o2.fq",
+        ])
+        .unwrap();
+        let Some(Command::Fastq(args)) = cli.command else {
+            panic!("not the fastq subcommand")
+        };
+        assert_eq!(args.in2, Some(PathBuf::from("r2.fq")));
+        assert_eq!(args.batch_size, DEFAULT_BATCH_SIZE);
+        assert!(Cli::try_parse_from(["rmduprs", "fastq", "-i", "r1.fq", "-o", "o1.fq"]).is_ok());
+        assert!(
+            Cli::try_parse_from([
+                "rmduprs", "fastq", "-i", "r1.fq", "-I", "r2.fq", "-o", "o1.fq"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tmp_codec_alias() {
+        let parse = |extra: &[&str]| {
+            Args::parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert_eq!(parse(&[]).tmp_compression, ChunkCodec::Lz4);
+        assert_eq!(
+            parse(&["--tmp-codec", "none"]).tmp_compression,
+            ChunkCodec::None
+        );
+        assert_eq!(
+            parse(&["--tmp-compression", "zstd"]).tmp_compression,
+            ChunkCodec::Zstd
+        );
+    }
+
+    #[test]
+    fn test_library_name_alias() {
+        let parse = |extra: &[&str]| {
+            Args::parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert_eq!(parse(&[]).default_library, DEFAULT_LIBRARY);
+        assert_eq!(parse(&["--library-name", "lib1"]).default_library, "lib1");
+        assert_eq!(
+            parse(&["--default-library", "lib2"]).default_library,
+            "lib2"
+        );
+    }
+
+    #[test]
+    fn test_only_duplicates_conflicts_with_removal() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert!(parse(&["--only-duplicates"]).unwrap().only_duplicates);
+        assert!(parse(&["--only-duplicates", "-r"]).is_err());
+        assert!(parse(&["--only-duplicates", "--duplicates-output", "d.bam"]).is_err());
+    }
+
+    #[test]
+    fn test_umi_tag_parsing() {
+        let parse = |extra: &[&str]| {
+            Args::parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert_eq!(parse(&[]).umi_tag, None);
+        assert_eq!(parse(&["--umi-tag", "BX"]).umi_tag, Some(*b"BX"));
+        assert_eq!(parse(&["--umi-tag"]).umi_tag, Some(*b"RX"));
+        assert_eq!(
+            parse(&["--umi-tag", "--umi-edit-distance", "1"]).umi_tag,
+            Some(*b"RX")
+        );
+        // Command lines from before the rename still key on the UMI
+        let old = parse(&["--barcode-tag", "RX", "--umi-edit-distance", "1"]);
+        assert_eq!(
+            (old.umi_tag, old.key_tag, old.umi_edit_distance),
+            (Some(*b"RX"), None, 1)
+        );
+        assert_eq!(
+            parse(&["--cell-barcode-tag"]).cell_barcode_tag,
+            Some(*b"CB")
+        );
+        assert_eq!(
+            parse(&["--cell-barcode-tag", "XC"]).cell_barcode_tag,
+            Some(*b"XC")
+        );
+        assert_eq!(parse(&["--key-tag", "BC"]).key_tag, Some(*b"BC"));
+        assert!(
+            Args::try_parse_from(["rmduprs", "-i", "in.bam", "-o", "out.bam", "--key-tag"])
+                .is_err()
+        );
+        assert!(parse_tag("R").is_err());
+        assert!(parse_tag("RXX").is_err());
+        assert!(parse_tag("1X").is_err());
+    }
+
+    #[test]
+    fn test_read_name_regex_parsing() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                ["rmduprs", "-i", "a.bam", "-o", "b.bam"]
+                    .iter()
+                    .chain(extra),
+            )
+        };
+        assert_eq!(parse(&[]).unwrap().read_name_regex, DEFAULT_READ_NAME_REGEX);
+        let args = parse(&["--read-name-regex", r"\w+_(\d+)_(\d+)_(\d+)"]).unwrap();
+        assert_eq!(args.read_name_regex, r"\w+_(\d+)_(\d+)_(\d+)");
+        assert!(parse(&["--read-name-regex", "(\\d+)"]).is_err());
+        assert!(parse(&["--read-name-regex", "((\\d+)"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_markdup_and_verify() {
+        let cli = Cli::parse_from(["rmduprs", "-i", "a.bam", "-o", "b.bam"]);
+        assert!(cli.command.is_none());
+        assert_eq!(cli.markdup.unwrap().input, ["a.bam"]);
+
+        let cli = Cli::parse_from(["rmduprs", "-i", "a.bam,b.bam", "-i", "c.bam", "-o", "d.bam"]);
+        assert_eq!(cli.markdup.unwrap().input, ["a.bam", "b.bam", "c.bam"]);
+
+        let cli = Cli::parse_from(["rmduprs", "verify", "--ours", "a.bam", "--theirs", "b.bam"]);
+        match cli.command {
+            Some(Command::Verify(v)) => {
+                assert_eq!(v.ours, PathBuf::from("a.bam"));
+                assert_eq!(v.max_report, 10);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cli_markdup_subcommand() {
+        let cli = Cli::parse_from(["rmduprs", "markdup", "-i", "a", "-o", "b"]);
+        assert!(matches!(cli.command, Some(Command::Markdup(_))));
+        let args = cli.markdup_args().unwrap();
+        assert_eq!(args.input, ["a"]);
+        assert_eq!(args.output, "b");
+        assert_eq!(args.threads, num_cpus());
+        assert_eq!(args.batch_size, None);
+        assert!(args.strip_mate_suffix);
+
+        // The implicit form parses to the same options
+        let implicit = Cli::parse_from(["rmduprs", "-i", "a", "-o", "b"])
+            .markdup_args()
+            .unwrap();
+        assert_eq!(format!("{:?}", implicit), format!("{:?}", args));
+
+        let verify = Cli::parse_from(["rmduprs", "verify", "--ours", "a", "--theirs", "b"]);
+        assert!(verify.apply_mask().is_none());
+        assert!(verify.markdup_args().is_none());
+
+        let apply = Cli::parse_from([
+            "rmduprs", "apply", "--mask", "m.rmd", "-i", "a", "-o", "b", "-r",
+        ]);
+        assert_eq!(apply.apply_mask(), Some(Path::new("m.rmd")));
+        let args = apply.markdup_args().unwrap();
+        assert_eq!(
+            (
+                args.input.as_slice(),
+                args.output.as_str(),
+                args.remove_duplicates
+            ),
+            (&["a".to_string()][..], "b", true)
+        );
+        assert!(Cli::try_parse_from(["rmduprs", "apply", "-i", "a", "-o", "b"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_fanin() {
+        assert_eq!(parse_fanin("256"), Ok(256));
+        assert_eq!(parse_fanin("2"), Ok(2));
+        assert!(parse_fanin("1").is_err());
+        assert!(parse_fanin("many").is_err());
+    }
+
+    #[test]
+    fn test_parse_bin_size() {
+        assert_eq!(parse_bin_size("1000"), Ok(1000));
+        assert_eq!(parse_bin_size("1"), Ok(1));
+        assert!(parse_bin_size("0").is_err());
+        assert!(parse_bin_size("1kb").is_err());
+    }
+
+    #[test]
+    fn test_parse_mem_size() {
+        assert_eq!(parse_mem_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_mem_size("512m"), Ok(512 << 20));
+        assert_eq!(parse_mem_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_mem_size("1000000"), Ok(1_000_000));
+        assert!(parse_mem_size("G").is_err());
+        assert!(parse_mem_size("-1G").is_err());
+        assert!(parse_mem_size("4X").is_err());
     }
 }
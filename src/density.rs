@@ -0,0 +1,116 @@
+//! Duplicate density along the genome
+//!
+//! Counts the duplicate reads marked in each window of `--bin-size` bases and
+//! writes the windows holding any as a bedGraph (`chrom`, 0-based `start`,
+//! `end`, count), in header order. Reads are binned at the 5' position of the
+//! group they were marked in, so both ends of a marked pair count at the
+//! pair's first end.
+
+use anyhow::Result;
+use noodles::sam;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Number of duplicates per (reference id, window)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DupDensity {
+    bin_size: u64,
+    bins: BTreeMap<(i32, u64), u64>,
+}
+
+impl DupDensity {
+    /// Create an empty density with windows of `bin_size` bases
+    pub fn new(bin_size: u64) -> Self {
+        Self {
+            bin_size: bin_size.max(1),
+            bins: BTreeMap::new(),
+        }
+    }
+
+    /// Record `count` duplicates at the 1-based position `pos` of `ref_id`
+    ///
+    /// A 5' position before the start of the reference (soft clips at the
+    /// very start) falls in the first window.
+    #[inline]
+    pub fn add(&mut self, ref_id: i32, pos: i64, count: u64) {
+        if count == 0 {
+            return;
+        }
+        let bin = (pos.max(1) - 1) as u64 / self.bin_size;
+        *self.bins.entry((ref_id, bin)).or_insert(0) += count;
+    }
+
+    /// Add the counts of `other` to these
+    pub fn merge(&mut self, other: &DupDensity) {
+        for (key, count) in &other.bins {
+            *self.bins.entry(*key).or_insert(0) += count;
+        }
+    }
+
+    /// Write the non-empty windows as bedGraph lines
+    ///
+    /// The last window of a reference ends at the reference length given by
+    /// its `@SQ` line.
+    pub fn write_bedgraph<W: Write>(&self, w: &mut W, header: &sam::Header) -> Result<()> {
+        let refs = header.reference_sequences();
+        for (&(ref_id, bin), count) in &self.bins {
+            let Some((name, map)) = usize::try_from(ref_id).ok().and_then(|i| refs.get_index(i))
+            else {
+                continue;
+            };
+            let start = bin * self.bin_size;
+            let end = (start + self.bin_size)
+                .min(map.length().get() as u64)
+                .max(start + 1);
+            writeln!(w, "{}\t{}\t{}\t{}", name, start, end, count)?;
+        }
+        Ok(())
+    }
+
+    /// Write the bedGraph to `path`
+    pub fn save(&self, path: &Path, header: &sam::Header) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_bedgraph(&mut out, header)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_bedgraph_bins() {
+        let header = sam::Header::builder()
+            .add_reference_sequence(
+                "chr1",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(2500).unwrap()),
+            )
+            .add_reference_sequence(
+                "chrM",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(16569).unwrap()),
+            )
+            .build();
+        let mut density = DupDensity::new(1000);
+        density.add(1, 301, 4);
+        density.add(0, 1500, 3);
+        density.add(0, 2000, 2);
+        density.add(0, 2001, 1);
+        density.add(0, -3, 1);
+        density.add(0, 700, 0);
+        let mut other = DupDensity::new(1000);
+        other.add(0, 1001, 1);
+        density.merge(&other);
+
+        let mut out = Vec::new();
+        density.write_bedgraph(&mut out, &header).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "chr1\t0\t1000\t1\nchr1\t1000\t2000\t6\nchr1\t2000\t2500\t1\nchrM\t0\t1000\t4\n"
+        );
+    }
+}
@@ -0,0 +1,485 @@
+//! Duplicate removal from FASTQ (`rmduprs fastq`)
+//!
+//! Amplicon and other pre-alignment workflows want copies removed before
+//! any alignment exists. Reads are grouped by their bases as with
+//! `--by-sequence`: a pair by the sequences of both ends, a single-end read
+//! by its own, optionally only over the first `--sequence-prefix` bases. In
+//! every set of identical templates the one with the highest score (sum of
+//! base qualities >= 15, Phred+33) is kept, the first in the file on a tie,
+//! and the others are left out of the output.
+//!
+//! The keys go through the same external sort as BAM input, so memory stays
+//! bounded by `--batch-size`. The inputs are read twice, once to find the
+//! duplicates and once to write the rest, so they must be regular files.
+//! Gzip and BGZF inputs are decompressed; an output path ending in `.gz` is
+//! written as BGZF, which any gzip reader accepts.
+
+use crate::algorithm::DEFAULT_MIN_BASE_QUALITY;
+use crate::args::FastqArgs;
+use crate::io;
+use crate::logln;
+use crate::metadata::Metadata;
+use crate::sequence::{hash_bases, mark_identical};
+use crate::utils::{format_duration, strip_mate_suffix};
+use anyhow::{Result, bail};
+use flate2::read::MultiGzDecoder;
+use noodles::bgzf::io::Writer as BgzfWriter;
+use roaring::RoaringBitmap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tempfile::Builder;
+
+/// One FASTQ record, its four lines without line ends
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FastqRecord {
+    /// Header line, starting with `@`
+    pub head: Vec<u8>,
+    pub sequence: Vec<u8>,
+    /// Separator line, starting with `+`
+    pub plus: Vec<u8>,
+    pub quality: Vec<u8>,
+}
+
+impl FastqRecord {
+    /// The read name: the header up to the first whitespace, without `@`
+    /// and a `/1` or `/2` mate suffix
+    pub fn name(&self) -> &[u8] {
+        let head = self.head.get(1..).unwrap_or_default();
+        let end = head
+            .iter()
+            .position(u8::is_ascii_whitespace)
+            .unwrap_or(head.len());
+        strip_mate_suffix(&head[..end])
+    }
+
+    /// Sum of the base qualities >= 15, as [`crate::algorithm::get_score`]
+    pub fn score(&self) -> u32 {
+        self.quality
+            .iter()
+            .map(|&q| q.saturating_sub(33))
+            .filter(|&q| q >= DEFAULT_MIN_BASE_QUALITY)
+            .map(u32::from)
+            .sum()
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for line in [&self.head, &self.sequence, &self.plus, &self.quality] {
+            w.write_all(line)?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reader of four-line FASTQ records
+pub struct FastqReader<R> {
+    inner: R,
+    path: PathBuf,
+    line: u64,
+}
+
+impl<R: BufRead> FastqReader<R> {
+    pub fn new(inner: R, path: &Path) -> Self {
+        Self {
+            inner,
+            path: path.to_path_buf(),
+            line: 0,
+        }
+    }
+
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> Result<bool> {
+        buf.clear();
+        if self.inner.read_until(b'\n', buf)? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+        Ok(true)
+    }
+
+    /// Read the next record into `record`; false at the end of the file
+    pub fn read_record(&mut self, record: &mut FastqRecord) -> Result<bool> {
+        if !self.read_line(&mut record.head)? {
+            return Ok(false);
+        }
+        if record.head.first() != Some(&b'@') {
+            bail!(
+                "{}:{}: expected a FASTQ header starting with '@'",
+                self.path.display(),
+                self.line
+            );
+        }
+        let complete = self.read_line(&mut record.sequence)?
+            && self.read_line(&mut record.plus)?
+            && self.read_line(&mut record.quality)?;
+        if !complete {
+            bail!(
+                "{}:{}: truncated FASTQ record",
+                self.path.display(),
+                self.line
+            );
+        }
+        if record.plus.first() != Some(&b'+') {
+            bail!(
+                "{}:{}: expected a '+' separator line",
+                self.path.display(),
+                self.line - 1
+            );
+        }
+        if record.sequence.len() != record.quality.len() {
+            bail!(
+                "{}:{}: {} bases but {} qualities",
+                self.path.display(),
+                self.line,
+                record.sequence.len(),
+                record.quality.len()
+            );
+        }
+        Ok(true)
+    }
+}
+
+/// A FASTQ file opened by [`open_fastq`]
+pub type FastqFileReader = FastqReader<Box<dyn BufRead>>;
+
+/// Open a FASTQ file, decompressing it when it starts with the gzip magic
+pub fn open_fastq(path: &Path) -> Result<FastqFileReader> {
+    let file = File::open(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let gzip = reader.fill_buf()?.starts_with(&[0x1f, 0x8b]);
+    let inner: Box<dyn BufRead> = if gzip {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    };
+    Ok(FastqReader::new(inner, path))
+}
+
+/// Create a FASTQ output, BGZF-compressed when the path ends in `.gz`
+fn create_fastq(path: &Path) -> Result<Box<dyn Write>> {
+    let file = File::create(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    Ok(if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BgzfWriter::new(file))
+    } else {
+        Box::new(BufWriter::new(file))
+    })
+}
+
+/// The readers of the first and, for paired input, second ends
+fn open_inputs(args: &FastqArgs) -> Result<(FastqFileReader, Option<FastqFileReader>)> {
+    Ok((
+        open_fastq(&args.in1)?,
+        args.in2.as_deref().map(open_fastq).transpose()?,
+    ))
+}
+
+/// Read the next template: a single read, or both ends of a pair
+///
+/// The ends must come in the same order in both files; a name mismatch or
+/// one file ending before the other is an error.
+fn next_template<R: BufRead>(
+    r1: &mut FastqReader<R>,
+    r2: Option<&mut FastqReader<R>>,
+    rec1: &mut FastqRecord,
+    rec2: &mut FastqRecord,
+) -> Result<bool> {
+    let more = r1.read_record(rec1)?;
+    let Some(r2) = r2 else {
+        return Ok(more);
+    };
+    if r2.read_record(rec2)? != more {
+        let short = if more { &r2.path } else { &r1.path };
+        bail!("{}: ends before its mate file", short.display());
+    }
+    if more && rec1.name() != rec2.name() {
+        bail!(
+            "{}:{}: mate names differ: {} and {}",
+            r2.path.display(),
+            r2.line - 3,
+            String::from_utf8_lossy(rec1.name()),
+            String::from_utf8_lossy(rec2.name())
+        );
+    }
+    Ok(more)
+}
+
+/// Find the duplicate templates of the inputs, by template index
+fn find_duplicates(args: &FastqArgs, tmp_dir: &Path) -> Result<(RoaringBitmap, u64)> {
+    let (mut r1, mut r2) = open_inputs(args)?;
+    let (mut rec1, mut rec2) = (FastqRecord::default(), FastqRecord::default());
+    let prefix = args.sequence_prefix;
+    let batch_size = args.batch_size.max(1);
+
+    let mut chunk = Vec::with_capacity(batch_size.min(crate::args::DEFAULT_BATCH_SIZE));
+    let mut tmp_files: Vec<PathBuf> = Vec::new();
+    let mut templates = 0u64;
+    while next_template(&mut r1, r2.as_mut(), &mut rec1, &mut rec2)? {
+        if templates > u64::from(u32::MAX) {
+            bail!(
+                "{}: more than {} reads are not supported",
+                args.in1.display(),
+                u32::MAX
+            );
+        }
+        let pos1 = hash_bases(rec1.sequence.iter().copied(), prefix);
+        chunk.push(if r2.is_some() {
+            let pos2 = hash_bases(rec2.sequence.iter().copied(), prefix);
+            Metadata::new_pe(
+                0,
+                0,
+                pos1,
+                0,
+                0,
+                pos2,
+                0,
+                rec1.score() + rec2.score(),
+                templates,
+                templates,
+            )
+        } else {
+            Metadata::new_se(0, 0, pos1, 0, rec1.score(), templates)
+        });
+        templates += 1;
+        if chunk.len() >= batch_size {
+            let path = io::save_chunk_parallel(
+                std::mem::take(&mut chunk),
+                tmp_dir,
+                tmp_files.len(),
+                args.tmp_compression,
+            )?;
+            tmp_files.push(path);
+        }
+    }
+    if !chunk.is_empty() {
+        tmp_files.push(io::save_chunk_parallel(
+            chunk,
+            tmp_dir,
+            tmp_files.len(),
+            args.tmp_compression,
+        )?);
+    }
+
+    let mut merge_seq = 0;
+    let paths = io::reduce_fanin(
+        tmp_files,
+        tmp_dir,
+        args.max_merge_fanin,
+        args.tmp_compression,
+        &mut merge_seq,
+    )?;
+    let (dup_mask, _, _) = mark_identical(&paths)?;
+    Ok((dup_mask, templates))
+}
+
+/// Remove duplicate reads from FASTQ: returns the number of templates read
+/// and of duplicates left out
+pub fn run_fastq(args: &FastqArgs) -> Result<(u64, u64)> {
+    let start = Instant::now();
+    let inputs: Vec<&Path> = [Some(args.in1.as_path()), args.in2.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let input_names: Vec<String> = inputs.iter().map(|p| p.display().to_string()).collect();
+    for input in &inputs {
+        if io::is_stream(input) {
+            bail!(
+                "{}: FASTQ input must be a regular file; it is read twice",
+                input.display()
+            );
+        }
+    }
+    for output in [Some(args.out1.as_path()), args.out2.as_deref()]
+        .into_iter()
+        .flatten()
+    {
+        io::check_overwrite(output, &input_names, args.force)?;
+    }
+
+    let tmp_root = args.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    let tmp_dir = Builder::new()
+        .prefix("markdup_rust")
+        .tempdir_in(&tmp_root)?;
+    logln!(
+        "finding duplicate read sequences in {}...",
+        input_names.join(" and ")
+    );
+    if let Some(prefix) = args.sequence_prefix {
+        logln!("  comparing the first {} bases of each end", prefix);
+    }
+    let (dup_mask, templates) = find_duplicates(args, tmp_dir.path())?;
+    let unit = if args.in2.is_some() { "pairs" } else { "reads" };
+    logln!(
+        "  found {} duplicate {} of {}",
+        dup_mask.len(),
+        unit,
+        templates
+    );
+
+    logln!("writing the remaining {}...", unit);
+    let (mut r1, mut r2) = open_inputs(args)?;
+    let mut out1 = create_fastq(&args.out1)?;
+    let mut out2 = args.out2.as_deref().map(create_fastq).transpose()?;
+    let (mut rec1, mut rec2) = (FastqRecord::default(), FastqRecord::default());
+    let mut index = 0u32;
+    while next_template(&mut r1, r2.as_mut(), &mut rec1, &mut rec2)? {
+        if !dup_mask.contains(index) {
+            rec1.write(&mut out1)?;
+            if let Some(out2) = out2.as_mut() {
+                rec2.write(out2)?;
+            }
+        }
+        index += 1;
+    }
+    out1.flush()?;
+    if let Some(out2) = out2.as_mut() {
+        out2.flush()?;
+    }
+    drop((out1, out2));
+
+    let (m, s) = format_duration(start.elapsed());
+    logln!(
+        "removed {} duplicate {} in {} min {} sec",
+        dup_mask.len(),
+        unit,
+        m,
+        s
+    );
+    Ok((templates, dup_mask.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastq(records: &[(&str, &str, u8)]) -> String {
+        records
+            .iter()
+            .map(|(name, bases, qual)| {
+                format!(
+                    "@{}\n{}\n+\n{}\n",
+                    name,
+                    bases,
+                    String::from_utf8(vec![qual + 33; bases.len()]).unwrap()
+                )
+            })
+            .collect()
+    }
+
+    fn fastq_args(dir: &Path, paired: bool, prefix: Option<usize>) -> FastqArgs {
+        FastqArgs {
+            in1: dir.join("in_R1.fq"),
+            in2: paired.then(|| dir.join("in_R2.fq.gz")),
+            out1: dir.join("out_R1.fq"),
+            out2: paired.then(|| dir.join("out_R2.fq.gz")),
+            sequence_prefix: prefix,
+            batch_size: 2,
+            tmp_dir: Some(dir.to_path_buf()),
+            force: true,
+            ..Default::default()
+        }
+    }
+
+    fn read_names(path: &Path) -> Vec<String> {
+        let mut reader = open_fastq(path).unwrap();
+        let mut record = FastqRecord::default();
+        let mut names = Vec::new();
+        while reader.read_record(&mut record).unwrap() {
+            names.push(String::from_utf8(record.head.clone()).unwrap());
+        }
+        names
+    }
+
+    #[test]
+    fn test_fastq_pairs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let r1 = [
+            ("p1/1", "ACGTACGT", 30), // copy of p2
+            ("p2/1", "ACGTACGT", 40), // best
+            ("p3/1", "ACGTACGT", 40), // second end differs
+            ("p4/1", "TTTTGGGG", 40), // ends swapped
+            ("p5/1", "ACGTACGA", 35), // second end differs, same prefix
+        ];
+        let r2 = [
+            ("p1/2", "TTTTGGGG", 30),
+            ("p2/2", "TTTTGGGG", 40),
+            ("p3/2", "TTTTGGGC", 40),
+            ("p4/2", "ACGTACGT", 40),
+            ("p5/2", "TTTTGGGG", 35),
+        ];
+        std::fs::write(tmp.path().join("in_R1.fq"), fastq(&r1)).unwrap();
+        // The second ends gzipped: both readers must give the same records
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(fastq(&r2).as_bytes()).unwrap();
+        std::fs::write(tmp.path().join("in_R2.fq.gz"), gz.finish().unwrap()).unwrap();
+
+        let args = fastq_args(tmp.path(), true, None);
+        assert_eq!(run_fastq(&args).unwrap(), (5, 1));
+        assert_eq!(read_names(&args.out1), ["@p2/1", "@p3/1", "@p4/1", "@p5/1"]);
+        assert_eq!(
+            read_names(args.out2.as_ref().unwrap()),
+            ["@p2/2", "@p3/2", "@p4/2", "@p5/2"]
+        );
+
+        let args = fastq_args(tmp.path(), true, Some(6));
+        assert_eq!(run_fastq(&args).unwrap(), (5, 3));
+        assert_eq!(read_names(&args.out1), ["@p2/1", "@p4/1"]);
+    }
+
+    #[test]
+    fn test_fastq_single_end() {
+        let tmp = tempfile::tempdir().unwrap();
+        let reads = [
+            ("s1", "ACGT", 30),
+            ("s2", "ACGT", 30),
+            ("s3", "ACGA", 10),
+            ("s4", "ACGT", 20),
+        ];
+        std::fs::write(tmp.path().join("in_R1.fq"), fastq(&reads)).unwrap();
+        let args = fastq_args(tmp.path(), false, None);
+        assert_eq!(run_fastq(&args).unwrap(), (4, 2));
+        assert_eq!(read_names(&args.out1), ["@s1", "@s3"]);
+    }
+
+    #[test]
+    fn test_fastq_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let args = fastq_args(tmp.path(), true, None);
+        std::fs::write(
+            &args.in1,
+            fastq(&[("a/1", "ACGT", 30), ("b/1", "ACGT", 30)]),
+        )
+        .unwrap();
+        std::fs::write(args.in2.as_ref().unwrap(), fastq(&[("a/2", "ACGT", 30)])).unwrap();
+        assert!(
+            run_fastq(&args)
+                .unwrap_err()
+                .to_string()
+                .contains("in_R2.fq.gz: ends before its mate file")
+        );
+        std::fs::write(
+            args.in2.as_ref().unwrap(),
+            fastq(&[("a/2", "ACGT", 30), ("c/2", "ACGT", 30)]),
+        )
+        .unwrap();
+        assert!(
+            run_fastq(&args)
+                .unwrap_err()
+                .to_string()
+                .contains("mate names differ: b and c")
+        );
+        std::fs::write(&args.in1, "@a\nACGT\n+\nIII\n").unwrap();
+        assert!(
+            run_fastq(&args)
+                .unwrap_err()
+                .to_string()
+                .contains("in_R1.fq:4: 4 bases but 3 qualities")
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! Duplication histogram for QC saturation curves
+//!
+//! Counts how many duplicate sets were observed with 1, 2, 3, ... copies,
+//! separately for read pairs and for fragments. A PE set is the pairs of a
+//! positional group that share both ends; an SE set is the fragments of a
+//! positional group. With UMIs, each UMI (or UMI family) is its own set.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Number of duplicate sets per set size, for PE and SE separately
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DupHistogram {
+    pub pe: HashMap<usize, u64>,
+    pub se: HashMap<usize, u64>,
+}
+
+impl DupHistogram {
+    /// Record a set of `copies` read pairs
+    #[inline]
+    pub fn add_pe(&mut self, copies: usize) {
+        *self.pe.entry(copies).or_insert(0) += 1;
+    }
+
+    /// Record a set of `copies` fragments
+    #[inline]
+    pub fn add_se(&mut self, copies: usize) {
+        *self.se.entry(copies).or_insert(0) += 1;
+    }
+
+    /// Add the counts of `other` to this histogram
+    pub fn merge(&mut self, other: &DupHistogram) {
+        for (copies, sets) in &other.pe {
+            *self.pe.entry(*copies).or_insert(0) += sets;
+        }
+        for (copies, sets) in &other.se {
+            *self.se.entry(*copies).or_insert(0) += sets;
+        }
+    }
+
+    /// Write the histogram as TSV with columns `type`, `copies`, `sets`
+    ///
+    /// Rows are ordered PE before SE, then by ascending set size.
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(w, "type\tcopies\tsets")?;
+        for (kind, counts) in [("PE", &self.pe), ("SE", &self.se)] {
+            let mut rows: Vec<_> = counts.iter().collect();
+            rows.sort_unstable();
+            for (copies, sets) in rows {
+                writeln!(w, "{}\t{}\t{}", kind, copies, sets)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the histogram TSV to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_tsv(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_tsv() {
+        let mut hist = DupHistogram::default();
+        hist.add_pe(1);
+        hist.add_pe(3);
+        hist.add_pe(1);
+        hist.add_se(2);
+        let mut out = Vec::new();
+        hist.write_tsv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "type\tcopies\tsets\nPE\t1\t2\nPE\t3\t1\nSE\t2\t1\n"
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//! Temporary chunk files
+//!
+//! Sorted metadata chunks are spilled to disk during the first pass and
+//! merged afterwards. Every chunk starts with a small uncompressed header
+//! (magic, format version, codec) so the reader can pick the right decoder
+//! without being told how the chunk was written.
+//!
+//! When there are more chunks than the merge should open at once, groups of
+//! them are first merged into larger intermediate chunks (see
+//! [`reduce_fanin`]).
+
+use crate::metadata::{MergeItem, Metadata};
+use anyhow::{Result, bail};
+use clap::ValueEnum;
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use rayon::prelude::*;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes at the start of every chunk file
+pub const CHUNK_MAGIC: [u8; 4] = *b"RMDC";
+
+/// Version of the chunk layout; bump whenever `Metadata` serialization changes
+///
+/// Version 5 uses the high bits of the `paired_end` byte as flags for the
+/// optional fields after `umi_seq`, in this order: 0x80 a 12-byte location
+/// (tile, x, y), 0x40 an 8-byte barcode hash, 0x20 a length-prefixed read
+/// name.
+pub const CHUNK_FORMAT_VERSION: u8 = 5;
+
+/// Compression codec for temporary chunk files
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkCodec {
+    /// LZ4 frame compression (fast, moderate ratio)
+    #[default]
+    Lz4,
+    /// Zstandard level 3 (slower, smaller files for network storage)
+    Zstd,
+    /// No compression (fastest on local NVMe)
+    None,
+}
+
+impl ChunkCodec {
+    fn id(self) -> u8 {
+        match self {
+            ChunkCodec::None => 0,
+            ChunkCodec::Lz4 => 1,
+            ChunkCodec::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(ChunkCodec::None),
+            1 => Some(ChunkCodec::Lz4),
+            2 => Some(ChunkCodec::Zstd),
+            _ => None,
+        }
+    }
+
+    /// File extension used for chunks written with this codec
+    pub fn extension(self) -> &'static str {
+        match self {
+            ChunkCodec::None => "bin",
+            ChunkCodec::Lz4 => "lz4",
+            ChunkCodec::Zstd => "zst",
+        }
+    }
+}
+
+/// Compressing writer for the body of a chunk
+enum ChunkEncoder<W: Write> {
+    None(W),
+    Lz4(FrameEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> ChunkEncoder<W> {
+    fn new(codec: ChunkCodec, w: W) -> Result<Self> {
+        Ok(match codec {
+            ChunkCodec::None => ChunkEncoder::None(w),
+            ChunkCodec::Lz4 => ChunkEncoder::Lz4(FrameEncoder::new(w)),
+            ChunkCodec::Zstd => ChunkEncoder::Zstd(zstd::Encoder::new(w, 3)?),
+        })
+    }
+
+    fn finish(self) -> Result<W> {
+        Ok(match self {
+            ChunkEncoder::None(w) => w,
+            ChunkEncoder::Lz4(e) => e.finish()?,
+            ChunkEncoder::Zstd(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for ChunkEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ChunkEncoder::None(w) => w.write(buf),
+            ChunkEncoder::Lz4(e) => e.write(buf),
+            ChunkEncoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ChunkEncoder::None(w) => w.flush(),
+            ChunkEncoder::Lz4(e) => e.flush(),
+            ChunkEncoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Decompressing reader for the body of a chunk
+pub enum ChunkDecoder {
+    None(File),
+    Lz4(FrameDecoder<File>),
+    Zstd(zstd::Decoder<'static, BufReader<File>>),
+}
+
+impl Read for ChunkDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ChunkDecoder::None(r) => r.read(buf),
+            ChunkDecoder::Lz4(r) => r.read(buf),
+            ChunkDecoder::Zstd(r) => r.read(buf),
+        }
+    }
+}
+
+/// Reader over the metadata records of one chunk file
+pub type ChunkReader = BufReader<ChunkDecoder>;
+
+/// File name of the chunk with sequence number `seq`, e.g. `chunk_00003.lz4`
+pub fn chunk_file_name(seq: usize, codec: ChunkCodec) -> String {
+    format!("chunk_{:05}.{}", seq, codec.extension())
+}
+
+/// File name of the part of chunk `seq` holding library `lib_id`, e.g.
+/// `chunk_00003_lib00001.lz4`
+pub fn library_chunk_file_name(seq: usize, lib_id: i32, codec: ChunkCodec) -> String {
+    format!("chunk_{:05}_lib{:05}.{}", seq, lib_id, codec.extension())
+}
+
+/// File name of intermediate chunk `seq` of a pre-merge, e.g. `merge_00002.lz4`
+pub fn merge_file_name(seq: usize, codec: ChunkCodec) -> String {
+    format!("merge_{:05}.{}", seq, codec.extension())
+}
+
+/// Parallel chunk saving with the selected compression codec
+///
+/// Sorts the chunk in parallel before saving. The file is named after `seq`,
+/// which the caller must keep unique within `dir`, so the same input always
+/// produces the same set of chunk files.
+pub fn save_chunk_parallel(
+    mut chunk: Vec<Metadata>,
+    dir: &Path,
+    seq: usize,
+    codec: ChunkCodec,
+) -> Result<PathBuf> {
+    chunk.par_sort_unstable();
+    let path = dir.join(chunk_file_name(seq, codec));
+    write_chunk(&chunk, &path, codec)?;
+    Ok(path)
+}
+
+/// Like [`save_chunk_parallel`], but write one file per library
+///
+/// `lib_id` is the primary sort key, so each library is a contiguous run of
+/// the sorted chunk. Returns the library id and path of every file written,
+/// in library order, so the merge can run one shard per library.
+pub fn save_chunk_by_library(
+    mut chunk: Vec<Metadata>,
+    dir: &Path,
+    seq: usize,
+    codec: ChunkCodec,
+) -> Result<Vec<(i32, PathBuf)>> {
+    chunk.par_sort_unstable();
+    let mut files = Vec::new();
+    for part in chunk.chunk_by(|a, b| a.lib_id == b.lib_id) {
+        let lib_id = part[0].lib_id;
+        let path = dir.join(library_chunk_file_name(seq, lib_id, codec));
+        write_chunk(part, &path, codec)?;
+        files.push((lib_id, path));
+    }
+    Ok(files)
+}
+
+/// Write sorted metadata to `path` with the chunk header
+fn write_chunk(chunk: &[Metadata], path: &Path, codec: ChunkCodec) -> Result<()> {
+    let mut enc = create_chunk(path, codec)?;
+    for m in chunk {
+        m.write_to(&mut enc)?;
+    }
+    enc.finish()?.flush()?;
+    Ok(())
+}
+
+/// Create `path` and write the chunk header, returning the body encoder
+fn create_chunk(path: &Path, codec: ChunkCodec) -> Result<ChunkEncoder<BufWriter<File>>> {
+    let mut out = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    out.write_all(&CHUNK_MAGIC)?;
+    out.write_all(&[CHUNK_FORMAT_VERSION, codec.id()])?;
+    ChunkEncoder::new(codec, out)
+}
+
+/// Metadata of several sorted chunk files in merged order
+///
+/// A K-way heap merge; each file is read one record at a time. A single
+/// file is already in order, so it is read straight through without the
+/// heap (the common case of an input that fits in one batch).
+pub struct MergedChunks {
+    readers: Vec<ChunkReader>,
+    heap: BinaryHeap<MergeItem>,
+}
+
+impl MergedChunks {
+    /// Open every chunk in `paths` and queue its first record
+    pub fn open(paths: &[PathBuf]) -> Result<Self> {
+        let mut readers: Vec<_> = paths
+            .iter()
+            .map(|p| open_chunk_reader(p))
+            .collect::<Result<_>>()?;
+        let mut heap = BinaryHeap::new();
+        if readers.len() == 1 {
+            return Ok(Self { readers, heap });
+        }
+        for (i, r) in readers.iter_mut().enumerate() {
+            if let Some(m) = Metadata::read_from(r)? {
+                heap.push(MergeItem { data: m, f_idx: i });
+            }
+        }
+        Ok(Self { readers, heap })
+    }
+}
+
+impl Iterator for MergedChunks {
+    type Item = Result<Metadata>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let [reader] = self.readers.as_mut_slice() {
+            return Metadata::read_from(reader).transpose();
+        }
+        let item = self.heap.pop()?;
+        match Metadata::read_from(&mut self.readers[item.f_idx]) {
+            Ok(Some(m)) => self.heap.push(MergeItem {
+                data: m,
+                f_idx: item.f_idx,
+            }),
+            Ok(None) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(Ok(item.data))
+    }
+}
+
+/// Merge sorted chunk files into a single sorted chunk at `path`
+pub fn merge_chunks(paths: &[PathBuf], path: &Path, codec: ChunkCodec) -> Result<()> {
+    let merged = MergedChunks::open(paths)?;
+    let mut enc = create_chunk(path, codec)?;
+    for m in merged {
+        m?.write_to(&mut enc)?;
+    }
+    enc.finish()?.flush()?;
+    Ok(())
+}
+
+/// Pre-merge chunks until at most `max_fanin` are left for the final merge
+///
+/// Consecutive groups of up to `max_fanin` chunks are merged into
+/// intermediate chunks in `dir`, named from `seq` onwards (which is advanced
+/// past the names used), and the merged inputs are deleted; this repeats
+/// level by level. `Metadata` order is total (record indices are unique), so
+/// the final merge yields exactly the sequence a flat merge would.
+pub fn reduce_fanin(
+    mut paths: Vec<PathBuf>,
+    dir: &Path,
+    max_fanin: usize,
+    codec: ChunkCodec,
+    seq: &mut usize,
+) -> Result<Vec<PathBuf>> {
+    let max_fanin = max_fanin.max(2);
+    while paths.len() > max_fanin {
+        let mut merged = Vec::with_capacity(paths.len().div_ceil(max_fanin));
+        for group in paths.chunks(max_fanin) {
+            if group.len() == 1 {
+                merged.push(group[0].clone());
+                continue;
+            }
+            let path = dir.join(merge_file_name(*seq, codec));
+            *seq += 1;
+            merge_chunks(group, &path, codec)?;
+            for p in group {
+                std::fs::remove_file(p)?;
+            }
+            merged.push(path);
+        }
+        paths = merged;
+    }
+    Ok(paths)
+}
+
+/// Open a chunk file for reading
+///
+/// The codec is taken from the chunk header; files with a foreign magic or
+/// an unsupported format version are rejected.
+pub fn open_chunk_reader(path: &Path) -> Result<ChunkReader> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 6];
+    file.read_exact(&mut header)?;
+    if header[..4] != CHUNK_MAGIC {
+        bail!("{}: not an rmduprs chunk file", path.display());
+    }
+    if header[4] != CHUNK_FORMAT_VERSION {
+        bail!(
+            "{}: chunk format version {} is not supported (expected {})",
+            path.display(),
+            header[4],
+            CHUNK_FORMAT_VERSION
+        );
+    }
+    let decoder = match ChunkCodec::from_id(header[5]) {
+        Some(ChunkCodec::None) => ChunkDecoder::None(file),
+        Some(ChunkCodec::Lz4) => ChunkDecoder::Lz4(FrameDecoder::new(file)),
+        Some(ChunkCodec::Zstd) => ChunkDecoder::Zstd(zstd::Decoder::new(file)?),
+        None => bail!("{}: unknown chunk codec id {}", path.display(), header[5]),
+    };
+    Ok(BufReader::with_capacity(1 << 18, decoder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_chunk() -> Vec<Metadata> {
+        vec![
+            Metadata::new_se(0, 0, 300, 0, 10, 2),
+            Metadata::new_pe(0, 0, 100, 0, 0, 250, 1, 60, 0, 5),
+            Metadata::new_se(0, 1, 50, 1, 20, 9).with_umi(42),
+        ]
+    }
+
+    #[test]
+    fn test_chunk_roundtrip_all_codecs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut expected = sample_chunk();
+        expected.sort();
+        let codecs = [ChunkCodec::Lz4, ChunkCodec::Zstd, ChunkCodec::None];
+        for (seq, codec) in codecs.into_iter().enumerate() {
+            let path = save_chunk_parallel(sample_chunk(), dir.path(), seq, codec).unwrap();
+            assert_eq!(path, dir.path().join(chunk_file_name(seq, codec)));
+            let mut reader = open_chunk_reader(&path).unwrap();
+            let mut got = Vec::new();
+            while let Some(m) = Metadata::read_from(&mut reader).unwrap() {
+                got.push(m);
+            }
+            assert_eq!(got, expected, "codec {:?}", codec);
+        }
+    }
+
+    #[test]
+    fn test_reduce_fanin_keeps_merge_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut expected = Vec::new();
+        let mut paths = Vec::new();
+        for seq in 0..11u64 {
+            let chunk: Vec<_> = (0..5)
+                .map(|i| {
+                    Metadata::new_se(0, 0, ((seq * 7 + i * 3) % 13) as i64, 0, 10, seq * 5 + i)
+                })
+                .collect();
+            expected.extend(chunk.iter().cloned());
+            paths.push(
+                save_chunk_parallel(chunk, dir.path(), seq as usize, ChunkCodec::Lz4).unwrap(),
+            );
+        }
+        expected.sort();
+
+        let mut seq = 0;
+        let paths = reduce_fanin(paths, dir.path(), 3, ChunkCodec::Lz4, &mut seq).unwrap();
+        // 11 chunks -> 4 -> 2
+        assert_eq!(paths.len(), 2);
+        assert_eq!(seq, 5);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let out = dir.path().join("all.lz4");
+        merge_chunks(&paths, &out, ChunkCodec::Lz4).unwrap();
+        let mut reader = open_chunk_reader(&out).unwrap();
+        let mut got = Vec::new();
+        while let Some(m) = Metadata::read_from(&mut reader).unwrap() {
+            got.push(m);
+        }
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_single_chunk_skips_the_heap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut expected = sample_chunk();
+        expected.sort();
+        let path = save_chunk_parallel(sample_chunk(), dir.path(), 0, ChunkCodec::Lz4).unwrap();
+
+        let merged = MergedChunks::open(std::slice::from_ref(&path)).unwrap();
+        assert!(merged.heap.is_empty());
+        let got: Vec<_> = merged.map(|m| m.unwrap()).collect();
+        assert_eq!(got, expected);
+
+        // The same file twice goes through the heap and yields every record twice
+        let merged = MergedChunks::open(&[path.clone(), path]).unwrap();
+        let got: Vec<_> = merged.map(|m| m.unwrap()).collect();
+        let twice: Vec<_> = expected
+            .iter()
+            .flat_map(|m| [m.clone(), m.clone()])
+            .collect();
+        assert_eq!(got, twice);
+    }
+
+    #[test]
+    fn test_chunk_names_are_deterministic() {
+        let save_all = || {
+            let dir = tempfile::tempdir().unwrap();
+            for seq in 0..3 {
+                save_chunk_parallel(sample_chunk(), dir.path(), seq, ChunkCodec::Lz4).unwrap();
+            }
+            let mut files: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| {
+                    let path = e.unwrap().path();
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, std::fs::read(&path).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        let first = save_all();
+        let names: Vec<_> = first.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["chunk_00000.lz4", "chunk_00001.lz4", "chunk_00002.lz4"]
+        );
+        assert_eq!(first, save_all());
+    }
+
+    #[test]
+    fn test_chunk_by_library_splits_on_lib_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut chunk = sample_chunk();
+        chunk.push(Metadata::new_se(2, 0, 10, 0, 10, 11));
+        chunk.push(Metadata::new_se(2, 0, 5, 0, 10, 12));
+        let files = save_chunk_by_library(chunk, dir.path(), 4, ChunkCodec::Lz4).unwrap();
+        let libs: Vec<_> = files.iter().map(|(lib, _)| *lib).collect();
+        assert_eq!(libs, [0, 2]);
+        assert_eq!(files[1].1, dir.path().join("chunk_00004_lib00002.lz4"));
+
+        let mut reader = open_chunk_reader(&files[1].1).unwrap();
+        let mut positions = Vec::new();
+        while let Some(m) = Metadata::read_from(&mut reader).unwrap() {
+            positions.push(m.pos1);
+        }
+        assert_eq!(positions, [5, 10]);
+    }
+
+    #[test]
+    fn test_chunk_rejects_bad_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bogus.lz4");
+        std::fs::write(&path, b"NOPE\x01\x01").unwrap();
+        assert!(open_chunk_reader(&path).is_err());
+
+        std::fs::write(&path, b"RMDC\xff\x01").unwrap();
+        let err = open_chunk_reader(&path).err().unwrap().to_string();
+        assert!(err.contains("version"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Input format detection
+//!
+//! Both passes read BAM. The format of each input is taken from its first
+//! bytes rather than its name: BGZF whose first block starts with `BAM\1` is
+//! BAM, `CRAM` is CRAM, and text, plain or gzip-compressed, is SAM. SAM is
+//! converted to a temporary BAM before the first pass, so that detection and
+//! the write pass replay the same records; CRAM needs its reference to be
+//! decoded and is refused with a hint to convert it. `--input-format` skips the
+//! detection.
+
+use anyhow::{Result, anyhow, bail};
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use noodles::bam;
+use noodles::bgzf::io::writer::{Builder as BgzfBuilder, CompressionLevel};
+use noodles::sam;
+use noodles::sam::alignment::io::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read};
+use std::path::Path;
+
+/// Format of an input file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    /// BGZF-compressed BAM
+    Bam,
+    /// SAM text, plain or gzip/BGZF-compressed
+    Sam,
+    /// CRAM (not supported; convert with `samtools view -b`)
+    Cram,
+}
+
+impl InputFormat {
+    /// Name of the format for messages
+    pub fn name(self) -> &'static str {
+        match self {
+            InputFormat::Bam => "BAM",
+            InputFormat::Sam => "SAM",
+            InputFormat::Cram => "CRAM",
+        }
+    }
+
+    /// The format named by the extension of `path` (`.bam`, `.sam`,
+    /// `.sam.gz`, `.cram`), if any
+    pub fn from_extension(path: &Path) -> Option<InputFormat> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".bam") {
+            Some(InputFormat::Bam)
+        } else if name.ends_with(".sam") || name.ends_with(".sam.gz") {
+            Some(InputFormat::Sam)
+        } else if name.ends_with(".cram") {
+            Some(InputFormat::Cram)
+        } else {
+            None
+        }
+    }
+
+    /// The format of the file at `path`, from its first bytes
+    pub fn detect(path: &Path) -> Result<InputFormat> {
+        let mut file =
+            BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?);
+        let head = file.fill_buf()?;
+        if head.is_empty() {
+            bail!("{}: input is empty", path.display());
+        }
+        if head.starts_with(b"CRAM") {
+            return Ok(InputFormat::Cram);
+        }
+        let mut magic = Vec::with_capacity(4);
+        if head.starts_with(&[0x1f, 0x8b]) {
+            MultiGzDecoder::new(file).take(4).read_to_end(&mut magic)?;
+            if magic == b"BAM\x01" {
+                return Ok(InputFormat::Bam);
+            }
+        } else {
+            magic.extend(head.iter().take(4));
+        }
+        if !magic.is_empty()
+            && magic
+                .iter()
+                .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+        {
+            return Ok(InputFormat::Sam);
+        }
+        bail!("{}: not a BAM, SAM or CRAM file", path.display())
+    }
+}
+
+/// Convert the SAM at `path` (plain or gzip-compressed) to a BAM in `dir`
+///
+/// The copy is written with the fastest compression, since it only lives
+/// until the output is written. Returns the file, which is removed when the
+/// path is dropped, and the number of records.
+pub fn sam_to_bam(path: &Path, dir: &Path) -> Result<(tempfile::TempPath, u64)> {
+    let mut input =
+        BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?);
+    let inner: Box<dyn BufRead> = if input.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(input)))
+    } else {
+        Box::new(input)
+    };
+    let mut reader = sam::io::Reader::new(inner);
+    let header = reader
+        .read_header()
+        .map_err(|e| anyhow!("{}: invalid SAM header: {}", path.display(), e))?;
+
+    let (file, temp_path) = tempfile::Builder::new()
+        .prefix("markdup_sam")
+        .suffix(".bam")
+        .tempfile_in(dir)?
+        .into_parts();
+    let bgzf = BgzfBuilder::default()
+        .set_compression_level(CompressionLevel::FAST)
+        .build_from_writer(BufWriter::new(file));
+    let mut writer = bam::io::Writer::from(bgzf);
+    writer.write_header(&header)?;
+    let mut record = sam::Record::default();
+    let mut count = 0u64;
+    loop {
+        match reader.read_record(&mut record) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => bail!("{}: record {}: {}", path.display(), count + 1, e),
+        }
+        writer
+            .write_alignment_record(&header, &record)
+            .map_err(|e| anyhow!("{}: record {}: {}", path.display(), count + 1, e))?;
+        count += 1;
+    }
+    writer.try_finish()?;
+    Ok((temp_path, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n\
+                       r1\t0\tchr1\t100\t60\t4M\t*\t0\t0\tACGT\tIIII\n\
+                       r2\t16\tchr1\t200\t60\t4M\t*\t0\t0\tACGT\tIIII\n";
+
+    #[test]
+    fn test_detect_input_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let bam = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/W62subA_chr1C_5M.bam");
+        assert_eq!(InputFormat::detect(&bam).unwrap(), InputFormat::Bam);
+
+        let write = |name: &str, data: &[u8]| {
+            let path = tmp.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        assert_eq!(
+            InputFormat::detect(&write("in.bam", SAM.as_bytes())).unwrap(),
+            InputFormat::Sam
+        );
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(SAM.as_bytes()).unwrap();
+        assert_eq!(
+            InputFormat::detect(&write("in.sam.gz", &gz.finish().unwrap())).unwrap(),
+            InputFormat::Sam
+        );
+        assert_eq!(
+            InputFormat::detect(&write("in.cram", b"CRAM\x03\x00")).unwrap(),
+            InputFormat::Cram
+        );
+        assert!(
+            InputFormat::detect(&write("empty.bam", b""))
+                .unwrap_err()
+                .to_string()
+                .contains("input is empty")
+        );
+        let err = InputFormat::detect(&write("x.bin", &[0, 1, 2, 3]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("not a BAM, SAM or CRAM file"));
+
+        assert_eq!(
+            InputFormat::from_extension(Path::new("dir.bam/IN.SAM.GZ")),
+            Some(InputFormat::Sam)
+        );
+        assert_eq!(
+            InputFormat::from_extension(Path::new("in.cram")),
+            Some(InputFormat::Cram)
+        );
+        assert_eq!(InputFormat::from_extension(Path::new("in.gz")), None);
+    }
+
+    #[test]
+    fn test_sam_to_bam() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sam = tmp.path().join("in.sam");
+        std::fs::write(&sam, SAM).unwrap();
+        let (bam, count) = sam_to_bam(&sam, tmp.path()).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(InputFormat::detect(&bam).unwrap(), InputFormat::Bam);
+        let mut reader = bam::io::reader::Builder.build_from_path(&bam).unwrap();
+        let header = reader.read_header().unwrap();
+        assert_eq!(header.reference_sequences().len(), 1);
+        let names: Vec<_> = reader
+            .records()
+            .map(|r| r.unwrap().name().unwrap().to_vec())
+            .collect();
+        assert_eq!(names, [b"r1".to_vec(), b"r2".to_vec()]);
+
+        std::fs::write(
+            &sam,
+            format!("{}r3\t0\tchr1\tX\t60\t4M\t*\t0\t0\tACGT\tIIII\n", SAM),
+        )
+        .unwrap();
+        let err = sam_to_bam(&sam, tmp.path()).unwrap_err().to_string();
+        assert!(err.contains("record 3"), "{}", err);
+    }
+}
@@ -0,0 +1,87 @@
+//! Duplicate masks saved between runs
+//!
+//! `--write-mask` saves the indices of the duplicate records found by the
+//! first pass, so the write pass can be redone later without detection. The
+//! file holds an 8-byte magic (`RMDMASK` and a version byte), the number of
+//! records in the input stream as a little-endian `u64`, so that a mask is
+//! not applied to a different input, and the bitmap in the portable Roaring
+//! format, which CRoaring and pyroaring read as well.
+
+use anyhow::{Result, bail};
+use roaring::RoaringBitmap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// First bytes of a mask file: `RMDMASK` and format version 1
+pub const MASK_MAGIC: &[u8; 8] = b"RMDMASK\x01";
+
+/// Save `mask`, the duplicates among `record_count` input records, to `path`
+pub fn write_mask(path: &Path, mask: &RoaringBitmap, record_count: u64) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(MASK_MAGIC)?;
+    out.write_all(&record_count.to_le_bytes())?;
+    mask.serialize_into(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Read a mask saved by [`write_mask`]: the duplicates and the number of
+/// records of the input it was computed on
+pub fn read_mask(path: &Path) -> Result<(RoaringBitmap, u64)> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MASK_MAGIC {
+        bail!(
+            "{}: not a duplicate mask written by --write-mask",
+            path.display()
+        );
+    }
+    let mut count = [0u8; 8];
+    input.read_exact(&mut count)?;
+    let record_count = u64::from_le_bytes(count);
+    let mask = RoaringBitmap::deserialize_from(&mut input)?;
+    if mask.max().is_some_and(|idx| u64::from(idx) >= record_count) {
+        bail!(
+            "{}: mask marks record {} of {}",
+            path.display(),
+            mask.max().unwrap_or(0),
+            record_count
+        );
+    }
+    Ok((mask, record_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("dups.rmd");
+        let mask: RoaringBitmap = [1, 2, 4, 70_000, 3_000_000].into_iter().collect();
+        write_mask(&path, &mask, 3_000_001).unwrap();
+        assert_eq!(read_mask(&path).unwrap(), (mask.clone(), 3_000_001));
+
+        // The bitmap after the header is plain portable Roaring
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(RoaringBitmap::deserialize_from(&bytes[16..]).unwrap(), mask);
+
+        write_mask(&path, &mask, 100).unwrap();
+        assert!(
+            read_mask(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("marks record 3000000 of 100")
+        );
+        std::fs::write(&path, b"RMDMASK\x02rest").unwrap();
+        assert!(
+            read_mask(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("not a duplicate mask")
+        );
+    }
+}
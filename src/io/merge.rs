@@ -0,0 +1,254 @@
+//! Reading one or more BAM inputs as a single stream
+//!
+//! Several coordinate-sorted BAMs (e.g. one per lane) are merged on the fly
+//! in coordinate order, so duplicate detection and the write pass both see
+//! one stream and the record index of a read is its position in that stream.
+//! A single input is passed through unchanged and need not be sorted.
+
+use super::check_bgzf_eof;
+use anyhow::{Result, bail};
+use noodles::bam;
+use noodles::sam;
+use noodles::sam::header::record::value::map::header::tag::SORT_ORDER;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::path::Path;
+
+/// BAM reader over a BGZF-compressed file
+pub type BamReader = bam::io::Reader<noodles::bgzf::io::Reader<File>>;
+
+/// Coordinate sort key: reference id (unplaced reads last), then position
+type SortKey = (usize, usize);
+
+fn sort_key(record: &bam::Record) -> std::io::Result<SortKey> {
+    let ref_id = record
+        .reference_sequence_id()
+        .transpose()?
+        .unwrap_or(usize::MAX);
+    let pos = record
+        .alignment_start()
+        .transpose()?
+        .map(|p| p.get())
+        .unwrap_or(0);
+    Ok((ref_id, pos))
+}
+
+/// Records of all inputs in coordinate order
+///
+/// Ties between inputs are broken by input order, so the stream (and thus
+/// every record index) is the same each time the inputs are opened.
+pub struct MergedRecords {
+    readers: Vec<BamReader>,
+    next: Vec<Option<bam::Record>>,
+    last: Vec<SortKey>,
+    heap: BinaryHeap<Reverse<(SortKey, usize)>>,
+    paths: Vec<String>,
+}
+
+impl MergedRecords {
+    /// Read the next record of input `i` into its slot and queue it
+    fn advance(&mut self, i: usize) -> std::io::Result<()> {
+        let mut record = bam::Record::default();
+        if self.readers[i].read_record(&mut record)? == 0 {
+            return Ok(());
+        }
+        let key = sort_key(&record)?;
+        if self.readers.len() > 1 {
+            if key < self.last[i] {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: input is not coordinate-sorted", self.paths[i]),
+                ));
+            }
+            self.last[i] = key;
+        }
+        self.next[i] = Some(record);
+        self.heap.push(Reverse((key, i)));
+        Ok(())
+    }
+}
+
+impl Iterator for MergedRecords {
+    type Item = std::io::Result<bam::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, i)) = self.heap.pop()?;
+        let record = self.next[i].take()?;
+        match self.advance(i) {
+            Ok(()) => Some(Ok(record)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Open every input and return the merged header and record stream
+///
+/// Every input must end with the BGZF EOF marker, so a truncated file is
+/// rejected before any work is done. With more than one input, every file
+/// must be coordinate-sorted and carry the same `@SQ` lines in the same
+/// order; their `@RG` lines are unioned.
+pub fn open_inputs(paths: &[String]) -> Result<(sam::Header, MergedRecords)> {
+    if paths.is_empty() {
+        bail!("no input BAM given");
+    }
+    let mut readers = Vec::with_capacity(paths.len());
+    let mut headers = Vec::with_capacity(paths.len());
+    for path in paths {
+        check_bgzf_eof(Path::new(path))?;
+        let mut reader = bam::io::reader::Builder.build_from_path(path)?;
+        headers.push(reader.read_header()?);
+        readers.push(reader);
+    }
+    let header = merge_headers(paths, &headers)?;
+
+    let n = readers.len();
+    let mut records = MergedRecords {
+        readers,
+        next: (0..n).map(|_| None).collect(),
+        last: vec![(0, 0); n],
+        heap: BinaryHeap::with_capacity(n),
+        paths: paths.to_vec(),
+    };
+    for i in 0..n {
+        records.advance(i)?;
+    }
+    Ok((header, records))
+}
+
+/// Merge the headers of several inputs into the output header
+///
+/// The first header is the base. `@SQ` lines must match it exactly (same
+/// names, lengths and order); `@RG` lines missing from it are appended, and
+/// a read group defined differently by two inputs is an error.
+pub fn merge_headers(paths: &[String], headers: &[sam::Header]) -> Result<sam::Header> {
+    let mut merged = headers[0].clone();
+    if headers.len() == 1 {
+        return Ok(merged);
+    }
+    let sq = |h: &sam::Header| -> Vec<(Vec<u8>, usize)> {
+        h.reference_sequences()
+            .iter()
+            .map(|(name, rs)| (name.to_vec(), rs.length().get()))
+            .collect()
+    };
+    let base_sq = sq(&headers[0]);
+    for (path, header) in paths.iter().zip(headers) {
+        if !is_coordinate_sorted(header) {
+            bail!(
+                "{}: multiple inputs must be coordinate-sorted (@HD SO:coordinate)",
+                path
+            );
+        }
+        if sq(header) != base_sq {
+            bail!(
+                "{}: @SQ lines differ from {}; inputs must share one reference in the same order",
+                path,
+                paths[0]
+            );
+        }
+        for (id, rg) in header.read_groups() {
+            match merged.read_groups().get(id) {
+                Some(existing) if existing != rg => {
+                    bail!(
+                        "{}: read group {} conflicts with an earlier input",
+                        path,
+                        id
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    merged.read_groups_mut().insert(id.clone(), rg.clone());
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Whether the header declares coordinate order (`@HD SO:coordinate`)
+pub fn is_coordinate_sorted(header: &sam::Header) -> bool {
+    header
+        .header()
+        .and_then(|hd| hd.other_fields().get(&SORT_ORDER))
+        .is_some_and(|so| so == "coordinate")
+}
+
+/// Whether the header declares queryname order (`@HD SO:queryname`)
+pub fn is_name_sorted(header: &sam::Header) -> bool {
+    header
+        .header()
+        .and_then(|hd| hd.other_fields().get(&SORT_ORDER))
+        .is_some_and(|so| so == "queryname")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::sam::header::record::value::map::{self, Map, ReadGroup, ReferenceSequence};
+    use std::num::NonZeroUsize;
+
+    fn header(refs: &[(&str, usize)], rg: &str) -> sam::Header {
+        let mut builder = sam::Header::builder().set_header(
+            Map::<map::Header>::builder()
+                .insert(SORT_ORDER, "coordinate")
+                .build()
+                .unwrap(),
+        );
+        for (name, len) in refs {
+            builder = builder.add_reference_sequence(
+                *name,
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(*len).unwrap()),
+            );
+        }
+        builder
+            .add_read_group(rg, Map::<ReadGroup>::default())
+            .build()
+    }
+
+    #[test]
+    fn test_merge_headers_unions_read_groups() {
+        let paths = ["a.bam".to_string(), "b.bam".to_string()];
+        let refs = [("chr1", 1000), ("chr2", 2000)];
+        let merged =
+            merge_headers(&paths, &[header(&refs, "lane1"), header(&refs, "lane2")]).unwrap();
+        assert_eq!(merged.reference_sequences().len(), 2);
+        let ids: Vec<_> = merged.read_groups().keys().map(|k| k.to_string()).collect();
+        assert_eq!(ids, ["lane1", "lane2"]);
+    }
+
+    #[test]
+    fn test_merge_headers_rejects_incompatible_sq() {
+        let paths = ["a.bam".to_string(), "b.bam".to_string()];
+        let a = header(&[("chr1", 1000), ("chr2", 2000)], "lane1");
+        let b = header(&[("chr2", 2000), ("chr1", 1000)], "lane2");
+        let err = merge_headers(&paths, &[a.clone(), b]).unwrap_err();
+        assert!(err.to_string().contains("@SQ"));
+
+        let unsorted = sam::Header::builder()
+            .add_reference_sequence(
+                "chr1",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(1000).unwrap()),
+            )
+            .build();
+        let err = merge_headers(&paths, &[a, unsorted]).unwrap_err();
+        assert!(err.to_string().contains("coordinate-sorted"));
+    }
+
+    #[test]
+    fn test_header_sort_order() {
+        assert!(!is_name_sorted(&header(&[("chr1", 1000)], "lane1")));
+        let named = sam::Header::builder()
+            .set_header(
+                Map::<map::Header>::builder()
+                    .insert(SORT_ORDER, "queryname")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        assert!(is_name_sorted(&named));
+        assert!(!is_name_sorted(&sam::Header::default()));
+        assert!(is_coordinate_sorted(&header(&[("chr1", 1000)], "lane1")));
+        assert!(!is_coordinate_sorted(&named));
+    }
+}
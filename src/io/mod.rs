@@ -3,25 +3,70 @@
 //! This module provides utilities for reading and writing BAM files,
 //! including header handling and flag modification.
 
-use anyhow::Result;
-use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+pub mod chunk;
+pub mod input;
+pub mod mask;
+pub mod merge;
+pub mod output;
+#[cfg(feature = "object_store")]
+pub mod remote;
+
+pub use chunk::{
+    ChunkCodec, ChunkReader, MergedChunks, chunk_file_name, library_chunk_file_name, merge_chunks,
+    merge_file_name, open_chunk_reader, reduce_fanin, save_chunk_by_library, save_chunk_parallel,
+};
+pub use input::{InputFormat, sam_to_bam};
+pub use mask::{MASK_MAGIC, read_mask, write_mask};
+pub use merge::{BamReader, MergedRecords, is_coordinate_sorted, is_name_sorted, open_inputs};
+pub use output::{
+    BAI_MAX_REFERENCE_LENGTH, BamIndexer, DuplicateTag, IndexFormat, IndexedWriter, OutputFormat,
+    OutputSink, OutputWriter, RecordTags, ShardedOutput, TaggingPolicy, index_path,
+    max_reference_length, run_bounded,
+};
+
+use anyhow::{Result, bail};
 use noodles::bam;
 use noodles::bgzf::io::Writer as BgzfWriter;
 use noodles::sam::alignment::io::Write as SamWrite;
+use noodles::sam::alignment::record::Flags;
 use noodles::sam::header::Header as SamHeader;
-use rayon::prelude::*;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-/// Offset of the flag field in a BAM record's binary format
+/// Offset of the flag field in a serialized BAM record
 ///
-/// The flag field is at bytes 12-13 (after ref_id=4 + pos=4 + bin_mq_nl=4)
-pub const FLAG_OFFSET: usize = 12;
+/// Offsets count from the start of the `block_size` prefix written by
+/// [`record_to_bytes`]: block_size=4 + ref_id=4 + pos=4 + l_read_name=1 +
+/// mapq=1 + bin=2 + n_cigar_op=2, so the flag is at bytes 18-19.
+pub const FLAG_OFFSET: usize = 18;
 
 /// The DUPLICATE flag bit in BAM format
 pub const DUPLICATE_FLAG: u16 = 0x400;
 
+/// Input path that reads BAM from standard input
+pub const STDIN_PATH: &str = "-";
+
+/// Output path that writes to standard output
+pub const STDOUT_PATH: &str = "-";
+
+/// Aux tag carrying the duplicate set id (`--tag-duplicate-set`)
+pub const DUPLICATE_SET_TAG: [u8; 2] = *b"DI";
+
+/// Aux tag carrying the number of copies in the duplicate set
+/// (`--tag-duplicate-set-size`)
+pub const DUPLICATE_SET_SIZE_TAG: [u8; 2] = *b"DS";
+
+/// Aux tag carrying the duplicate type (`--tagging-policy`)
+pub const DUPLICATE_TYPE_TAG: [u8; 2] = *b"DT";
+
+/// Aux tag carrying the molecule id (`--tag-molecule-id`)
+pub const MOLECULE_ID_TAG: [u8; 2] = *b"MI";
+
+/// Aux tag carrying the name of the read kept from a duplicate's set
+/// (`--tag-original-name`), as `samtools markdup -t` writes it
+pub const ORIGINAL_NAME_TAG: [u8; 2] = *b"do";
+
 /// Modify the DUPLICATE flag in raw BAM record bytes
 ///
 /// Returns the modified flag value.
@@ -44,6 +89,293 @@ pub fn toggle_duplicate_flag(data: &mut [u8], is_duplicate: bool) -> Option<u16>
     Some(new_flag)
 }
 
+/// Whether the `block_size` prefix of a serialized record matches its length
+///
+/// Checked on every record written in debug builds, so that a byte patch or
+/// an appended tag that leaves the prefix stale fails at once instead of
+/// producing a BAM that readers misparse from that record on.
+#[inline]
+pub fn block_size_matches(data: &[u8]) -> bool {
+    match data.first_chunk::<4>() {
+        Some(prefix) => u32::from_le_bytes(*prefix) as usize == data.len() - 4,
+        None => false,
+    }
+}
+
+/// Set or clear the DUPLICATE flag of a decoded BAM record
+///
+/// The record counterpart of [`toggle_duplicate_flag`], for writers that
+/// need a `bam::Record` rather than its bytes. With `additive` a flag already
+/// set in the input is kept. `bam::Record` has no flag setter, so a record
+/// whose flag changes is re-encoded, patched and decoded in place; `header`
+/// is needed for the encoding. Returns the new flags.
+pub fn set_duplicate_on_record(
+    header: &SamHeader,
+    record: &mut bam::Record,
+    is_duplicate: bool,
+    additive: bool,
+) -> Result<Flags> {
+    let flags = record.flags();
+    let is_duplicate = is_duplicate || (additive && flags.is_duplicate());
+    if flags.is_duplicate() == is_duplicate {
+        return Ok(flags);
+    }
+    let mut data = record_to_bytes(header, record)?;
+    toggle_duplicate_flag(&mut data, is_duplicate);
+    bam::io::Reader::from(&data[..]).read_record(record)?;
+    Ok(record.flags())
+}
+
+/// Append a `uint32` aux field to raw BAM record bytes
+///
+/// `data` is a record as produced by [`record_to_bytes`]; the field goes
+/// after the existing ones and `block_size` is updated to match.
+pub fn append_u32_tag(data: &mut Vec<u8>, tag: [u8; 2], value: u32) {
+    data.extend_from_slice(&tag);
+    data.push(b'I');
+    data.extend_from_slice(&value.to_le_bytes());
+    let block_size = (data.len() - 4) as u32;
+    data[..4].copy_from_slice(&block_size.to_le_bytes());
+}
+
+/// Append a string (`Z`) aux field to raw BAM record bytes
+///
+/// As [`append_u32_tag`]; `value` must not contain a NUL byte.
+pub fn append_string_tag(data: &mut Vec<u8>, tag: [u8; 2], value: &[u8]) {
+    data.extend_from_slice(&tag);
+    data.push(b'Z');
+    data.extend_from_slice(value);
+    data.push(0);
+    let block_size = (data.len() - 4) as u32;
+    data[..4].copy_from_slice(&block_size.to_le_bytes());
+}
+
+/// The empty block every complete BGZF file ends with
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Fail early when a BGZF file lacks its EOF marker block
+///
+/// A truncated BAM (e.g. after an interrupted transfer) would otherwise only
+/// fail partway through the first pass. Stdin (`-`) and other non-seekable
+/// inputs such as pipes are not checked.
+pub fn check_bgzf_eof(path: &Path) -> Result<()> {
+    if path == Path::new("-") {
+        return Ok(());
+    }
+    let mut file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Ok(());
+    }
+    let len = file.metadata()?.len();
+    let mut tail = [0u8; 28];
+    if len >= tail.len() as u64 {
+        file.seek(SeekFrom::End(-(tail.len() as i64)))?;
+        file.read_exact(&mut tail)?;
+    }
+    if tail != BGZF_EOF {
+        bail!(
+            "{}: input appears truncated (missing BGZF EOF marker)",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Whether `path` can be read only once: `-` for standard input, or a FIFO,
+/// character device or socket such as the `/dev/fd/N` of `<(...)`
+pub fn is_stream(path: &Path) -> bool {
+    if path == Path::new(STDIN_PATH) {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(path)
+            .map(|m| {
+                let t = m.file_type();
+                t.is_fifo() || t.is_char_device() || t.is_socket()
+            })
+            .unwrap_or(false)
+    }
+    #[cfg(not(unix))]
+    false
+}
+
+/// Copy `reader` to a new temporary file in `dir`
+///
+/// Returns the file, which is removed when the path is dropped, and the
+/// number of bytes copied.
+pub fn spool_to_file(mut reader: impl Read, dir: &Path) -> Result<(tempfile::TempPath, u64)> {
+    let file = tempfile::Builder::new()
+        .prefix("markdup_stdin")
+        .suffix(".bam")
+        .tempfile_in(dir)?;
+    let mut out = std::io::BufWriter::with_capacity(1 << 20, file);
+    let bytes = std::io::copy(&mut reader, &mut out)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    Ok((file.into_temp_path(), bytes))
+}
+
+/// Fail early when `path` cannot be written
+///
+/// Outputs are only created after the first pass and merge, so an unwritable
+/// path would otherwise surface after all the work is done. An existing file
+/// is opened for writing without truncating it; otherwise a temporary file is
+/// created (and removed again) next to it. Stdout (`-`) is not checked.
+///
+/// Object store URLs (`s3://`, `gs://`, ...) are refused here with a hint to
+/// stream standard output to the store's own uploader instead; only
+/// [`check_output`] takes them, with the `object_store` feature.
+pub fn check_writable(path: &Path) -> Result<()> {
+    if path == Path::new("-") {
+        return Ok(());
+    }
+    if let Some(scheme) = url_scheme(path) {
+        bail!(
+            "{}: {}:// paths are only written for --output and --duplicates-output, in builds with the \
+             object_store feature; otherwise use '-' and pipe it to an uploader \
+             (e.g. `-o - | aws s3 cp - s3://bucket/out.bam`)",
+            path.display(),
+            scheme
+        );
+    }
+    let result = if path.is_dir() {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::IsADirectory,
+            "is a directory",
+        ))
+    } else if path.exists() {
+        std::fs::OpenOptions::new().write(true).open(path).map(drop)
+    } else {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let probe = parent.join(format!(".rmduprs-probe-{}", std::process::id()));
+        File::create_new(&probe).and_then(|_| std::fs::remove_file(&probe))
+    };
+    if let Err(e) = result {
+        bail!("{}: output path is not writable: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+/// Fail early when the marked output `path` cannot be written, as
+/// [`check_writable`] does
+///
+/// With the `object_store` feature `path` may also be an object store URL,
+/// which must be reachable and, without `force`, not name an existing object.
+#[cfg_attr(not(feature = "object_store"), allow(unused_variables))]
+pub fn check_output(path: &Path, force: bool) -> Result<()> {
+    match url_scheme(path) {
+        #[cfg(feature = "object_store")]
+        Some(_) => remote::check_url(path, force),
+        _ => check_writable(path),
+    }
+}
+
+/// Create the `--shard-by-chromosome` directory, refusing one that already
+/// holds files unless `force`
+pub fn prepare_shard_dir(dir: &Path, force: bool) -> Result<()> {
+    if url_scheme(dir).is_some() {
+        return check_writable(dir);
+    }
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        bail!(
+            "{}: cannot create the shard directory: {}",
+            dir.display(),
+            e
+        );
+    }
+    if !force && std::fs::read_dir(dir)?.next().is_some() {
+        bail!(
+            "{}: shard directory is not empty; use --force to overwrite its shards",
+            dir.display()
+        );
+    }
+    check_writable(&dir.join("unmapped.bam"))
+}
+
+/// The scheme of a `scheme://...` URL, such as `s3` or `gs`
+pub fn url_scheme(path: &Path) -> Option<&str> {
+    let (scheme, _) = path.to_str()?.split_once("://")?;
+    let is_scheme = !scheme.is_empty()
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    is_scheme.then_some(scheme)
+}
+
+/// Refuse an output path that is one of the inputs or, without `force`, an
+/// existing file
+///
+/// Inputs are compared by file identity (device and inode on Unix), so a
+/// hard link or a different spelling of the input path is caught as well;
+/// `force` does not override that. Stdout (`-`) is not checked.
+pub fn check_overwrite(output: &Path, inputs: &[String], force: bool) -> Result<()> {
+    if output == Path::new("-") {
+        return Ok(());
+    }
+    let Ok(out_meta) = std::fs::metadata(output) else {
+        return Ok(());
+    };
+    for input in inputs {
+        if let Ok(in_meta) = std::fs::metadata(input)
+            && same_file(output, &out_meta, Path::new(input), &in_meta)
+        {
+            bail!(
+                "{}: output path is the same file as input {}",
+                output.display(),
+                input
+            );
+        }
+    }
+    if !force {
+        bail!(
+            "{}: output file exists; use --force to overwrite it",
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(_: &Path, a: &std::fs::Metadata, _: &Path, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, _: &std::fs::Metadata, b: &Path, _: &std::fs::Metadata) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+///
+/// `None` when the query fails or the platform has no `statvfs`.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid out-pointer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes available on the filesystem holding `path`; not known off Unix
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
 /// Check if a record is a duplicate based on index
 #[inline]
 pub fn is_duplicate(idx: usize, dup_mask: &roaring::RoaringBitmap) -> bool {
@@ -51,10 +383,7 @@ pub fn is_duplicate(idx: usize, dup_mask: &roaring::RoaringBitmap) -> bool {
 }
 
 /// Write header to BGZF-compressed BAM file
-pub fn write_header(
-    writer: &mut BgzfWriter<File>,
-    header: &SamHeader,
-) -> Result<()> {
+pub fn write_header<W: Write>(writer: &mut BgzfWriter<W>, header: &SamHeader) -> Result<()> {
     let mut header_buf = Vec::new();
     {
         let mut writer = bam::io::Writer::from(&mut header_buf);
@@ -65,6 +394,38 @@ pub fn write_header(
     Ok(())
 }
 
+/// `ID` and `PN` of the `@PG` line this program adds to the output header
+pub const PROGRAM_ID: &str = "rmduprs";
+
+/// Copy of `header` with a `@PG` line for this run appended
+///
+/// Like Picard and sambamba, the new line's `PP` chains to the last `@PG`
+/// line of the input, and its `ID` gets a `.1`, `.2`, ... suffix when an
+/// earlier run already used `rmduprs`. `command_line` becomes `CL`.
+pub fn add_program(header: &SamHeader, command_line: &str) -> Result<SamHeader> {
+    use noodles::sam::header::record::value::{Map, map::Program, map::program::tag};
+
+    let mut header = header.clone();
+    let programs = header.programs().as_ref();
+    let previous = programs.keys().last().cloned();
+    let id = std::iter::once(PROGRAM_ID.to_string())
+        .chain((1..).map(|n| format!("{}.{}", PROGRAM_ID, n)))
+        .find(|id| !programs.contains_key(id.as_bytes()))
+        .expect("unbounded ids");
+    let mut builder = Map::<Program>::builder()
+        .insert(tag::NAME, PROGRAM_ID)
+        .insert(tag::VERSION, env!("CARGO_PKG_VERSION"))
+        .insert(tag::COMMAND_LINE, command_line.replace(['\t', '\n'], " "));
+    if let Some(previous) = previous {
+        builder = builder.insert(tag::PREVIOUS_PROGRAM_ID, previous);
+    }
+    let program = builder
+        .build()
+        .map_err(|e| anyhow::anyhow!("invalid @PG line: {}", e))?;
+    header.programs_mut().as_mut().insert(id.into(), program);
+    Ok(header)
+}
+
 /// Serialize a BAM record to raw bytes
 pub fn record_to_bytes(
     header: &SamHeader,
@@ -80,8 +441,8 @@ pub fn record_to_bytes(
 
 /// Write a BAM record with optional duplicate flag modification
 #[allow(dead_code)]
-pub fn write_record_with_dup_flag(
-    writer: &mut BgzfWriter<File>,
+pub fn write_record_with_dup_flag<W: Write>(
+    writer: &mut BgzfWriter<W>,
     header: &SamHeader,
     record: &bam::Record,
     idx: usize,
@@ -101,28 +462,6 @@ pub fn write_record_with_dup_flag(
     Ok(())
 }
 
-/// Parallel chunk saving with LZ4 compression
-///
-/// Sorts the chunk in parallel before saving.
-pub fn save_chunk_parallel(
-    mut chunk: Vec<super::metadata::Metadata>,
-    dir: &Path,
-) -> Result<std::path::PathBuf> {
-    chunk.par_sort_unstable();
-    let path = dir.join(format!("{}.lz4", fastrand::u64(..)));
-    let mut enc = FrameEncoder::new(BufWriter::with_capacity(1 << 20, File::create(&path)?));
-    for m in chunk {
-        m.write_to(&mut enc)?;
-    }
-    enc.finish()?;
-    Ok(path)
-}
-
-/// Open a chunk file for reading
-pub fn open_chunk_reader(path: &Path) -> BufReader<FrameDecoder<File>> {
-    BufReader::with_capacity(1 << 18, FrameDecoder::new(File::open(path).unwrap()))
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,8 +469,8 @@ mod tests {
     #[test]
     fn test_flag_offset_constant() {
         // Verify flag offset matches BAM spec
-        // ref_id (4 bytes) + pos (4 bytes) + bin_mq_nl (4 bytes) = 12
-        assert_eq!(FLAG_OFFSET, 12);
+        // block_size (4) + ref_id (4) + pos (4) + l_read_name/mapq/bin (4) + n_cigar_op (2) = 18
+        assert_eq!(FLAG_OFFSET, 18);
     }
 
     #[test]
@@ -143,34 +482,387 @@ mod tests {
     #[test]
     fn test_toggle_duplicate_flag_set() {
         let mut data = [0u8; 20];
-        data[12] = 0x00; // flag = 0
-        data[13] = 0x00;
+        data[18] = 0x00; // flag = 0
+        data[19] = 0x00;
 
         let result = toggle_duplicate_flag(&mut data, true);
         assert_eq!(result, Some(0x400));
-        assert_eq!(u16::from_le_bytes([data[12], data[13]]), 0x400);
+        assert_eq!(u16::from_le_bytes([data[18], data[19]]), 0x400);
     }
 
     #[test]
     fn test_toggle_duplicate_flag_clear() {
         let mut data = [0u8; 20];
-        data[12] = 0x00;
-        data[13] = 0x04; // flag = 0x400
+        data[18] = 0x00;
+        data[19] = 0x04; // flag = 0x400
 
         let result = toggle_duplicate_flag(&mut data, false);
         assert_eq!(result, Some(0x000));
-        assert_eq!(u16::from_le_bytes([data[12], data[13]]), 0x000);
+        assert_eq!(u16::from_le_bytes([data[18], data[19]]), 0x000);
     }
 
     #[test]
     fn test_toggle_duplicate_flag_preserve_other_bits() {
         let mut data = [0u8; 20];
-        data[12] = 0x02; // flag = 0x402 (PAIRED | DUPLICATE)
-        data[13] = 0x00;
+        data[18] = 0x02; // flag = 0x402 (PAIRED | DUPLICATE)
+        data[19] = 0x00;
 
         let result = toggle_duplicate_flag(&mut data, true);
         assert_eq!(result, Some(0x402)); // PAIRED | DUPLICATE
-        assert_eq!(data[12], 0x02);
+        assert_eq!(data[18], 0x02);
+    }
+
+    #[test]
+    fn test_toggle_duplicate_flag_on_encoded_record() {
+        use noodles::sam::alignment::RecordBuf;
+        use noodles::sam::alignment::record::{Flags, MappingQuality};
+
+        let header = SamHeader::default();
+        let record = RecordBuf::builder()
+            .set_flags(Flags::SEGMENTED | Flags::UNMAPPED)
+            .set_mapping_quality(MappingQuality::new(60).unwrap())
+            .build();
+        let mut data = Vec::new();
+        bam::io::Writer::from(&mut data)
+            .write_alignment_record(&header, &record)
+            .unwrap();
+
+        toggle_duplicate_flag(&mut data, true);
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&data[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        assert!(decoded.flags().is_duplicate());
+        assert!(decoded.flags().is_segmented());
+        assert_eq!(decoded.mapping_quality(), MappingQuality::new(60));
+    }
+
+    #[test]
+    fn test_append_u32_tag_on_encoded_record() {
+        use noodles::sam::alignment::RecordBuf;
+        use noodles::sam::alignment::record::data::field::Tag;
+        use noodles::sam::alignment::record_buf::data::field::Value;
+
+        let header = SamHeader::default();
+        let record = RecordBuf::builder()
+            .set_name("r1")
+            .set_data(
+                [(Tag::READ_GROUP, Value::from("rg1"))]
+                    .into_iter()
+                    .collect(),
+            )
+            .build();
+        let mut data = Vec::new();
+        bam::io::Writer::from(&mut data)
+            .write_alignment_record(&header, &record)
+            .unwrap();
+
+        append_u32_tag(&mut data, DUPLICATE_SET_TAG, 7);
+        append_string_tag(&mut data, DUPLICATE_TYPE_TAG, b"SQ");
+        assert!(block_size_matches(&data));
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&data[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        let decoded = RecordBuf::try_from_alignment_record(&header, &decoded).unwrap();
+        assert_eq!(
+            decoded.data().get(&Tag::READ_GROUP),
+            Some(&Value::from("rg1"))
+        );
+        assert_eq!(
+            decoded.data().get(&Tag::from(DUPLICATE_SET_TAG)),
+            Some(&Value::UInt32(7))
+        );
+        assert_eq!(
+            decoded.data().get(&Tag::from(DUPLICATE_TYPE_TAG)),
+            Some(&Value::from("SQ"))
+        );
+    }
+
+    #[test]
+    fn test_check_bgzf_eof() {
+        let tmp = tempfile::tempdir().unwrap();
+        let bam = format!("{}/test/W62subA_chr1C_5M.bam", env!("CARGO_MANIFEST_DIR"));
+        assert!(check_bgzf_eof(Path::new(&bam)).is_ok());
+        assert!(check_bgzf_eof(Path::new("-")).is_ok());
+
+        let data = std::fs::read(&bam).unwrap();
+        let truncated = tmp.path().join("truncated.bam");
+        std::fs::write(&truncated, &data[..data.len() - BGZF_EOF.len()]).unwrap();
+        let err = check_bgzf_eof(&truncated).unwrap_err().to_string();
+        assert!(err.contains("truncated"));
+
+        std::fs::write(&truncated, b"").unwrap();
+        assert!(check_bgzf_eof(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("out.bam");
+        assert!(check_writable(&out).is_ok());
+        assert!(!out.exists());
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+        assert!(check_writable(Path::new("-")).is_ok());
+
+        std::fs::write(&out, b"keep").unwrap();
+        assert!(check_writable(&out).is_ok());
+        assert_eq!(std::fs::read(&out).unwrap(), b"keep");
+
+        let missing = tmp.path().join("no/such/dir/out.bam");
+        let err = check_writable(&missing).unwrap_err().to_string();
+        assert!(err.contains("no/such/dir/out.bam"), "{}", err);
+        assert!(check_writable(tmp.path()).is_err());
+
+        let err = check_writable(Path::new("s3://bucket/out.bam"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("s3:// paths are only written for --output"),
+            "{}",
+            err
+        );
+        assert!(check_writable(Path::new("gs://bucket/out.bam")).is_err());
+        #[cfg(not(feature = "object_store"))]
+        assert!(check_output(Path::new("s3://bucket/out.bam"), true).is_err());
+        assert!(check_output(&out, false).is_ok());
+        assert_eq!(url_scheme(Path::new("dir/a://b")), None);
+        assert_eq!(url_scheme(Path::new("out.bam")), None);
+    }
+
+    #[test]
+    fn test_record_bytes_keep_aux_data() {
+        use noodles::sam::alignment::RecordBuf;
+        use noodles::sam::alignment::record::data::field::Tag;
+        use noodles::sam::alignment::record_buf::data::field::{Value, value::Array};
+        use noodles::sam::alignment::record_buf::{QualityScores, Sequence};
+
+        let header = SamHeader::default();
+        let data = [
+            (Tag::READ_GROUP, Value::from("rg0")),
+            (Tag::ALIGNMENT_HIT_COUNT, Value::from(3u8)),
+            (Tag::from([b'X', b'f']), Value::from(-1.5f32)),
+            (
+                Tag::from([b'X', b'b']),
+                Value::Array(Array::UInt8(vec![0, 1, 255])),
+            ),
+            (
+                Tag::from([b'X', b'i']),
+                Value::Array(Array::Int32(vec![-70000, 0, 70000])),
+            ),
+            (Tag::from([b'X', b'h']), Value::Hex(b"1AE301".into())),
+            (Tag::from([b'X', b'c']), Value::Character(b'q')),
+        ]
+        .into_iter()
+        .collect();
+        let buf = RecordBuf::builder()
+            .set_name("rich")
+            .set_flags(Flags::SEGMENTED | Flags::UNMAPPED)
+            .set_sequence(Sequence::from(b"ACGTN".to_vec()))
+            .set_quality_scores(QualityScores::from(vec![30, 31, 32, 33, 2]))
+            .set_data(data)
+            .build();
+        let mut original = Vec::new();
+        bam::io::Writer::from(&mut original)
+            .write_alignment_record(&header, &buf)
+            .unwrap();
+        let mut record = bam::Record::default();
+        bam::io::Reader::from(&original[..])
+            .read_record(&mut record)
+            .unwrap();
+
+        let mut bytes = record_to_bytes(&header, &record).unwrap();
+        assert_eq!(bytes, original);
+        toggle_duplicate_flag(&mut bytes, true);
+        assert!(block_size_matches(&bytes));
+        for (i, (a, b)) in bytes.iter().zip(&original).enumerate() {
+            if !(FLAG_OFFSET..FLAG_OFFSET + 2).contains(&i) {
+                assert_eq!(a, b, "byte {}", i);
+            }
+        }
+
+        append_u32_tag(&mut bytes, DUPLICATE_SET_TAG, 7);
+        assert!(block_size_matches(&bytes));
+        let mut decoded = bam::Record::default();
+        bam::io::Reader::from(&bytes[..])
+            .read_record(&mut decoded)
+            .unwrap();
+        let decoded = RecordBuf::try_from_alignment_record(&header, &decoded).unwrap();
+        assert!(decoded.flags().is_duplicate());
+        assert_eq!(decoded.data().len(), buf.data().len() + 1);
+        for (tag, value) in buf.data().iter() {
+            assert_eq!(decoded.data().get(&tag), Some(value));
+        }
+
+        assert!(!block_size_matches(&bytes[..bytes.len() - 1]));
+        assert!(!block_size_matches(&bytes[..3]));
+    }
+
+    #[test]
+    fn test_set_duplicate_on_record() {
+        use noodles::sam::alignment::RecordBuf;
+
+        let header = SamHeader::default();
+        let record = |flags: Flags| {
+            let buf = RecordBuf::builder()
+                .set_name("r1")
+                .set_flags(Flags::SEGMENTED | Flags::UNMAPPED | flags)
+                .build();
+            let mut data = Vec::new();
+            bam::io::Writer::from(&mut data)
+                .write_alignment_record(&header, &buf)
+                .unwrap();
+            let mut record = bam::Record::default();
+            bam::io::Reader::from(&data[..])
+                .read_record(&mut record)
+                .unwrap();
+            record
+        };
+
+        // (input has DUPLICATE, is_duplicate, additive) -> output has DUPLICATE
+        for (had, is_dup, additive, expected) in [
+            (false, false, false, false),
+            (false, true, false, true),
+            (true, false, false, false),
+            (true, true, false, true),
+            (false, false, true, false),
+            (false, true, true, true),
+            (true, false, true, true),
+            (true, true, true, true),
+        ] {
+            let input = if had {
+                Flags::DUPLICATE
+            } else {
+                Flags::empty()
+            };
+            let mut rec = record(input);
+            let flags = set_duplicate_on_record(&header, &mut rec, is_dup, additive).unwrap();
+            assert_eq!(
+                flags.is_duplicate(),
+                expected,
+                "{:?}",
+                (had, is_dup, additive)
+            );
+            assert_eq!(rec.flags(), flags);
+            assert!(flags.is_segmented() && flags.is_unmapped());
+            assert_eq!(rec.name().map(|n| n.to_vec()), Some(b"r1".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_available_space() {
+        let tmp = tempfile::tempdir().unwrap();
+        if cfg!(unix) {
+            assert!(available_space(tmp.path()).is_some_and(|n| n > 0));
+        }
+        assert_eq!(available_space(&tmp.path().join("no/such/dir")), None);
+    }
+
+    #[test]
+    fn test_is_stream() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("in.bam");
+        std::fs::write(&file, b"bam").unwrap();
+        assert!(is_stream(Path::new(STDIN_PATH)));
+        assert!(!is_stream(&file));
+        assert!(!is_stream(tmp.path()));
+        assert!(!is_stream(&tmp.path().join("missing.bam")));
+        #[cfg(unix)]
+        assert!(is_stream(Path::new("/dev/null")));
+    }
+
+    #[test]
+    fn test_spool_to_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let (path, bytes) = spool_to_file(&data[..], tmp.path()).unwrap();
+        assert_eq!(bytes, data.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        drop(path);
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_check_overwrite() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in.bam");
+        let out = tmp.path().join("out.bam");
+        std::fs::write(&input, b"input").unwrap();
+        let inputs = [input.to_string_lossy().into_owned()];
+
+        assert!(check_overwrite(&out, &inputs, false).is_ok());
+        std::fs::write(&out, b"old").unwrap();
+        let err = check_overwrite(&out, &inputs, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("use --force"), "{}", err);
+        assert!(check_overwrite(&out, &inputs, true).is_ok());
+        assert_eq!(std::fs::read(&out).unwrap(), b"old");
+
+        // The input itself, by another spelling or through a hard link, is
+        // refused even with --force
+        let same = tmp.path().join(".").join("in.bam");
+        let link = tmp.path().join("link.bam");
+        std::fs::hard_link(&input, &link).unwrap();
+        for path in [&input, &same, &link] {
+            let err = check_overwrite(path, &inputs, true)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("same file as input"), "{}", err);
+        }
+        assert!(check_overwrite(Path::new("-"), &inputs, false).is_ok());
+    }
+
+    #[test]
+    fn test_add_program_chains_to_last_program() {
+        use noodles::sam::header::record::value::{Map, map::Program, map::program::tag};
+
+        let header = add_program(&SamHeader::default(), "rmduprs -i a.bam").unwrap();
+        let programs = header.programs().as_ref();
+        let pg = &programs[&b"rmduprs"[..]];
+        assert_eq!(
+            pg.other_fields().get(&tag::VERSION).map(|v| v.to_string()),
+            Some(env!("CARGO_PKG_VERSION").into())
+        );
+        assert_eq!(
+            pg.other_fields()
+                .get(&tag::COMMAND_LINE)
+                .map(|v| v.to_string()),
+            Some("rmduprs -i a.bam".into())
+        );
+        assert!(pg.other_fields().get(&tag::PREVIOUS_PROGRAM_ID).is_none());
+
+        // Rerun after another tool: a fresh id, chained to that tool
+        let mut header = header;
+        let samtools = Map::<Program>::builder()
+            .insert(tag::PREVIOUS_PROGRAM_ID, "rmduprs")
+            .build()
+            .unwrap();
+        header
+            .programs_mut()
+            .as_mut()
+            .insert("samtools".into(), samtools);
+        let header = add_program(&header, "rmduprs\t-r").unwrap();
+        let ids: Vec<_> = header
+            .programs()
+            .as_ref()
+            .keys()
+            .map(|id| id.to_string())
+            .collect();
+        assert_eq!(ids, ["rmduprs", "samtools", "rmduprs.1"]);
+        let pg = &header.programs().as_ref()[&b"rmduprs.1"[..]];
+        assert_eq!(
+            pg.other_fields()
+                .get(&tag::PREVIOUS_PROGRAM_ID)
+                .map(|v| v.to_string()),
+            Some("samtools".into())
+        );
+        assert_eq!(
+            pg.other_fields()
+                .get(&tag::COMMAND_LINE)
+                .map(|v| v.to_string()),
+            Some("rmduprs -r".into())
+        );
     }
 
     #[test]
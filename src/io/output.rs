@@ -0,0 +1,1167 @@
+//! Output writers for the marked records
+//!
+//! BAM output keeps the fast path: each record is re-encoded to BAM bytes
+//! and the DUPLICATE bit is patched in place. SAM output has no byte form to
+//! patch, so the record is decoded into a `RecordBuf`, its flags updated and
+//! the result formatted as text, optionally BGZF-compressed (`.sam.gz`, which
+//! htslib reads like plain SAM). All write the same header, so anything
+//! added to it (e.g. a `@PG` line) appears in every format.
+//!
+//! With `--tag-duplicate-set`, members of a duplicate set also get a `DI`
+//! tag. In BAM it is appended to the raw bytes; a record that already has a
+//! `DI` tag goes through `RecordBuf` instead so the stale value is replaced.
+//! `--tag-duplicate-set-size` adds the number of copies in the set as `DS`.
+//! `--tagging-policy` adds the `DT` tag the same way, and removes a `DT`
+//! left in the input on records that are not tagged; `--tag-molecule-id`
+//! adds an `MI` tag with the set id, as fgbio's consensus callers expect,
+//! and `--tag-original-name` a `do` tag with the name of the read kept.
+//!
+//! The writers are generic over their destination; [`OutputWriter::create`]
+//! opens a file, or standard output for `-` so the output can be piped into
+//! the next tool. Progress messages go to stderr (or the log file) and never
+//! mix with the records. With the `object_store` feature an `s3://` or
+//! `gs://` URL is uploaded as it is written (see [`super::remote`]).
+//!
+//! With `--write-index`, a [`BamIndexer`] follows the BAM writer's virtual
+//! position and the `.bai` (or `.csi`, for references past 2^29 bp) is
+//! written when the output is finished, saving a separate `samtools index`
+//! pass over the file.
+//!
+//! [`run_bounded`] lets the write pass read and encode on separate threads
+//! with a bounded queue between them, so a writer that falls behind holds
+//! the reader back instead of letting records pile up in memory.
+
+use super::{
+    DUPLICATE_SET_SIZE_TAG, DUPLICATE_SET_TAG, DUPLICATE_TYPE_TAG, MOLECULE_ID_TAG,
+    ORIGINAL_NAME_TAG, STDOUT_PATH, append_string_tag, append_u32_tag, block_size_matches,
+    record_to_bytes, toggle_duplicate_flag, write_header,
+};
+use anyhow::{Result, anyhow};
+use clap::ValueEnum;
+use noodles::bam;
+use noodles::bgzf::VirtualPosition;
+use noodles::bgzf::io::Writer as BgzfWriter;
+use noodles::bgzf::io::writer::{Builder as BgzfBuilder, CompressionLevel};
+use noodles::csi::{
+    self,
+    binning_index::index::ReferenceSequence,
+    binning_index::index::reference_sequence::{
+        bin::Chunk,
+        index::{BinnedIndex, LinearIndex},
+    },
+    binning_index::{self, BinningIndex as _, Indexer, ReferenceSequence as _},
+};
+use noodles::sam;
+use noodles::sam::alignment::Record as _;
+use noodles::sam::alignment::RecordBuf;
+use noodles::sam::alignment::io::Write as _;
+use noodles::sam::alignment::record::Flags;
+use noodles::sam::alignment::record::data::field::Tag;
+use noodles::sam::alignment::record_buf::data::field::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Format of the output file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// BGZF-compressed BAM
+    #[default]
+    Bam,
+    /// Uncompressed SAM text
+    Sam,
+    /// BGZF-compressed SAM text
+    #[value(name = "sam.gz", alias = "sam-gz")]
+    SamGz,
+}
+
+impl OutputFormat {
+    /// File extension of the format, without the leading dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Bam => "bam",
+            OutputFormat::Sam => "sam",
+            OutputFormat::SamGz => "sam.gz",
+        }
+    }
+
+    /// `explicit` if given, otherwise the format named by the extension of
+    /// `path` (`.sam` or `.sam.gz`), otherwise BAM
+    pub fn resolve(explicit: Option<OutputFormat>, path: &Path) -> OutputFormat {
+        if let Some(format) = explicit {
+            return format;
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".sam.gz") {
+            OutputFormat::SamGz
+        } else if name.ends_with(".sam") {
+            OutputFormat::Sam
+        } else {
+            OutputFormat::Bam
+        }
+    }
+}
+
+/// Which duplicates get a `DT` tag naming their type, as Picard's
+/// `TAGGING_POLICY`
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TaggingPolicy {
+    /// Write no `DT` tags and leave those in the input alone
+    #[default]
+    DontTag,
+    /// Tag optical duplicates `DT:Z:SQ`
+    OpticalOnly,
+    /// Tag optical duplicates `DT:Z:SQ` and the others `DT:Z:LB`
+    All,
+}
+
+/// What to do with the `DT` tag of a record under a [`TaggingPolicy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateTag {
+    /// Remove the tag, for records that are not tagged
+    Clear,
+    /// `DT:Z:LB`: a PCR duplicate made in library preparation
+    Library,
+    /// `DT:Z:SQ`: an optical duplicate made by the sequencer
+    Sequencing,
+}
+
+impl DuplicateTag {
+    /// Value of the tag, `None` for [`DuplicateTag::Clear`]
+    pub fn value(self) -> Option<&'static str> {
+        match self {
+            DuplicateTag::Clear => None,
+            DuplicateTag::Library => Some("LB"),
+            DuplicateTag::Sequencing => Some("SQ"),
+        }
+    }
+}
+
+/// Aux tags the write pass sets on a record; `None` leaves a tag as it is
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordTags {
+    /// `DI:i`, the id of the record's duplicate set
+    pub dup_set: Option<u32>,
+    /// `DS:i`, the number of copies in the record's duplicate set
+    pub set_size: Option<u32>,
+    /// `DT:Z`, set or removed
+    pub dup_type: Option<DuplicateTag>,
+    /// `MI:Z`, the molecule id, written as the decimal set id
+    pub molecule_id: Option<u32>,
+    /// `do:Z`, the name of the read kept from the record's duplicate set
+    pub original_name: Option<Vec<u8>>,
+}
+
+impl RecordTags {
+    /// Whether `record` already has a tag these replace
+    fn replaces_any(&self, record: &bam::Record) -> bool {
+        let has = |tag: [u8; 2]| record.data().get(&tag).is_some();
+        (self.dup_set.is_some() && has(DUPLICATE_SET_TAG))
+            || (self.set_size.is_some() && has(DUPLICATE_SET_SIZE_TAG))
+            || (self.dup_type.is_some() && has(DUPLICATE_TYPE_TAG))
+            || (self.molecule_id.is_some() && has(MOLECULE_ID_TAG))
+            || (self.original_name.is_some() && has(ORIGINAL_NAME_TAG))
+    }
+}
+
+/// Destination opened by [`OutputWriter::create`]
+pub enum OutputSink {
+    Stdout(std::io::Stdout),
+    File(File),
+    /// Object store upload, completed by [`OutputSink::close`]
+    #[cfg(feature = "object_store")]
+    Remote(Box<super::remote::RemoteWriter>),
+}
+
+impl OutputSink {
+    /// Open `path`: standard output for `-`, an object store upload for a
+    /// URL (with the `object_store` feature), otherwise a file
+    pub fn create(path: &Path) -> Result<OutputSink> {
+        if path == Path::new(STDOUT_PATH) {
+            return Ok(OutputSink::Stdout(std::io::stdout()));
+        }
+        #[cfg(feature = "object_store")]
+        if super::url_scheme(path).is_some() {
+            return Ok(OutputSink::Remote(Box::new(
+                super::remote::RemoteWriter::create(path)?,
+            )));
+        }
+        Ok(OutputSink::File(File::create(path)?))
+    }
+
+    /// Flush the output and complete an upload
+    pub fn close(mut self) -> Result<()> {
+        self.flush()?;
+        match self {
+            #[cfg(feature = "object_store")]
+            OutputSink::Remote(writer) => writer.complete(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputSink::Stdout(stdout) => stdout.write(buf),
+            OutputSink::File(file) => file.write(buf),
+            #[cfg(feature = "object_store")]
+            OutputSink::Remote(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputSink::Stdout(stdout) => stdout.flush(),
+            OutputSink::File(file) => file.flush(),
+            #[cfg(feature = "object_store")]
+            OutputSink::Remote(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Writer for the marked output in the selected format
+pub enum OutputWriter<W: Write = OutputSink> {
+    Bam(BgzfWriter<W>),
+    Sam(sam::io::Writer<BufWriter<W>>),
+    SamGz(sam::io::Writer<BgzfWriter<W>>),
+}
+
+impl OutputWriter {
+    /// Create `path` (standard output for `-`) and write `header` to it
+    ///
+    /// `level` is the compression level of BGZF output.
+    pub fn create(
+        path: &Path,
+        format: OutputFormat,
+        level: CompressionLevel,
+        header: &sam::Header,
+    ) -> Result<Self> {
+        OutputWriter::new(OutputSink::create(path)?, format, level, header)
+    }
+
+    /// Finish the output as [`OutputWriter::finish`] does, then close it;
+    /// an upload to an object store only completes here
+    pub fn close(self) -> Result<()> {
+        self.finish()?.close()
+    }
+}
+
+impl<W: Write> OutputWriter<W> {
+    /// Write `header` to `file` and return a writer for the records
+    pub fn new(
+        file: W,
+        format: OutputFormat,
+        level: CompressionLevel,
+        header: &sam::Header,
+    ) -> Result<Self> {
+        let bgzf = |file| {
+            BgzfBuilder::default()
+                .set_compression_level(level)
+                .build_from_writer(file)
+        };
+        Ok(match format {
+            OutputFormat::Bam => {
+                let mut writer = bgzf(file);
+                write_header(&mut writer, header)?;
+                OutputWriter::Bam(writer)
+            }
+            OutputFormat::Sam => {
+                let mut writer = sam::io::Writer::new(BufWriter::with_capacity(1 << 20, file));
+                writer.write_header(header)?;
+                OutputWriter::Sam(writer)
+            }
+            OutputFormat::SamGz => {
+                let mut writer = sam::io::Writer::new(bgzf(file));
+                writer.write_header(header)?;
+                OutputWriter::SamGz(writer)
+            }
+        })
+    }
+
+    /// Write one record, setting or clearing its DUPLICATE flag
+    ///
+    /// `is_duplicate` of `None` leaves the flag as it is in the input;
+    /// `tags` are added, replacing the record's own, or removed.
+    pub fn write_record(
+        &mut self,
+        header: &sam::Header,
+        record: &bam::Record,
+        is_duplicate: Option<bool>,
+        tags: RecordTags,
+    ) -> Result<()> {
+        match self {
+            OutputWriter::Bam(writer) => {
+                let mut data = if tags.replaces_any(record) {
+                    let buf = record_buf(header, record, is_duplicate, &tags)?;
+                    let mut data = Vec::new();
+                    bam::io::Writer::from(&mut data).write_alignment_record(header, &buf)?;
+                    data
+                } else {
+                    let mut data = record_to_bytes(header, record)?;
+                    if let Some(id) = tags.dup_set {
+                        append_u32_tag(&mut data, DUPLICATE_SET_TAG, id);
+                    }
+                    if let Some(size) = tags.set_size {
+                        append_u32_tag(&mut data, DUPLICATE_SET_SIZE_TAG, size);
+                    }
+                    if let Some(value) = tags.dup_type.and_then(DuplicateTag::value) {
+                        append_string_tag(&mut data, DUPLICATE_TYPE_TAG, value.as_bytes());
+                    }
+                    if let Some(id) = tags.molecule_id {
+                        append_string_tag(&mut data, MOLECULE_ID_TAG, id.to_string().as_bytes());
+                    }
+                    if let Some(name) = &tags.original_name {
+                        append_string_tag(&mut data, ORIGINAL_NAME_TAG, name);
+                    }
+                    data
+                };
+                if let Some(is_dup) = is_duplicate {
+                    toggle_duplicate_flag(&mut data, is_dup);
+                }
+                debug_assert!(
+                    block_size_matches(&data),
+                    "stale block_size in a {}-byte record",
+                    data.len()
+                );
+                writer.write_all(&data)?;
+            }
+            OutputWriter::Sam(writer) => {
+                let buf = record_buf(header, record, is_duplicate, &tags)?;
+                writer.write_alignment_record(header, &buf)?;
+            }
+            OutputWriter::SamGz(writer) => {
+                let buf = record_buf(header, record, is_duplicate, &tags)?;
+                writer.write_alignment_record(header, &buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Virtual position of the next record in BAM output; `None` for SAM
+    pub fn virtual_position(&self) -> Option<VirtualPosition> {
+        match self {
+            OutputWriter::Bam(writer) => Some(writer.virtual_position()),
+            OutputWriter::Sam(_) | OutputWriter::SamGz(_) => None,
+        }
+    }
+
+    /// Flush buffered data and, for BGZF output, write the EOF block;
+    /// returns the destination
+    pub fn finish(self) -> Result<W> {
+        let mut file = match self {
+            OutputWriter::Bam(writer) => writer.finish()?,
+            OutputWriter::Sam(writer) => writer
+                .into_inner()
+                .into_inner()
+                .map_err(|e| e.into_error())?,
+            OutputWriter::SamGz(writer) => writer.into_inner().finish()?,
+        };
+        file.flush()?;
+        Ok(file)
+    }
+}
+
+/// Format of the index written with `--write-index`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// BAI, for references of up to 2^29 bp
+    Bai,
+    /// CSI, for references of any length
+    Csi,
+}
+
+/// Longest reference a BAI index can address (positions below 2^29)
+pub const BAI_MAX_REFERENCE_LENGTH: usize = 1 << 29;
+
+/// Smallest interval of a CSI index, as `samtools index -c` uses
+const CSI_MIN_SHIFT: u8 = 14;
+
+impl IndexFormat {
+    /// `explicit` if given, otherwise BAI unless a reference of `header` is
+    /// too long for it
+    pub fn resolve(explicit: Option<IndexFormat>, header: &sam::Header) -> IndexFormat {
+        explicit.unwrap_or(if max_reference_length(header) > BAI_MAX_REFERENCE_LENGTH {
+            IndexFormat::Csi
+        } else {
+            IndexFormat::Bai
+        })
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            IndexFormat::Bai => "bai",
+            IndexFormat::Csi => "csi",
+        }
+    }
+}
+
+/// Length of the longest reference in `header` (0 without any)
+pub fn max_reference_length(header: &sam::Header) -> usize {
+    header
+        .reference_sequences()
+        .values()
+        .map(|rs| rs.length().get())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Path of the index written next to `output`: `<output>.bai` or
+/// `<output>.csi`, as `samtools index` names them
+pub fn index_path(output: &Path, format: IndexFormat) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(format.extension());
+    PathBuf::from(path)
+}
+
+/// Index of BAM output, built while the records are written
+///
+/// Each record is added with the virtual position it ends at; the previous
+/// record's end is where it starts. The output must be coordinate-sorted.
+/// A CSI index gets enough levels to cover the longest reference, so
+/// positions past 2^29 (common in plant genomes) are binned correctly.
+pub struct BamIndexer {
+    format: IndexFormat,
+    indexer: Indexer<LinearIndex>,
+    reference_count: usize,
+    start: VirtualPosition,
+}
+
+impl BamIndexer {
+    /// Index records of `header`'s references written from `start`, the
+    /// position after the header
+    pub fn new(format: IndexFormat, header: &sam::Header, start: VirtualPosition) -> Self {
+        let indexer = match format {
+            IndexFormat::Bai => Indexer::default(),
+            IndexFormat::Csi => {
+                Indexer::new(CSI_MIN_SHIFT, csi_depth(max_reference_length(header)))
+            }
+        };
+        BamIndexer {
+            format,
+            indexer,
+            reference_count: header.reference_sequences().len(),
+            start,
+        }
+    }
+
+    /// Add `record`, which ends at `end` in the output
+    pub fn add_record(&mut self, record: &bam::Record, end: VirtualPosition) -> Result<()> {
+        let context = match (
+            record.reference_sequence_id().transpose()?,
+            record.alignment_start().transpose()?,
+            record.alignment_end().transpose()?,
+        ) {
+            (Some(id), Some(start), Some(end)) => {
+                Some((id, start, end, !record.flags().is_unmapped()))
+            }
+            _ => None,
+        };
+        self.indexer
+            .add_record(context, Chunk::new(self.start, end))?;
+        self.start = end;
+        Ok(())
+    }
+
+    /// Write the index to `path`
+    pub fn write(self, path: &Path) -> Result<()> {
+        let index = self.indexer.build(self.reference_count);
+        match self.format {
+            IndexFormat::Bai => bam::bai::fs::write(path, &index)?,
+            IndexFormat::Csi => csi::fs::write(path, &binned(&index))?,
+        }
+        Ok(())
+    }
+}
+
+/// CSI form of `index`: the same bins, each with the linear index entry of
+/// its first window as its first offset, as htslib sets it
+///
+/// A query skips chunks ending before that offset. Taking it from the first
+/// record of the bin itself (noodles' CSI indexer) would skip records of
+/// other bins that overlap the query start and come earlier in the file.
+fn binned(index: &binning_index::Index<LinearIndex>) -> csi::Index {
+    let depth = index.depth();
+    let reference_sequences = index
+        .reference_sequences()
+        .iter()
+        .map(|rs| {
+            let linear = rs.index();
+            let offsets: BinnedIndex = rs
+                .bins()
+                .keys()
+                .map(|&id| {
+                    (
+                        id,
+                        bin_first_window(id, depth)
+                            .and_then(|w| linear.get(w))
+                            .copied()
+                            .unwrap_or_default(),
+                    )
+                })
+                .collect();
+            ReferenceSequence::new(rs.bins().clone(), offsets, rs.metadata().cloned())
+        })
+        .collect();
+    let mut builder = csi::Index::builder()
+        .set_min_shift(index.min_shift())
+        .set_depth(depth)
+        .set_reference_sequences(reference_sequences);
+    if let Some(n) = index.unplaced_unmapped_record_count() {
+        builder = builder.set_unplaced_unmapped_record_count(n);
+    }
+    builder.build()
+}
+
+/// Index of the first smallest-interval window covered by bin `id` in a
+/// binning index of `depth` levels; `None` for ids past the deepest level
+fn bin_first_window(id: usize, depth: u8) -> Option<usize> {
+    let mut first = 0;
+    for level in 0..=u32::from(depth) {
+        let next = first + (1 << (3 * level));
+        if id < next {
+            return Some((id - first) << (3 * (u32::from(depth) - level)));
+        }
+        first = next;
+    }
+    None
+}
+
+/// Levels of a CSI index whose bins cover `max_length` bp, computed as
+/// htslib does
+fn csi_depth(max_length: usize) -> u8 {
+    let max_length = max_length as u64 + 256;
+    let mut depth = 0;
+    while max_length > 1u64 << (CSI_MIN_SHIFT + 3 * depth) {
+        depth += 1;
+    }
+    depth
+}
+
+/// Output writer that indexes what it writes (`--write-index`)
+pub struct IndexedWriter {
+    writer: OutputWriter,
+    index: Option<(BamIndexer, PathBuf)>,
+}
+
+impl IndexedWriter {
+    /// Create `path` as [`OutputWriter::create`] does and, with `index`,
+    /// index BAM output into [`index_path`]
+    pub fn create(
+        path: &Path,
+        format: OutputFormat,
+        level: CompressionLevel,
+        header: &sam::Header,
+        index: Option<IndexFormat>,
+    ) -> Result<Self> {
+        let writer = OutputWriter::create(path, format, level, header)?;
+        let index = index
+            .zip(writer.virtual_position())
+            .map(|(index_format, start)| {
+                (
+                    BamIndexer::new(index_format, header, start),
+                    index_path(path, index_format),
+                )
+            });
+        Ok(IndexedWriter { writer, index })
+    }
+
+    /// Write one record as [`OutputWriter::write_record`] does
+    pub fn write_record(
+        &mut self,
+        header: &sam::Header,
+        record: &bam::Record,
+        is_duplicate: Option<bool>,
+        tags: RecordTags,
+    ) -> Result<()> {
+        self.writer
+            .write_record(header, record, is_duplicate, tags)?;
+        if let (Some((indexer, _)), Some(end)) = (&mut self.index, self.writer.virtual_position()) {
+            indexer.add_record(record, end)?;
+        }
+        Ok(())
+    }
+
+    /// Finish the output, then write its index
+    pub fn finish(self) -> Result<()> {
+        self.writer.close()?;
+        if let Some((indexer, path)) = self.index {
+            indexer.write(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Output split by reference sequence (`--shard-by-chromosome`)
+///
+/// Each reference with reads gets `<dir>/<name>.<ext>` and reads without a
+/// reference go to `<dir>/unmapped.<ext>`. Every shard carries the full
+/// header, so mates on other references stay valid and the shards can be
+/// merged back with `samtools merge`. With coordinate-sorted input a shard
+/// is finished as soon as the next reference starts, so only one file is
+/// open at a time; otherwise every shard stays open until the end.
+pub struct ShardedOutput {
+    dir: PathBuf,
+    format: OutputFormat,
+    level: CompressionLevel,
+    index: Option<IndexFormat>,
+    sorted: bool,
+    open: HashMap<Option<usize>, IndexedWriter>,
+    finished: HashSet<Option<usize>>,
+}
+
+impl ShardedOutput {
+    /// Shards of `format` in `dir`, each indexed in `index` when given
+    pub fn new(
+        dir: &Path,
+        format: OutputFormat,
+        level: CompressionLevel,
+        header: &sam::Header,
+        index: Option<IndexFormat>,
+    ) -> Self {
+        ShardedOutput {
+            dir: dir.to_path_buf(),
+            format,
+            level,
+            index,
+            sorted: super::is_coordinate_sorted(header),
+            open: HashMap::new(),
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Path of the shard for reference `ref_id` (`None` for unplaced reads)
+    pub fn shard_path(&self, header: &sam::Header, ref_id: Option<usize>) -> PathBuf {
+        let name = ref_id
+            .and_then(|id| header.reference_sequences().get_index(id))
+            .map(|(name, _)| String::from_utf8_lossy(name).replace(['/', '\\', '\0'], "_"))
+            .unwrap_or_else(|| "unmapped".to_string());
+        self.dir
+            .join(format!("{}.{}", name, self.format.extension()))
+    }
+
+    /// Write one record to the shard of its reference
+    pub fn write_record(
+        &mut self,
+        header: &sam::Header,
+        record: &bam::Record,
+        is_duplicate: Option<bool>,
+        tags: RecordTags,
+    ) -> Result<()> {
+        let ref_id = record.reference_sequence_id().transpose()?;
+        if !self.open.contains_key(&ref_id) {
+            if self.finished.contains(&ref_id) {
+                let path = self.shard_path(header, ref_id);
+                return Err(anyhow!(
+                    "{}: input is not coordinate-sorted; reads came back to this shard",
+                    path.display()
+                ));
+            }
+            if self.sorted {
+                for (done, writer) in self.open.drain() {
+                    writer.finish()?;
+                    self.finished.insert(done);
+                }
+            }
+            let path = self.shard_path(header, ref_id);
+            let writer = IndexedWriter::create(&path, self.format, self.level, header, self.index)?;
+            self.open.insert(ref_id, writer);
+        }
+        self.open
+            .get_mut(&ref_id)
+            .expect("shard was just opened")
+            .write_record(header, record, is_duplicate, tags)
+    }
+
+    /// Finish every open shard and return how many shards were written
+    pub fn finish(self) -> Result<usize> {
+        let count = self.finished.len() + self.open.len();
+        for (_, writer) in self.open {
+            writer.finish()?;
+        }
+        Ok(count)
+    }
+}
+
+/// Run `produce` and `consume` on two threads joined by a queue of at most
+/// `depth` items
+///
+/// `produce` is handed a `send` callback that blocks while the queue is full.
+/// When `consume` fails the queue is closed, `send` returns an error and the
+/// consumer's error is reported.
+pub fn run_bounded<T: Send>(
+    depth: usize,
+    produce: impl FnOnce(&mut dyn FnMut(T) -> Result<()>) -> Result<()>,
+    mut consume: impl FnMut(T) -> Result<()> + Send,
+) -> Result<()> {
+    let (tx, rx) = std::sync::mpsc::sync_channel(depth);
+    std::thread::scope(|s| {
+        let consumer = s.spawn(move || -> Result<()> {
+            for item in rx {
+                consume(item)?;
+            }
+            Ok(())
+        });
+        let produced =
+            produce(&mut |item| tx.send(item).map_err(|_| anyhow!("output writer stopped")));
+        drop(tx);
+        let consumed = consumer
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("output writer panicked")));
+        consumed.and(produced)
+    })
+}
+
+/// Decode `record` with its DUPLICATE flag and `tags` applied
+fn record_buf(
+    header: &sam::Header,
+    record: &bam::Record,
+    is_duplicate: Option<bool>,
+    tags: &RecordTags,
+) -> Result<RecordBuf> {
+    let mut buf = RecordBuf::try_from_alignment_record(header, record)?;
+    if let Some(is_dup) = is_duplicate {
+        buf.flags_mut().set(Flags::DUPLICATE, is_dup);
+    }
+    if let Some(id) = tags.dup_set {
+        buf.data_mut()
+            .insert(Tag::from(DUPLICATE_SET_TAG), Value::UInt32(id));
+    }
+    if let Some(size) = tags.set_size {
+        buf.data_mut()
+            .insert(Tag::from(DUPLICATE_SET_SIZE_TAG), Value::UInt32(size));
+    }
+    if let Some(id) = tags.molecule_id {
+        buf.data_mut()
+            .insert(Tag::from(MOLECULE_ID_TAG), Value::from(id.to_string()));
+    }
+    if let Some(name) = &tags.original_name {
+        buf.data_mut().insert(
+            Tag::from(ORIGINAL_NAME_TAG),
+            Value::String(name.as_slice().into()),
+        );
+    }
+    match tags.dup_type.map(DuplicateTag::value) {
+        Some(Some(value)) => {
+            buf.data_mut()
+                .insert(Tag::from(DUPLICATE_TYPE_TAG), Value::from(value));
+        }
+        Some(None) => {
+            buf.data_mut().remove(&Tag::from(DUPLICATE_TYPE_TAG));
+        }
+        None => {}
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bam() -> String {
+        format!("{}/test/W62subA_chr1C_5M.bam", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_run_bounded_caps_queued_items() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        // A fast producer against a writer that sleeps on every item: the
+        // number of items produced but not yet written never exceeds the
+        // queue depth plus the one being written and the one being sent
+        let depth = 8;
+        let produced = AtomicUsize::new(0);
+        let written = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        run_bounded(
+            depth,
+            |send| {
+                for i in 0..200u32 {
+                    let in_flight = produced.fetch_add(1, Ordering::SeqCst) + 1
+                        - written.load(Ordering::SeqCst);
+                    peak.fetch_max(in_flight, Ordering::SeqCst);
+                    send(vec![i; 1024])?;
+                }
+                Ok(())
+            },
+            |item: Vec<u32>| {
+                std::thread::sleep(Duration::from_micros(200));
+                assert_eq!(item.len(), 1024);
+                written.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(written.load(Ordering::SeqCst), 200);
+        assert!(peak.load(Ordering::SeqCst) <= depth + 2, "{:?}", peak);
+
+        // A failing writer stops the producer and its error is reported
+        let err = run_bounded(
+            depth,
+            |send| {
+                for i in 0..1000u32 {
+                    send(i)?;
+                }
+                Ok(())
+            },
+            |i| {
+                if i == 5 {
+                    Err(anyhow!("disk full"))
+                } else {
+                    Ok(())
+                }
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+    }
+
+    #[test]
+    fn test_output_format_from_extension() {
+        let resolve = |explicit, path: &str| OutputFormat::resolve(explicit, Path::new(path));
+        assert_eq!(resolve(None, "out.bam"), OutputFormat::Bam);
+        assert_eq!(resolve(None, "out.sam"), OutputFormat::Sam);
+        assert_eq!(resolve(None, "dir.sam/OUT.SAM.GZ"), OutputFormat::SamGz);
+        assert_eq!(resolve(None, "out.gz"), OutputFormat::Bam);
+        assert_eq!(
+            resolve(Some(OutputFormat::Bam), "out.sam"),
+            OutputFormat::Bam
+        );
+        assert_eq!(
+            OutputFormat::from_str("sam.gz", false),
+            Ok(OutputFormat::SamGz)
+        );
+        assert_eq!(
+            OutputFormat::from_str("sam-gz", false),
+            Ok(OutputFormat::SamGz)
+        );
+    }
+
+    #[test]
+    fn test_writer_over_any_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut reader = bam::io::reader::Builder
+            .build_from_path(test_bam())
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let records: Vec<_> = reader.records().take(20).map(|r| r.unwrap()).collect();
+
+        for format in [OutputFormat::Bam, OutputFormat::Sam, OutputFormat::SamGz] {
+            let path = tmp.path().join("out");
+            let level = CompressionLevel::default();
+            let mut to_file = OutputWriter::create(&path, format, level, &header).unwrap();
+            let mut to_vec = OutputWriter::new(Vec::new(), format, level, &header).unwrap();
+            for (i, record) in records.iter().enumerate() {
+                to_file
+                    .write_record(&header, record, Some(i % 2 == 0), RecordTags::default())
+                    .unwrap();
+                to_vec
+                    .write_record(&header, record, Some(i % 2 == 0), RecordTags::default())
+                    .unwrap();
+            }
+            to_file.close().unwrap();
+            let data = to_vec.finish().unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), data, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_stale_duplicate_type_tag_is_replaced() {
+        let header = sam::Header::default();
+        let input = RecordBuf::builder()
+            .set_name("r1")
+            .set_data(
+                [(Tag::from(DUPLICATE_TYPE_TAG), Value::from("LB"))]
+                    .into_iter()
+                    .collect(),
+            )
+            .build();
+        let mut data = Vec::new();
+        bam::io::Writer::from(&mut data)
+            .write_alignment_record(&header, &input)
+            .unwrap();
+        let mut record = bam::Record::default();
+        bam::io::Reader::from(&data[..])
+            .read_record(&mut record)
+            .unwrap();
+
+        let written = |dup_type| {
+            let mut writer = OutputWriter::new(
+                Vec::new(),
+                OutputFormat::Bam,
+                CompressionLevel::default(),
+                &header,
+            )
+            .unwrap();
+            let tags = RecordTags {
+                dup_type,
+                ..Default::default()
+            };
+            writer
+                .write_record(&header, &record, Some(true), tags)
+                .unwrap();
+            let OutputWriter::Bam(writer) = writer else {
+                unreachable!()
+            };
+            let data = writer.finish().unwrap();
+            let mut reader = bam::io::Reader::new(&data[..]);
+            reader.read_header().unwrap();
+            let record = reader.records().next().unwrap().unwrap();
+            let buf = RecordBuf::try_from_alignment_record(&header, &record).unwrap();
+            buf.data().get(&Tag::from(DUPLICATE_TYPE_TAG)).cloned()
+        };
+        assert_eq!(written(None), Some(Value::from("LB")));
+        assert_eq!(
+            written(Some(DuplicateTag::Sequencing)),
+            Some(Value::from("SQ"))
+        );
+        assert_eq!(written(Some(DuplicateTag::Clear)), None);
+    }
+
+    #[test]
+    fn test_index_answers_queries_like_a_reindex() {
+        // Indexing while writing finds the same records as indexing the
+        // finished file. Chunk ends can differ: the writer points past the
+        // last record in its block, a reader at the start of the next block
+        let tmp = tempfile::tempdir().unwrap();
+        let mut reader = bam::io::reader::Builder
+            .build_from_path(test_bam())
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let path = tmp.path().join("out.bam");
+        let level = CompressionLevel::default();
+        let mut writer = OutputWriter::create(&path, OutputFormat::Bam, level, &header).unwrap();
+        let start = writer.virtual_position().unwrap();
+        let mut bai = BamIndexer::new(IndexFormat::Bai, &header, start);
+        let mut csi = BamIndexer::new(IndexFormat::Csi, &header, start);
+        let mut starts = Vec::new();
+        for (i, record) in reader.records().take(50_000).enumerate() {
+            let record = record.unwrap();
+            starts.extend(
+                record
+                    .alignment_start()
+                    .transpose()
+                    .unwrap()
+                    .map(usize::from),
+            );
+            writer
+                .write_record(&header, &record, Some(i % 7 == 0), RecordTags::default())
+                .unwrap();
+            let end = writer.virtual_position().unwrap();
+            bai.add_record(&record, end).unwrap();
+            csi.add_record(&record, end).unwrap();
+        }
+        writer.close().unwrap();
+        let bai_file = index_path(&path, IndexFormat::Bai);
+        let csi_file = index_path(&path, IndexFormat::Csi);
+        assert_eq!(bai_file, tmp.path().join("out.bam.bai"));
+        assert_eq!(csi_file, tmp.path().join("out.bam.csi"));
+        bai.write(&bai_file).unwrap();
+        csi.write(&csi_file).unwrap();
+
+        fn query(
+            path: &Path,
+            header: &sam::Header,
+            index: impl csi::BinningIndex + 'static,
+            region: &str,
+        ) -> Vec<Vec<u8>> {
+            let mut reader = bam::io::indexed_reader::Builder::default()
+                .set_index(index)
+                .build_from_path(path)
+                .unwrap();
+            reader.read_header().unwrap();
+            let region = region.parse().unwrap();
+            let records: Vec<_> = reader
+                .query(header, &region)
+                .unwrap()
+                .records()
+                .map(|r| r.unwrap())
+                .collect();
+            records
+                .iter()
+                .map(|r| record_to_bytes(header, r).unwrap())
+                .collect()
+        }
+        let written_bai = bam::bai::fs::read(&bai_file).unwrap();
+        let written_csi = csi::fs::read(&csi_file).unwrap();
+        assert_eq!(csi::BinningIndex::depth(&written_csi), 6);
+        let reindexed = bam::fs::index(&path).unwrap();
+        let (first, middle, last) = (starts[0], starts[25_000], starts[starts.len() - 1]);
+        for region in [
+            "chr1C".to_string(),
+            format!("chr1C:1-{}", first),
+            format!("chr1C:{}-{}", middle, middle + 5000),
+            format!("chr1C:{}-{}", last, last),
+        ] {
+            let want = query(&path, &header, reindexed.clone(), &region);
+            assert!(!want.is_empty(), "{}", region);
+            assert!(
+                query(&path, &header, written_bai.clone(), &region) == want,
+                "bai {}",
+                region
+            );
+            assert!(
+                query(&path, &header, written_csi.clone(), &region) == want,
+                "csi {}",
+                region
+            );
+        }
+        assert!(
+            OutputWriter::new(Vec::new(), OutputFormat::Sam, level, &header)
+                .unwrap()
+                .virtual_position()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_index_format_follows_longest_reference() {
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+        use std::num::NonZeroUsize;
+
+        let header = |len: usize| {
+            sam::Header::builder()
+                .add_reference_sequence(
+                    "short",
+                    Map::<ReferenceSequence>::new(NonZeroUsize::new(1000).unwrap()),
+                )
+                .add_reference_sequence(
+                    "long",
+                    Map::<ReferenceSequence>::new(NonZeroUsize::new(len).unwrap()),
+                )
+                .build()
+        };
+        assert_eq!(
+            IndexFormat::resolve(None, &header(BAI_MAX_REFERENCE_LENGTH)),
+            IndexFormat::Bai
+        );
+        assert_eq!(
+            IndexFormat::resolve(None, &header(BAI_MAX_REFERENCE_LENGTH + 1)),
+            IndexFormat::Csi
+        );
+        assert_eq!(
+            IndexFormat::resolve(Some(IndexFormat::Csi), &header(1000)),
+            IndexFormat::Csi
+        );
+        assert_eq!(max_reference_length(&sam::Header::default()), 0);
+
+        // Levels as htslib picks them: 5 reach 2^29, wheat's 830 Mb needs 6
+        assert_eq!(csi_depth(BAI_MAX_REFERENCE_LENGTH - 256), 5);
+        assert_eq!(csi_depth(BAI_MAX_REFERENCE_LENGTH), 6);
+        assert_eq!(csi_depth(830_829_764), 6);
+        assert_eq!(csi_depth(1 << 35), 8);
+
+        // Bin 0 spans everything; the last bin of a level ends at the top
+        assert_eq!(bin_first_window(0, 5), Some(0));
+        assert_eq!(bin_first_window(1, 5), Some(0));
+        assert_eq!(bin_first_window(2, 5), Some(1 << 12));
+        assert_eq!(bin_first_window(4681, 5), Some(0));
+        assert_eq!(bin_first_window(37448, 5), Some((1 << 15) - 1));
+        assert_eq!(bin_first_window(37449, 5), None);
+        assert_eq!(bin_first_window(37449, 6), Some(0));
+    }
+
+    #[test]
+    fn test_sam_and_bam_output_agree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut reader = bam::io::reader::Builder
+            .build_from_path(test_bam())
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let records: Vec<_> = reader.records().take(50).map(|r| r.unwrap()).collect();
+
+        let bam_path = tmp.path().join("out.bam");
+        let sam_path = tmp.path().join("out.sam");
+        let sam_gz_path = tmp.path().join("out.sam.gz");
+        let dup_type = |i: usize| {
+            [
+                None,
+                Some(DuplicateTag::Clear),
+                Some(DuplicateTag::Library),
+                Some(DuplicateTag::Sequencing),
+            ][i % 4]
+        };
+        let outputs = [
+            (&bam_path, OutputFormat::Bam),
+            (&sam_path, OutputFormat::Sam),
+            (&sam_gz_path, OutputFormat::SamGz),
+        ];
+        for (path, format) in outputs {
+            let mut writer =
+                OutputWriter::create(path, format, CompressionLevel::default(), &header).unwrap();
+            for (i, record) in records.iter().enumerate() {
+                let tags = RecordTags {
+                    dup_set: (i % 5 == 0).then_some(i as u32),
+                    set_size: (i % 5 == 0).then_some(2),
+                    dup_type: dup_type(i),
+                    molecule_id: (i % 2 == 0).then_some(i as u32),
+                    original_name: (i % 3 == 0).then(|| format!("kept{}", i).into_bytes()),
+                };
+                writer
+                    .write_record(&header, record, Some(i % 3 == 0), tags)
+                    .unwrap();
+            }
+            writer.close().unwrap();
+        }
+
+        let text = std::fs::read_to_string(&sam_path).unwrap();
+        assert!(text.starts_with("@HD\t"));
+        let mut bgzf = noodles::bgzf::io::Reader::new(File::open(&sam_gz_path).unwrap());
+        let mut unzipped = String::new();
+        std::io::Read::read_to_string(&mut bgzf, &mut unzipped).unwrap();
+        assert_eq!(unzipped, text);
+        let mut sam_reader = sam::io::Reader::new(text.as_bytes());
+        let sam_header = sam_reader.read_header().unwrap();
+        assert_eq!(
+            sam_header.reference_sequences().len(),
+            header.reference_sequences().len()
+        );
+
+        let mut bam_reader = bam::io::reader::Builder.build_from_path(&bam_path).unwrap();
+        bam_reader.read_header().unwrap();
+        let sam_records: Vec<_> = sam_reader
+            .record_bufs(&sam_header)
+            .map(|r| r.unwrap())
+            .collect();
+        let bam_records: Vec<_> = bam_reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(sam_records.len(), 50);
+        for (i, (s, b)) in sam_records.iter().zip(&bam_records).enumerate() {
+            assert_eq!(s.flags().is_duplicate(), i % 3 == 0);
+            let b = RecordBuf::try_from_alignment_record(&header, b).unwrap();
+            let di = Tag::from(DUPLICATE_SET_TAG);
+            let expected = (i % 5 == 0).then_some(Value::UInt32(i as u32));
+            assert_eq!(b.data().get(&di), expected.as_ref());
+            assert_eq!(
+                s.data().get(&di).and_then(|v| v.as_int()),
+                expected.and_then(|v| v.as_int())
+            );
+            let ds = Tag::from(DUPLICATE_SET_SIZE_TAG);
+            let expected = (i % 5 == 0).then_some(Value::UInt32(2));
+            assert_eq!(b.data().get(&ds), expected.as_ref());
+            assert_eq!(
+                s.data().get(&ds).and_then(|v| v.as_int()),
+                expected.and_then(|v| v.as_int())
+            );
+            let dt = Tag::from(DUPLICATE_TYPE_TAG);
+            let expected = dup_type(i).and_then(DuplicateTag::value).map(Value::from);
+            assert_eq!(b.data().get(&dt), expected.as_ref());
+            assert_eq!(s.data().get(&dt), expected.as_ref());
+            let mi = Tag::from(MOLECULE_ID_TAG);
+            let expected = (i % 2 == 0).then(|| Value::from(i.to_string()));
+            assert_eq!(b.data().get(&mi), expected.as_ref());
+            assert_eq!(s.data().get(&mi), expected.as_ref());
+            let original = Tag::from(ORIGINAL_NAME_TAG);
+            let expected = (i % 3 == 0).then(|| Value::from(format!("kept{}", i)));
+            assert_eq!(b.data().get(&original), expected.as_ref());
+            assert_eq!(s.data().get(&original), expected.as_ref());
+            assert_eq!(u16::from(s.flags()), u16::from(b.flags()));
+            assert_eq!(s.name().map(|n| n.to_vec()), b.name().map(|n| n.to_vec()));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Output streamed to object storage (`--output s3://bucket/out.bam`)
+//!
+//! Only built with the `object_store` feature. The output is sent as a
+//! multipart upload while it is written: parts of [`PART_SIZE`] go up as they
+//! fill, with at most [`PARTS_IN_FLIGHT`] held in memory, so nothing but the
+//! temp chunks touches the local disk. The object only appears in the store
+//! once [`RemoteWriter::complete`] has run; an upload dropped before that is
+//! aborted. Credentials and regions come from the environment, as for the
+//! cloud CLIs (`AWS_ACCESS_KEY_ID`, `AWS_REGION`,
+//! `GOOGLE_APPLICATION_CREDENTIALS`, ...).
+
+use anyhow::{Context, Result, bail};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, ObjectStoreExt, WriteMultipart};
+use std::io::{self, Write};
+use std::path::Path;
+use tokio::runtime::Runtime;
+
+/// Size of each uploaded part (S3 needs at least 5 MiB for all but the last)
+pub const PART_SIZE: usize = 16 << 20;
+
+/// Parts uploaded at once; writing waits while this many are in flight
+pub const PARTS_IN_FLIGHT: usize = 4;
+
+/// Store and object named by a URL such as `s3://bucket/out.bam`
+fn open_store(path: &Path) -> Result<(Box<dyn ObjectStore>, ObjectPath)> {
+    let text = path
+        .to_str()
+        .with_context(|| format!("{}: not a valid URL", path.display()))?;
+    let url = url::Url::parse(text).with_context(|| format!("{}: not a valid URL", text))?;
+    object_store::parse_url_opts(&url, std::env::vars())
+        .with_context(|| format!("{}: cannot open the object store", text))
+}
+
+/// Runtime driving the store client from the synchronous writer
+fn runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?)
+}
+
+/// Fail early when `path` cannot be uploaded to: a URL no store takes, a
+/// store that cannot be reached, or an existing object without `force`
+pub fn check_url(path: &Path, force: bool) -> Result<()> {
+    let (store, location) = open_store(path)?;
+    match runtime()?.block_on(store.head(&location)) {
+        Ok(_) if !force => bail!(
+            "{}: output object exists; use --force to overwrite it",
+            path.display()
+        ),
+        Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(e) => bail!("{}: output object is not writable: {}", path.display(), e),
+    }
+}
+
+/// Multipart upload of one object, written through [`Write`]
+pub struct RemoteWriter {
+    runtime: Runtime,
+    upload: Option<WriteMultipart>,
+}
+
+impl RemoteWriter {
+    /// Start the upload of `path`
+    pub fn create(path: &Path) -> Result<RemoteWriter> {
+        let (store, location) = open_store(path)?;
+        let runtime = runtime()?;
+        let upload = runtime
+            .block_on(store.put_multipart(&location))
+            .with_context(|| format!("{}: cannot start the upload", path.display()))?;
+        Ok(RemoteWriter {
+            runtime,
+            upload: Some(WriteMultipart::new_with_chunk_size(upload, PART_SIZE)),
+        })
+    }
+
+    /// Send the last part and complete the upload, which makes the object
+    /// visible in the store
+    pub fn complete(mut self) -> Result<()> {
+        if let Some(upload) = self.upload.take() {
+            self.runtime
+                .block_on(upload.finish())
+                .context("cannot complete the upload")?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for RemoteWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let upload = self
+            .upload
+            .as_mut()
+            .ok_or_else(|| io::Error::other("upload already completed"))?;
+        self.runtime
+            .block_on(upload.wait_for_capacity(PARTS_IN_FLIGHT))
+            .map_err(io::Error::other)?;
+        // Full parts are spawned on the runtime right away
+        let _guard = self.runtime.enter();
+        upload.write(buf);
+        Ok(buf.len())
+    }
+
+    /// Parts go up as they fill; the rest is sent by [`RemoteWriter::complete`]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RemoteWriter {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            // Uploaded parts would otherwise be kept (and billed) by the store
+            let _ = self.runtime.block_on(upload.abort());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_url(path: &Path) -> std::path::PathBuf {
+        format!("file://{}", path.display()).into()
+    }
+
+    #[test]
+    fn test_remote_writer_uploads_on_complete() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.bam");
+        let url = file_url(&path);
+        let data: Vec<u8> = (0..PART_SIZE + 1000).map(|i| i as u8).collect();
+
+        assert!(check_url(&url, false).is_ok());
+        let mut writer = RemoteWriter::create(&url).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        writer.complete().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        let err = check_url(&url, false).unwrap_err().to_string();
+        assert!(err.contains("output object exists"), "{}", err);
+        assert!(check_url(&url, true).is_ok());
+    }
+
+    #[test]
+    fn test_remote_writer_aborts_when_dropped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("out.bam");
+        let mut writer = RemoteWriter::create(&file_url(&path)).unwrap();
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+        assert!(!path.exists());
+    }
+}
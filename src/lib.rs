@@ -9,24 +9,46 @@
 //! use rmduprs::{Args, run_markdup};
 //!
 //! let args = Args {
-//!     input: "input.bam".to_string(),
+//!     input: vec!["input.bam".to_string()],
 //!     output: "output.bam".to_string(),
-//!     remove_duplicates: false,
 //!     threads: 8,
-//!     batch_size: 2_000_000,
-//!     tmp_dir: None,
+//!     ..Default::default()
 //! };
 //!
 //! run_markdup(&args)?;
+//!
+//! // Or consume the duplicate decisions without writing a BAM
+//! for item in rmduprs::mark_iter(&args)? {
+//!     let (record, is_dup) = item?;
+//! }
+//! ```
 
 pub mod algorithm;
 pub mod args;
+pub mod density;
+pub mod fastq;
+pub mod histogram;
 pub mod io;
+pub mod loci;
 pub mod metadata;
+pub mod metrics;
+pub mod optical;
+pub mod pending;
+pub mod pipeline;
+#[cfg(feature = "python")]
+mod python;
+pub mod sequence;
+pub mod tiles;
 pub mod utils;
+pub mod verify;
 
 // Re-export commonly used items
-pub use algorithm::{get_5p_pos, get_score, identify_dups};
+pub use algorithm::{
+    DuplicatePolicy, SambambaPolicy, SamtoolsPolicy, ScoreStrategy, SecondEndKey, for_each_group,
+    get_3p_pos_with, get_5p_pos, get_5p_pos_with, get_score, get_score_with, identify_dups,
+    identify_dups_with_policy,
+};
 pub use args::Args;
 pub use io::{DUPLICATE_FLAG, FLAG_OFFSET, toggle_duplicate_flag};
 pub use metadata::Metadata;
+pub use pipeline::{MarkdupSummary, duplicate_mask, mark_iter, run_apply, run_markdup};
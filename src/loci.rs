@@ -0,0 +1,135 @@
+//! Positional groups that held duplicates
+//!
+//! For every group with at least one duplicate, records the group's
+//! reference, 5' position and strand, how many templates it held and how
+//! many of them were marked, and writes them as BED6+2 lines sorted by
+//! position: `chrom`, 0-based `start`, `end`, name `.`, score (duplicates,
+//! capped at 1000 as BED requires), strand (`.` with `--strand-agnostic`),
+//! group size and duplicates. A pair counts once, at its first end, as in
+//! the metrics. Groups of different libraries at the same locus are summed.
+
+use anyhow::Result;
+use noodles::sam;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Strand of a locus: forward, reverse, or both with `--strand-agnostic`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Strand {
+    Forward,
+    Reverse,
+    Both,
+}
+
+impl Strand {
+    fn symbol(self) -> char {
+        match self {
+            Strand::Forward => '+',
+            Strand::Reverse => '-',
+            Strand::Both => '.',
+        }
+    }
+}
+
+/// Group size and duplicates per (reference id, 5' position, strand)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DupLoci {
+    loci: BTreeMap<(i32, i64, Strand), (u64, u64)>,
+}
+
+impl DupLoci {
+    /// Record a group of `size` templates at the 0-based 5' position `pos` of
+    /// `ref_id`, `duplicates` of them marked; groups without any are skipped
+    #[inline]
+    pub fn add(&mut self, ref_id: i32, pos: i64, strand: Strand, size: u64, duplicates: u64) {
+        if duplicates == 0 {
+            return;
+        }
+        let entry = self.loci.entry((ref_id, pos, strand)).or_insert((0, 0));
+        entry.0 += size;
+        entry.1 += duplicates;
+    }
+
+    /// Add the groups of `other` to these
+    pub fn merge(&mut self, other: &DupLoci) {
+        for (&(ref_id, pos, strand), &(size, duplicates)) in &other.loci {
+            self.add(ref_id, pos, strand, size, duplicates);
+        }
+    }
+
+    /// Write the loci as BED6+2 lines
+    ///
+    /// A 5' position before the start of the reference (soft clips at the
+    /// very start) is written at its first base.
+    pub fn write_bed<W: Write>(&self, w: &mut W, header: &sam::Header) -> Result<()> {
+        let refs = header.reference_sequences();
+        for (&(ref_id, pos, strand), &(size, duplicates)) in &self.loci {
+            let Some((name, _)) = usize::try_from(ref_id).ok().and_then(|i| refs.get_index(i))
+            else {
+                continue;
+            };
+            let start = pos.max(0);
+            writeln!(
+                w,
+                "{}\t{}\t{}\t.\t{}\t{}\t{}\t{}",
+                name,
+                start,
+                start + 1,
+                duplicates.min(1000),
+                strand.symbol(),
+                size,
+                duplicates
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the BED to `path`
+    pub fn save(&self, path: &Path, header: &sam::Header) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_bed(&mut out, header)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+    use std::num::NonZeroUsize;
+
+    #[test]
+    fn test_bed_lines() {
+        let header = sam::Header::builder()
+            .add_reference_sequence(
+                "chr1",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(2500).unwrap()),
+            )
+            .add_reference_sequence(
+                "chrM",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(16569).unwrap()),
+            )
+            .build();
+        let mut loci = DupLoci::default();
+        loci.add(1, 300, Strand::Both, 1500, 1200);
+        loci.add(0, 1499, Strand::Reverse, 3, 2);
+        loci.add(0, 1499, Strand::Forward, 2, 1);
+        loci.add(0, -3, Strand::Forward, 2, 1);
+        loci.add(0, 700, Strand::Forward, 4, 0);
+        let mut other = DupLoci::default();
+        other.add(0, 1499, Strand::Reverse, 2, 1);
+        loci.merge(&other);
+
+        let mut out = Vec::new();
+        loci.write_bed(&mut out, &header).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "chr1\t0\t1\t.\t1\t+\t2\t1\n\
+             chr1\t1499\t1500\t.\t1\t+\t2\t1\n\
+             chr1\t1499\t1500\t.\t3\t-\t5\t3\n\
+             chrM\t300\t301\t.\t1000\t.\t1500\t1200\n"
+        );
+    }
+}
@@ -3,63 +3,123 @@
 //! This module defines the Metadata struct that stores read information
 //! for duplicate detection, with serialization support for temporary files.
 
+use crate::optical::Location;
 use anyhow::Result;
 use std::io::{Read, Write};
 
+/// Bit of the `paired_end` byte telling that a location follows `umi_seq`
+const HAS_LOCATION: u8 = 0x80;
+
+/// Bit of the `paired_end` byte telling that a barcode follows the location
+const HAS_BARCODE: u8 = 0x40;
+
+/// Bit of the `paired_end` byte telling that a read name follows the barcode
+const HAS_NAME: u8 = 0x20;
+
+/// `paired_end` of a read placed at its pair's position by its mate CIGAR
+/// (`MC`), which is the first end of the pair; see [`Metadata::into_half`]
+pub const HALF_FIRST_END: u8 = 2;
+
+/// `paired_end` of such a read that is the second end of its pair
+pub const HALF_SECOND_END: u8 = 3;
+
+/// Bit of a pair's `rev2`, above the strand bit, telling that the read at
+/// `pos1` is the second read of its template (`--orientation-class`)
+pub const SECOND_READ_FIRST: u8 = 0x2;
+
 /// Metadata for a read or read pair used in duplicate detection
 ///
 /// The ordering of fields matches Sambamba's markdup comparator:
 /// lib_id -> ref_id1 -> pos1 -> rev1 -> ref_id2 -> pos2 -> rev2 -> score
+///
+/// `barcode` sorts directly after `lib_id` and splits positional groups like
+/// a library does: it is a 64-bit hash of the cell barcode and barcode tag
+/// values (`--cell-barcode-tag`, `--key-tag`; 0 when neither is in use),
+/// so reads of different cells or samples are never duplicates of each other.
+///
+/// `umi` sorts directly after `rev2` so reads carrying the same UMI stay
+/// adjacent inside a positional group. It is a 64-bit hash of the UMI tag
+/// value (0 when no UMI tag is in use), so two different UMIs could
+/// in principle collide; at 2^64 buckets this is astronomically unlikely.
+///
+/// `rev2` of a pair also carries [`SECOND_READ_FIRST`] with
+/// `--orientation-class`, so F1R2 and F2R1 pairs (and F1F2 and F2F1, ...)
+/// with the same strands at the same ends are told apart.
+///
+/// `tlen` is the signed template length of the read at `pos1` when pairs are
+/// told apart by insert size (`--use-tlen`) and 0 otherwise. It sorts after
+/// `pos2`, so pairs with both ends in common but different insert sizes are
+/// not adjacent.
+///
+/// `score1` is the score of the read at `pos1` alone; for a pair, `score`
+/// is the sum over both ends and the second end scores `score - score1`.
+///
+/// `umi_seq` carries the raw UMI bytes only when UMI clustering
+/// (`--umi-edit-distance`) needs them; it is empty otherwise. Likewise
+/// `location`, the flowcell position parsed from the read name, is only set
+/// for optical duplicate detection, and `name`, the read name (at most 255
+/// bytes), only when ties are broken by name (`--compat samtools`).
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct Metadata {
     pub lib_id: i32,
+    pub barcode: u64,
     pub ref_id1: i32,
-    pub pos1: i32,
+    pub pos1: i64,
     pub rev1: u8,
     pub rev2: u8,
+    pub umi: u64,
     pub ref_id2: i32,
-    pub pos2: i32,
+    pub pos2: i64,
+    pub tlen: i32,
     pub score: u32,
+    pub score1: u32,
     pub idx1: u64,
     pub idx2: u64,
-    pub paired_end: u8, // 0 = SE/fragment, 1 = PE/second end
+    pub paired_end: u8, // 0 = SE/fragment, 1 = PE/second end, 2/3 = half of a pair
+    pub umi_seq: Vec<u8>,
+    pub location: Option<Location>,
+    pub name: Vec<u8>,
 }
 
 impl Metadata {
     /// Create new metadata for a single-end read
     #[inline]
-    pub fn new_se(
-        lib_id: i32,
-        ref_id1: i32,
-        pos1: i32,
-        rev1: u8,
-        score: u32,
-        idx1: u64,
-    ) -> Self {
+    pub fn new_se(lib_id: i32, ref_id1: i32, pos1: i64, rev1: u8, score: u32, idx1: u64) -> Self {
         Self {
             lib_id,
+            barcode: 0,
             ref_id1,
             pos1,
             rev1,
             rev2: 0,
+            umi: 0,
             ref_id2: -1,
             pos2: 0,
+            tlen: 0,
             score,
+            score1: score,
             idx1,
             idx2: 0,
             paired_end: 0,
+            umi_seq: Vec::new(),
+            location: None,
+            name: Vec::new(),
         }
     }
 
     /// Create new metadata for a paired-end read
+    ///
+    /// The pair score is split evenly between the ends until
+    /// [`with_score1`](Self::with_score1) sets the first end's share.
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     pub fn new_pe(
         lib_id: i32,
         ref_id1: i32,
-        pos1: i32,
+        pos1: i64,
         rev1: u8,
         ref_id2: i32,
-        pos2: i32,
+        pos2: i64,
         rev2: u8,
         score: u32,
         idx1: u64,
@@ -67,17 +127,136 @@ impl Metadata {
     ) -> Self {
         Self {
             lib_id,
+            barcode: 0,
             ref_id1,
             pos1,
             rev1,
             rev2,
+            umi: 0,
             ref_id2,
             pos2,
+            tlen: 0,
             score,
+            score1: score / 2,
             idx1,
             idx2,
             paired_end: 1,
+            umi_seq: Vec::new(),
+            location: None,
+            name: Vec::new(),
+        }
+    }
+
+    /// Turn a read waiting for its mate into half of its pair, keyed by the
+    /// pair's position from the mate's (ref_id, 5' pos) and strand
+    ///
+    /// Both reads of a pair compute the same key, so they meet while merging
+    /// and are joined there instead of waiting in memory. `idx2` holds a hash
+    /// of the read name to match them with. The mate must not share the
+    /// read's position, where the first end depends on the input order.
+    ///
+    /// `read_end` is the read's end of the template (1 or 2), or 0 when the
+    /// pair's orientation class is not kept; with 2 at `pos1`, the key gets
+    /// [`SECOND_READ_FIRST`] from both halves alike.
+    pub fn into_half(
+        mut self,
+        mate: (i32, i64),
+        mate_rev: u8,
+        name_hash: u64,
+        read_end: u8,
+    ) -> Self {
+        let own = (self.ref_id1, self.pos1, self.rev1);
+        let mate = (mate.0, mate.1, mate_rev);
+        let (first, second, end) = if (own.0, own.1) < (mate.0, mate.1) {
+            (own, mate, HALF_FIRST_END)
+        } else {
+            (mate, own, HALF_SECOND_END)
+        };
+        (self.ref_id1, self.pos1, self.rev1) = first;
+        (self.ref_id2, self.pos2, self.rev2) = second;
+        let first_end = match (end, read_end) {
+            (_, 0) => 0,
+            (HALF_FIRST_END, own_end) => own_end,
+            (_, own_end) => 3 - own_end,
+        };
+        if first_end == 2 {
+            self.rev2 |= SECOND_READ_FIRST;
+        }
+        self.idx2 = name_hash;
+        self.paired_end = end;
+        self
+    }
+
+    /// End of the template (1 or 2) of the read of a half, as given to
+    /// [`into_half`](Self::into_half); 1 for the first end when it was 0
+    #[inline]
+    pub fn half_read_end(&self) -> u8 {
+        let second_first = (self.rev2 & SECOND_READ_FIRST != 0) as u8;
+        if self.paired_end == HALF_FIRST_END {
+            1 + second_first
+        } else {
+            2 - second_first
+        }
+    }
+
+    /// Whether this is half of a pair, see [`into_half`](Self::into_half)
+    #[inline]
+    pub fn is_half(&self) -> bool {
+        matches!(self.paired_end, HALF_FIRST_END | HALF_SECOND_END)
+    }
+
+    /// The read of a half on its own again, as it waited for its mate
+    pub fn from_half(mut self) -> Self {
+        if self.paired_end == HALF_SECOND_END {
+            (self.ref_id1, self.pos1, self.rev1) =
+                (self.ref_id2, self.pos2, self.rev2 & !SECOND_READ_FIRST);
         }
+        (self.ref_id2, self.pos2, self.rev2) = (-1, 0, 0);
+        self.idx2 = 0;
+        self.paired_end = 1;
+        self
+    }
+
+    /// Set the score of the read at `pos1` alone
+    #[inline]
+    pub fn with_score1(mut self, score1: u32) -> Self {
+        self.score1 = score1;
+        self
+    }
+
+    /// Set the template length that pairs must share to be duplicates
+    #[inline]
+    pub fn with_tlen(mut self, tlen: i32) -> Self {
+        self.tlen = tlen;
+        self
+    }
+
+    /// Attach a hashed barcode to this metadata
+    #[inline]
+    pub fn with_barcode(mut self, barcode: u64) -> Self {
+        self.barcode = barcode;
+        self
+    }
+
+    /// Attach a hashed UMI to this metadata
+    #[inline]
+    pub fn with_umi(mut self, umi: u64) -> Self {
+        self.umi = umi;
+        self
+    }
+
+    /// Attach the raw UMI bytes (at most 255 are kept)
+    #[inline]
+    pub fn with_umi_seq(mut self, seq: &[u8]) -> Self {
+        self.umi_seq = seq[..seq.len().min(u8::MAX as usize)].to_vec();
+        self
+    }
+
+    /// Attach the read name (at most 255 bytes are kept)
+    #[inline]
+    pub fn with_name(mut self, name: &[u8]) -> Self {
+        self.name = name[..name.len().min(u8::MAX as usize)].to_vec();
+        self
     }
 
     /// Serialize metadata to binary format (little-endian)
@@ -86,12 +265,38 @@ impl Metadata {
         w.write_all(&self.ref_id1.to_le_bytes())?;
         w.write_all(&self.pos1.to_le_bytes())?;
         w.write_all(&[self.rev1, self.rev2])?;
+        w.write_all(&self.umi.to_le_bytes())?;
         w.write_all(&self.ref_id2.to_le_bytes())?;
         w.write_all(&self.pos2.to_le_bytes())?;
+        w.write_all(&self.tlen.to_le_bytes())?;
         w.write_all(&self.score.to_le_bytes())?;
+        w.write_all(&self.score1.to_le_bytes())?;
         w.write_all(&self.idx1.to_le_bytes())?;
         w.write_all(&self.idx2.to_le_bytes())?;
-        w.write_all(&[self.paired_end])?;
+        let has_location = if self.location.is_some() {
+            HAS_LOCATION
+        } else {
+            0
+        };
+        let has_barcode = if self.barcode != 0 { HAS_BARCODE } else { 0 };
+        let has_name = if self.name.is_empty() { 0 } else { HAS_NAME };
+        w.write_all(&[
+            self.paired_end | has_location | has_barcode | has_name,
+            self.umi_seq.len() as u8,
+        ])?;
+        w.write_all(&self.umi_seq)?;
+        if let Some(loc) = &self.location {
+            w.write_all(&loc.tile.to_le_bytes())?;
+            w.write_all(&loc.x.to_le_bytes())?;
+            w.write_all(&loc.y.to_le_bytes())?;
+        }
+        if self.barcode != 0 {
+            w.write_all(&self.barcode.to_le_bytes())?;
+        }
+        if !self.name.is_empty() {
+            w.write_all(&[self.name.len() as u8])?;
+            w.write_all(&self.name)?;
+        }
         Ok(())
     }
 
@@ -107,48 +312,109 @@ impl Metadata {
 
         r.read_exact(&mut buf4)?;
         let ref_id1 = i32::from_le_bytes(buf4);
-        r.read_exact(&mut buf4)?;
-        let pos1 = i32::from_le_bytes(buf4);
+        let mut buf8 = [0u8; 8];
+        r.read_exact(&mut buf8)?;
+        let pos1 = i64::from_le_bytes(buf8);
 
         let mut buf2 = [0u8; 2];
         r.read_exact(&mut buf2)?;
         let (rev1, rev2) = (buf2[0], buf2[1]);
 
+        r.read_exact(&mut buf8)?;
+        let umi = u64::from_le_bytes(buf8);
+
         r.read_exact(&mut buf4)?;
         let ref_id2 = i32::from_le_bytes(buf4);
+        r.read_exact(&mut buf8)?;
+        let pos2 = i64::from_le_bytes(buf8);
         r.read_exact(&mut buf4)?;
-        let pos2 = i32::from_le_bytes(buf4);
+        let tlen = i32::from_le_bytes(buf4);
         r.read_exact(&mut buf4)?;
         let score = u32::from_le_bytes(buf4);
+        r.read_exact(&mut buf4)?;
+        let score1 = u32::from_le_bytes(buf4);
 
-        let mut buf8 = [0u8; 8];
         r.read_exact(&mut buf8)?;
         let idx1 = u64::from_le_bytes(buf8);
         r.read_exact(&mut buf8)?;
         let idx2 = u64::from_le_bytes(buf8);
 
-        let mut buf1 = [0u8; 1];
-        r.read_exact(&mut buf1)?;
-        let paired_end = buf1[0];
+        r.read_exact(&mut buf2)?;
+        let paired_end = buf2[0] & !(HAS_LOCATION | HAS_BARCODE | HAS_NAME);
+        let mut umi_seq = vec![0u8; buf2[1] as usize];
+        r.read_exact(&mut umi_seq)?;
+        let location = if buf2[0] & HAS_LOCATION != 0 {
+            let mut coords = [0u32; 3];
+            for c in &mut coords {
+                r.read_exact(&mut buf4)?;
+                *c = u32::from_le_bytes(buf4);
+            }
+            Some(Location {
+                tile: coords[0],
+                x: coords[1],
+                y: coords[2],
+            })
+        } else {
+            None
+        };
+        let barcode = if buf2[0] & HAS_BARCODE != 0 {
+            r.read_exact(&mut buf8)?;
+            u64::from_le_bytes(buf8)
+        } else {
+            0
+        };
+        let name = if buf2[0] & HAS_NAME != 0 {
+            let mut len = [0u8; 1];
+            r.read_exact(&mut len)?;
+            let mut name = vec![0u8; len[0] as usize];
+            r.read_exact(&mut name)?;
+            name
+        } else {
+            Vec::new()
+        };
 
         Ok(Some(Self {
             lib_id,
+            barcode,
             ref_id1,
             pos1,
             rev1,
             rev2,
+            umi,
             ref_id2,
             pos2,
+            tlen,
             score,
+            score1,
             idx1,
             idx2,
             paired_end,
+            umi_seq,
+            location,
+            name,
         }))
     }
 
     /// Get the binary size of metadata
+    ///
+    /// This is the fixed part of a record; a non-empty `umi_seq` adds its
+    /// length on top, a location 12 bytes, a barcode 8 and a name its length
+    /// and one, see
+    /// [`encoded_len`](Self::encoded_len).
     pub fn binary_size() -> usize {
-        4 + 4 + 4 + 2 + 4 + 4 + 4 + 8 + 8 + 1 // 43 bytes
+        4 + 4 + 8 + 2 + 8 + 4 + 8 + 4 + 4 + 4 + 8 + 8 + 1 + 1 // 68 bytes
+    }
+
+    /// Bytes written by [`write_to`](Self::write_to) for this record
+    pub fn encoded_len(&self) -> usize {
+        let location = if self.location.is_some() { 12 } else { 0 };
+        let barcode = if self.barcode != 0 { 8 } else { 0 };
+        let name = if self.name.is_empty() {
+            0
+        } else {
+            1 + self.name.len()
+        };
+        Self::binary_size() + self.umi_seq.len() + location + barcode + name
     }
 }
 
@@ -203,7 +469,9 @@ mod tests {
             50,  // score
             42,  // idx1
             43,  // idx2
-        );
+        )
+        .with_score1(20)
+        .with_tlen(-350);
         let mut buf = Vec::new();
         meta.write_to(&mut buf).unwrap();
 
@@ -213,11 +481,84 @@ mod tests {
         assert_eq!(meta, read_meta);
         assert_eq!(meta.paired_end, 1);
         assert_eq!(meta.idx2, 43);
+        assert_eq!(read_meta.score1, 20);
+        assert_eq!(read_meta.tlen, -350);
     }
 
     #[test]
     fn test_metadata_binary_size() {
-        assert_eq!(Metadata::binary_size(), 43);
+        assert_eq!(Metadata::binary_size(), 68);
+    }
+
+    #[test]
+    fn test_metadata_umi_roundtrip_and_ordering() {
+        let meta = Metadata::new_se(0, 0, 100, 0, 50, 1).with_umi(0xdead_beef);
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), Metadata::binary_size());
+
+        let read_meta = Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap();
+        assert_eq!(read_meta.umi, 0xdead_beef);
+
+        let meta = meta.with_umi_seq(b"ACGT");
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), Metadata::binary_size() + 4);
+        let read_meta = Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap();
+        assert_eq!(read_meta.umi_seq, b"ACGT");
+
+        let mut meta = meta;
+        meta.paired_end = 1;
+        meta.location = Some(Location {
+            tile: 1101,
+            x: 15589,
+            y: 1331,
+        });
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), Metadata::binary_size() + 4 + 12);
+        assert_eq!(buf.len(), meta.encoded_len());
+        assert_eq!(
+            Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap(),
+            meta
+        );
+
+        let meta = meta.with_barcode(0x1234_5678_9abc);
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), Metadata::binary_size() + 4 + 12 + 8);
+        assert_eq!(buf.len(), meta.encoded_len());
+        assert_eq!(
+            Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap(),
+            meta
+        );
+
+        let meta = meta.with_name(b"M00123:13:FC:1:1101:15589:1331");
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), Metadata::binary_size() + 4 + 12 + 8 + 1 + 30);
+        assert_eq!(buf.len(), meta.encoded_len());
+        assert_eq!(
+            Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap(),
+            meta
+        );
+        assert_eq!(
+            Metadata::new_se(0, 0, 100, 0, 50, 1)
+                .with_name(&[b'r'; 300])
+                .name
+                .len(),
+            255
+        );
+
+        // The barcode splits a library ahead of every position
+        let a = Metadata::new_se(0, 5, 900, 1, 50, 1).with_barcode(1);
+        let b = Metadata::new_se(0, 0, 100, 0, 50, 2).with_barcode(2);
+        assert!(a < b);
+
+        // UMI sorts after rev2 but before the mate position and score
+        let a = Metadata::new_pe(0, 0, 100, 0, 0, 900, 1, 90, 1, 2).with_umi(1);
+        let b = Metadata::new_pe(0, 0, 100, 0, 0, 200, 1, 10, 3, 4).with_umi(2);
+        assert!(a < b);
     }
 
     #[test]
@@ -236,10 +577,71 @@ mod tests {
         assert!(m3 < m2); // m3.pos1=100 < m2.pos1=200
     }
 
+    #[test]
+    fn test_metadata_large_position_roundtrip() {
+        // Beyond i32::MAX, as on >2 Gbp plant chromosomes
+        let meta = Metadata::new_pe(0, 3, 3_000_000_123, 0, 3, 3_000_000_456, 1, 60, 7, 8);
+        let mut buf = Vec::new();
+        meta.write_to(&mut buf).unwrap();
+        let read_meta = Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap();
+        assert_eq!(read_meta.pos1, 3_000_000_123);
+        assert_eq!(read_meta.pos2, 3_000_000_456);
+        assert!(Metadata::new_se(0, 3, 2_999_999_999, 0, 0, 0) < read_meta);
+    }
+
     #[test]
     fn test_metadata_read_from_empty() {
         let mut cursor = Cursor::new(Vec::new());
         let result = Metadata::read_from(&mut cursor).unwrap();
         assert!(result.is_none());
     }
+
+    #[test]
+    fn test_halves_of_a_pair_share_their_key() {
+        let mut read = Metadata::new_se(1, 0, 1000, 0, 50, 7);
+        read.paired_end = 1;
+        let mut mate = Metadata::new_se(1, 0, 1300, 1, 40, 9);
+        mate.paired_end = 1;
+
+        let first = read.clone().into_half((0, 1300), 1, 99, 0);
+        let second = mate.clone().into_half((0, 1000), 0, 99, 0);
+        assert_eq!(
+            (first.paired_end, second.paired_end),
+            (HALF_FIRST_END, HALF_SECOND_END)
+        );
+        let key = |m: &Metadata| (m.ref_id1, m.pos1, m.rev1, m.ref_id2, m.pos2, m.rev2, m.idx2);
+        assert_eq!(key(&first), key(&second));
+        assert_eq!(key(&first), (0, 1000, 0, 0, 1300, 1, 99));
+        assert!(first.is_half() && !read.is_half());
+
+        let mut buf = Vec::new();
+        second.write_to(&mut buf).unwrap();
+        let decoded = Metadata::read_from(&mut Cursor::new(buf)).unwrap().unwrap();
+        assert_eq!(decoded, second);
+        assert_eq!((first.from_half(), decoded.from_half()), (read, mate));
+    }
+
+    #[test]
+    fn test_halves_agree_on_orientation_class() {
+        let mut read = Metadata::new_se(1, 0, 1000, 0, 50, 7);
+        read.paired_end = 1;
+        let mut mate = Metadata::new_se(1, 0, 1300, 1, 40, 9);
+        mate.paired_end = 1;
+
+        // Read 2 at the first end: F2R1
+        let first = read.clone().into_half((0, 1300), 1, 99, 2);
+        let second = mate.clone().into_half((0, 1000), 0, 99, 1);
+        assert_eq!(
+            (first.rev2, second.rev2),
+            (1 | SECOND_READ_FIRST, 1 | SECOND_READ_FIRST)
+        );
+        assert_eq!((first.half_read_end(), second.half_read_end()), (2, 1));
+        assert_eq!(second.from_half(), mate);
+
+        // Read 1 at the first end: F1R2
+        let first = read.into_half((0, 1300), 1, 99, 1);
+        let second = mate.into_half((0, 1000), 0, 99, 2);
+        assert_eq!((first.rev2, second.rev2), (1, 1));
+        assert_eq!((first.half_read_end(), second.half_read_end()), (1, 2));
+    }
 }
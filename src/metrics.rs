@@ -0,0 +1,217 @@
+//! Picard-compatible duplication metrics
+//!
+//! One row per library with the fields of Picard's `DuplicationMetrics`, so
+//! tools that parse `MarkDuplicates` output (e.g. MultiQC) read the file as
+//! is. Optical duplicates are only counted with
+//! `--optical-duplicate-pixel-distance` and report 0 otherwise.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Counts for one library
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LibraryMetrics {
+    pub unpaired_reads_examined: u64,
+    pub read_pairs_examined: u64,
+    pub secondary_or_supplementary_rds: u64,
+    pub unmapped_reads: u64,
+    pub unpaired_read_duplicates: u64,
+    pub read_pair_duplicates: u64,
+    pub read_pair_optical_duplicates: u64,
+}
+
+impl LibraryMetrics {
+    /// Add the counts of `other` to these
+    pub fn add(&mut self, other: &LibraryMetrics) {
+        self.unpaired_reads_examined += other.unpaired_reads_examined;
+        self.read_pairs_examined += other.read_pairs_examined;
+        self.secondary_or_supplementary_rds += other.secondary_or_supplementary_rds;
+        self.unmapped_reads += other.unmapped_reads;
+        self.unpaired_read_duplicates += other.unpaired_read_duplicates;
+        self.read_pair_duplicates += other.read_pair_duplicates;
+        self.read_pair_optical_duplicates += other.read_pair_optical_duplicates;
+    }
+
+    /// Fraction of examined reads that are duplicates
+    pub fn percent_duplication(&self) -> f64 {
+        let examined = self.unpaired_reads_examined + 2 * self.read_pairs_examined;
+        if examined == 0 {
+            return 0.0;
+        }
+        let dups = self.unpaired_read_duplicates + 2 * self.read_pair_duplicates;
+        dups as f64 / examined as f64
+    }
+
+    /// Lander-Waterman estimate of the number of unique molecules
+    ///
+    /// Same bisection as Picard's `estimateLibrarySize`, from read pairs only
+    /// and with optical duplicates left out, as they say nothing about the
+    /// library; `None` when there are no duplicate pairs to extrapolate from.
+    pub fn estimated_library_size(&self) -> Option<u64> {
+        let n = (self.read_pairs_examined - self.read_pair_optical_duplicates) as f64;
+        let c = (self.read_pairs_examined - self.read_pair_duplicates) as f64;
+        if self.read_pair_duplicates == 0 || c <= 0.0 {
+            return None;
+        }
+        let f = |x: f64| c / x - 1.0 + (-n / x).exp();
+
+        let mut lo = 1.0;
+        let mut hi = 100.0;
+        if f(lo * c) < 0.0 {
+            return None;
+        }
+        while f(hi * c) > 0.0 {
+            hi *= 10.0;
+        }
+        for _ in 0..40 {
+            let mid = (lo + hi) / 2.0;
+            let u = f(mid * c);
+            if u == 0.0 {
+                break;
+            } else if u > 0.0 {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Some((c * (lo + hi) / 2.0) as u64)
+    }
+}
+
+/// Metrics for every library seen, keyed by library id
+#[derive(Debug, Clone, Default)]
+pub struct DuplicationMetrics {
+    names: BTreeMap<i32, String>,
+    libraries: BTreeMap<i32, LibraryMetrics>,
+}
+
+impl DuplicationMetrics {
+    /// Create empty metrics; `names` maps library ids to their `LB` names
+    pub fn new(names: impl IntoIterator<Item = (i32, String)>) -> Self {
+        Self {
+            names: names.into_iter().collect(),
+            libraries: BTreeMap::new(),
+        }
+    }
+
+    /// Counts of library `lib_id`, created on first use
+    #[inline]
+    pub fn library(&mut self, lib_id: i32) -> &mut LibraryMetrics {
+        self.libraries.entry(lib_id).or_default()
+    }
+
+    /// Counts summed over all libraries
+    pub fn total(&self) -> LibraryMetrics {
+        let mut total = LibraryMetrics::default();
+        for m in self.libraries.values() {
+            total.add(m);
+        }
+        total
+    }
+
+    /// Name and counts of every library seen, in library id order
+    pub fn libraries(&self) -> impl Iterator<Item = (&str, &LibraryMetrics)> {
+        self.libraries
+            .iter()
+            .map(|(lib_id, m)| (self.names.get(lib_id).map_or("unknown", String::as_str), m))
+    }
+
+    /// Add the per-library counts of `other` to these
+    pub fn merge(&mut self, other: &DuplicationMetrics) {
+        for (lib_id, m) in &other.libraries {
+            self.library(*lib_id).add(m);
+        }
+    }
+
+    /// Write the metrics in Picard's metrics file layout
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(w, "## METRICS CLASS\tpicard.sam.DuplicationMetrics")?;
+        writeln!(
+            w,
+            "LIBRARY\tUNPAIRED_READS_EXAMINED\tREAD_PAIRS_EXAMINED\tSECONDARY_OR_SUPPLEMENTARY_RDS\t\
+             UNMAPPED_READS\tUNPAIRED_READ_DUPLICATES\tREAD_PAIR_DUPLICATES\t\
+             READ_PAIR_OPTICAL_DUPLICATES\tPERCENT_DUPLICATION\tESTIMATED_LIBRARY_SIZE"
+        )?;
+        for (name, m) in self.libraries() {
+            let size = m
+                .estimated_library_size()
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}",
+                name,
+                m.unpaired_reads_examined,
+                m.read_pairs_examined,
+                m.secondary_or_supplementary_rds,
+                m.unmapped_reads,
+                m.unpaired_read_duplicates,
+                m.read_pair_duplicates,
+                m.read_pair_optical_duplicates,
+                m.percent_duplication(),
+                size
+            )?;
+        }
+        writeln!(w)?;
+        Ok(())
+    }
+
+    /// Write the metrics file to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_tsv(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_tsv() {
+        let mut metrics = DuplicationMetrics::new([(0, "libA".to_string())]);
+        let a = metrics.library(0);
+        a.unpaired_reads_examined = 10;
+        a.read_pairs_examined = 45;
+        a.secondary_or_supplementary_rds = 3;
+        a.unmapped_reads = 7;
+        a.unpaired_read_duplicates = 2;
+        a.read_pair_duplicates = 4;
+        a.read_pair_optical_duplicates = 1;
+        metrics.library(1).unmapped_reads = 1;
+
+        let mut out = Vec::new();
+        metrics.write_tsv(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "## METRICS CLASS\tpicard.sam.DuplicationMetrics");
+        assert!(lines[1].starts_with("LIBRARY\tUNPAIRED_READS_EXAMINED\t"));
+        let row: Vec<_> = lines[2].split('\t').collect();
+        assert_eq!(&row[..8], ["libA", "10", "45", "3", "7", "2", "4", "1"]);
+        assert_eq!(row[8], "0.100000");
+        assert!(lines[3].starts_with("unknown\t0\t0\t0\t1\t"));
+        assert_eq!(metrics.total().unmapped_reads, 8);
+    }
+
+    #[test]
+    fn test_estimated_library_size() {
+        // 1,000 pairs drawn from 2,000 molecules leave ~787 unique
+        let m = LibraryMetrics {
+            read_pairs_examined: 1000,
+            read_pair_duplicates: 213,
+            ..Default::default()
+        };
+        let size = m.estimated_library_size().unwrap();
+        assert!((1950..2050).contains(&size), "{}", size);
+
+        let no_dups = LibraryMetrics {
+            read_pairs_examined: 1000,
+            ..Default::default()
+        };
+        assert_eq!(no_dups.estimated_library_size(), None);
+    }
+}
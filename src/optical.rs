@@ -0,0 +1,374 @@
+//! Optical duplicate detection (`--optical-duplicate-pixel-distance`)
+//!
+//! An optical duplicate is a copy of a cluster made by the sequencer rather
+//! than by PCR: on patterned flowcells a cluster can seed its neighbour
+//! (pad hopping), and on any flowcell one cluster can be called twice. Such
+//! copies sit next to each other, so as in Picard a duplicate counts as
+//! optical when it lies on the same tile as another copy of its set and
+//! within the pixel distance on both axes.
+//!
+//! The tile and x/y coordinates come from the read name. The fast parse
+//! follows Picard's default: a name of five `:`-separated fields (old
+//! Illumina, `HWUSI-EAS100R:6:73:941:1973#0/1`) has them in fields 3 to 5,
+//! and one of seven fields (CASAVA 1.8, `M00123:13:000000000-A1B2C:1:1101:15589:1331`)
+//! in fields 5 to 7; each number is read up to its first non-digit. Reads
+//! whose names fit neither layout have no location and are never optical.
+//! Other layouts (e.g. from BGI or Element instruments, or renamed reads)
+//! are read with `--read-name-regex`, whose three capture groups are the
+//! tile, x and y; Picard's default pattern, given as is, keeps the fast
+//! parse.
+//!
+//! Sets are the copies of one molecule found within a positional group:
+//! pairs sharing the mate position (and UMI family), or the fragments of a
+//! group without paired reads. A pair counts once, and the read kept from a
+//! set is never optical.
+
+use crate::algorithm::{DedupOptions, umi_families};
+use crate::metadata::Metadata;
+use anyhow::{Result, anyhow, bail};
+use regex::bytes::Regex;
+use roaring::RoaringBitmap;
+use std::collections::HashMap;
+
+/// Tile and cluster coordinates of a read on the flowcell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    pub tile: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Leading decimal digits of `field` as a number
+fn parse_leading_u32(field: &[u8]) -> Option<u32> {
+    let digits = field.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    field[..digits].iter().try_fold(0u32, |n, &b| {
+        n.checked_mul(10)?.checked_add(u32::from(b - b'0'))
+    })
+}
+
+/// Location encoded in an Illumina-style read name, if any
+pub fn parse_location(name: &[u8]) -> Option<Location> {
+    let mut fields = [&[][..]; 7];
+    let mut count = 0;
+    for field in name.split(|&b| b == b':') {
+        if count == fields.len() {
+            return None;
+        }
+        fields[count] = field;
+        count += 1;
+    }
+    let [tile, x, y] = match count {
+        5 => [fields[2], fields[3], fields[4]],
+        7 => [fields[4], fields[5], fields[6]],
+        _ => return None,
+    };
+    Some(Location {
+        tile: parse_leading_u32(tile)?,
+        x: parse_leading_u32(x)?,
+        y: parse_leading_u32(y)?,
+    })
+}
+
+/// Picard's default `READ_NAME_REGEX`, the default of `--read-name-regex`
+pub const DEFAULT_READ_NAME_REGEX: &str = "(?:.*:)?([0-9]+)[^:]*:([0-9]+)[^:]*:([0-9]+)[^:]*$";
+
+/// How tile and x/y are read from read names (`--read-name-regex`)
+pub enum NameParser {
+    /// [`parse_location`], for [`DEFAULT_READ_NAME_REGEX`]
+    Fast,
+    /// A pattern whose three capture groups are the tile, x and y
+    Regex(Regex),
+}
+
+impl NameParser {
+    /// Parser for `pattern`, which must have three capture groups
+    pub fn new(pattern: &str) -> Result<NameParser> {
+        if pattern == DEFAULT_READ_NAME_REGEX {
+            return Ok(NameParser::Fast);
+        }
+        // Whole-name match, as Picard's `Matcher.matches`
+        let regex = Regex::new(&format!("^(?:{})$", pattern))
+            .map_err(|e| anyhow!("invalid read name regex {:?}: {}", pattern, e))?;
+        let groups = regex.captures_len() - 1;
+        if groups != 3 {
+            bail!(
+                "read name regex {:?} has {} capture groups; it needs 3 (tile, x, y)",
+                pattern,
+                groups
+            );
+        }
+        Ok(NameParser::Regex(regex))
+    }
+
+    /// Location encoded in `name`, if it has one
+    pub fn parse(&self, name: &[u8]) -> Option<Location> {
+        let regex = match self {
+            NameParser::Fast => return parse_location(name),
+            NameParser::Regex(regex) => regex,
+        };
+        let groups = regex.captures(name)?;
+        let field = |i: usize| groups.get(i).and_then(|m| parse_leading_u32(m.as_bytes()));
+        Some(Location {
+            tile: field(1)?,
+            x: field(2)?,
+            y: field(3)?,
+        })
+    }
+}
+
+/// Which members of a duplicate set are optical duplicates
+///
+/// `locations` holds the location of every copy; `keeper` is the copy left
+/// unmarked, which is never optical. Every copy within `distance` of the
+/// keeper is optical, and so is every copy within `distance` of an earlier
+/// copy in (tile, x, y) order, as in Picard's `OpticalDuplicateFinder`.
+pub fn find_optical(
+    locations: &[Option<Location>],
+    keeper: Option<usize>,
+    distance: u32,
+) -> Vec<bool> {
+    let close = |a: &Location, b: &Location| {
+        a.tile == b.tile && a.x.abs_diff(b.x) <= distance && a.y.abs_diff(b.y) <= distance
+    };
+    let mut optical = vec![false; locations.len()];
+    if let Some(Some(kept)) = keeper.map(|k| locations[k]) {
+        for (i, loc) in locations.iter().enumerate() {
+            optical[i] = Some(i) != keeper && loc.is_some_and(|loc| close(&kept, &loc));
+        }
+    }
+    let mut order: Vec<(Location, usize)> = locations
+        .iter()
+        .enumerate()
+        .filter_map(|(i, loc)| loc.map(|loc| (loc, i)))
+        .collect();
+    order.sort_unstable();
+    for (a, &(lhs, _)) in order.iter().enumerate() {
+        for &(rhs, j) in &order[a + 1..] {
+            if rhs.tile != lhs.tile || rhs.x > lhs.x.saturating_add(distance) {
+                break;
+            }
+            if Some(j) != keeper && rhs.y.abs_diff(lhs.y) <= distance {
+                optical[j] = true;
+            }
+        }
+    }
+    optical
+}
+
+/// Add the optical duplicates among the duplicates of `group` to `optical`
+///
+/// `dup_mask` must already hold the duplicates of the group, found with
+/// `opts`; the sets are formed as duplicate detection forms them, so reads
+/// of one UMI family (`--umi-edit-distance`) share a set and fragments on
+/// both strands do under `--strand-agnostic`. Returns the number of pairs
+/// found optical.
+pub fn mark_optical(
+    group: &[Metadata],
+    dup_mask: &RoaringBitmap,
+    opts: &DedupOptions,
+    distance: u32,
+    optical: &mut RoaringBitmap,
+) -> u64 {
+    let family_of =
+        (opts.umi_edit_distance > 0).then(|| umi_families(group, opts.umi_edit_distance));
+    let family = |m: &Metadata| match &family_of {
+        Some(family_of) => family_of[m.umi_seq.as_slice()] as u64,
+        None => m.umi,
+    };
+    type SetKey = (u8, u64, u8, u8, i32, i64, i32);
+    let mut sets: HashMap<SetKey, Vec<&Metadata>> = HashMap::new();
+    for m in group {
+        let key = if m.ref_id2 != -1 {
+            (1, family(m), m.rev1, m.rev2, m.ref_id2, m.pos2, m.tlen)
+        } else if m.paired_end == 0 {
+            (0, family(m), 0, 0, 0, m.pos2, 0)
+        } else {
+            continue;
+        };
+        sets.entry(key).or_default().push(m);
+    }
+
+    let mut optical_pairs = 0;
+    for (key, set) in sets {
+        let kept: Vec<usize> = (0..set.len())
+            .filter(|&i| !dup_mask.contains(set[i].idx1 as u32))
+            .collect();
+        // A fragment set with nothing kept is orphans marked for sharing a
+        // position with pairs, not copies of one another
+        if set.len() < 2 || kept.len() > 1 || (key.0 == 0 && kept.is_empty()) {
+            continue;
+        }
+        let locations: Vec<Option<Location>> = set.iter().map(|m| m.location).collect();
+        for (m, _) in set
+            .iter()
+            .zip(find_optical(&locations, kept.first().copied(), distance))
+            .filter(|(_, o)| *o)
+        {
+            optical.insert(m.idx1 as u32);
+            if key.0 == 1 {
+                optical.insert(m.idx2 as u32);
+                optical_pairs += 1;
+            }
+        }
+    }
+    optical_pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(tile: u32, x: u32, y: u32) -> Option<Location> {
+        Some(Location { tile, x, y })
+    }
+
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            parse_location(b"M00123:13:000000000-A1B2C:1:1101:15589:1331"),
+            at(1101, 15589, 1331)
+        );
+        assert_eq!(
+            parse_location(b"HWUSI-EAS100R:6:73:941:1973#0/1"),
+            at(73, 941, 1973)
+        );
+        assert_eq!(parse_location(b"read1"), None);
+        assert_eq!(parse_location(b"a:b:c:d:e:f"), None);
+        assert_eq!(parse_location(b"A:1:2:3:4:5:6:7"), None);
+        assert_eq!(parse_location(b"A:1:x:3:4"), None);
+        assert_eq!(parse_location(b"A:1:2:3:99999999999"), None);
+    }
+
+    #[test]
+    fn test_name_parser() {
+        let names: [&[u8]; 4] = [
+            b"M00123:13:000000000-A1B2C:1:1101:15589:1331",
+            b"HWUSI-EAS100R:6:73:941:1973#0/1",
+            b"read1",
+            b"A:1:x:3:4",
+        ];
+        // Picard's default pattern gives what the fast parse gives
+        let fast = NameParser::new(DEFAULT_READ_NAME_REGEX).unwrap();
+        assert!(matches!(fast, NameParser::Fast));
+        let slow =
+            NameParser::Regex(Regex::new(&format!("^(?:{})$", DEFAULT_READ_NAME_REGEX)).unwrap());
+        for name in names {
+            assert_eq!(
+                slow.parse(name),
+                fast.parse(name),
+                "{}",
+                String::from_utf8_lossy(name)
+            );
+        }
+
+        let underscores = NameParser::new(r"\w+?_(\d+)_(\d+)_(\d+)").unwrap();
+        assert_eq!(underscores.parse(b"run1_1101_155_13"), at(1101, 155, 13));
+        assert_eq!(underscores.parse(b"M00123:13:FC:1:1101:15589:1331"), None);
+
+        let err = NameParser::new("(\\d+):(\\d+)").err().unwrap().to_string();
+        assert!(err.contains("has 2 capture groups"), "{}", err);
+        assert!(NameParser::new("(").is_err());
+    }
+
+    #[test]
+    fn test_find_optical() {
+        // 0 kept; 1 next to it; 2 next to 1 but not 0; 3 on another tile;
+        // 4 far away; 5 without a location
+        let locations = [
+            at(1101, 100, 100),
+            at(1101, 150, 120),
+            at(1101, 240, 130),
+            at(1102, 100, 100),
+            at(1101, 5000, 100),
+            None,
+        ];
+        assert_eq!(
+            find_optical(&locations, Some(0), 100),
+            [false, true, true, false, false, false]
+        );
+        assert_eq!(find_optical(&locations, Some(0), 10), [false; 6]);
+        // The keeper is never optical, even next to an earlier copy
+        assert_eq!(
+            find_optical(&locations, Some(1), 100),
+            [true, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_mark_optical() {
+        let loc = |x| at(1101, x, 500);
+        let pair = |pos2, score, idx: u64, x| {
+            let mut m = Metadata::new_pe(0, 0, 100, 0, 0, pos2, 1, score, idx, idx + 100);
+            m.location = loc(x);
+            m
+        };
+        let group = [
+            pair(400, 60, 0, 1000), // kept
+            pair(400, 50, 1, 1050), // optical
+            pair(400, 40, 2, 9000), // PCR duplicate
+            pair(700, 60, 3, 1010), // another set, alone
+        ];
+        let opts = DedupOptions::default();
+        let dup_mask: RoaringBitmap = [1, 101, 2, 102].into_iter().collect();
+        let mut optical = RoaringBitmap::new();
+        assert_eq!(mark_optical(&group, &dup_mask, &opts, 100, &mut optical), 1);
+        assert_eq!(optical.iter().collect::<Vec<_>>(), [1, 101]);
+
+        // Orphan fragments, all marked, are not a set of copies
+        let frag = |idx, x| {
+            let mut m = Metadata::new_se(0, 0, 100, 0, 30, idx);
+            m.location = loc(x);
+            m
+        };
+        let orphans = [frag(10, 1000), frag(11, 1001)];
+        let dup_mask: RoaringBitmap = [10, 11].into_iter().collect();
+        let mut optical = RoaringBitmap::new();
+        assert_eq!(
+            mark_optical(&orphans, &dup_mask, &opts, 100, &mut optical),
+            0
+        );
+        assert!(optical.is_empty());
+        let dup_mask: RoaringBitmap = [11].into_iter().collect();
+        mark_optical(&orphans, &dup_mask, &opts, 100, &mut optical);
+        assert_eq!(optical.iter().collect::<Vec<_>>(), [11]);
+    }
+
+    #[test]
+    fn test_mark_optical_follows_umi_families() {
+        // AAAA and AAAT are one mismatch apart: one family with
+        // --umi-edit-distance 1, so the AAAT pair is a copy of the AAAA pair
+        let pair = |seq: &[u8], score, idx: u64, x| {
+            let mut m = Metadata::new_pe(0, 0, 100, 0, 0, 400, 1, score, idx, idx + 100)
+                .with_umi(crate::utils::hash_barcode(seq))
+                .with_umi_seq(seq);
+            m.location = at(1101, x, 500);
+            m
+        };
+        let group = [
+            pair(b"AAAA", 60, 0, 1000),
+            pair(b"AAAA", 50, 1, 9000),
+            pair(b"AAAT", 40, 2, 1050),
+        ];
+        let dup_mask: RoaringBitmap = [1, 101, 2, 102].into_iter().collect();
+
+        let mut optical = RoaringBitmap::new();
+        let exact = DedupOptions::default();
+        assert_eq!(
+            mark_optical(&group, &dup_mask, &exact, 100, &mut optical),
+            0
+        );
+
+        let clustered = DedupOptions {
+            umi_edit_distance: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            mark_optical(&group, &dup_mask, &clustered, 100, &mut optical),
+            1
+        );
+        assert_eq!(optical.iter().collect::<Vec<_>>(), [2, 102]);
+    }
+}
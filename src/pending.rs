@@ -0,0 +1,365 @@
+//! Reads waiting for their mate during the first pass
+//!
+//! In a coordinate-sorted file most mates turn up within a few hundred
+//! records, but long-range and inter-chromosomal pairs can keep an entry
+//! alive for most of the file. When a byte budget is set and exceeded, the
+//! oldest-inserted half of the entries is moved to an append-only spill file.
+//! Only a 64-bit hash of each spilled name and its file offset stay in
+//! memory; the entry is read back (and its full name compared) when the mate
+//! appears, so pairing remains exact.
+//!
+//! Name-sorted input keeps mates adjacent, so [`PendingMates::adjacent`]
+//! holds just the last read: a read that arrives while another name is held
+//! can no longer find its mate, and the held read is set aside as unmatched.
+//!
+//! Each entry also keeps which end of the pair it is (the FIRST/LAST segment
+//! flag bits), so a read whose name is already waiting for the same end can
+//! be recognised as a duplicate name rather than a mate.
+
+use crate::metadata::Metadata;
+use crate::utils::hash_barcode;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Estimated bytes per in-memory entry, excluding the read name itself
+///
+/// Key vector header, value and roughly one pointer of hash table overhead.
+pub const PENDING_ENTRY_BYTES: usize =
+    std::mem::size_of::<Vec<u8>>() + std::mem::size_of::<(Metadata, u8)>() + 8;
+
+/// Estimated bytes per spilled entry kept in the in-memory index
+pub const SPILLED_ENTRY_BYTES: usize = 2 * std::mem::size_of::<u64>() + 8;
+
+/// Spilled entries: name hash -> offset in the spill file
+struct Spill {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    reader: File,
+    offset: u64,
+    index: HashMap<u64, u64>,
+    dirty: bool,
+}
+
+impl Spill {
+    fn create(dir: &Path) -> Result<Self> {
+        let path = dir.join("pending_mates.bin");
+        let writer = BufWriter::with_capacity(1 << 20, File::create(&path)?);
+        let reader = File::open(&path)?;
+        Ok(Self {
+            path,
+            writer,
+            reader,
+            offset: 0,
+            index: HashMap::new(),
+            dirty: false,
+        })
+    }
+
+    fn write(&mut self, hash: u64, name: &[u8], mate: &Metadata, end: u8) -> Result<()> {
+        let mut buf = Vec::with_capacity(entry_len(name, mate));
+        buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(name);
+        buf.push(end);
+        mate.write_to(&mut buf)?;
+        self.writer.write_all(&buf)?;
+        self.index.insert(hash, self.offset);
+        self.offset += buf.len() as u64;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Read the entry at `offset`, returning its name, metadata and end
+    fn read_at(&mut self, offset: u64) -> Result<(Vec<u8>, Metadata, u8)> {
+        if self.dirty {
+            self.writer.flush()?;
+            self.dirty = false;
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        read_entry(&mut self.reader)
+    }
+}
+
+fn read_entry<R: Read>(r: &mut R) -> Result<(Vec<u8>, Metadata, u8)> {
+    let mut len = [0u8; 2];
+    r.read_exact(&mut len)?;
+    let mut name = vec![0u8; u16::from_le_bytes(len) as usize];
+    r.read_exact(&mut name)?;
+    let mut end = [0u8; 1];
+    r.read_exact(&mut end)?;
+    let mate = Metadata::read_from(r)?
+        .ok_or_else(|| anyhow::anyhow!("truncated pending mate spill file"))?;
+    Ok((name, mate, end[0]))
+}
+
+/// Bytes taken by an entry in the spill file
+#[inline]
+fn entry_len(name: &[u8], mate: &Metadata) -> usize {
+    2 + name.len() + 1 + mate.encoded_len()
+}
+
+/// Reads whose mate has not been seen yet, keyed by read name
+pub struct PendingMates {
+    map: HashMap<Vec<u8>, (Metadata, u8)>,
+    bytes: usize,
+    limit: Option<usize>,
+    dir: PathBuf,
+    spill: Option<Spill>,
+    total_spilled: usize,
+    adjacent: bool,
+    unmatched: Vec<Metadata>,
+}
+
+impl PendingMates {
+    /// Create an empty map; with `limit` set, entries beyond that many
+    /// estimated bytes are spilled to a file in `dir`
+    pub fn new(limit: Option<usize>, dir: &Path) -> Self {
+        Self {
+            map: HashMap::new(),
+            bytes: 0,
+            limit,
+            dir: dir.to_path_buf(),
+            spill: None,
+            total_spilled: 0,
+            adjacent: false,
+            unmatched: Vec::new(),
+        }
+    }
+
+    /// Create an empty holder for name-sorted input, where a mate is always
+    /// the next primary read with its name
+    pub fn adjacent(dir: &Path) -> Self {
+        Self {
+            adjacent: true,
+            ..Self::new(None, dir)
+        }
+    }
+
+    /// Estimated memory held by the map and the spill index
+    pub fn bytes(&self) -> usize {
+        self.bytes + self.spilled() * SPILLED_ENTRY_BYTES
+    }
+
+    /// Number of entries currently on disk
+    pub fn spilled(&self) -> usize {
+        self.spill.as_ref().map_or(0, |s| s.index.len())
+    }
+
+    /// Number of entries ever moved to disk
+    pub fn total_spilled(&self) -> usize {
+        self.total_spilled
+    }
+
+    /// Remove and return the read named `name` with the end it was inserted
+    /// as, from memory or the spill file
+    pub fn take(&mut self, name: &[u8]) -> Result<Option<(Metadata, u8)>> {
+        if let Some((mate, end)) = self.map.remove(name) {
+            self.bytes -= entry_bytes(name, &mate);
+            return Ok(Some((mate, end)));
+        }
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(None);
+        };
+        let hash = hash_barcode(name);
+        let Some(&offset) = spill.index.get(&hash) else {
+            return Ok(None);
+        };
+        let (spilled_name, mate, end) = spill.read_at(offset)?;
+        if spilled_name != name {
+            return Ok(None);
+        }
+        spill.index.remove(&hash);
+        Ok(Some((mate, end)))
+    }
+
+    /// Hold `mate` until the read named `name` shows up
+    ///
+    /// `end` is the FIRST/LAST segment flag bits of `mate` (see [`read_end`]).
+    pub fn insert(&mut self, name: Vec<u8>, mate: Metadata, end: u8) -> Result<()> {
+        if self.adjacent {
+            for (held_name, (held, _)) in self.map.drain() {
+                self.bytes -= entry_bytes(&held_name, &held);
+                self.unmatched.push(held);
+            }
+        }
+        self.bytes += entry_bytes(&name, &mate);
+        self.map.insert(name, (mate, end));
+        if self.limit.is_some_and(|limit| self.bytes > limit) {
+            self.spill_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// Move the oldest-inserted half of the in-memory entries to disk
+    ///
+    /// Record indices grow with insertion order, so `idx1` tells the age. An
+    /// entry whose name hash is already in the spill index stays in memory,
+    /// which keeps the index one-to-one.
+    fn spill_oldest(&mut self) -> Result<()> {
+        if self.map.len() < 2 {
+            return Ok(());
+        }
+        let mut ages: Vec<u64> = self.map.values().map(|(m, _)| m.idx1).collect();
+        let half = ages.len() / 2;
+        let cutoff = *ages.select_nth_unstable(half).1;
+
+        if self.spill.is_none() {
+            self.spill = Some(Spill::create(&self.dir)?);
+        }
+        let spill = self.spill.as_mut().unwrap();
+        let before = spill.index.len();
+        let mut freed = 0;
+        let mut error = None;
+        self.map.retain(|name, (mate, end)| {
+            if error.is_some() || mate.idx1 >= cutoff {
+                return true;
+            }
+            let hash = hash_barcode(name);
+            if spill.index.contains_key(&hash) {
+                return true;
+            }
+            match spill.write(hash, name, mate, *end) {
+                Ok(()) => {
+                    freed += entry_bytes(name, mate);
+                    false
+                }
+                Err(e) => {
+                    error = Some(e);
+                    true
+                }
+            }
+        });
+        self.bytes -= freed;
+        self.total_spilled += spill.index.len() - before;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// All reads still waiting for a mate, in memory and on disk
+    pub fn into_remaining(mut self) -> Result<Vec<Metadata>> {
+        let mut remaining = std::mem::take(&mut self.unmatched);
+        remaining.extend(self.map.drain().map(|(_, (m, _))| m));
+        if let Some(mut spill) = self.spill.take() {
+            spill.writer.flush()?;
+            let mut offsets: Vec<u64> = spill.index.values().copied().collect();
+            offsets.sort_unstable();
+            let mut reader = BufReader::new(File::open(&spill.path)?);
+            let mut pos = 0;
+            for offset in offsets {
+                reader.seek_relative(offset as i64 - pos as i64)?;
+                let (name, mate, _) = read_entry(&mut reader)?;
+                pos = offset + entry_len(&name, &mate) as u64;
+                remaining.push(mate);
+            }
+        }
+        Ok(remaining)
+    }
+}
+
+/// Which end of a pair a read is: its FIRST (1) and LAST (2) segment bits
+#[inline]
+pub fn read_end(flags: noodles::sam::alignment::record::Flags) -> u8 {
+    ((u16::from(flags) >> 6) & 0b11) as u8
+}
+
+#[inline]
+fn entry_bytes(name: &[u8], mate: &Metadata) -> usize {
+    PENDING_ENTRY_BYTES + name.len() + mate.umi_seq.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mate whose encoded length varies with `idx`
+    fn mate(idx: u64) -> Metadata {
+        let mut mate = Metadata::new_se(0, 0, idx as i64 * 10, 0, 30, idx).with_barcode(idx % 3);
+        if idx % 4 == 1 {
+            mate.location = Some(crate::optical::Location {
+                tile: 1101,
+                x: idx as u32,
+                y: 7,
+            });
+        }
+        mate
+    }
+
+    #[test]
+    fn test_pending_mates_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pending = PendingMates::new(None, dir.path());
+        pending.insert(b"r1".to_vec(), mate(1), 1).unwrap();
+        assert!(pending.take(b"r2").unwrap().is_none());
+        assert_eq!(pending.take(b"r1").unwrap(), Some((mate(1), 1)));
+        assert_eq!(pending.bytes(), 0);
+        assert_eq!(pending.spilled(), 0);
+    }
+
+    #[test]
+    fn test_pending_mates_adjacent() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut pending = PendingMates::adjacent(dir.path());
+        pending.insert(b"a".to_vec(), mate(1), 1).unwrap();
+        assert_eq!(pending.take(b"a").unwrap(), Some((mate(1), 1)));
+
+        // "b" lost its mate: "c" arriving sets it aside
+        pending.insert(b"b".to_vec(), mate(2), 1).unwrap();
+        pending.insert(b"c".to_vec(), mate(3), 1).unwrap();
+        assert!(pending.take(b"b").unwrap().is_none());
+        assert_eq!(pending.take(b"c").unwrap(), Some((mate(3), 1)));
+        pending.insert(b"d".to_vec(), mate(4), 2).unwrap();
+
+        let rest: Vec<u64> = pending
+            .into_remaining()
+            .unwrap()
+            .iter()
+            .map(|m| m.idx1)
+            .collect();
+        assert_eq!(rest, [2, 4]);
+    }
+
+    #[test]
+    fn test_pending_mates_spill_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        // Room for about ten entries before spilling kicks in
+        let limit = 10 * (PENDING_ENTRY_BYTES + 8);
+        let mut pending = PendingMates::new(Some(limit), dir.path());
+        for i in 0..1000u64 {
+            pending
+                .insert(
+                    format!("read{:04}", i).into_bytes(),
+                    mate(i),
+                    (i % 2 + 1) as u8,
+                )
+                .unwrap();
+        }
+        assert!(pending.spilled() > 900);
+        assert_eq!(pending.total_spilled(), pending.spilled());
+        assert!(pending.bytes() < limit + 1000 * SPILLED_ENTRY_BYTES);
+
+        // Mates come back in an arbitrary order, from memory and disk alike
+        for i in (0..1000u64).filter(|i| i % 3 == 0).rev() {
+            let name = format!("read{:04}", i).into_bytes();
+            assert_eq!(
+                pending.take(&name).unwrap(),
+                Some((mate(i), (i % 2 + 1) as u8))
+            );
+            assert!(pending.take(&name).unwrap().is_none());
+        }
+        assert!(pending.take(b"unknown").unwrap().is_none());
+
+        let mut rest: Vec<u64> = pending
+            .into_remaining()
+            .unwrap()
+            .iter()
+            .map(|m| m.idx1)
+            .collect();
+        rest.sort_unstable();
+        let expected: Vec<u64> = (0..1000).filter(|i| i % 3 != 0).collect();
+        assert_eq!(rest, expected);
+    }
+}
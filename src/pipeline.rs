@@ -0,0 +1,4312 @@
+//! End-to-end duplicate marking pipeline
+//!
+//! The detection phases (first pass over the input, spilling sorted metadata
+//! chunks, k-way merge with per-group duplicate detection) produce a bitmap of
+//! duplicate record indices; the write pass then sets or clears the DUPLICATE
+//! flag of every primary record in file order.
+//!
+//! Record indices are 0-based positions in the input stream (the merged
+//! stream when several inputs are given, see [`crate::io::merge`]), counting
+//! every record including unmapped, secondary and supplementary ones, so the
+//! same index refers to the same record in every pass.
+
+use crate::algorithm::{
+    Compat, DEFAULT_MIN_BASE_QUALITY, DedupOptions, DupSets, DuplicatePolicy, InterChromPolicy,
+    OrphanPolicy, SambambaPolicy, SamtoolsPolicy, ScoreStrategy, SecondEndKey,
+    count_base_qualities, for_each_group_with, get_3p_pos_with, get_5p_pos_with, get_mate_5p_pos,
+    identify_dups_with_policy,
+};
+use crate::args::{Args, DEFAULT_BATCH_SIZE, DEFAULT_LIBRARY, effective_threads};
+use crate::density::DupDensity;
+use crate::histogram::DupHistogram;
+use crate::io::{
+    self, DuplicateTag, IndexFormat, IndexedWriter, InputFormat, MergedChunks, MergedRecords,
+    OutputFormat, OutputWriter, RecordTags, ShardedOutput, TaggingPolicy, open_inputs,
+};
+use crate::loci::{DupLoci, Strand};
+use crate::metadata::{HALF_FIRST_END, Metadata, SECOND_READ_FIRST};
+use crate::metrics::DuplicationMetrics;
+use crate::optical::{self, NameParser};
+use crate::pending::{PendingMates, read_end};
+use crate::sequence;
+use crate::tiles::TileMetrics;
+use crate::utils::{Timings, format_bytes, format_duration, hash_barcode, strip_mate_suffix};
+use crate::{log, logln};
+use anyhow::Result;
+use bstr::BStr;
+use noodles::bam;
+use noodles::bgzf::io::writer::CompressionLevel;
+use noodles::sam;
+use rayon::prelude::*;
+use roaring::RoaringBitmap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::{Builder, TempPath};
+
+pub use crate::io::BamReader;
+
+/// Counts of one markdup run, as logged
+#[derive(Debug, Clone, Default)]
+pub struct MarkdupSummary {
+    /// Records in the input stream
+    pub records: u64,
+    /// End pairs sorted, those paired by mate CIGAR while merging included
+    pub pe: u64,
+    /// Single ends sorted, unmatched pairs included
+    pub se: u64,
+    /// Paired reads whose mate is on another reference, whatever
+    /// `--inter-chrom-policy` did with them
+    pub inter_chromosomal: u64,
+    /// Reads that waited for their mate on disk, spilled under `--max-mem`
+    pub spilled_mates: u64,
+    /// Reads marked as duplicates
+    pub duplicates: u64,
+    /// Duplicates found optical with `--optical-duplicate-pixel-distance`
+    pub optical_duplicates: u64,
+    pub orphan_duplicates: usize,
+    pub pe_duplicates: usize,
+    pub se_only_duplicates: usize,
+    /// Picard-style counts per library
+    pub metrics: DuplicationMetrics,
+}
+
+/// Run the full markdup pipeline: detect duplicates in `args.input` and write
+/// the flagged records to `args.output`
+///
+/// With several inputs this merges and marks in one step: duplicates are
+/// detected across all files and the output is their coordinate-sorted merge.
+pub fn run_markdup(args: &Args) -> Result<MarkdupSummary> {
+    let total_start = Instant::now();
+
+    check_outputs(args)?;
+    check_compat(args)?;
+    if args.tagging_policy == TaggingPolicy::OpticalOnly
+        && args.optical_duplicate_pixel_distance.is_none()
+    {
+        anyhow::bail!(
+            "--tagging-policy optical-only needs --optical-duplicate-pixel-distance to find optical duplicates"
+        );
+    }
+    let (args, _spooled) = prepare_inputs(args)?;
+    let args = args.as_ref();
+
+    if let Some(max_reads) = args.sample_qualities {
+        report_base_qualities(args, max_reads)?;
+    }
+
+    let mut timings = Timings::default();
+    let mut summary = MarkdupSummary::default();
+    let (header, records) = open_inputs(&args.input)?;
+    if args.write_index {
+        check_index_format(args, &header)?;
+    }
+    let (dup_mask, dup_sets, optical) =
+        find_duplicates(args, records, &header, &mut timings, &mut summary)?;
+    if let Some(path) = &args.write_mask {
+        io::write_mask(path, &dup_mask, summary.records)?;
+        logln!("saved the duplicate mask to {}", path.display());
+    }
+    let write_start = Instant::now();
+    write_output(args, &header, &dup_mask, dup_sets.as_ref(), &optical)?;
+    timings.write = write_start.elapsed();
+
+    let total_dur = total_start.elapsed();
+    let (total_m, total_s) = format_duration(total_dur);
+    logln!("done in {} min {} sec", total_m, total_s);
+    timings.total = total_dur;
+    if let Some(path) = &args.timing_json {
+        timings.save(path)?;
+    }
+
+    Ok(summary)
+}
+
+/// Write `args.input` marked with a mask saved by `--write-mask`, skipping
+/// detection
+///
+/// This is the write pass of [`run_markdup`] alone, with the same output
+/// options, e.g. to redo a write that failed or to write the same calls with
+/// `--remove-duplicates`. The mask holds no duplicate sets, so
+/// `--tag-duplicate-set`, `--tag-duplicate-set-size`, `--tag-molecule-id`
+/// and `--tag-original-name` are refused, and it must come from an input with
+/// the same number of records.
+pub fn run_apply(args: &Args, mask_path: &Path) -> Result<()> {
+    let total_start = Instant::now();
+
+    if args.tag_duplicate_set
+        || args.tag_duplicate_set_size
+        || args.tag_molecule_id
+        || args.tag_original_name
+    {
+        anyhow::bail!(
+            "duplicate set tags (DI, DS, MI, do) need detection; a saved mask has no duplicate sets"
+        );
+    }
+    if args.tagging_policy != TaggingPolicy::DontTag {
+        anyhow::bail!(
+            "--tagging-policy needs detection; a saved mask does not tell optical duplicates apart"
+        );
+    }
+    check_outputs(args)?;
+    let (mask, mask_records) = io::read_mask(mask_path)?;
+    let (args, _spooled) = prepare_inputs(args)?;
+    let args = args.as_ref();
+
+    let (header, _) = open_inputs(&args.input)?;
+    if args.write_index {
+        check_index_format(args, &header)?;
+    }
+    logln!(
+        "applying {} duplicates from {}",
+        mask.len(),
+        mask_path.display()
+    );
+    let record_count = write_output(args, &header, &mask, None, &RoaringBitmap::new())?;
+    if record_count as u64 != mask_records {
+        anyhow::bail!(
+            "{}: mask was saved for {} records but the input has {}; the output is not valid",
+            mask_path.display(),
+            mask_records,
+            record_count
+        );
+    }
+
+    let (total_m, total_s) = format_duration(total_start.elapsed());
+    logln!("done in {} min {} sec", total_m, total_s);
+    Ok(())
+}
+
+/// Fail before the expensive passes if any output cannot be written
+fn check_outputs(args: &Args) -> Result<()> {
+    if let Some(dir) = &args.shard_by_chromosome {
+        io::prepare_shard_dir(dir, args.force)?;
+    } else {
+        io::check_overwrite(Path::new(&args.output), &args.input, args.force)?;
+        io::check_output(Path::new(&args.output), args.force)?;
+    }
+    if let Some(path) = &args.duplicates_output {
+        if path == Path::new(&args.output) {
+            anyhow::bail!(
+                "{}: --duplicates-output is the same path as --output",
+                path.display()
+            );
+        }
+        io::check_overwrite(path, &args.input, args.force)?;
+        io::check_output(path, args.force)?;
+    }
+    let side_outputs = [
+        &args.metrics_file,
+        &args.histogram,
+        &args.dup_bed,
+        &args.dup_loci_bed,
+        &args.dup_names_out,
+        &args.write_mask,
+        &args.tile_metrics,
+        &args.timing_json,
+    ];
+    for path in side_outputs.into_iter().flatten() {
+        io::check_writable(path)?;
+    }
+    if args.write_index {
+        check_indexable(args)?;
+    }
+    Ok(())
+}
+
+/// Check that `--write-index` can index the output: BAM written to a file
+fn check_indexable(args: &Args) -> Result<()> {
+    if args.output == io::STDOUT_PATH {
+        anyhow::bail!("--write-index needs an output file, not standard output");
+    }
+    if io::url_scheme(Path::new(&args.output)).is_some() {
+        anyhow::bail!("--write-index needs a local output file, not an object store URL");
+    }
+    if OutputFormat::resolve(args.output_format, Path::new(&args.output)) != OutputFormat::Bam {
+        anyhow::bail!("--write-index needs BAM output");
+    }
+    Ok(())
+}
+
+/// Check that the input described by `header` can be indexed in the
+/// `--index-format` and that the index file can be created
+///
+/// Without `--index-format`, references longer than BAI can address switch
+/// the index to CSI.
+fn check_index_format(args: &Args, header: &sam::Header) -> Result<()> {
+    if !io::is_coordinate_sorted(header) {
+        anyhow::bail!("--write-index needs coordinate-sorted input (@HD SO:coordinate)");
+    }
+    let max_length = io::max_reference_length(header);
+    let format = IndexFormat::resolve(args.index_format, header);
+    if format == IndexFormat::Bai && max_length > io::BAI_MAX_REFERENCE_LENGTH {
+        anyhow::bail!(
+            "a reference of {} bp is too long for a BAI index (at most 2^29 bp); use --index-format csi",
+            max_length
+        );
+    }
+    if args.index_format.is_none() && format == IndexFormat::Csi {
+        logln!(
+            "writing a CSI index: the longest reference ({} bp) is too long for BAI",
+            max_length
+        );
+    }
+    if args.shard_by_chromosome.is_some() {
+        return Ok(());
+    }
+    io::check_writable(&io::index_path(Path::new(&args.output), format))
+}
+
+/// Log the base quality distribution of the first `max_reads` records
+///
+/// Binned qualities (e.g. NovaSeq's 2/12/23/37) leave only a few distinct
+/// values, and `--min-base-quality` then only matters where it falls
+/// between two bins.
+fn report_base_qualities(args: &Args, max_reads: usize) -> Result<()> {
+    let (_, records) = open_inputs(&args.input)?;
+    let (counts, reads) = count_base_qualities(records, max_reads)?;
+    let total: u64 = counts.iter().sum();
+    let scored: u64 = counts[args.min_base_quality as usize..].iter().sum();
+    let distinct = counts.iter().filter(|&&n| n > 0).count();
+    let percent = |n: u64| {
+        if total == 0 {
+            0.0
+        } else {
+            100.0 * n as f64 / total as f64
+        }
+    };
+    logln!(
+        "sampled {} bases of {} reads: {} distinct qualities, {:.1}% >= {}",
+        total,
+        reads,
+        distinct,
+        percent(scored),
+        args.min_base_quality
+    );
+    for (q, &n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+        logln!("  Q{}\t{}\t{:.1}%", q, n, percent(n));
+    }
+    Ok(())
+}
+
+/// Compressed BAM bytes per record assumed when projecting the record count
+/// from the input size; about 150 bp reads, so shorter reads are undercounted
+const ESTIMATED_BAM_BYTES_PER_RECORD: u64 = 64;
+
+/// Check that the temporary directory can be written and log how much of it
+/// the chunk files are expected to need
+///
+/// The record count is projected from the input file sizes, so the estimate
+/// is rough: less free space than the estimate is a warning, or an error
+/// with `--strict`.
+fn check_tmp_dir(args: &Args, dir: &Path) -> Result<()> {
+    if let Err(e) = tempfile::tempfile_in(dir) {
+        anyhow::bail!(
+            "{}: temporary directory is not writable: {}",
+            dir.display(),
+            e
+        );
+    }
+    let input_bytes: u64 = args
+        .input
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|meta| meta.len())
+        .sum();
+    if input_bytes == 0 {
+        return Ok(());
+    }
+    let estimate = input_bytes / ESTIMATED_BAM_BYTES_PER_RECORD * Metadata::binary_size() as u64;
+    let Some(free) = io::available_space(dir) else {
+        logln!(
+            "temp space: ~{} estimated for chunks in {}",
+            format_bytes(estimate),
+            dir.display()
+        );
+        return Ok(());
+    };
+    logln!(
+        "temp space: ~{} estimated for chunks, {} free in {}",
+        format_bytes(estimate),
+        format_bytes(free),
+        dir.display()
+    );
+    if free < estimate {
+        if args.strict {
+            anyhow::bail!(
+                "{}: {} free, but the chunk files may need ~{}; use --tmp-dir elsewhere",
+                dir.display(),
+                format_bytes(free),
+                format_bytes(estimate)
+            );
+        }
+        logln!("warning: the temporary directory may run out of space; consider --tmp-dir");
+    }
+    Ok(())
+}
+
+/// Why some reads fall back to the unnamed default library, if they do
+///
+/// `None` when `--library-name` (or `--ignore-rg`) names the fallback on
+/// purpose, or every read group has an LB (SM with `--group-by sample`).
+fn unnamed_library_source(args: &Args, header: &sam::Header) -> Option<String> {
+    if args.ignore_rg || args.default_library != DEFAULT_LIBRARY {
+        return None;
+    }
+    if header.read_groups().is_empty() {
+        return Some("the header has no read groups".to_string());
+    }
+    let tag = args.group_by.tag();
+    let unnamed: Vec<_> = header
+        .read_groups()
+        .iter()
+        .filter(|(_, rg)| rg.other_fields().get(&tag).is_none())
+        .map(|(id, _)| id.to_string())
+        .collect();
+    let tag = String::from_utf8_lossy(&tag);
+    match unnamed.as_slice() {
+        [] => None,
+        [id] => Some(format!("read group {} has no {}", id, tag)),
+        ids => Some(format!("read groups {} have no {}", ids.join(", "), tag)),
+    }
+}
+
+/// Refuse options that change detection away from the tool `--compat`
+/// reproduces
+fn check_compat(args: &Args) -> Result<()> {
+    if args.compat == Compat::Sambamba {
+        return Ok(());
+    }
+    let mut changed = Vec::new();
+    if args.orphan_policy != OrphanPolicy::Sambamba {
+        changed.push("--orphan-policy");
+    }
+    if args.min_base_quality != DEFAULT_MIN_BASE_QUALITY {
+        changed.push("--min-base-quality");
+    }
+    if args.score != ScoreStrategy::SumQual {
+        changed.push("--score");
+    }
+    if args.strand_agnostic {
+        changed.push("--strand-agnostic");
+    }
+    if args.position_tolerance > 0 {
+        changed.push("--position-tolerance");
+    }
+    if args.use_tlen {
+        changed.push("--use-tlen");
+    }
+    if args.include_qcfail {
+        changed.push("--include-qcfail");
+    }
+    if args.min_mapq > 0 {
+        changed.push("--min-mapq");
+    }
+    if args.ignore_hard_clips {
+        changed.push("--ignore-hard-clips");
+    }
+    if args.inter_chrom_policy != InterChromPolicy::Pair {
+        changed.push("--inter-chrom-policy");
+    }
+    if args.orientation_class {
+        changed.push("--orientation-class");
+    }
+    if args.use_3p_end {
+        changed.push("--use-3p-end");
+    }
+    if !changed.is_empty() {
+        anyhow::bail!(
+            "--compat {} cannot be combined with {}",
+            args.compat.name(),
+            changed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// `args` with every input made readable as BAM by both passes
+///
+/// Detection and the write pass each read the input from the start, which a
+/// pipe allows only once, so an input that can only be read once (`-` for
+/// standard input, a FIFO, or `<(...)` process substitution) is first copied
+/// (still compressed) to the temporary directory and every pass replays the
+/// copy. A SAM input, detected from its first bytes or named by
+/// `--input-format`, is then converted to a temporary BAM, and CRAM is
+/// refused. The copies are removed when the returned paths are dropped, so
+/// they must outlive every pass.
+fn prepare_inputs(args: &Args) -> Result<(Cow<'_, Args>, Vec<TempPath>)> {
+    if args.input.iter().filter(|p| *p == io::STDIN_PATH).count() > 1 {
+        anyhow::bail!(
+            "standard input ('{}') can be given as an input only once",
+            io::STDIN_PATH
+        );
+    }
+    let tmp_root = args.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    let mut args = Cow::Borrowed(args);
+    let mut spooled = Vec::new();
+    for i in 0..args.input.len() {
+        let input = args.input[i].clone();
+        let mut path = input.clone();
+        if io::is_stream(Path::new(&input)) {
+            let start = Instant::now();
+            let (copy, bytes) = if input == io::STDIN_PATH {
+                io::spool_to_file(std::io::stdin().lock(), &tmp_root)?
+            } else {
+                let stream =
+                    std::fs::File::open(&input).map_err(|e| anyhow::anyhow!("{}: {}", input, e))?;
+                io::spool_to_file(stream, &tmp_root)?
+            };
+            let source = if input == io::STDIN_PATH {
+                "standard input"
+            } else {
+                input.as_str()
+            };
+            logln!(
+                "copied {} of input from {} to {} in {:.1} sec",
+                format_bytes(bytes),
+                source,
+                tmp_root.display(),
+                start.elapsed().as_secs_f64()
+            );
+            path = copy.to_string_lossy().into_owned();
+            spooled.push(copy);
+        }
+
+        let format = match args.input_format {
+            Some(format) => format,
+            None => {
+                let format = InputFormat::detect(Path::new(&path))?;
+                if let Some(named) = InputFormat::from_extension(Path::new(&input))
+                    && named != format
+                {
+                    logln!(
+                        "warning: {} holds {}, not {}; reading it as {}",
+                        input,
+                        format.name(),
+                        named.name(),
+                        format.name()
+                    );
+                }
+                format
+            }
+        };
+        match format {
+            InputFormat::Bam => {}
+            InputFormat::Cram => {
+                anyhow::bail!(
+                    "{}: CRAM input is not supported; convert it with `samtools view -b -T ref.fa` first",
+                    input
+                )
+            }
+            InputFormat::Sam => {
+                let start = Instant::now();
+                let (bam, records) = io::sam_to_bam(Path::new(&path), &tmp_root)?;
+                logln!(
+                    "converted {} SAM records of {} to BAM in {:.1} sec",
+                    records,
+                    input,
+                    start.elapsed().as_secs_f64()
+                );
+                path = bam.to_string_lossy().into_owned();
+                spooled.push(bam);
+            }
+        }
+        if path != input {
+            args.to_mut().input[i] = path;
+        }
+    }
+    Ok((args, spooled))
+}
+
+/// Run only the detection phases and return the indices of duplicate records
+///
+/// Bit `i` is set when the `i`-th record of the input (0-based, counting all
+/// records) is a duplicate. The bitmap is compact: roughly 2 bytes per
+/// duplicate while duplicates are sparse, and never more than 8 KiB per
+/// 65,536 input records (about 1 bit per record) when they are dense, so even
+/// a billion-record BAM needs at most ~128 MiB.
+pub fn duplicate_mask(args: &Args) -> Result<RoaringBitmap> {
+    let (args, _spooled) = prepare_inputs(args)?;
+    let (header, records) = open_inputs(&args.input)?;
+    Ok(collect_duplicates(&args, records, &header)?.0)
+}
+
+/// Iterate over the input records paired with their duplicate status
+///
+/// Duplicates are detected first (see [`duplicate_mask`]); the input is then
+/// re-read and each record yielded in file order. Secondary and
+/// supplementary records are only reported as duplicates with
+/// `args.mark_supplementary`, and unmapped mates with
+/// `args.mark_unmapped_mates`, matching the write pass. The bitmap is held
+/// for the lifetime of the iterator.
+pub fn mark_iter(args: &Args) -> Result<MarkIter> {
+    let (args, spooled) = prepare_inputs(args)?;
+    let dup_mask = duplicate_mask(&args)?;
+    let inherited = InheritedMarks::new(&args, &dup_mask)?;
+    let (header, records) = open_inputs(&args.input)?;
+    Ok(MarkIter {
+        records,
+        header,
+        dup_mask,
+        inherited,
+        idx: 0,
+        _spooled: spooled,
+    })
+}
+
+/// Iterator returned by [`mark_iter`]
+pub struct MarkIter {
+    records: MergedRecords,
+    header: sam::Header,
+    dup_mask: RoaringBitmap,
+    /// Marks passed on by name, with `--mark-supplementary` and
+    /// `--mark-unmapped-mates`
+    inherited: InheritedMarks,
+    idx: usize,
+    /// Spooled copies of streamed inputs, removed once the iterator is dropped
+    _spooled: Vec<TempPath>,
+}
+
+impl MarkIter {
+    /// Header of the input (merged when there are several inputs)
+    pub fn header(&self) -> &sam::Header {
+        &self.header
+    }
+
+    /// The duplicate bitmap driving this iterator
+    pub fn dup_mask(&self) -> &RoaringBitmap {
+        &self.dup_mask
+    }
+}
+
+impl Iterator for MarkIter {
+    type Item = std::io::Result<(bam::Record, bool)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.records.next()? {
+            Ok(record) => {
+                let is_dup = is_marked(&record, self.idx, &self.dup_mask, &self.inherited);
+                self.idx += 1;
+                Some(Ok((record, is_dup)))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Estimated bytes held per entry of the in-memory chunk
+///
+/// This is the in-memory `Metadata` (its `binary_size()` on-disk form plus
+/// padding and the empty `umi_seq` vector); raw UMI bytes are counted on top.
+const CHUNK_ENTRY_BYTES: usize = std::mem::size_of::<Metadata>();
+
+/// Estimated bytes per `pe_second_ends` entry
+const SECOND_END_BYTES: usize = std::mem::size_of::<(SecondEndKey, u32)>() + 8;
+
+/// Adaptive chunks are never flushed below this many records, so a large
+/// set of pending mates cannot degrade the first pass into tiny chunks
+const MIN_ADAPTIVE_BATCH: usize = 10_000;
+
+/// When the first pass spills its in-memory chunk to disk
+///
+/// An explicit `--batch-size` flushes at a fixed record count. Otherwise
+/// `--max-mem` flushes once the estimated live memory (chunk, pending mates
+/// and second-end keys) reaches the budget; with neither option the default
+/// batch size applies.
+pub(crate) struct Batching {
+    pub(crate) batch_size: usize,
+    max_mem: Option<usize>,
+}
+
+impl Batching {
+    pub(crate) fn from_args(args: &Args) -> Self {
+        match (args.batch_size, args.max_mem) {
+            (Some(batch_size), _) => Self {
+                batch_size,
+                max_mem: None,
+            },
+            (None, Some(max_mem)) => {
+                let max_mem = usize::try_from(max_mem).unwrap_or(usize::MAX);
+                Self {
+                    batch_size: (max_mem / CHUNK_ENTRY_BYTES).max(MIN_ADAPTIVE_BATCH),
+                    max_mem: Some(max_mem),
+                }
+            }
+            (None, None) => Self {
+                batch_size: DEFAULT_BATCH_SIZE,
+                max_mem: None,
+            },
+        }
+    }
+
+    /// Initial capacity of the in-memory chunk; an adaptive chunk grows on
+    /// demand instead of reserving its whole budget up front
+    fn capacity(&self) -> usize {
+        match self.max_mem {
+            None => self.batch_size,
+            Some(_) => self.batch_size.min(DEFAULT_BATCH_SIZE),
+        }
+    }
+
+    /// Share of the budget pending mates may hold before they are spilled
+    fn pending_limit(&self) -> Option<usize> {
+        self.max_mem.map(|max_mem| max_mem / 4)
+    }
+
+    fn should_flush(&self, chunk_len: usize, extra_bytes: usize) -> bool {
+        match self.max_mem {
+            None => chunk_len >= self.batch_size,
+            Some(max_mem) => {
+                chunk_len >= MIN_ADAPTIVE_BATCH
+                    && (chunk_len >= self.batch_size
+                        || chunk_len * CHUNK_ENTRY_BYTES + extra_bytes >= max_mem)
+            }
+        }
+    }
+}
+
+/// Whether duplicate marking applies to this record
+#[inline]
+fn is_primary(record: &bam::Record) -> bool {
+    !record.flags().is_secondary() && !record.flags().is_supplementary()
+}
+
+/// The mate score `samtools fixmate -m` stores in the `ms` tag, if any
+#[inline]
+fn mate_score_tag(record: &bam::Record) -> Option<u32> {
+    let value = record.data().get(b"ms")?.ok()?;
+    value.as_int().and_then(|score| u32::try_from(score).ok())
+}
+
+/// The pair record of two mates, each held as a read waiting for its mate;
+/// `mate` comes before `read` in the input
+///
+/// Also returns the pair's second end, with its score, for the map of PE
+/// second ends. `read_ms` is the `ms` tag of `read` under `--compat samtools`,
+/// and `ends` are the template ends of `mate` and `read` (see
+/// [`read_end`]), which set [`SECOND_READ_FIRST`] with `--orientation-class`.
+fn join_mates(
+    args: &Args,
+    mate: Metadata,
+    read: Metadata,
+    read_ms: Option<u32>,
+    ends: (u8, u8),
+) -> (Metadata, SecondEndKey, u32) {
+    let (ref_id, pos, rev, index, score) =
+        (read.ref_id1, read.pos1, read.rev1, read.idx1, read.score);
+    let (m_ref, m_pos, m_rev, m_idx) = (mate.ref_id1, mate.pos1, mate.rev1, mate.idx1);
+    let is_first = args.compat.is_first_end((ref_id, pos), (m_ref, m_pos));
+    let (r1, p1, rv1, i1, s1, r2, p2, rv2, i2, s2) = if is_first {
+        (
+            ref_id, pos, rev, index, score, m_ref, m_pos, m_rev, m_idx, mate.score,
+        )
+    } else {
+        (
+            m_ref, m_pos, m_rev, m_idx, mate.score, ref_id, pos, rev, index, score,
+        )
+    };
+    let (rv1, rv2) = args.compat.pair_strands((r1, p1) == (r2, p2), rv1, rv2);
+    // The first end's TLEN (0 without --use-tlen)
+    let tlen = if is_first { read.tlen } else { mate.tlen };
+    // samtools markdup scores a pair by the read's own score and the mate
+    // score fixmate stored in its ms tag
+    let pair_score = match args.compat {
+        Compat::Samtools => score + read_ms.unwrap_or(mate.score),
+        _ => s1 + s2,
+    };
+    let second_end = (mate.lib_id, mate.barcode, r2, p2, rv2, mate.umi);
+    let first_read_end = if is_first { ends.1 } else { ends.0 };
+    let pair_rev2 = match args.orientation_class && first_read_end == 2 {
+        true => rv2 | SECOND_READ_FIRST,
+        false => rv2,
+    };
+    let pair = Metadata {
+        lib_id: mate.lib_id,
+        barcode: mate.barcode,
+        ref_id1: r1,
+        pos1: p1,
+        rev1: rv1,
+        ref_id2: r2,
+        pos2: p2,
+        rev2: pair_rev2,
+        umi: mate.umi,
+        tlen,
+        score: pair_score,
+        score1: s1,
+        idx1: i1,
+        idx2: i2,
+        paired_end: 1,
+        umi_seq: mate.umi_seq,
+        location: mate.location.or(read.location),
+        name: mate.name,
+    };
+    (pair, second_end, s2)
+}
+
+/// Whether QC-failed reads are left out of detection and marking: with
+/// `--exclude-qcfail`, and under `--compat samtools` as samtools markdup
+/// does without `--include-fails`
+#[inline]
+pub(crate) fn excludes_qcfail(args: &Args) -> bool {
+    args.exclude_qcfail || args.compat == Compat::Samtools
+}
+
+/// Whether the write pass sets or clears the DUPLICATE flag of this record;
+/// secondary and supplementary records only with `--mark-supplementary`
+#[inline]
+fn is_markable(record: &bam::Record, args: &Args, excluded_refs: &HashSet<usize>) -> bool {
+    (args.mark_supplementary || is_primary(record))
+        && !(excludes_qcfail(args) && record.flags().is_qc_fail())
+        && !is_on_excluded_ref(record, excluded_refs)
+        && !is_below_min_mapq(record, args)
+        && !(args.inter_chrom_policy == InterChromPolicy::Skip && is_inter_chromosomal(record))
+}
+
+/// Whether `record` is a paired read with its mate on another reference
+#[inline]
+fn is_inter_chromosomal(record: &bam::Record) -> bool {
+    let flags = record.flags();
+    flags.is_segmented()
+        && !flags.is_mate_unmapped()
+        && matches!(
+            (record.reference_sequence_id(), record.mate_reference_sequence_id()),
+            (Some(Ok(ref_id)), Some(Ok(mate_ref_id))) if ref_id != mate_ref_id
+        )
+}
+
+/// Whether `record` maps with a quality below `--min-mapq`; a missing
+/// quality (255) never is, nor an unmapped record
+#[inline]
+fn is_below_min_mapq(record: &bam::Record, args: &Args) -> bool {
+    !record.flags().is_unmapped()
+        && record
+            .mapping_quality()
+            .is_some_and(|mapq| mapq.get() < args.min_mapq)
+}
+
+/// Whether `record` is placed on a reference given to `--exclude-ref`
+#[inline]
+fn is_on_excluded_ref(record: &bam::Record, excluded_refs: &HashSet<usize>) -> bool {
+    !excluded_refs.is_empty()
+        && matches!(record.reference_sequence_id(), Some(Ok(id)) if excluded_refs.contains(&id))
+}
+
+/// Resolve `--exclude-ref` names to reference sequence ids of `header`
+fn excluded_ref_ids(header: &sam::Header, names: &[String]) -> Result<HashSet<usize>> {
+    names
+        .iter()
+        .map(|name| {
+            header
+                .reference_sequences()
+                .get_index_of(name.as_bytes())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("--exclude-ref {}: no @SQ line with this name", name)
+                })
+        })
+        .collect()
+}
+
+/// First pass and merge: collect the indices of duplicate records
+///
+/// `records` yields every record of the input stream after the header. The
+/// duplicate sets are returned as well when any of `args.tag_duplicate_set`,
+/// `args.tag_duplicate_set_size`, `args.tag_molecule_id` and
+/// `args.tag_original_name` is set.
+pub fn collect_duplicates<I>(
+    args: &Args,
+    records: I,
+    header: &sam::Header,
+) -> Result<(RoaringBitmap, Option<DupSets>)>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    let (dup_mask, dup_sets, _) = find_duplicates(
+        args,
+        records,
+        header,
+        &mut Timings::default(),
+        &mut MarkdupSummary::default(),
+    )?;
+    Ok((dup_mask, dup_sets))
+}
+
+/// [`collect_duplicates`], also recording the read, sort and merge phase
+/// durations in `timings` and the counts in `summary`
+///
+/// The optical duplicates found with `args.optical_duplicate_pixel_distance`
+/// are returned last, for `--tagging-policy`.
+fn find_duplicates<I>(
+    args: &Args,
+    records: I,
+    header: &sam::Header,
+    timings: &mut Timings,
+    summary: &mut MarkdupSummary,
+) -> Result<(RoaringBitmap, Option<DupSets>, RoaringBitmap)>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    let tmp_root = args.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    check_tmp_dir(args, &tmp_root)?;
+    let tmp_dir = Builder::new()
+        .prefix("markdup_rust")
+        .tempdir_in(&tmp_root)?;
+
+    // Build library map, keyed by LB or with --group-by sample by SM; read
+    // groups without it (or a header without read groups, or --ignore-rg)
+    // fall back to --default-library
+    let mut lib_map = HashMap::new();
+    for (_id, rg) in header.read_groups().iter().filter(|_| !args.ignore_rg) {
+        let lib_name = rg
+            .other_fields()
+            .get(&args.group_by.tag())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| args.default_library.clone());
+        let next_id = lib_map.len() as i32;
+        lib_map.entry(lib_name).or_insert(next_id);
+    }
+    if lib_map.is_empty() {
+        lib_map.insert(args.default_library.clone(), 0);
+    }
+    if let Some(unnamed) = unnamed_library_source(args, header) {
+        logln!(
+            "warning: {}; their reads are counted as library \"{}\" (name it with --library-name)",
+            unnamed,
+            DEFAULT_LIBRARY
+        );
+    }
+    let mut metrics = DuplicationMetrics::new(lib_map.iter().map(|(name, &id)| (id, name.clone())));
+
+    let get_lib_id = |rec: &bam::Record| -> i32 {
+        if args.ignore_rg {
+            return 0;
+        }
+        rec.data()
+            .get(noodles::sam::alignment::record::data::field::Tag::READ_GROUP.as_ref())
+            .and_then(|v| v.ok())
+            .and_then(|v| {
+                if let noodles::sam::alignment::record::data::field::Value::String(s) = v {
+                    header.read_groups().get::<BStr>(s.as_ref()).and_then(|rg| {
+                        let lib_name = rg
+                            .other_fields()
+                            .get(&args.group_by.tag())
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|| args.default_library.clone());
+                        lib_map.get(&lib_name).cloned()
+                    })
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(0)
+    };
+
+    // UMI hash (and raw bytes when clustering) from the UMI tag;
+    // 0 when disabled or the tag is absent
+    let umi_tag = args.umi_tag;
+    let keep_umi_seq = args.umi_edit_distance > 0;
+    let get_umi = move |rec: &bam::Record| -> (u64, Vec<u8>) {
+        match umi_tag
+            .and_then(|tag| rec.data().get(&tag))
+            .and_then(|v| v.ok())
+        {
+            Some(noodles::sam::alignment::record::data::field::Value::String(s)) => {
+                let seq = if keep_umi_seq { s.to_vec() } else { Vec::new() };
+                (hash_barcode(s), seq)
+            }
+            _ => (0, Vec::new()),
+        }
+    };
+    // Barcode hash over the --cell-barcode-tag and --key-tag values, each
+    // followed by a NUL so that values cannot run into each other; 0 when
+    // both are disabled or absent
+    let barcode_tags: Vec<[u8; 2]> = args
+        .cell_barcode_tag
+        .into_iter()
+        .chain(args.key_tag)
+        .collect();
+    let get_barcode = move |rec: &bam::Record| -> u64 {
+        let mut key = Vec::new();
+        let mut found = false;
+        for tag in &barcode_tags {
+            if let Some(Ok(noodles::sam::alignment::record::data::field::Value::String(s))) =
+                rec.data().get(tag)
+            {
+                key.extend_from_slice(s);
+                found = true;
+            }
+            key.push(0);
+        }
+        if found { hash_barcode(&key) } else { 0 }
+    };
+    if args.by_sequence {
+        let umi = |rec: &bam::Record| get_umi(rec).0;
+        let (dup_mask, dup_sets) = sequence::find_duplicates(
+            args,
+            records,
+            tmp_dir.path(),
+            &get_lib_id,
+            &umi,
+            timings,
+            summary,
+        )?;
+        return Ok((dup_mask, dup_sets, RoaringBitmap::new()));
+    }
+
+    let find_start = Instant::now();
+    let mut pe_count: u64 = 0;
+    let mut se_count: u64 = 0;
+    let mut unmatched_pairs_count: u64 = 0;
+    let mut no_ref_span_count: u64 = 0;
+    let mut unmapped_skipped: u64 = 0;
+    let mut secondary_skipped: u64 = 0;
+    let mut supplementary_skipped: u64 = 0;
+    let mut qcfail_skipped: u64 = 0;
+    let mut qcfail_included: u64 = 0;
+    let mut duplicate_name_count: u64 = 0;
+    // Reads placed at their pair's position by their mate CIGAR
+    let mut half_count: u64 = 0;
+    let mut nameless_pairs: u64 = 0;
+    let mut unlocated_count: u64 = 0;
+    let name_parser = match args.optical_duplicate_pixel_distance {
+        Some(_) => Some(NameParser::new(&args.read_name_regex)?),
+        None => None,
+    };
+    // samtools breaks ties between copies on the read name
+    let keep_names = args.compat == Compat::Samtools;
+    let mut excluded_ref_skipped: u64 = 0;
+    let mut low_mapq_skipped: u64 = 0;
+    let mut inter_chrom_count: u64 = 0;
+    let excluded_refs = excluded_ref_ids(header, &args.exclude_ref)?;
+
+    logln!("finding positions of the duplicate reads in the file...");
+
+    let batching = Batching::from_args(args);
+    match batching.max_mem {
+        Some(max_mem) => logln!(
+            "  memory budget {}: batches of up to {} records",
+            format_bytes(max_mem as u64),
+            batching.batch_size
+        ),
+        None => logln!("  batch size {} records", batching.batch_size),
+    }
+
+    let name_sorted = args.name_sorted || io::is_name_sorted(header);
+    if name_sorted && args.input.len() > 1 {
+        anyhow::bail!(
+            "name-sorted input must be a single BAM; several inputs are merged in coordinate order"
+        );
+    }
+    // Name-sorted input pairs adjacent mates without holding any, and
+    // samtools mode needs the later mate's ms tag to score a pair
+    let mate_cigar = !name_sorted && !args.no_mate_cigar && args.compat != Compat::Samtools;
+    let mut pending_pairs = if name_sorted {
+        logln!("  input is name-sorted: pairing adjacent mates");
+        PendingMates::adjacent(tmp_dir.path())
+    } else {
+        PendingMates::new(batching.pending_limit(), tmp_dir.path())
+    };
+    // Estimated heap bytes of UMI sequences in the chunk
+    let mut umi_bytes = 0usize;
+    let mut chunk = Vec::with_capacity(batching.capacity());
+    // Chunk files with their merge shard: the library when chunks are split
+    // by library (more than one library), otherwise a single shard 0
+    let split_by_library = lib_map.len() > 1;
+    let mut tmp_files: Vec<(i32, PathBuf)> = Vec::new();
+    let mut chunk_seq = 0;
+    let mut sort_dur = Duration::ZERO;
+    let mut save_chunk = |chunk: Vec<Metadata>| -> Result<()> {
+        let sort_start = Instant::now();
+        if split_by_library {
+            tmp_files.extend(io::save_chunk_by_library(
+                chunk,
+                tmp_dir.path(),
+                chunk_seq,
+                args.tmp_compression,
+            )?);
+        } else {
+            tmp_files.push((
+                0,
+                io::save_chunk_parallel(chunk, tmp_dir.path(), chunk_seq, args.tmp_compression)?,
+            ));
+        }
+        chunk_seq += 1;
+        sort_dur += sort_start.elapsed();
+        Ok(())
+    };
+
+    // Also collect PE second-end positions during first pass
+    let mut pe_second_ends: HashMap<SecondEndKey, u32> = HashMap::new();
+
+    let mut record_count: u64 = 0;
+    for (index, result) in records.enumerate() {
+        let record = result?;
+        record_count += 1;
+        let flags = record.flags();
+        if flags.is_unmapped() {
+            unmapped_skipped += 1;
+            metrics.library(get_lib_id(&record)).unmapped_reads += 1;
+            continue;
+        }
+        if flags.is_secondary() || flags.is_supplementary() {
+            if flags.is_secondary() {
+                secondary_skipped += 1;
+            } else {
+                supplementary_skipped += 1;
+            }
+            metrics
+                .library(get_lib_id(&record))
+                .secondary_or_supplementary_rds += 1;
+            continue;
+        }
+        if excludes_qcfail(args) && flags.is_qc_fail() {
+            // The mate, if any, is then left unpaired and treated as a fragment
+            qcfail_skipped += 1;
+            continue;
+        }
+        if is_on_excluded_ref(&record, &excluded_refs) {
+            // Likewise for a mate on a reference that is not excluded
+            excluded_ref_skipped += 1;
+            continue;
+        }
+        if is_below_min_mapq(&record, args) {
+            // Likewise for a mate that maps well enough
+            low_mapq_skipped += 1;
+            continue;
+        }
+        let inter_chromosomal = is_inter_chromosomal(&record);
+        if inter_chromosomal {
+            inter_chrom_count += 1;
+            if args.inter_chrom_policy == InterChromPolicy::Skip {
+                continue;
+            }
+        }
+
+        let lib_id = get_lib_id(&record);
+        let pos = get_5p_pos_with(&record, !args.ignore_hard_clips)?;
+        if pos < 0 {
+            // No reference-consuming CIGAR op: treated as unmapped
+            no_ref_span_count += 1;
+            metrics.library(lib_id).unmapped_reads += 1;
+            continue;
+        }
+        let score = if args.include_qcfail && flags.is_qc_fail() {
+            // Only kept when no copy passed QC
+            qcfail_included += 1;
+            0
+        } else {
+            // Passing reads score at least 1, so a QC-failed read loses even
+            // the ties that the input order would otherwise break for it
+            args.compat
+                .read_score(args.score.score(&record, args.min_base_quality))
+                + u32::from(args.include_qcfail)
+        };
+        // Kept in pos2, which fragments otherwise leave at 0
+        let end_3p = if args.use_3p_end {
+            get_3p_pos_with(&record, !args.ignore_hard_clips)?
+        } else {
+            0
+        };
+        let read_name = match (keep_names, record.name()) {
+            (true, Some(name)) => name.to_vec(),
+            _ => Vec::new(),
+        };
+        let ref_id = record
+            .reference_sequence_id()
+            .transpose()?
+            .map(|i| i as i32)
+            .unwrap_or(-1);
+        let rev = flags.is_reverse_complemented();
+        let (umi, umi_seq) = get_umi(&record);
+        let barcode = get_barcode(&record);
+        let location = match (&name_parser, record.name()) {
+            (Some(parser), Some(name)) => parser.parse(name),
+            _ => None,
+        };
+        if name_parser.is_some() && location.is_none() {
+            unlocated_count += 1;
+        }
+
+        // A paired read without a name cannot be matched with its mate and
+        // is treated as a fragment unless --strict asks to stop
+        let paired_name = if flags.is_segmented() && !flags.is_mate_unmapped() {
+            let name = record.name();
+            if name.is_none() {
+                if args.strict {
+                    anyhow::bail!("paired read at record {} has no name", index);
+                }
+                nameless_pairs += 1;
+            }
+            name
+        } else {
+            None
+        };
+
+        if let Some(name) = paired_name {
+            let mut name: &[u8] = name;
+            if args.strip_mate_suffix {
+                name = strip_mate_suffix(name);
+            }
+            let name = name.to_vec();
+            let end = read_end(flags);
+            let mut read = Metadata::new_se(lib_id, ref_id, pos, rev as u8, score, index as u64)
+                .with_umi(umi)
+                .with_barcode(barcode);
+            read.paired_end = 1;
+            read.umi_seq = umi_seq;
+            read.location = location;
+            read.name = read_name;
+            if args.use_tlen {
+                read.tlen = record.template_length();
+            }
+            if inter_chromosomal && args.inter_chrom_policy == InterChromPolicy::Single {
+                // A fragment, deduplicated with the other fragments here
+                umi_bytes += read.umi_seq.len();
+                metrics.library(lib_id).unpaired_reads_examined += 1;
+                read.paired_end = 0;
+                read.pos2 = end_3p;
+                chunk.push(read);
+                se_count += 1;
+            } else if let Some((mate, mate_end)) = pending_pairs.take(&name)? {
+                if end != 0 && end == mate_end {
+                    // Same end twice: a repeated read name, not a mate. It is
+                    // still paired as before unless --strict asks to stop
+                    if args.strict {
+                        anyhow::bail!(
+                            "read name {} appears twice for the same end of a pair",
+                            BStr::new(&name)
+                        );
+                    }
+                    duplicate_name_count += 1;
+                }
+                umi_bytes += mate.umi_seq.len();
+                let read_ms = if args.compat == Compat::Samtools {
+                    mate_score_tag(&record)
+                } else {
+                    None
+                };
+                let (pair, second_end, s2) = join_mates(args, mate, read, read_ms, (mate_end, end));
+                let best = pe_second_ends.entry(second_end).or_insert(0);
+                *best = (*best).max(s2);
+                pe_count += 1;
+                metrics.library(pair.lib_id).read_pairs_examined += 1;
+                chunk.push(pair);
+            } else if let Some(mate_pos) = mate_cigar
+                .then(|| get_mate_5p_pos(&record, !args.ignore_hard_clips))
+                .flatten()
+                .filter(|&mate_pos| mate_pos.1 >= 0 && mate_pos != (ref_id, pos))
+            {
+                // Placed at the pair's position right away; the mates are
+                // joined while merging instead of waiting here
+                umi_bytes += read.umi_seq.len();
+                let mate_rev = flags.is_mate_reverse_complemented() as u8;
+                let end = if args.orientation_class { end } else { 0 };
+                chunk.push(read.into_half(mate_pos, mate_rev, hash_barcode(&name), end));
+                half_count += 1;
+            } else {
+                // Held as an unmatched second end until its mate shows up
+                pending_pairs.insert(name, read, end)?;
+            }
+        } else {
+            // Fragment: single-end read, mate unmapped or nameless pair
+            umi_bytes += umi_seq.len();
+            chunk.push(Metadata {
+                lib_id,
+                barcode,
+                ref_id1: ref_id,
+                pos1: pos,
+                rev1: rev as u8,
+                ref_id2: -1,
+                pos2: end_3p,
+                rev2: 0,
+                umi,
+                tlen: 0,
+                score,
+                score1: score,
+                idx1: index as u64,
+                idx2: 0,
+                paired_end: 0,
+                umi_seq,
+                location,
+                name: read_name,
+            });
+            se_count += 1;
+            metrics.library(lib_id).unpaired_reads_examined += 1;
+        }
+
+        let extra_bytes =
+            pending_pairs.bytes() + umi_bytes + pe_second_ends.len() * SECOND_END_BYTES;
+        if batching.should_flush(chunk.len(), extra_bytes) {
+            umi_bytes = 0;
+            let chunk_to_save =
+                std::mem::replace(&mut chunk, Vec::with_capacity(batching.capacity()));
+            save_chunk(chunk_to_save)?;
+        }
+    }
+
+    // Handle remaining pending pairs
+    let spilled_mates = pending_pairs.total_spilled() as u64;
+    if spilled_mates > 0 {
+        logln!(
+            "  spilled {} reads waiting for their mate to disk",
+            spilled_mates
+        );
+    }
+    for mate in pending_pairs.into_remaining()? {
+        metrics.library(mate.lib_id).unpaired_reads_examined += 1;
+        chunk.push(mate);
+        se_count += 1;
+        unmatched_pairs_count += 1;
+    }
+    if !chunk.is_empty() {
+        save_chunk(chunk)?;
+    }
+    let read_sort_dur = find_start.elapsed();
+    timings.sort = sort_dur;
+    timings.read = read_sort_dur.saturating_sub(sort_dur);
+
+    if duplicate_name_count > 0 {
+        logln!(
+            "  found {} reads whose name was already waiting for the same end (duplicate read names?)",
+            duplicate_name_count
+        );
+    }
+    if nameless_pairs > 0 {
+        logln!(
+            "  treated {} paired reads without a name as single ends",
+            nameless_pairs
+        );
+    }
+    if unlocated_count > 0 {
+        logln!(
+            "  found no tile and x/y in {} read names; they are never optical duplicates",
+            unlocated_count
+        );
+    }
+    if no_ref_span_count > 0 {
+        logln!(
+            "  skipped {} mapped reads whose CIGAR consumes no reference",
+            no_ref_span_count
+        );
+    }
+    logln!("  sorted {} end pairs", pe_count);
+    logln!(
+        "     and {} single ends (among them {} unmatched pairs)",
+        se_count,
+        unmatched_pairs_count
+    );
+    if half_count > 0 {
+        logln!(
+            "     and {} reads placed by their mate CIGAR, to be paired while merging",
+            half_count
+        );
+    }
+    logln!(
+        "  skipped {} unmapped, {} secondary and {} supplementary reads",
+        unmapped_skipped,
+        secondary_skipped,
+        supplementary_skipped
+    );
+    if excludes_qcfail(args) {
+        logln!("  skipped {} QC-failed reads", qcfail_skipped);
+    }
+    if args.include_qcfail {
+        logln!("  gave {} QC-failed reads a score of 0", qcfail_included);
+    }
+    if !excluded_refs.is_empty() {
+        logln!(
+            "  skipped {} reads on excluded references",
+            excluded_ref_skipped
+        );
+    }
+    if args.min_mapq > 0 {
+        logln!(
+            "  skipped {} reads with MAPQ below {}",
+            low_mapq_skipped,
+            args.min_mapq
+        );
+    }
+    match args.inter_chrom_policy {
+        InterChromPolicy::Pair if inter_chrom_count > 0 => {
+            logln!(
+                "  paired {} reads with their mate on another reference",
+                inter_chrom_count
+            )
+        }
+        InterChromPolicy::Pair => {}
+        InterChromPolicy::Single => {
+            logln!(
+                "  sorted {} reads with their mate on another reference as fragments",
+                inter_chrom_count
+            )
+        }
+        InterChromPolicy::Skip => logln!(
+            "  skipped {} reads with their mate on another reference",
+            inter_chrom_count
+        ),
+    }
+
+    // Merge and dedup, one shard per library when chunks were split; a
+    // shard with too many chunks to open at once is pre-merged first
+    let mut shards: BTreeMap<i32, Vec<PathBuf>> = BTreeMap::new();
+    for (shard, path) in tmp_files {
+        shards.entry(shard).or_default().push(path);
+    }
+    let chunk_count: usize = shards.values().map(Vec::len).sum();
+    let mut merge_seq = 0;
+    let shards: Vec<Vec<PathBuf>> = shards
+        .into_values()
+        .map(|paths| {
+            io::reduce_fanin(
+                paths,
+                tmp_dir.path(),
+                args.max_merge_fanin,
+                args.tmp_compression,
+                &mut merge_seq,
+            )
+        })
+        .collect::<Result<_>>()?;
+    if merge_seq > 0 {
+        logln!(
+            "  pre-merged {} chunks into {} (fan-in limit {})",
+            chunk_count,
+            shards.iter().map(Vec::len).sum::<usize>(),
+            args.max_merge_fanin
+        );
+    }
+
+    log!("  collecting indices of duplicate reads... ");
+    let collect_start = Instant::now();
+    let MergeOutcome {
+        dup_mask,
+        hist,
+        density,
+        loci,
+        dup_sets,
+        optical,
+        metrics: dup_metrics,
+        orphan: total_orphan,
+        pe: total_pe,
+        se_only: total_se_only,
+        mate_cigar_pairs,
+        mate_cigar_unmatched,
+    } = merge_shards(&shards, &pe_second_ends, args)?;
+    metrics.merge(&dup_metrics);
+    pe_count += mate_cigar_pairs as u64;
+    se_count += mate_cigar_unmatched as u64;
+
+    if let (Some(hist), Some(path)) = (&hist, &args.histogram) {
+        hist.save(path)?;
+    }
+    if let (Some(density), Some(path)) = (&density, &args.dup_bed) {
+        density.save(path, header)?;
+    }
+    if let (Some(loci), Some(path)) = (&loci, &args.dup_loci_bed) {
+        loci.save(path, header)?;
+    }
+    if let Some(path) = &args.metrics_file {
+        metrics.save(path)?;
+    }
+
+    let collect_dur = collect_start.elapsed();
+    logln!("done in {} ms", collect_dur.as_millis());
+    if half_count > 0 {
+        logln!(
+            "  paired {} pairs by mate CIGAR ({} reads left unmatched)",
+            mate_cigar_pairs,
+            mate_cigar_unmatched
+        );
+    }
+    logln!("  found {} duplicates", dup_mask.len());
+    if let Some(sets) = &dup_sets {
+        logln!("  in {} duplicate sets", sets.len());
+    }
+    logln!(
+        "  (orphan={}, pe={}, se_only={})",
+        total_orphan,
+        total_pe,
+        total_se_only
+    );
+    if args.optical_duplicate_pixel_distance.is_some() {
+        logln!("  {} of them optical duplicates", optical.len());
+    }
+
+    *summary = MarkdupSummary {
+        records: record_count,
+        pe: pe_count,
+        se: se_count,
+        inter_chromosomal: inter_chrom_count,
+        spilled_mates,
+        duplicates: dup_mask.len(),
+        optical_duplicates: optical.len(),
+        orphan_duplicates: total_orphan,
+        pe_duplicates: total_pe,
+        se_only_duplicates: total_se_only,
+        metrics,
+    };
+
+    let find_dur = find_start.elapsed();
+    timings.merge = find_dur.saturating_sub(read_sort_dur);
+    let (find_m, find_s) = format_duration(find_dur);
+    logln!(
+        "collected list of positions in {} min {} sec",
+        find_m,
+        find_s
+    );
+
+    Ok((dup_mask, dup_sets, optical))
+}
+
+/// Duplicates found by merging chunk files
+#[derive(Default)]
+pub struct MergeOutcome {
+    pub dup_mask: RoaringBitmap,
+    pub hist: Option<DupHistogram>,
+    pub density: Option<DupDensity>,
+    pub loci: Option<DupLoci>,
+    pub dup_sets: Option<DupSets>,
+    /// Duplicates found optical, with `--optical-duplicate-pixel-distance`
+    pub optical: RoaringBitmap,
+    /// Duplicate counts per library; the examined counts are only those of
+    /// reads paired by their mate CIGAR, which are counted while merging
+    pub metrics: DuplicationMetrics,
+    pub orphan: usize,
+    pub pe: usize,
+    pub se_only: usize,
+    /// Pairs joined from reads placed by their mate CIGAR
+    pub mate_cigar_pairs: usize,
+    /// Reads placed by their mate CIGAR whose mate never showed up
+    pub mate_cigar_unmatched: usize,
+}
+
+impl MergeOutcome {
+    /// Fold in the outcome of a shard that comes after this one in merge order
+    fn absorb(&mut self, other: MergeOutcome) {
+        self.dup_mask |= other.dup_mask;
+        match (&mut self.hist, other.hist) {
+            (Some(hist), Some(other)) => hist.merge(&other),
+            (hist @ None, other) => *hist = other,
+            _ => {}
+        }
+        match (&mut self.density, other.density) {
+            (Some(density), Some(other)) => density.merge(&other),
+            (density @ None, other) => *density = other,
+            _ => {}
+        }
+        match (&mut self.loci, other.loci) {
+            (Some(loci), Some(other)) => loci.merge(&other),
+            (loci @ None, other) => *loci = other,
+            _ => {}
+        }
+        match (&mut self.dup_sets, other.dup_sets) {
+            (Some(sets), Some(other)) => sets.append(other),
+            (sets @ None, other) => *sets = other,
+            _ => {}
+        }
+        self.optical |= other.optical;
+        self.metrics.merge(&other.metrics);
+        self.orphan += other.orphan;
+        self.pe += other.pe;
+        self.se_only += other.se_only;
+        self.mate_cigar_pairs += other.mate_cigar_pairs;
+        self.mate_cigar_unmatched += other.mate_cigar_unmatched;
+    }
+}
+
+/// Merge the sorted chunk files of every shard and detect duplicates
+///
+/// Each shard is a set of chunk files whose positional groups never span
+/// another shard (in practice, one library each), so the shards are merged
+/// in parallel on the rayon pool. Their outcomes are combined in shard order,
+/// which gives exactly the result of one merge over all files; in particular
+/// duplicate set ids are numbered as a single merge would number them.
+pub fn merge_shards(
+    shards: &[Vec<PathBuf>],
+    pe_second_ends: &HashMap<SecondEndKey, u32>,
+    args: &Args,
+) -> Result<MergeOutcome> {
+    let outcomes: Vec<MergeOutcome> = shards
+        .par_iter()
+        .map(|paths| merge_shard(paths, pe_second_ends, args))
+        .collect::<Result<_>>()?;
+    let mut total = MergeOutcome::default();
+    for outcome in outcomes {
+        total.absorb(outcome);
+    }
+    Ok(total)
+}
+
+/// K-way merge of one shard's chunk files with per-group duplicate detection
+fn merge_shard(
+    paths: &[PathBuf],
+    pe_second_ends: &HashMap<SecondEndKey, u32>,
+    args: &Args,
+) -> Result<MergeOutcome> {
+    let dedup_opts = DedupOptions {
+        umi_edit_distance: args.umi_edit_distance,
+        orphan_policy: args.orphan_policy,
+        strand_agnostic: args.strand_agnostic,
+        position_tolerance: args.position_tolerance,
+    };
+    let mut outcome = MergeOutcome {
+        hist: args.histogram.as_ref().map(|_| DupHistogram::default()),
+        density: args
+            .dup_bed
+            .as_ref()
+            .map(|_| DupDensity::new(args.bin_size)),
+        loci: args.dup_loci_bed.as_ref().map(|_| DupLoci::default()),
+        dup_sets: (args.tag_duplicate_set
+            || args.tag_duplicate_set_size
+            || args.tag_molecule_id
+            || args.tag_original_name)
+            .then(DupSets::default),
+        ..Default::default()
+    };
+
+    let mut error = None;
+    let sorted = MergedChunks::open(paths)?.map_while(|m| m.map_err(|e| error = Some(e)).ok());
+    let joined = RefCell::new(JoinedMates::default());
+    let sorted = JoinHalves::new(sorted, args, &joined);
+    let policy: &dyn DuplicatePolicy = match args.compat {
+        Compat::Samtools => &SamtoolsPolicy,
+        Compat::Sambamba | Compat::Picard => &SambambaPolicy,
+    };
+    for_each_group_with(sorted, &dedup_opts, |group| {
+        let joined = joined.borrow();
+        let (o, p, s) = identify_dups_with_policy(
+            group,
+            &mut outcome.dup_mask,
+            &(pe_second_ends, &joined.second_ends),
+            &dedup_opts,
+            outcome.hist.as_mut(),
+            outcome.dup_sets.as_mut(),
+            policy,
+        );
+        if let Some(density) = outcome.density.as_mut() {
+            density.add(group[0].ref_id1, group[0].pos1, (o + p + s) as u64);
+        }
+        if let Some(loci) = outcome.loci.as_mut() {
+            let strand = match group[0].rev1 {
+                _ if args.strand_agnostic => Strand::Both,
+                0 => Strand::Forward,
+                _ => Strand::Reverse,
+            };
+            loci.add(
+                group[0].ref_id1,
+                group[0].pos1,
+                strand,
+                group.len() as u64,
+                (o + s + p / 2) as u64,
+            );
+        }
+        if let Some(distance) = args.optical_duplicate_pixel_distance
+            && p + s > 0
+        {
+            let pairs = optical::mark_optical(
+                group,
+                &outcome.dup_mask,
+                &dedup_opts,
+                distance,
+                &mut outcome.optical,
+            );
+            outcome
+                .metrics
+                .library(group[0].lib_id)
+                .read_pair_optical_duplicates += pairs;
+        }
+        let lib = outcome.metrics.library(group[0].lib_id);
+        lib.unpaired_read_duplicates += (o + s) as u64;
+        lib.read_pair_duplicates += (p / 2) as u64;
+        outcome.orphan += o;
+        outcome.pe += p;
+        outcome.se_only += s;
+    });
+    let joined = joined.into_inner();
+    outcome.metrics.merge(&joined.metrics);
+    outcome.mate_cigar_pairs = joined.pairs;
+    outcome.mate_cigar_unmatched = joined.unmatched;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(outcome),
+    }
+}
+
+/// What joining the halves of pairs adds to a shard's merge
+#[derive(Default)]
+struct JoinedMates {
+    /// Second ends of the joined pairs, alongside those of the first pass
+    second_ends: HashMap<SecondEndKey, u32>,
+    /// Examined pairs and unmatched reads per library
+    metrics: DuplicationMetrics,
+    pairs: usize,
+    unmatched: usize,
+}
+
+/// Merged metadata with the halves of pairs joined (see [`Metadata::into_half`])
+///
+/// Both halves of a pair sort at the pair's first end, so they meet in the
+/// run of records at that position and are joined there as the first pass
+/// joins pending mates. A half whose mate never shows up (filtered out, or
+/// missing from the input) goes on as an unmatched mate at its own position,
+/// which never comes before the run it was found in. The second ends of the
+/// joined pairs are recorded before the stream reaches them.
+struct JoinHalves<'a, I: Iterator<Item = Metadata>> {
+    sorted: std::iter::Peekable<I>,
+    args: &'a Args,
+    joined: &'a RefCell<JoinedMates>,
+    /// Unmatched second ends waiting for their own position
+    deferred: BinaryHeap<Reverse<Metadata>>,
+    run: std::vec::IntoIter<Metadata>,
+}
+
+impl<'a, I: Iterator<Item = Metadata>> JoinHalves<'a, I> {
+    fn new(sorted: I, args: &'a Args, joined: &'a RefCell<JoinedMates>) -> Self {
+        Self {
+            sorted: sorted.peekable(),
+            args,
+            joined,
+            deferred: BinaryHeap::new(),
+            run: Vec::new().into_iter(),
+        }
+    }
+
+    /// Join the halves in `run`, which all share one position
+    fn join(&mut self, run: &mut Vec<Metadata>) {
+        let mut halves: Vec<Metadata> = run.extract_if(.., |m| m.is_half()).collect();
+        // Mates share the name hash; a first end before a second end
+        halves.sort_unstable_by_key(|m| (m.idx2, m.paired_end, m.idx1));
+        let mut joined = self.joined.borrow_mut();
+        for mates in halves.chunk_by(|a, b| a.idx2 == b.idx2) {
+            let (firsts, seconds) =
+                mates.split_at(mates.partition_point(|m| m.paired_end == HALF_FIRST_END));
+            for (first, second) in firsts.iter().zip(seconds) {
+                let (mate, read) = if first.idx1 < second.idx1 {
+                    (first, second)
+                } else {
+                    (second, first)
+                };
+                let ends = (mate.half_read_end(), read.half_read_end());
+                let (mate, read) = (mate.clone().from_half(), read.clone().from_half());
+                let (pair, second_end, s2) = join_mates(self.args, mate, read, None, ends);
+                let best = joined.second_ends.entry(second_end).or_insert(0);
+                *best = (*best).max(s2);
+                joined.metrics.library(pair.lib_id).read_pairs_examined += 1;
+                joined.pairs += 1;
+                run.push(pair);
+            }
+            let paired = firsts.len().min(seconds.len());
+            for half in firsts[paired..].iter().chain(&seconds[paired..]) {
+                joined.metrics.library(half.lib_id).unpaired_reads_examined += 1;
+                joined.unmatched += 1;
+                let mate = half.clone().from_half();
+                if half.paired_end == HALF_FIRST_END {
+                    run.push(mate);
+                } else {
+                    self.deferred.push(Reverse(mate));
+                }
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = Metadata>> Iterator for JoinHalves<'_, I> {
+    type Item = Metadata;
+
+    fn next(&mut self) -> Option<Metadata> {
+        let position = |m: &Metadata| (m.lib_id, m.barcode, m.ref_id1, m.pos1);
+        loop {
+            if let Some(m) = self.run.next() {
+                return Some(m);
+            }
+            let next = self.sorted.peek().map(position);
+            let deferred = self.deferred.peek().map(|Reverse(m)| position(m));
+            let at = match (next, deferred) {
+                (Some(next), Some(deferred)) => next.min(deferred),
+                (next, deferred) => next.or(deferred)?,
+            };
+            let mut run = Vec::new();
+            while let Some(m) = self.sorted.next_if(|m| position(m) == at) {
+                run.push(m);
+            }
+            let mut reordered = false;
+            while self
+                .deferred
+                .peek()
+                .is_some_and(|Reverse(m)| position(m) == at)
+            {
+                run.extend(self.deferred.pop().map(|Reverse(m)| m));
+                reordered = true;
+            }
+            if run.iter().any(Metadata::is_half) {
+                self.join(&mut run);
+                reordered = true;
+            }
+            if reordered {
+                run.sort_unstable();
+            }
+            self.run = run.into_iter();
+        }
+    }
+}
+
+/// A record with the DUPLICATE flag and aux tags to write it with, and
+/// whether it goes to `--duplicates-output` instead of the output
+type MarkedRecord = (bam::Record, Option<bool>, RecordTags, bool);
+
+/// Indices of the records the write pass has to look up: the duplicates
+/// and, with `dup_sets`, every member of a duplicate set
+///
+/// Kept as a bitmap so it stays as compact as `dup_mask`; the write pass walks
+/// it in file order with a cursor, which is several times faster than a
+/// `contains` per record (see `benches/mark_cursor.rs`).
+fn touched_indices(dup_mask: &RoaringBitmap, dup_sets: Option<&DupSets>) -> RoaringBitmap {
+    let mut touched = dup_mask.clone();
+    if let Some(sets) = dup_sets {
+        touched.extend(sets.members());
+    }
+    touched
+}
+
+/// Names of the representatives of `dup_sets`, by record index
+///
+/// A representative can come after its copies in the input, so the names
+/// are read in a pass of their own before the write pass.
+fn representative_names(args: &Args, dup_sets: &DupSets) -> Result<HashMap<u64, Vec<u8>>> {
+    let reps: RoaringBitmap = dup_sets.representatives().map(|idx| idx as u32).collect();
+    let mut names = HashMap::with_capacity(reps.len() as usize);
+    let mut reps = reps.iter().peekable();
+    let (_, records) = open_inputs(&args.input)?;
+    for (idx, result) in records.enumerate() {
+        if reps.peek().is_none() {
+            break;
+        }
+        let record = result?;
+        if reps.next_if_eq(&(idx as u32)).is_some() {
+            names.insert(
+                idx as u64,
+                record.name().map_or(b"*".to_vec(), |name| name.to_vec()),
+            );
+        }
+    }
+    Ok(names)
+}
+
+/// Whether unmapped mates of duplicates are marked: with
+/// `--mark-unmapped-mates`, and under `--compat picard` as Picard does
+#[inline]
+fn marks_unmapped_mates(args: &Args) -> bool {
+    args.mark_unmapped_mates || args.compat == Compat::Picard
+}
+
+/// Duplicate marks passed on by read name to the records that take no part
+/// in detection: secondary and supplementary records with
+/// `--mark-supplementary`, and unmapped mates placed by a mapped read with
+/// `--mark-unmapped-mates`
+#[derive(Default)]
+struct InheritedMarks {
+    /// Names of the primary records in the duplicate mask
+    names: HashSet<Vec<u8>>,
+    supplementary: bool,
+    unmapped_mates: bool,
+    strip_mate_suffix: bool,
+}
+
+impl InheritedMarks {
+    /// Read the names of the duplicates, when any record inherits a mark
+    ///
+    /// Such a record can come before its primary in the input, so the names
+    /// are read in a pass of their own before the write pass.
+    fn new(args: &Args, dup_mask: &RoaringBitmap) -> Result<Self> {
+        let mut marks = Self {
+            supplementary: args.mark_supplementary,
+            unmapped_mates: marks_unmapped_mates(args),
+            strip_mate_suffix: args.strip_mate_suffix,
+            ..Default::default()
+        };
+        if !marks.supplementary && !marks.unmapped_mates {
+            return Ok(marks);
+        }
+        marks.names.reserve(dup_mask.len() as usize);
+        let mut dups = dup_mask.iter().peekable();
+        let (_, records) = open_inputs(&args.input)?;
+        for (idx, result) in records.enumerate() {
+            if dups.peek().is_none() {
+                break;
+            }
+            let record = result?;
+            if dups.next_if_eq(&(idx as u32)).is_some()
+                && is_primary(&record)
+                && let Some(name) = record.name()
+            {
+                let name = marks.key(name);
+                marks.names.insert(name.to_vec());
+            }
+        }
+        Ok(marks)
+    }
+
+    fn key<'a>(&self, name: &'a [u8]) -> &'a [u8] {
+        if self.strip_mate_suffix {
+            strip_mate_suffix(name)
+        } else {
+            name
+        }
+    }
+
+    /// Whether `record` takes its mark from the primary records of its name
+    fn follows(&self, record: &bam::Record) -> bool {
+        let flags = record.flags();
+        if !is_primary(record) {
+            self.supplementary
+        } else {
+            self.unmapped_mates
+                && flags.is_unmapped()
+                && flags.is_segmented()
+                && !flags.is_mate_unmapped()
+        }
+    }
+
+    fn contains(&self, record: &bam::Record) -> bool {
+        record
+            .name()
+            .is_some_and(|name| self.names.contains(self.key(name)))
+    }
+}
+
+/// Whether `record` is a duplicate by `dup_mask`, or by `inherited` a record
+/// of a duplicate's read
+#[inline]
+fn is_marked(
+    record: &bam::Record,
+    idx: usize,
+    dup_mask: &RoaringBitmap,
+    inherited: &InheritedMarks,
+) -> bool {
+    if inherited.follows(record) {
+        inherited.contains(record)
+    } else {
+        is_primary(record) && dup_mask.contains(idx as u32)
+    }
+}
+
+/// BGZF compression level of the output: none with `--uncompressed`,
+/// otherwise `--compression-level` or the BGZF default of 6
+fn compression_level(args: &Args) -> CompressionLevel {
+    if args.uncompressed {
+        return CompressionLevel::NONE;
+    }
+    args.compression_level
+        .and_then(CompressionLevel::new)
+        .unwrap_or_default()
+}
+
+/// Where the write pass puts the records it keeps
+enum RecordSink {
+    Single(IndexedWriter),
+    Sharded(ShardedOutput),
+}
+
+impl RecordSink {
+    fn write_record(
+        &mut self,
+        header: &sam::Header,
+        record: &bam::Record,
+        is_dup: Option<bool>,
+        tags: RecordTags,
+    ) -> Result<()> {
+        match self {
+            RecordSink::Single(writer) => writer.write_record(header, record, is_dup, tags),
+            RecordSink::Sharded(shards) => shards.write_record(header, record, is_dup, tags),
+        }
+    }
+}
+
+/// Write pass: copy the input to `args.output` in the output format,
+/// setting the DUPLICATE flag of every primary record in `dup_mask` and
+/// clearing it elsewhere
+///
+/// With `dup_sets`, every member of a duplicate set also gets a `DI` tag
+/// with `args.tag_duplicate_set`, a `DS` tag with its set's size with
+/// `args.tag_duplicate_set_size` and an `MI` tag with `args.tag_molecule_id`;
+/// with `args.tag_original_name` its duplicates get a `do` tag with the name
+/// of the read kept. With `args.tagging_policy` duplicates get a `DT` tag,
+/// `SQ` for those in `optical` and `LB` for the others.
+/// With `args.additive`, flags already set in the input are kept, and with
+/// `args.exclude_qcfail` QC-failed reads are written unchanged, as are reads
+/// on the references of `args.exclude_ref`, reads mapped below
+/// `args.min_mapq` and pairs skipped by `args.inter_chrom_policy`. Duplicates are dropped with `args.remove_duplicates` and
+/// moved to `args.duplicates_output` when set; with `args.only_duplicates`
+/// they are all that is written. The names of
+/// the duplicates go to `args.dup_names_out` when set, and the reads and
+/// duplicates of every tile to `args.tile_metrics`. With
+/// `args.shard_by_chromosome` the output is one file per reference. Every
+/// output header gets a `@PG` line for this run unless `args.no_pg`.
+/// Returns the number of input records.
+pub fn write_output(
+    args: &Args,
+    header: &sam::Header,
+    dup_mask: &RoaringBitmap,
+    dup_sets: Option<&DupSets>,
+    optical: &RoaringBitmap,
+) -> Result<usize> {
+    logln!("marking duplicates...");
+    let write_start = Instant::now();
+
+    let with_program;
+    let header = if args.no_pg {
+        header
+    } else {
+        let command_line: Vec<String> = std::env::args().collect();
+        with_program = io::add_program(header, &command_line.join(" "))?;
+        &with_program
+    };
+
+    let output = Path::new(&args.output);
+    let format = OutputFormat::resolve(args.output_format, output);
+    let index_format = args
+        .write_index
+        .then(|| IndexFormat::resolve(args.index_format, header));
+    let mut writer = match &args.shard_by_chromosome {
+        Some(dir) => RecordSink::Sharded(ShardedOutput::new(
+            dir,
+            format,
+            compression_level(args),
+            header,
+            index_format,
+        )),
+        None => RecordSink::Single(IndexedWriter::create(
+            output,
+            format,
+            compression_level(args),
+            header,
+            index_format,
+        )?),
+    };
+    let mut dup_writer = match &args.duplicates_output {
+        Some(path) => {
+            let format = OutputFormat::resolve(None, path);
+            Some(OutputWriter::create(
+                path,
+                format,
+                compression_level(args),
+                header,
+            )?)
+        }
+        None => None,
+    };
+    let mut dup_names = match &args.dup_names_out {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let name_parser = match args.tile_metrics {
+        Some(_) => Some(NameParser::new(&args.read_name_regex)?),
+        None => None,
+    };
+    let mut tiles = TileMetrics::default();
+    let removes_duplicates = args.remove_duplicates || dup_writer.is_some();
+    let (_, records) = open_inputs(&args.input)?;
+    let excluded_refs = excluded_ref_ids(header, &args.exclude_ref)?;
+    let touched = touched_indices(dup_mask, dup_sets);
+    let mut touched = touched.iter().peekable();
+    let original_names = match dup_sets.filter(|_| args.tag_original_name) {
+        Some(sets) => representative_names(args, sets)?,
+        None => HashMap::new(),
+    };
+    let inherited = InheritedMarks::new(args, dup_mask)?;
+
+    // BAM output patches the flag bytes directly; SAM goes through RecordBuf.
+    // With more than one thread, records are encoded on a writer thread fed
+    // through a queue of --write-queue-depth records
+    let mut record_count = 0usize;
+    let mut duplicate_count = 0usize;
+    let mut supplementary_count = 0usize;
+    let mut unmapped_mate_count = 0usize;
+    let produce = |send: &mut dyn FnMut(MarkedRecord) -> Result<()>| {
+        for (idx, result) in records.enumerate() {
+            let record = result?;
+            let is_touched = touched.next_if_eq(&(idx as u32)).is_some();
+            let primary = is_primary(&record);
+            let markable = is_markable(&record, args, &excluded_refs);
+            let is_dup = markable
+                .then(|| {
+                    (is_touched || inherited.follows(&record))
+                        && is_marked(&record, idx, dup_mask, &inherited)
+                })
+                .filter(|&is_dup| is_dup || !args.additive);
+            record_count += 1;
+            let duplicate = is_dup == Some(true)
+                || (markable && args.additive && record.flags().is_duplicate());
+            duplicate_count += usize::from(duplicate);
+            supplementary_count += usize::from(duplicate && !primary);
+            unmapped_mate_count +=
+                usize::from(duplicate && primary && record.flags().is_unmapped());
+            let location = name_parser
+                .as_ref()
+                .filter(|_| markable && primary)
+                .zip(record.name())
+                .and_then(|(p, n)| p.parse(n));
+            if let Some(location) = location {
+                tiles.add(location.tile, duplicate, optical.contains(idx as u32));
+            }
+            if let Some(names) = dup_names.as_mut().filter(|_| duplicate) {
+                names.write_all(record.name().map_or(&b"*"[..], |name| name.as_ref()))?;
+                names.write_all(b"\n")?;
+            }
+            if args.only_duplicates && !duplicate {
+                continue;
+            }
+            let removed = removes_duplicates && duplicate;
+            if removed && args.duplicates_output.is_none() {
+                continue;
+            }
+            let set = dup_sets
+                .filter(|_| markable && is_touched)
+                .and_then(|sets| {
+                    sets.get(idx as u32)
+                        .map(|(id, rep)| (id, sets.size(id), rep))
+                });
+            let set_id = set.map(|(id, _, _)| id);
+            let dup_type = match (args.tagging_policy, is_dup) {
+                (TaggingPolicy::DontTag, _) | (_, None) => None,
+                (_, Some(true)) if optical.contains(idx as u32) => Some(DuplicateTag::Sequencing),
+                (TaggingPolicy::All, Some(true)) => Some(DuplicateTag::Library),
+                _ => Some(DuplicateTag::Clear),
+            };
+            let tags = RecordTags {
+                dup_set: set_id.filter(|_| args.tag_duplicate_set),
+                set_size: set
+                    .map(|(_, size, _)| size)
+                    .filter(|_| args.tag_duplicate_set_size),
+                dup_type,
+                molecule_id: set_id.filter(|_| args.tag_molecule_id),
+                original_name: set
+                    .filter(|_| is_dup == Some(true))
+                    .and_then(|(_, _, rep)| original_names.get(&rep))
+                    .cloned(),
+            };
+            send((record, is_dup, tags, removed))?;
+        }
+        Ok(())
+    };
+    let write = |(record, is_dup, tags, removed): MarkedRecord| {
+        if let Some(dup_writer) = dup_writer.as_mut().filter(|_| removed) {
+            return dup_writer.write_record(header, &record, is_dup, tags);
+        }
+        writer.write_record(header, &record, is_dup, tags)
+    };
+    if effective_threads(args) > 1 {
+        io::run_bounded(args.write_queue_depth, produce, write)?;
+    } else {
+        let mut write = write;
+        produce(&mut write)?;
+    }
+    let shard_count = match writer {
+        RecordSink::Single(writer) => writer.finish().map(|()| None)?,
+        RecordSink::Sharded(shards) => Some(shards.finish()?),
+    };
+    if let Some(dup_writer) = dup_writer {
+        dup_writer.close()?;
+    }
+    if let Some(mut names) = dup_names {
+        names.flush()?;
+    }
+    if let Some(path) = &args.tile_metrics {
+        tiles.save(path)?;
+    }
+
+    let write_dur = write_start.elapsed();
+    logln!("wrote output in {:.1} sec", write_dur.as_secs_f64());
+    logln!("  processed {} records", record_count);
+    if args.mark_supplementary {
+        logln!(
+            "  marked {} secondary and supplementary reads of duplicates",
+            supplementary_count
+        );
+    }
+    if marks_unmapped_mates(args) {
+        logln!(
+            "  marked {} unmapped mates of duplicates",
+            unmapped_mate_count
+        );
+    }
+    if let (Some(count), Some(dir)) = (shard_count, &args.shard_by_chromosome) {
+        logln!("  wrote {} shards to {}", count, dir.display());
+    }
+    if let Some(path) = &args.duplicates_output {
+        logln!(
+            "  moved {} duplicates to {}",
+            duplicate_count,
+            path.display()
+        );
+    } else if args.remove_duplicates {
+        logln!("  removed {} duplicates", duplicate_count);
+    } else if args.only_duplicates {
+        logln!("  wrote only the {} duplicates", duplicate_count);
+    }
+
+    Ok(record_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_bam() -> String {
+        format!("{}/test/W62subA_chr1C_5M.bam", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    /// 100M read on `ref_id` starting at 1-based `start`
+    fn mapped_read(
+        name: &str,
+        flags: sam::alignment::record::Flags,
+        ref_id: usize,
+        start: usize,
+        qual: u8,
+    ) -> sam::alignment::RecordBuf {
+        use noodles::core::Position;
+        use noodles::sam::alignment::record::cigar::{Op, op::Kind};
+        use noodles::sam::alignment::record_buf::{Cigar, QualityScores, Sequence};
+
+        sam::alignment::RecordBuf::builder()
+            .set_name(name)
+            .set_flags(flags)
+            .set_reference_sequence_id(ref_id)
+            .set_alignment_start(Position::try_from(start).unwrap())
+            .set_cigar(Cigar::from(vec![Op::new(Kind::Match, 100)]))
+            .set_sequence(Sequence::from(vec![b'A'; 100]))
+            .set_quality_scores(QualityScores::from(vec![qual; 100]))
+            .build()
+    }
+
+    /// Run detection over in-memory records with a two-chromosome header
+    fn detect(records: &[sam::alignment::RecordBuf], max_mem: Option<u64>) -> RoaringBitmap {
+        let args = Args {
+            max_mem,
+            ..Default::default()
+        };
+        detect_with(records, args)
+    }
+
+    fn detect_with(records: &[sam::alignment::RecordBuf], args: Args) -> RoaringBitmap {
+        try_detect_with(records, args).unwrap()
+    }
+
+    fn try_detect_with(records: &[sam::alignment::RecordBuf], args: Args) -> Result<RoaringBitmap> {
+        try_detect_with_header(records, args, two_chromosome_header().build())
+    }
+
+    fn two_chromosome_header() -> sam::header::Builder {
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+        let len = std::num::NonZeroUsize::new(10_000_000).unwrap();
+        sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chr2", Map::<ReferenceSequence>::new(len))
+    }
+
+    fn try_detect_with_header(
+        records: &[sam::alignment::RecordBuf],
+        args: Args,
+        header: sam::Header,
+    ) -> Result<RoaringBitmap> {
+        Ok(detect_with_summary(records, args, header)?.0)
+    }
+
+    fn detect_with_summary(
+        records: &[sam::alignment::RecordBuf],
+        mut args: Args,
+        header: sam::Header,
+    ) -> Result<(RoaringBitmap, MarkdupSummary)> {
+        use noodles::sam::alignment::io::Write as _;
+
+        let mut data = Vec::new();
+        let mut writer = bam::io::Writer::from(&mut data);
+        writer.write_header(&header).unwrap();
+        for record in records {
+            writer.write_alignment_record(&header, record).unwrap();
+        }
+
+        let tmp = tempfile::tempdir().unwrap();
+        args.tmp_dir = Some(tmp.path().to_path_buf());
+        let mut reader = bam::io::Reader::from(&data[..]);
+        let header = reader.read_header().unwrap();
+        let mut summary = MarkdupSummary::default();
+        let (dup_mask, _, _) = find_duplicates(
+            &args,
+            reader.records(),
+            &header,
+            &mut Timings::default(),
+            &mut summary,
+        )?;
+        Ok((dup_mask, summary))
+    }
+
+    #[test]
+    fn test_tmp_dir_space_check() {
+        let tmp = tempfile::tempdir().unwrap();
+        let missing = tmp.path().join("missing");
+        let err = check_tmp_dir(&Args::default(), &missing)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("temporary directory is not writable"),
+            "{}",
+            err
+        );
+
+        // A sparse 8 TiB "input" projects more chunk data than the disk holds
+        let input = tmp.path().join("huge.bam");
+        std::fs::File::create(&input)
+            .unwrap()
+            .set_len(1 << 43)
+            .unwrap();
+        let args = |strict: bool| Args {
+            input: vec![input.to_string_lossy().into_owned()],
+            strict,
+            ..Default::default()
+        };
+        assert!(check_tmp_dir(&args(false), tmp.path()).is_ok());
+        if io::available_space(tmp.path()).is_some_and(|free| free < 1 << 43) {
+            let err = check_tmp_dir(&args(true), tmp.path())
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains(" TiB; use --tmp-dir elsewhere"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_touched_indices() {
+        let dup_mask: RoaringBitmap = [3, 9].into_iter().collect();
+        assert_eq!(touched_indices(&dup_mask, None), dup_mask);
+        let mut sets = DupSets::default();
+        sets.add(1, 2, [1, 3]);
+        sets.add(20, 2, [20, 21, 9]);
+        let touched = touched_indices(&dup_mask, Some(&sets));
+        assert_eq!(touched.iter().collect::<Vec<_>>(), [1, 3, 9, 20, 21]);
+    }
+
+    #[test]
+    fn test_batching_from_args() {
+        let fixed = Batching::from_args(&Args::default());
+        assert_eq!(fixed.batch_size, DEFAULT_BATCH_SIZE);
+        assert!(!fixed.should_flush(DEFAULT_BATCH_SIZE - 1, usize::MAX));
+
+        let adaptive = Batching::from_args(&Args {
+            max_mem: Some(1 << 30),
+            ..Default::default()
+        });
+        assert_eq!(adaptive.batch_size, (1 << 30) / CHUNK_ENTRY_BYTES);
+        assert!(!adaptive.should_flush(MIN_ADAPTIVE_BATCH, 0));
+        // Pending mates eat into the budget and trigger an earlier flush
+        assert!(adaptive.should_flush(MIN_ADAPTIVE_BATCH, 1 << 30));
+        assert!(!adaptive.should_flush(MIN_ADAPTIVE_BATCH - 1, 1 << 30));
+
+        // An explicit batch size disables the adaptive behaviour
+        let explicit = Batching::from_args(&Args {
+            batch_size: Some(500),
+            max_mem: Some(1 << 30),
+            ..Default::default()
+        });
+        assert_eq!(explicit.max_mem, None);
+        assert!(explicit.should_flush(500, 0));
+    }
+
+    #[test]
+    fn test_max_mem_spills_small_chunks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let args = Args {
+            input: vec![test_bam()],
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            max_mem: Some(1 << 20),
+            ..Default::default()
+        };
+        assert_eq!(duplicate_mask(&args).unwrap().len(), 374);
+    }
+
+    #[test]
+    fn test_bounded_fanin_matches_flat_merge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let args = Args {
+            input: vec![test_bam()],
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let flat = duplicate_mask(&args).unwrap();
+
+        // ~75 tiny chunks merged at most 4 at a time: three pre-merge levels
+        let args = Args {
+            batch_size: Some(500),
+            max_merge_fanin: 4,
+            ..args
+        };
+        assert_eq!(duplicate_mask(&args).unwrap(), flat);
+    }
+
+    #[test]
+    fn test_single_chunk_matches_multi_chunk_merge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let args = Args {
+            input: vec![test_bam()],
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            tag_duplicate_set: true,
+            ..Default::default()
+        };
+        let detect = |args: &Args| {
+            let (header, records) = open_inputs(&args.input).unwrap();
+            collect_duplicates(args, records, &header).unwrap()
+        };
+
+        // The whole file fits in one default batch, so the merge reads a
+        // single chunk straight through
+        let single = detect(&args);
+        let multi = detect(&Args {
+            batch_size: Some(5000),
+            ..args
+        });
+        assert_eq!(single.0.len(), 374);
+        assert_eq!(single, multi);
+    }
+
+    #[test]
+    fn test_inter_chromosomal_pairs_do_not_alias_fragments() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Two copies of a chr1:100(+) / chr2:500(-) pair, then fragments whose
+        // 5' end is 500(-) on chr1 and on chr2 respectively
+        let records = [
+            mapped_read("a", fwd, 0, 101, 30),
+            mapped_read("b", fwd, 0, 101, 20),
+            mapped_read("a", rev, 1, 401, 30),
+            mapped_read("b", rev, 1, 401, 20),
+            mapped_read("f", Flags::REVERSE_COMPLEMENTED, 0, 401, 40),
+            mapped_read("g", Flags::REVERSE_COMPLEMENTED, 1, 401, 40),
+        ];
+        let mask = detect(&records, None);
+
+        // The lower-scoring pair is a duplicate; the chr1 fragment shares only
+        // the coordinate with the far mates and stays unmarked, while the chr2
+        // fragment sits on a second end and is marked as an orphan
+        assert_eq!(mask.iter().collect::<Vec<_>>(), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn test_library_shards_match_single_merge() {
+        let mut rng = fastrand::Rng::with_seed(11);
+        let reads: Vec<Metadata> = (0..6000u64)
+            .map(|i| {
+                let lib = rng.i32(0..3);
+                let pos = rng.i64(0..200) * 10;
+                if rng.bool() {
+                    Metadata::new_se(lib, 0, pos, rng.u8(0..2), rng.u32(0..100), 2 * i)
+                } else {
+                    let pos2 = pos + rng.i64(1..4) * 100;
+                    Metadata::new_pe(
+                        lib,
+                        0,
+                        pos,
+                        0,
+                        0,
+                        pos2,
+                        1,
+                        rng.u32(0..100),
+                        2 * i,
+                        2 * i + 1,
+                    )
+                }
+            })
+            .collect();
+        let tmp = tempfile::tempdir().unwrap();
+        let (single_dir, split_dir) = (tmp.path().join("single"), tmp.path().join("split"));
+        std::fs::create_dir(&single_dir).unwrap();
+        std::fs::create_dir(&split_dir).unwrap();
+
+        let mut single = Vec::new();
+        let mut split: BTreeMap<i32, Vec<PathBuf>> = BTreeMap::new();
+        for (seq, part) in reads.chunks(1000).enumerate() {
+            let codec = Default::default();
+            single.push(io::save_chunk_parallel(part.to_vec(), &single_dir, seq, codec).unwrap());
+            for (lib, path) in
+                io::save_chunk_by_library(part.to_vec(), &split_dir, seq, codec).unwrap()
+            {
+                split.entry(lib).or_default().push(path);
+            }
+        }
+        let split: Vec<_> = split.into_values().collect();
+        assert_eq!(split.len(), 3);
+
+        let args = Args {
+            histogram: Some(tmp.path().join("unused.tsv")),
+            tag_duplicate_set: true,
+            ..Default::default()
+        };
+        let pe_second_ends = HashMap::new();
+        let a = merge_shards(&[single], &pe_second_ends, &args).unwrap();
+        let b = merge_shards(&split, &pe_second_ends, &args).unwrap();
+        assert!(a.dup_mask.len() > 100);
+        assert_eq!(a.dup_mask, b.dup_mask);
+        assert_eq!(a.hist, b.hist);
+        assert_eq!(a.dup_sets, b.dup_sets);
+        assert_eq!((a.orphan, a.pe, a.se_only), (b.orphan, b.pe, b.se_only));
+        assert_eq!(a.metrics.total(), b.metrics.total());
+    }
+
+    #[test]
+    fn test_mark_iter_matches_duplicate_mask() {
+        let tmp = tempfile::tempdir().unwrap();
+        let args = Args {
+            input: vec![test_bam()],
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mask = duplicate_mask(&args).unwrap();
+        assert_eq!(mask.len(), 374);
+
+        let mut total = 0;
+        let mut dups = RoaringBitmap::new();
+        for (idx, item) in mark_iter(&args).unwrap().enumerate() {
+            let (_, is_dup) = item.unwrap();
+            if is_dup {
+                dups.insert(idx as u32);
+            }
+            total += 1;
+        }
+        assert_eq!(total, 99_017);
+        assert_eq!(dups, mask);
+    }
+
+    #[test]
+    fn test_multiple_inputs_match_single_input() {
+        use noodles::sam::alignment::io::Write as _;
+
+        // Split the test BAM into two "lanes", keeping mates together
+        let tmp = tempfile::tempdir().unwrap();
+        let lanes = [tmp.path().join("lane1.bam"), tmp.path().join("lane2.bam")];
+        let mut reader = bam::io::reader::Builder
+            .build_from_path(test_bam())
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let mut writers: Vec<_> = lanes
+            .iter()
+            .map(|p| bam::io::writer::Builder.build_from_path(p).unwrap())
+            .collect();
+        for w in &mut writers {
+            w.write_header(&header).unwrap();
+        }
+        for result in reader.records() {
+            let record = result.unwrap();
+            let lane = (hash_barcode(record.name().unwrap()) % 2) as usize;
+            writers[lane]
+                .write_alignment_record(&header, &record)
+                .unwrap();
+        }
+        for w in &mut writers {
+            w.try_finish().unwrap();
+        }
+
+        // Which of several equal-scoring copies survives depends on record
+        // order, so compare where duplicates sit rather than which reads
+        let dup_sites = |args: &Args| -> (usize, Vec<(Option<usize>, i64, bool)>) {
+            let mut total = 0;
+            let mut sites = Vec::new();
+            for item in mark_iter(args).unwrap() {
+                let (record, is_dup) = item.unwrap();
+                total += 1;
+                if is_dup {
+                    sites.push((
+                        record.reference_sequence_id().transpose().unwrap(),
+                        get_5p_pos_with(&record, true).unwrap(),
+                        record.flags().is_reverse_complemented(),
+                    ));
+                }
+            }
+            sites.sort();
+            (total, sites)
+        };
+        let single = dup_sites(&Args {
+            input: vec![test_bam()],
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        });
+        let merged = dup_sites(&Args {
+            input: lanes
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect(),
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        });
+        assert_eq!(merged.0, 99_017);
+        assert_eq!(merged.1.len(), 374);
+        assert_eq!(merged, single);
+    }
+
+    #[test]
+    fn test_widely_separated_pairs_spill_pending_mates() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Two copies of 5,000 pairs whose mates sit 5 Mbp downstream on chr1
+        // or on chr2, so every first end waits until the far half of the file
+        let pairs = 5_000;
+        let mut records = Vec::new();
+        for i in 0..pairs {
+            records.push(mapped_read(&format!("p{}a", i), fwd, 0, 1 + i * 100, 30));
+            records.push(mapped_read(&format!("p{}b", i), fwd, 0, 1 + i * 100, 20));
+        }
+        for chr in 0..2 {
+            for i in (0..pairs).filter(|i| i % 2 == chr) {
+                let start = 5_000_001 + i * 100;
+                records.push(mapped_read(&format!("p{}a", i), rev, chr, start, 30));
+                records.push(mapped_read(&format!("p{}b", i), rev, chr, start, 20));
+            }
+        }
+
+        let run = |max_mem| {
+            let args = Args {
+                max_mem,
+                ..Default::default()
+            };
+            detect_with_summary(&records, args, two_chromosome_header().build()).unwrap()
+        };
+        let (in_memory, summary) = run(None);
+        assert_eq!(summary.spilled_mates, 0);
+        // 256 KiB leaves room for only a few hundred pending mates
+        let (spilled, summary) = run(Some(256 << 10));
+        assert!(summary.spilled_mates > 0);
+        assert_eq!(in_memory.len(), 2 * pairs as u64);
+        assert_eq!(spilled, in_memory);
+        for idx in &spilled {
+            assert!(records[idx as usize].name().unwrap().ends_with(b"b"));
+        }
+
+        // With mate CIGARs nothing waits: the pairs are joined while merging
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|record| {
+                let name = std::str::from_utf8(record.name().unwrap()).unwrap();
+                let i: usize = name.trim_matches(['p', 'a', 'b']).parse().unwrap();
+                match record.flags().is_reverse_complemented() {
+                    false => with_mate(record, i % 2, 5_000_001 + i * 100, true),
+                    true => with_mate(record, 0, 1 + i * 100, false),
+                }
+            })
+            .collect();
+        assert_eq!(detect(&records, Some(256 << 10)), in_memory);
+    }
+
+    /// `record` with its mate's position, strand and 100M CIGAR (`MC`)
+    fn with_mate(
+        mut record: sam::alignment::RecordBuf,
+        mate_ref: usize,
+        mate_start: usize,
+        mate_rev: bool,
+    ) -> sam::alignment::RecordBuf {
+        use noodles::core::Position;
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+
+        *record.mate_reference_sequence_id_mut() = Some(mate_ref);
+        *record.mate_alignment_start_mut() = Position::new(mate_start);
+        record
+            .flags_mut()
+            .set(Flags::MATE_REVERSE_COMPLEMENTED, mate_rev);
+        record
+            .data_mut()
+            .insert(Tag::MATE_CIGAR, Value::from("100M"));
+        record
+    }
+
+    #[test]
+    fn test_mate_cigar_pairing_matches_pending_mates() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let rev = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        let mut records = vec![
+            // Two copies of a pair; "b" is the duplicate
+            with_mate(mapped_read("a", fwd, 0, 101, 30), 0, 401, true),
+            with_mate(mapped_read("b", fwd, 0, 101, 20), 0, 401, true),
+            // First end whose mate is missing from the input
+            with_mate(mapped_read("c", fwd, 0, 101, 40), 0, 401, true),
+            // Both ends at one 5' position (2000), paired by name
+            with_mate(mapped_read("t", fwd, 0, 2001, 30), 0, 1901, true),
+            with_mate(mapped_read("t", rev, 0, 1901, 30), 0, 2001, false),
+            // A pair across chromosomes, and a copy of it
+            with_mate(mapped_read("x", fwd, 0, 5001, 30), 1, 301, true),
+            with_mate(mapped_read("y", fwd, 0, 5001, 20), 1, 301, true),
+            // Second ends; "d" lost its mate and meets "a" and "b" at 500
+            with_mate(mapped_read("a", rev, 0, 401, 30), 0, 101, false),
+            with_mate(mapped_read("b", rev, 0, 401, 20), 0, 101, false),
+            with_mate(mapped_read("d", rev, 0, 401, 25), 0, 101, false),
+            with_mate(mapped_read("x", rev, 1, 301, 30), 0, 5001, false),
+            with_mate(mapped_read("y", rev, 1, 301, 20), 0, 5001, false),
+        ];
+        // A QC-failed copy of "a", left out with --exclude-qcfail
+        records.insert(
+            1,
+            with_mate(
+                mapped_read("q", fwd | Flags::QC_FAIL, 0, 101, 50),
+                0,
+                401,
+                true,
+            ),
+        );
+        records.push(with_mate(mapped_read("q", rev, 0, 401, 50), 0, 101, false));
+
+        for args in [
+            Args::default(),
+            Args {
+                exclude_qcfail: true,
+                ..Default::default()
+            },
+            Args {
+                orphan_policy: OrphanPolicy::KeepBest,
+                ..Default::default()
+            },
+            Args {
+                use_tlen: true,
+                compat: Compat::Picard,
+                ..Default::default()
+            },
+        ] {
+            let by_name = detect_with(
+                &records,
+                Args {
+                    no_mate_cigar: true,
+                    ..args.clone()
+                },
+            );
+            assert_eq!(detect_with(&records, args), by_name);
+        }
+        // The "q" pair outscores "a" and "b", "x" beats "y"; the leftover
+        // mates "c" and "d" count as pair ends and are not marked
+        let marked: Vec<_> = detect_with(&records, Args::default()).iter().collect();
+        assert_eq!(marked, [0, 2, 7, 8, 9, 12]);
+    }
+
+    #[test]
+    fn test_histogram_of_duplicate_set_sizes() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        let mut records = Vec::new();
+        // Three copies of one pair, plus a pair sharing only its first end
+        for name in ["a1", "a2", "a3"] {
+            records.push(mapped_read(name, fwd, 0, 101, 30));
+            records.push(mapped_read(name, rev, 0, 401, 30));
+        }
+        records.push(mapped_read("c", fwd, 0, 101, 30));
+        records.push(mapped_read("c", rev, 0, 501, 30));
+        // A lone pair, two fragments at one position and a lone fragment
+        records.push(mapped_read("b", fwd, 0, 1001, 30));
+        records.push(mapped_read("b", rev, 0, 1301, 30));
+        records.push(mapped_read("f1", Flags::empty(), 1, 101, 30));
+        records.push(mapped_read("f2", Flags::empty(), 1, 101, 20));
+        records.push(mapped_read("g", Flags::empty(), 1, 2001, 30));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("hist.tsv");
+        let args = Args {
+            histogram: Some(path.clone()),
+            ..Default::default()
+        };
+        let mask = detect_with(&records, args);
+        assert_eq!(mask.len(), 5);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "type\tcopies\tsets\nPE\t1\t2\nPE\t3\t1\nSE\t1\t1\nSE\t2\t1\n"
+        );
+    }
+
+    #[test]
+    fn test_dup_bed_counts_a_duplicate_cluster() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Five copies of one fragment at chr1:1501, two copies of a pair
+        // starting at chr2:101 and a lone fragment elsewhere
+        let mut records: Vec<_> = (0..5)
+            .map(|i| mapped_read(&format!("f{}", i), Flags::empty(), 0, 1501, 30 - i as u8))
+            .collect();
+        for name in ["p1", "p2"] {
+            records.push(mapped_read(name, fwd, 1, 101, 30));
+            records.push(mapped_read(name, rev, 1, 401, 30));
+        }
+        records.push(mapped_read("g", Flags::empty(), 0, 5001, 30));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("dups.bedgraph");
+        let args = Args {
+            dup_bed: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(detect_with(&records, args).len(), 6);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "chr1\t1000\t2000\t4\nchr2\t0\t1000\t2\n"
+        );
+    }
+
+    #[test]
+    fn test_use_tlen_keeps_pairs_with_different_insert_sizes() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let rev = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        // Same 5' positions on both ends, but an indel in "b" changes TLEN
+        let mut records = Vec::new();
+        for (name, tlen, qual) in [("a", 400, 30), ("b", 394, 20)] {
+            let mut first = mapped_read(name, fwd, 0, 101, qual);
+            *first.template_length_mut() = tlen;
+            let mut last = mapped_read(name, rev, 0, 401, qual);
+            *last.template_length_mut() = -tlen;
+            records.push(first);
+            records.push(last);
+        }
+        records.swap(1, 2);
+
+        assert_eq!(
+            detect_with(&records, Args::default())
+                .iter()
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+        let args = Args {
+            use_tlen: true,
+            ..Default::default()
+        };
+        assert!(detect_with(&records, args).is_empty());
+    }
+
+    #[test]
+    fn test_metrics_count_skipped_reads() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        let mut records = Vec::new();
+        for name in ["a1", "a2"] {
+            records.push(mapped_read(name, fwd, 0, 101, 30));
+            records.push(mapped_read(name, rev, 0, 401, 30));
+        }
+        records.push(mapped_read("f", Flags::empty(), 0, 601, 30));
+        records.push(mapped_read("s1", Flags::SECONDARY, 0, 701, 30));
+        records.push(mapped_read("s2", Flags::SUPPLEMENTARY, 0, 801, 30));
+        records.push(mapped_read("u", Flags::UNMAPPED, 0, 901, 30));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        let args = Args {
+            metrics_file: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(detect_with(&records, args).len(), 2);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let row: Vec<_> = text.lines().nth(2).unwrap().split('\t').collect();
+        assert_eq!(
+            &row[..9],
+            ["unknown", "1", "2", "2", "1", "0", "1", "0", "0.400000"]
+        );
+    }
+
+    #[test]
+    fn test_optical_duplicates_in_metrics() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Three copies of one pair: "a" kept, "b" 40 pixels from it, "c" on
+        // another tile
+        let mut records = Vec::new();
+        let copies = [
+            ("M1:7:FC:1:1101:1000:2000", 30),
+            ("M1:7:FC:1:1101:1040:2000", 20),
+            ("M1:7:FC:1:1102:1000:2000", 20),
+        ];
+        for (name, qual) in copies {
+            records.push(mapped_read(name, fwd, 0, 101, qual));
+            records.push(mapped_read(name, rev, 0, 401, qual));
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        let optical_column = |distance: Option<u32>| -> String {
+            let args = Args {
+                metrics_file: Some(path.clone()),
+                optical_duplicate_pixel_distance: distance,
+                ..Default::default()
+            };
+            assert_eq!(detect_with(&records, args).len(), 4);
+            let text = std::fs::read_to_string(&path).unwrap();
+            text.lines()
+                .nth(2)
+                .unwrap()
+                .split('\t')
+                .nth(7)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(optical_column(None), "0");
+        assert_eq!(optical_column(Some(100)), "1");
+        assert_eq!(optical_column(Some(10)), "0");
+    }
+
+    #[test]
+    fn test_umi_tag_keeps_different_umis_apart() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Three pairs and three fragments at one position each; the third
+        // pair and fragment carry another UMI, the fragment "f4" none
+        let mut records = Vec::new();
+        for (name, umi, qual) in [("p1", "AACC", 30), ("p2", "AACC", 20), ("p3", "GGTT", 10)] {
+            for (flags, start) in [(fwd, 101), (rev, 401)] {
+                let mut record = mapped_read(name, flags, 0, start, qual);
+                record
+                    .data_mut()
+                    .insert(Tag::from(*b"RX"), Value::from(umi));
+                records.push(record);
+            }
+        }
+        for (name, umi, qual) in [
+            ("f1", Some("AACC"), 30),
+            ("f2", Some("AACC"), 20),
+            ("f3", Some("GGTT"), 10),
+            ("f4", None, 5),
+        ] {
+            let mut record = mapped_read(name, Flags::empty(), 0, 901, qual);
+            if let Some(umi) = umi {
+                record
+                    .data_mut()
+                    .insert(Tag::from(*b"RX"), Value::from(umi));
+            }
+            records.push(record);
+        }
+        let marked = |umi_tag| {
+            detect_with(
+                &records,
+                Args {
+                    umi_tag,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(None), [2, 3, 4, 5, 7, 8, 9]);
+        assert_eq!(marked(Some(*b"RX")), [2, 3, 7]);
+    }
+
+    #[test]
+    fn test_cell_barcode_tag_keeps_cells_apart() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Pairs and fragments at shared positions, in two cells; the pair
+        // "p3" is in cell B, so fragment "f3" of cell B is its orphan
+        let mut records = Vec::new();
+        for (name, cell, qual) in [("p1", "A", 30), ("p2", "A", 20), ("p3", "B", 10)] {
+            for (flags, start) in [(fwd, 101), (rev, 401)] {
+                let mut record = mapped_read(name, flags, 0, start, qual);
+                record
+                    .data_mut()
+                    .insert(Tag::CELL_BARCODE_ID, Value::from(cell));
+                records.push(record);
+            }
+        }
+        for (name, cell, start) in [("f1", "A", 901), ("f2", "B", 901), ("f3", "B", 101)] {
+            let mut record = mapped_read(name, Flags::empty(), 0, start, 30);
+            record
+                .data_mut()
+                .insert(Tag::CELL_BARCODE_ID, Value::from(cell));
+            records.push(record);
+        }
+        let marked = |cell_barcode_tag| {
+            detect_with(
+                &records,
+                Args {
+                    cell_barcode_tag,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(None), [2, 3, 4, 5, 7, 8]);
+        assert_eq!(marked(Some(*b"CB")), [2, 3, 8]);
+    }
+
+    #[test]
+    fn test_key_tag_joins_the_cell_barcode() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Pairs at one position; "p4" has no sample barcode and "p5" moves
+        // the sample barcode into the cell barcode
+        let mut records = Vec::new();
+        for (name, cell, sample, qual) in [
+            ("p1", "A", Some("x"), 30),
+            ("p2", "A", Some("y"), 20),
+            ("p3", "B", Some("x"), 10),
+            ("p4", "A", None, 5),
+            ("p5", "Ax", None, 5),
+        ] {
+            for (flags, start) in [(fwd, 101), (rev, 401)] {
+                let mut record = mapped_read(name, flags, 0, start, qual);
+                record
+                    .data_mut()
+                    .insert(Tag::CELL_BARCODE_ID, Value::from(cell));
+                if let Some(sample) = sample {
+                    record
+                        .data_mut()
+                        .insert(Tag::SAMPLE_BARCODE_SEQUENCE, Value::from(sample));
+                }
+                records.push(record);
+            }
+        }
+        let marked = |cell_barcode_tag, key_tag| {
+            detect_with(
+                &records,
+                Args {
+                    cell_barcode_tag,
+                    key_tag,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(None, None), [2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(marked(None, Some(*b"BC")), [4, 5, 8, 9]);
+        assert_eq!(marked(Some(*b"CB"), None), [2, 3, 6, 7]);
+        assert_eq!(marked(Some(*b"CB"), Some(*b"BC")), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_compat_picard() {
+        use noodles::sam::alignment::record::cigar::{Op, op::Kind};
+        use noodles::sam::alignment::record_buf::{Cigar, QualityScores, Sequence};
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Pairs with both 5' ends at 100: "p" lists its reverse read first,
+        // "q" its forward read, so Sambamba sees RF and FR pairs and Picard
+        // two FR copies
+        let pairs = [
+            mapped_read("p", rev, 0, 1, 30),
+            mapped_read("q", fwd, 0, 101, 20),
+            mapped_read("p", fwd, 0, 101, 30),
+            mapped_read("q", rev, 0, 1, 20),
+        ];
+        let marked = |records: &[sam::alignment::RecordBuf], compat| {
+            detect_with(
+                records,
+                Args {
+                    compat,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(&pairs, Compat::Sambamba), Vec::<u32>::new());
+        assert_eq!(marked(&pairs, Compat::Picard), [1, 3]);
+
+        // Long fragments scoring 20000 and 24000 both reach Picard's cap,
+        // so the first one is kept
+        let long = |name, len: usize| {
+            let mut record = mapped_read(name, Flags::empty(), 0, 5001, 40);
+            *record.cigar_mut() = Cigar::from(vec![Op::new(Kind::Match, len)]);
+            *record.sequence_mut() = Sequence::from(vec![b'A'; len]);
+            *record.quality_scores_mut() = QualityScores::from(vec![40; len]);
+            record
+        };
+        let fragments = [long("f1", 500), long("f2", 600)];
+        assert_eq!(marked(&fragments, Compat::Sambamba), [0]);
+        assert_eq!(marked(&fragments, Compat::Picard), [1]);
+
+        let args = Args {
+            compat: Compat::Picard,
+            strand_agnostic: true,
+            min_base_quality: 20,
+            ..Default::default()
+        };
+        let err = check_compat(&args).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "--compat picard cannot be combined with --min-base-quality, --strand-agnostic"
+        );
+        assert!(
+            check_compat(&Args {
+                compat: Compat::Picard,
+                ..Default::default()
+            })
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_compat_samtools() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        let mut records = vec![
+            // Tied fragments: Sambamba keeps the first, samtools "f1"
+            mapped_read("f2", Flags::empty(), 0, 5001, 30),
+            mapped_read("f1", Flags::empty(), 0, 5001, 30),
+            // A QC-failed copy, which samtools leaves alone
+            mapped_read("g1", Flags::empty(), 0, 7001, 30),
+            mapped_read("g2", Flags::QC_FAIL, 0, 7001, 20),
+        ];
+        // "b" scores lower than "a" but its ms tags claim a better mate
+        for (name, qual) in [("a", 30), ("b", 20)] {
+            for (flags, start) in [(fwd, 101), (rev, 401)] {
+                let mut record = mapped_read(name, flags, 0, start, qual);
+                if name == "b" {
+                    record
+                        .data_mut()
+                        .insert(Tag::from(*b"ms"), Value::from(10_000));
+                }
+                records.push(record);
+            }
+        }
+        let marked = |compat| {
+            detect_with(
+                &records,
+                Args {
+                    compat,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(Compat::Sambamba), [1, 3, 6, 7]);
+        assert_eq!(marked(Compat::Samtools), [0, 4, 5]);
+        assert!(
+            check_compat(&Args {
+                compat: Compat::Samtools,
+                use_tlen: true,
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_score_strategy_picks_the_copy_to_keep() {
+        use sam::alignment::record::{Flags, MappingQuality};
+        // Same 5' position: "a" has better qualities, "b" a better MAPQ
+        let records: Vec<_> = [("a", 30, 10), ("b", 20, 60)]
+            .into_iter()
+            .map(|(name, qual, mapq)| {
+                let mut record = mapped_read(name, Flags::empty(), 0, 1001, qual);
+                *record.mapping_quality_mut() = MappingQuality::new(mapq);
+                record
+            })
+            .collect();
+        let marked = |score| {
+            detect_with(
+                &records,
+                Args {
+                    score,
+                    ..Default::default()
+                },
+            )
+            .iter()
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(ScoreStrategy::SumQual), [1]);
+        assert_eq!(marked(ScoreStrategy::SumAllQual), [1]);
+        assert_eq!(marked(ScoreStrategy::Mapq), [0]);
+        // Equal reference lengths: the first read is kept
+        assert_eq!(marked(ScoreStrategy::TotalMappedRefLength), [1]);
+        let args = Args {
+            compat: Compat::Picard,
+            score: ScoreStrategy::Mapq,
+            ..Default::default()
+        };
+        assert!(check_compat(&args).is_err());
+    }
+
+    #[test]
+    fn test_read_name_regex_feeds_optical_and_tile_metrics() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // Names the fast parse cannot read: "a" kept, "b" 40 pixels from
+        // it, "c" on another tile; "x" has no location
+        let mut records = Vec::new();
+        for (name, start, qual) in [
+            ("a_1101_1000_2000", 101, 30),
+            ("b_1101_1040_2000", 101, 20),
+            ("c_1102_1000_2000", 101, 20),
+        ] {
+            records.push(mapped_read(name, fwd, 0, start, qual));
+            records.push(mapped_read(name, rev, 0, start + 300, qual));
+        }
+        records.push(mapped_read("x", Flags::empty(), 0, 5001, 30));
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("tiles.tsv");
+        let args = |read_name_regex: &str| Args {
+            optical_duplicate_pixel_distance: Some(100),
+            read_name_regex: read_name_regex.to_string(),
+            tile_metrics: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(
+            mark_records(&records, args(r"[a-z]_(\d+)_(\d+)_(\d+)"))
+                .iter()
+                .filter(|&&d| d)
+                .count(),
+            4
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "tile\treads\tduplicates\toptical_duplicates\tpercent_duplication\n\
+             1101\t4\t2\t2\t0.500000\n\
+             1102\t2\t2\t0\t1.000000\n"
+        );
+
+        mark_records(&records, args(optical::DEFAULT_READ_NAME_REGEX));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "tile\treads\tduplicates\toptical_duplicates\tpercent_duplication\n"
+        );
+    }
+
+    #[test]
+    fn test_tagging_policy_writes_duplicate_types() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // "a" kept, "b" an optical copy of it, "c" a PCR copy on another
+        // tile; "d" is unique but comes with a stale DT tag
+        let mut records = Vec::new();
+        let copies = [
+            ("M1:7:FC:1:1101:1000:2000", 101, 30),
+            ("M1:7:FC:1:1101:1040:2000", 101, 20),
+            ("M1:7:FC:1:1102:1000:2000", 101, 20),
+            ("M1:7:FC:1:1101:5000:2000", 2001, 20),
+        ];
+        for (name, start, qual) in copies {
+            records.push(mapped_read(name, fwd, 0, start, qual));
+            records.push(mapped_read(name, rev, 0, start + 300, qual));
+        }
+        for record in &mut records[6..] {
+            record
+                .data_mut()
+                .insert(Tag::from(io::DUPLICATE_TYPE_TAG), Value::from("LB"));
+        }
+        let types = |tagging_policy| -> Vec<Option<String>> {
+            let args = Args {
+                optical_duplicate_pixel_distance: Some(100),
+                tagging_policy,
+                ..Default::default()
+            };
+            run_on_records(&records, args)
+                .iter()
+                .map(|r| {
+                    let value = r.data().get(&io::DUPLICATE_TYPE_TAG).transpose().unwrap();
+                    value.map(|v| match v {
+                        sam::alignment::record::data::field::Value::String(s) => s.to_string(),
+                        v => panic!("DT is not a string: {:?}", v),
+                    })
+                })
+                .collect()
+        };
+        let tags = |names: [Option<&str>; 4]| -> Vec<Option<String>> {
+            names
+                .iter()
+                .flat_map(|n| [n.map(String::from), n.map(String::from)])
+                .collect()
+        };
+        assert_eq!(
+            types(TaggingPolicy::DontTag),
+            tags([None, None, None, Some("LB")])
+        );
+        assert_eq!(
+            types(TaggingPolicy::OpticalOnly),
+            tags([None, Some("SQ"), None, None])
+        );
+        assert_eq!(
+            types(TaggingPolicy::All),
+            tags([None, Some("SQ"), Some("LB"), None])
+        );
+    }
+
+    #[test]
+    fn test_default_library_names_reads_without_read_group() {
+        use sam::alignment::record::Flags;
+        let records = [
+            mapped_read("f1", Flags::empty(), 0, 601, 30),
+            mapped_read("f2", Flags::empty(), 0, 601, 20),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        let args = Args {
+            metrics_file: Some(path.clone()),
+            default_library: "foo".to_string(),
+            ..Default::default()
+        };
+        detect_with(&records, args);
+        let text = std::fs::read_to_string(&path).unwrap();
+        let rows: Vec<_> = text.lines().skip(2).filter(|l| !l.is_empty()).collect();
+        assert_eq!(rows.len(), 1);
+        let row: Vec<_> = rows[0].split('\t').collect();
+        // LIBRARY, UNPAIRED_READS_EXAMINED, ..., UNPAIRED_READ_DUPLICATES
+        assert_eq!((row[0], row[1], row[5]), ("foo", "2", "1"));
+    }
+
+    #[test]
+    fn test_ignore_rg_merges_libraries() {
+        use noodles::sam::header::record::value::{Map, map::ReadGroup, map::read_group};
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+
+        // One library sequenced on two lanes, with the lane in LB
+        let mut builder = two_chromosome_header();
+        for lane in ["L1", "L2"] {
+            let mut rg = Map::<ReadGroup>::default();
+            rg.other_fields_mut()
+                .insert(read_group::tag::LIBRARY, format!("lib-{}", lane).into());
+            builder = builder.add_read_group(lane, rg);
+        }
+        let header = builder.build();
+        let records: Vec<_> = [("a", "L1", 30), ("b", "L2", 20)]
+            .into_iter()
+            .map(|(name, lane, qual)| {
+                let mut record = mapped_read(name, Flags::empty(), 0, 1001, qual);
+                record.data_mut().insert(Tag::READ_GROUP, Value::from(lane));
+                record
+            })
+            .collect();
+        let marked = |ignore_rg| {
+            let args = Args {
+                ignore_rg,
+                ..Default::default()
+            };
+            try_detect_with_header(&records, args, header.clone())
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert!(marked(false).is_empty());
+        assert_eq!(marked(true), [1]);
+    }
+
+    #[test]
+    fn test_group_by_sample_keeps_samples_apart() {
+        use crate::algorithm::GroupBy;
+        use noodles::sam::header::record::value::{Map, map::ReadGroup, map::read_group};
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Tag;
+        use sam::alignment::record_buf::data::field::Value;
+
+        // Two samples prepared under one library name
+        let mut builder = two_chromosome_header();
+        for sample in ["S1", "S2"] {
+            let mut rg = Map::<ReadGroup>::default();
+            rg.other_fields_mut()
+                .insert(read_group::tag::LIBRARY, "lib".into());
+            rg.other_fields_mut()
+                .insert(read_group::tag::SAMPLE, sample.into());
+            builder = builder.add_read_group(sample, rg);
+        }
+        let header = builder.build();
+        let records: Vec<_> = [("a", "S1", 30), ("b", "S2", 20), ("c", "S2", 10)]
+            .into_iter()
+            .map(|(name, sample, qual)| {
+                let mut record = mapped_read(name, Flags::empty(), 0, 1001, qual);
+                record
+                    .data_mut()
+                    .insert(Tag::READ_GROUP, Value::from(sample));
+                record
+            })
+            .collect();
+        let marked = |group_by| {
+            let args = Args {
+                group_by,
+                ..Default::default()
+            };
+            try_detect_with_header(&records, args, header.clone())
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(marked(GroupBy::Library), [1, 2]);
+        assert_eq!(marked(GroupBy::Sample), [2]);
+    }
+
+    #[test]
+    fn test_unnamed_library_warning() {
+        use noodles::sam::header::record::value::{Map, map::ReadGroup, map::read_group};
+
+        let args = Args::default();
+        let bare = two_chromosome_header().build();
+        assert_eq!(
+            unnamed_library_source(&args, &bare).unwrap(),
+            "the header has no read groups"
+        );
+        let named = Args {
+            default_library: "lib1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(unnamed_library_source(&named, &bare), None);
+        assert_eq!(
+            unnamed_library_source(
+                &Args {
+                    ignore_rg: true,
+                    ..Default::default()
+                },
+                &bare
+            ),
+            None
+        );
+
+        let mut with_lb = Map::<ReadGroup>::default();
+        with_lb
+            .other_fields_mut()
+            .insert(read_group::tag::LIBRARY, "lib1".into());
+        let header = two_chromosome_header()
+            .add_read_group("L1", with_lb)
+            .add_read_group("L2", Map::<ReadGroup>::default())
+            .build();
+        assert_eq!(
+            unnamed_library_source(&args, &header).unwrap(),
+            "read group L2 has no LB"
+        );
+    }
+
+    /// Run the full pipeline on a one-chromosome BAM holding `records` and
+    /// return the DUPLICATE flag of every output record
+    fn mark_records(records: &[sam::alignment::RecordBuf], args: Args) -> Vec<bool> {
+        run_on_records(records, args)
+            .iter()
+            .map(|r| r.flags().is_duplicate())
+            .collect()
+    }
+
+    #[test]
+    fn test_mark_supplementary_follows_primary() {
+        use sam::alignment::record::Flags;
+
+        // "b" is a duplicate of "a"; its supplementary record comes first,
+        // and the secondary record of "a" was marked by an earlier tool
+        let records = [
+            mapped_read("b", Flags::SUPPLEMENTARY, 0, 51, 30),
+            mapped_read("a", Flags::empty(), 0, 101, 30),
+            mapped_read("b", Flags::empty(), 0, 101, 20),
+            mapped_read("a", Flags::SECONDARY | Flags::DUPLICATE, 0, 201, 30),
+            mapped_read("b", Flags::SECONDARY, 0, 301, 30),
+        ];
+        assert_eq!(
+            mark_records(&records, Args::default()),
+            [false, false, true, true, false]
+        );
+        let args = Args {
+            mark_supplementary: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            mark_records(&records, args),
+            [true, false, true, false, true]
+        );
+        let args = Args {
+            mark_supplementary: true,
+            remove_duplicates: true,
+            ..Default::default()
+        };
+        assert_eq!(run_on_records(&records, args).len(), 2);
+    }
+
+    #[test]
+    fn test_mark_unmapped_mates_follows_mapped_read() {
+        use sam::alignment::record::Flags;
+        let mapped = Flags::SEGMENTED | Flags::MATE_UNMAPPED;
+        let unmapped = Flags::SEGMENTED | Flags::UNMAPPED;
+
+        // "b" is a duplicate of "a"; its unmapped mate comes first and keeps
+        // the /2 suffix
+        let records = [
+            mapped_read("a", mapped, 0, 101, 30),
+            mapped_read("a", unmapped, 0, 101, 30),
+            mapped_read("b/2", unmapped, 0, 101, 30),
+            mapped_read("b/1", mapped, 0, 101, 20),
+        ];
+        assert_eq!(
+            mark_records(&records, Args::default()),
+            [false, false, false, true]
+        );
+        let args = Args {
+            mark_unmapped_mates: true,
+            ..Default::default()
+        };
+        assert_eq!(mark_records(&records, args), [false, false, true, true]);
+        let args = Args {
+            compat: Compat::Picard,
+            ..Default::default()
+        };
+        assert_eq!(mark_records(&records, args), [false, false, true, true]);
+        let args = Args {
+            mark_unmapped_mates: true,
+            strip_mate_suffix: false,
+            ..Default::default()
+        };
+        assert_eq!(mark_records(&records, args), [false, false, false, true]);
+    }
+
+    /// Run the pipeline on `records` and return the records written
+    #[test]
+    fn test_duplicate_set_tags_mark_whole_sets() {
+        use sam::alignment::record::Flags;
+        use sam::alignment::record::data::field::Value;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        // "d", "a" and "b" are copies of one molecule, of which "a" is kept
+        // though it comes after "d"; "c" is unique
+        let mut records = Vec::new();
+        for (name, start, qual) in [
+            ("d", 101, 10),
+            ("a", 101, 30),
+            ("b", 101, 20),
+            ("c", 2001, 30),
+        ] {
+            records.push(mapped_read(name, fwd, 0, start, qual));
+            records.push(mapped_read(name, rev, 0, start + 300, qual));
+        }
+        let args = Args {
+            tag_duplicate_set: true,
+            tag_duplicate_set_size: true,
+            tag_molecule_id: true,
+            tag_original_name: true,
+            ..Default::default()
+        };
+        let written = run_on_records(&records, args);
+        type Tags = (Option<String>, Option<i64>, Option<i64>, Option<String>);
+        let tags: Vec<Tags> = written
+            .iter()
+            .map(|r| {
+                let string = |tag| match r.data().get(&tag).transpose().unwrap() {
+                    Some(Value::String(s)) => Some(s.to_string()),
+                    None => None,
+                    Some(v) => panic!("{:?} is not a string: {:?}", tag, v),
+                };
+                let int = |tag| {
+                    r.data()
+                        .get(&tag)
+                        .transpose()
+                        .unwrap()
+                        .and_then(|v| v.as_int())
+                };
+                let (mi, original) = (string(io::MOLECULE_ID_TAG), string(io::ORIGINAL_NAME_TAG));
+                (
+                    mi,
+                    int(io::DUPLICATE_SET_TAG),
+                    int(io::DUPLICATE_SET_SIZE_TAG),
+                    original,
+                )
+            })
+            .collect();
+        // Kept and marked members alike, with the DI set id as the molecule
+        // id and three pairs in the set; only the duplicates name the read kept
+        let kept = (Some("0".to_string()), Some(0), Some(3), None);
+        let copy = (
+            Some("0".to_string()),
+            Some(0),
+            Some(3),
+            Some("a".to_string()),
+        );
+        let unique = (None, None, None, None);
+        assert_eq!(
+            tags,
+            [
+                copy.clone(),
+                copy.clone(),
+                kept.clone(),
+                kept,
+                copy.clone(),
+                copy,
+                unique.clone(),
+                unique
+            ]
+        );
+
+        let without_di = run_on_records(
+            &records,
+            Args {
+                tag_molecule_id: true,
+                ..Default::default()
+            },
+        );
+        assert!(
+            without_di
+                .iter()
+                .all(|r| r.data().get(&io::DUPLICATE_SET_TAG).is_none())
+        );
+        assert_eq!(
+            without_di
+                .iter()
+                .filter(|r| r.data().get(&io::MOLECULE_ID_TAG).is_some())
+                .count(),
+            6
+        );
+    }
+
+    fn run_on_records(records: &[sam::alignment::RecordBuf], mut args: Args) -> Vec<bam::Record> {
+        use noodles::sam::alignment::io::Write as _;
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+        use std::num::NonZeroUsize;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in.bam");
+        let output = tmp.path().join("out.bam");
+        let len = NonZeroUsize::new(10_000_000).unwrap();
+        let header = sam::Header::builder()
+            .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+            .add_reference_sequence("chr2", Map::<ReferenceSequence>::new(len))
+            .build();
+        let mut writer = bam::io::writer::Builder.build_from_path(&input).unwrap();
+        writer.write_header(&header).unwrap();
+        for record in records {
+            writer.write_alignment_record(&header, record).unwrap();
+        }
+        writer.try_finish().unwrap();
+
+        args.input = vec![input.to_string_lossy().into_owned()];
+        args.output = output.to_string_lossy().into_owned();
+        args.tmp_dir = Some(tmp.path().to_path_buf());
+        run_markdup(&args).unwrap();
+        let mut reader = bam::io::reader::Builder.build_from_path(&output).unwrap();
+        reader.read_header().unwrap();
+        reader.records().map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_positions_past_bai_range_get_a_csi_index() {
+        use noodles::core::Position;
+        use noodles::csi::BinningIndex as _;
+        use noodles::sam::alignment::io::Write as _;
+        use noodles::sam::header::record::value::map::header::tag::SORT_ORDER;
+        use noodles::sam::header::record::value::map::{self, Map, ReferenceSequence};
+        use sam::alignment::record::Flags;
+        use std::num::NonZeroUsize;
+
+        // Wheat-sized reference: positions past 2^29 and close to 2^31
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in.bam");
+        let output = tmp.path().join("out.bam");
+        let header = sam::Header::builder()
+            .set_header(
+                Map::<map::Header>::builder()
+                    .insert(SORT_ORDER, "coordinate")
+                    .build()
+                    .unwrap(),
+            )
+            .add_reference_sequence(
+                "chr1A",
+                Map::<ReferenceSequence>::new(NonZeroUsize::new(2_100_000_000).unwrap()),
+            )
+            .build();
+        let records = [
+            mapped_read("a1", Flags::empty(), 0, 600_000_001, 30),
+            mapped_read("a2", Flags::empty(), 0, 600_000_001, 20),
+            mapped_read("b1", Flags::empty(), 0, 2_000_000_001, 20),
+            mapped_read("b2", Flags::empty(), 0, 2_000_000_001, 30),
+        ];
+        let mut writer = bam::io::writer::Builder.build_from_path(&input).unwrap();
+        writer.write_header(&header).unwrap();
+        for record in &records {
+            writer.write_alignment_record(&header, record).unwrap();
+        }
+        writer.try_finish().unwrap();
+
+        let args = |index_format| Args {
+            input: vec![input.to_string_lossy().into_owned()],
+            output: output.to_string_lossy().into_owned(),
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            write_index: true,
+            index_format,
+            force: true,
+            ..Default::default()
+        };
+        let err = run_markdup(&args(Some(IndexFormat::Bai)))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("too long for a BAI index"), "{}", err);
+
+        run_markdup(&args(None)).unwrap();
+        assert!(!tmp.path().join("out.bam.bai").exists());
+        let index = noodles::csi::fs::read(tmp.path().join("out.bam.csi")).unwrap();
+        assert_eq!(index.depth(), 6);
+        let mut reader = bam::io::indexed_reader::Builder::default()
+            .set_index(index)
+            .build_from_path(&output)
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let start = Position::try_from(2_000_000_001).unwrap();
+        let region = "chr1A:2000000001-2000000001".parse().unwrap();
+        let flags: Vec<_> = reader
+            .query(&header, &region)
+            .unwrap()
+            .records()
+            .map(|r| {
+                let r = r.unwrap();
+                assert_eq!(r.alignment_start().unwrap().unwrap(), start);
+                r.flags().is_duplicate()
+            })
+            .collect();
+        assert_eq!(flags, [true, false]);
+    }
+
+    #[test]
+    fn test_shard_by_chromosome() {
+        use noodles::sam::alignment::io::Write as _;
+        use noodles::sam::header::record::value::map::header::tag::SORT_ORDER;
+        use noodles::sam::header::record::value::map::{self, Map, ReferenceSequence};
+        use sam::alignment::record::Flags;
+        use std::num::NonZeroUsize;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let write_input = |name: &str, sorted: bool, records: &[sam::alignment::RecordBuf]| {
+            let len = NonZeroUsize::new(10_000_000).unwrap();
+            let mut builder = sam::Header::builder();
+            if sorted {
+                builder = builder.set_header(
+                    Map::<map::Header>::builder()
+                        .insert(SORT_ORDER, "coordinate")
+                        .build()
+                        .unwrap(),
+                );
+            }
+            let header = builder
+                .add_reference_sequence("chr1", Map::<ReferenceSequence>::new(len))
+                .add_reference_sequence("HLA/A", Map::<ReferenceSequence>::new(len))
+                .build();
+            let input = tmp.path().join(name);
+            let mut writer = bam::io::writer::Builder.build_from_path(&input).unwrap();
+            writer.write_header(&header).unwrap();
+            for record in records {
+                writer.write_alignment_record(&header, record).unwrap();
+            }
+            writer.try_finish().unwrap();
+            input.to_string_lossy().into_owned()
+        };
+        let unmapped = sam::alignment::RecordBuf::builder()
+            .set_name("u")
+            .set_flags(Flags::UNMAPPED)
+            .build();
+        let records = [
+            mapped_read("f1", Flags::empty(), 0, 101, 30),
+            mapped_read("f2", Flags::empty(), 0, 101, 20),
+            mapped_read("f3", Flags::empty(), 1, 501, 30),
+            unmapped.clone(),
+        ];
+        let args = |input: String, dir: &Path| Args {
+            input: vec![input],
+            output: String::new(),
+            shard_by_chromosome: Some(dir.to_path_buf()),
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        let read_shard = |path: &Path| -> (usize, Vec<(String, bool)>) {
+            let mut reader = bam::io::reader::Builder.build_from_path(path).unwrap();
+            let header = reader.read_header().unwrap();
+            let records = reader
+                .records()
+                .map(|r| {
+                    let r = r.unwrap();
+                    (r.name().unwrap().to_string(), r.flags().is_duplicate())
+                })
+                .collect();
+            (header.reference_sequences().len(), records)
+        };
+
+        // Sorted input, indexed: one shard at a time, each with the full header
+        let dir = tmp.path().join("sorted");
+        let sorted = write_input("sorted.bam", true, &records);
+        run_markdup(&Args {
+            write_index: true,
+            ..args(sorted.clone(), &dir)
+        })
+        .unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "HLA_A.bam",
+                "HLA_A.bam.bai",
+                "chr1.bam",
+                "chr1.bam.bai",
+                "unmapped.bam",
+                "unmapped.bam.bai"
+            ]
+        );
+        let f = |name: &str, dup| (name.to_string(), dup);
+        assert_eq!(
+            read_shard(&dir.join("chr1.bam")),
+            (2, vec![f("f1", false), f("f2", true)])
+        );
+        assert_eq!(
+            read_shard(&dir.join("HLA_A.bam")),
+            (2, vec![f("f3", false)])
+        );
+        assert_eq!(
+            read_shard(&dir.join("unmapped.bam")),
+            (2, vec![f("u", false)])
+        );
+
+        // A used directory needs --force
+        let err = run_markdup(&args(sorted.clone(), &dir))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("shard directory is not empty"), "{}", err);
+        run_markdup(&Args {
+            force: true,
+            ..args(sorted, &dir)
+        })
+        .unwrap();
+
+        // Unsorted input keeps every shard open; a sorted header that lies is
+        // caught instead of truncating a finished shard
+        let shuffled = [
+            records[0].clone(),
+            records[2].clone(),
+            records[1].clone(),
+            records[3].clone(),
+        ];
+        let dir = tmp.path().join("unsorted");
+        run_markdup(&args(write_input("unsorted.bam", false, &shuffled), &dir)).unwrap();
+        assert_eq!(
+            read_shard(&dir.join("chr1.bam")),
+            (2, vec![f("f1", false), f("f2", true)])
+        );
+        assert_eq!(
+            read_shard(&dir.join("HLA_A.bam")),
+            (2, vec![f("f3", false)])
+        );
+        let dir = tmp.path().join("lying");
+        let err = run_markdup(&args(write_input("lying.bam", true, &shuffled), &dir))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("not coordinate-sorted"), "{}", err);
+    }
+
+    #[test]
+    fn test_additive_keeps_existing_duplicate_flags() {
+        use sam::alignment::record::Flags;
+
+        // "old" was marked by an earlier tool; "f2" is our only duplicate
+        let records = [
+            mapped_read("old", Flags::DUPLICATE, 0, 101, 30),
+            mapped_read("f1", Flags::empty(), 0, 501, 30),
+            mapped_read("f2", Flags::empty(), 0, 501, 20),
+        ];
+        let marked = |additive: bool| {
+            mark_records(
+                &records,
+                Args {
+                    additive,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(marked(false), [false, false, true]);
+        assert_eq!(marked(true), [true, false, true]);
+    }
+
+    #[test]
+    fn test_queued_writer_matches_inline_write() {
+        use sam::alignment::record::Flags;
+        let records: Vec<_> = (0..50)
+            .map(|i| {
+                mapped_read(
+                    &format!("f{}", i),
+                    Flags::empty(),
+                    0,
+                    101 + (i % 7) * 100,
+                    20 + i as u8,
+                )
+            })
+            .collect();
+        let marked = |threads: usize| {
+            mark_records(
+                &records,
+                Args {
+                    threads,
+                    write_queue_depth: 2,
+                    ..Default::default()
+                },
+            )
+        };
+        let inline = marked(1);
+        assert_eq!(inline.iter().filter(|&&d| d).count(), 43);
+        assert_eq!(marked(4), inline);
+    }
+
+    #[test]
+    fn test_timing_json_lists_every_phase() {
+        use sam::alignment::record::Flags;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("timing.json");
+        let records = [
+            mapped_read("a", Flags::empty(), 0, 101, 30),
+            mapped_read("b", Flags::empty(), 0, 101, 20),
+        ];
+        let args = Args {
+            timing_json: Some(path.clone()),
+            ..Default::default()
+        };
+        assert_eq!(mark_records(&records, args), [false, true]);
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert!(json.starts_with("{\"read_sec\": "), "{}", json);
+        for key in ["sort_sec", "merge_sec", "write_sec", "total_sec"] {
+            assert!(json.contains(&format!("\"{}\": ", key)), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_exclude_qcfail() {
+        use sam::alignment::record::Flags;
+
+        // The QC-failed read scores best, and "q2" comes in already marked
+        let records = [
+            mapped_read("qc", Flags::QC_FAIL, 0, 101, 40),
+            mapped_read("f1", Flags::empty(), 0, 101, 30),
+            mapped_read("f2", Flags::empty(), 0, 101, 20),
+            mapped_read("q2", Flags::QC_FAIL | Flags::DUPLICATE, 0, 501, 30),
+        ];
+        let args = |exclude_qcfail: bool| Args {
+            exclude_qcfail,
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_with(&records, args(false))
+                .iter()
+                .collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(
+            detect_with(&records, args(true)).iter().collect::<Vec<_>>(),
+            [2]
+        );
+        assert_eq!(
+            mark_records(&records, args(false)),
+            [false, true, true, false]
+        );
+        assert_eq!(
+            mark_records(&records, args(true)),
+            [false, false, true, true]
+        );
+
+        // Included, the QC-failed read is a copy but not the one kept
+        let args = Args {
+            include_qcfail: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_with(&records, args.clone())
+                .iter()
+                .collect::<Vec<_>>(),
+            [0, 2]
+        );
+        assert_eq!(mark_records(&records, args), [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_include_qcfail_loses_score_ties() {
+        use sam::alignment::record::Flags;
+
+        // No base reaches --min-base-quality, so both reads score 0 and the
+        // QC-failed one comes first in the input
+        let records = [
+            mapped_read("qc", Flags::QC_FAIL, 0, 101, 10),
+            mapped_read("ok", Flags::empty(), 0, 101, 10),
+        ];
+        assert_eq!(mark_records(&records, Args::default()), [false, true]);
+        let args = Args {
+            include_qcfail: true,
+            ..Default::default()
+        };
+        assert_eq!(mark_records(&records, args), [true, false]);
+    }
+
+    #[test]
+    fn test_min_mapq_leaves_low_quality_reads_out() {
+        use noodles::sam::alignment::record::MappingQuality;
+        use sam::alignment::record::Flags;
+
+        // Multi-mapped "m0" scores best and "m1" comes in already marked
+        let read = |name, flags, start, qual, mapq| {
+            let mut record = mapped_read(name, flags, 0, start, qual);
+            *record.mapping_quality_mut() = MappingQuality::new(mapq);
+            record
+        };
+        let records = [
+            read("m0", Flags::empty(), 101, 40, 0),
+            read("u1", Flags::empty(), 101, 30, 60),
+            read("u2", Flags::empty(), 101, 20, 20),
+            read("m1", Flags::DUPLICATE, 501, 30, 5),
+            read("u3", Flags::empty(), 501, 20, 255),
+        ];
+        assert_eq!(
+            detect_with(&records, Args::default())
+                .iter()
+                .collect::<Vec<_>>(),
+            [1, 2, 4]
+        );
+        let args = || Args {
+            min_mapq: 10,
+            ..Default::default()
+        };
+        // A missing MAPQ (255) is kept in
+        assert_eq!(
+            detect_with(&records, args()).iter().collect::<Vec<_>>(),
+            [2]
+        );
+        assert_eq!(
+            mark_records(&records, args()),
+            [false, false, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_excluded_reference_is_left_untouched() {
+        use sam::alignment::record::Flags;
+        let first = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let last = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        // Pair "a" has its mate on chr2; "m1" comes in already marked
+        let records = [
+            mapped_read("a", first, 0, 101, 40),
+            mapped_read("f", Flags::empty(), 0, 101, 45),
+            mapped_read("m1", Flags::DUPLICATE, 1, 101, 40),
+            mapped_read("m2", Flags::empty(), 1, 101, 20),
+            mapped_read("a", last, 1, 5001, 40),
+        ];
+        assert_eq!(
+            mark_records(&records, Args::default()),
+            [false, true, false, true, false]
+        );
+
+        // On chr2 nothing is marked or cleared and no read is a representative;
+        // "a" on chr1 is left as an unmatched pair, which still outranks "f"
+        assert_eq!(
+            detect_with(&records, Args::default())
+                .iter()
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+        let args = || Args {
+            exclude_ref: vec!["chr2".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            detect_with(&records, args()).iter().collect::<Vec<_>>(),
+            [1]
+        );
+        assert_eq!(
+            mark_records(&records, args()),
+            [false, true, true, false, false]
+        );
+
+        let args = Args {
+            exclude_ref: vec!["chrM".to_string()],
+            ..Default::default()
+        };
+        let err = try_detect_with(&records, args).unwrap_err().to_string();
+        assert_eq!(err, "--exclude-ref chrM: no @SQ line with this name");
+    }
+
+    #[test]
+    fn test_inter_chrom_policy() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let rev = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        // Pairs "a" and "b" share their chr1 end but not their chr2 end; "a"
+        // comes in already marked
+        let records = [
+            with_mate(
+                mapped_read("a", fwd | Flags::DUPLICATE, 0, 101, 30),
+                1,
+                5001,
+                true,
+            ),
+            with_mate(mapped_read("b", fwd, 0, 101, 20), 1, 6001, true),
+            mapped_read("f", Flags::empty(), 0, 101, 40),
+            with_mate(mapped_read("a", rev, 1, 5001, 30), 0, 101, false),
+            with_mate(mapped_read("b", rev, 1, 6001, 20), 0, 101, false),
+        ];
+        let dups = |inter_chrom_policy| {
+            let args = Args {
+                inter_chrom_policy,
+                ..Default::default()
+            };
+            detect_with(&records, args).iter().collect::<Vec<_>>()
+        };
+        // Two different pairs, with the fragment as an orphan
+        assert_eq!(dups(InterChromPolicy::Pair), [2]);
+        // Three fragments at chr1, of which "f" scores best
+        assert_eq!(dups(InterChromPolicy::Single), [0, 1]);
+        // Nothing left to pair with the fragment
+        assert!(dups(InterChromPolicy::Skip).is_empty());
+        let args = Args {
+            inter_chrom_policy: InterChromPolicy::Skip,
+            ..Default::default()
+        };
+        assert_eq!(
+            mark_records(&records, args),
+            [true, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn test_orientation_class_separates_f1r2_from_f2r1() {
+        use sam::alignment::record::Flags;
+        let (first, last) = (
+            Flags::SEGMENTED | Flags::FIRST_SEGMENT,
+            Flags::SEGMENTED | Flags::LAST_SEGMENT,
+        );
+        let rev = Flags::REVERSE_COMPLEMENTED;
+
+        // Same strands at the same ends, but "a" is F1R2 and "b" F2R1; "c"
+        // is another F1R2 copy
+        let mut records = Vec::new();
+        for (name, (fwd_end, rev_end), qual) in [
+            ("a", (first, last), 30),
+            ("b", (last, first), 40),
+            ("c", (first, last), 20),
+        ] {
+            records.push(mapped_read(name, fwd_end, 0, 101, qual));
+            records.push(mapped_read(name, rev_end | rev, 0, 401, qual));
+        }
+        let dups = |records: &[sam::alignment::RecordBuf], orientation_class| {
+            let args = Args {
+                orientation_class,
+                ..Default::default()
+            };
+            detect_with(records, args).iter().collect::<Vec<_>>()
+        };
+        assert_eq!(dups(&records, false), [0, 1, 4, 5]);
+        assert_eq!(dups(&records, true), [4, 5]);
+
+        // Likewise when the mates are paired by their mate CIGAR
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|r| {
+                let (start, rev) = if r.flags().is_reverse_complemented() {
+                    (101, false)
+                } else {
+                    (401, true)
+                };
+                with_mate(r, 0, start, rev)
+            })
+            .collect();
+        assert_eq!(dups(&records, false), [0, 1, 4, 5]);
+        assert_eq!(dups(&records, true), [4, 5]);
+    }
+
+    #[test]
+    fn test_use_3p_end_separates_fragments_by_length() {
+        use noodles::sam::alignment::record::cigar::{Op, op::Kind};
+        use noodles::sam::alignment::record_buf::{Cigar, QualityScores, Sequence};
+        use sam::alignment::record::Flags;
+        let read = |name, flags, start, len: usize, qual| {
+            let mut record = mapped_read(name, flags, 0, start, qual);
+            *record.cigar_mut() = Cigar::from(vec![Op::new(Kind::Match, len)]);
+            *record.sequence_mut() = Sequence::from(vec![b'A'; len]);
+            *record.quality_scores_mut() = QualityScores::from(vec![qual; len]);
+            record
+        };
+
+        // "a" and "c" span 101-200 and "b" 101-250; "r1" and "r2" share
+        // their 5' end at 300 on the reverse strand but start apart
+        let records = [
+            read("a", Flags::empty(), 101, 100, 30),
+            read("b", Flags::empty(), 101, 150, 30),
+            read("c", Flags::empty(), 101, 100, 20),
+            read("r1", Flags::REVERSE_COMPLEMENTED, 201, 100, 30),
+            read("r2", Flags::REVERSE_COMPLEMENTED, 101, 200, 20),
+        ];
+        let dups = |use_3p_end| {
+            let args = Args {
+                use_3p_end,
+                ..Default::default()
+            };
+            detect_with(&records, args).iter().collect::<Vec<_>>()
+        };
+        assert_eq!(dups(false), [0, 2, 3]);
+        assert_eq!(dups(true), [2]);
+    }
+
+    #[test]
+    fn test_mate_suffixes_are_stripped_when_pairing() {
+        use sam::alignment::record::Flags;
+        let fwd = Flags::SEGMENTED;
+        let rev = Flags::SEGMENTED | Flags::REVERSE_COMPLEMENTED;
+
+        let mut records = Vec::new();
+        for name in ["a", "b"] {
+            records.push(mapped_read(&format!("{}/1", name), fwd, 0, 101, 30));
+            records.push(mapped_read(&format!("{}/2", name), rev, 0, 401, 30));
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        let examined = |strip_mate_suffix: bool| -> Vec<String> {
+            let args = Args {
+                metrics_file: Some(path.clone()),
+                strip_mate_suffix,
+                ..Default::default()
+            };
+            detect_with(&records, args);
+            let text = std::fs::read_to_string(&path).unwrap();
+            let row: Vec<_> = text
+                .lines()
+                .nth(2)
+                .unwrap()
+                .split('\t')
+                .map(String::from)
+                .collect();
+            row[1..3].to_vec()
+        };
+        // UNPAIRED_READS_EXAMINED, READ_PAIRS_EXAMINED
+        assert_eq!(examined(true), ["0", "2"]);
+        assert_eq!(examined(false), ["4", "0"]);
+    }
+
+    #[test]
+    fn test_name_sorted_pairs_adjacent_mates() {
+        use sam::alignment::record::Flags;
+        let first = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let last = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        // Queryname order: two copies of a pair, then a read whose mate is missing
+        let records = vec![
+            mapped_read("a", first, 0, 101, 30),
+            mapped_read("a", last, 0, 401, 30),
+            mapped_read("b", first, 0, 101, 20),
+            mapped_read("b", last, 0, 401, 20),
+            mapped_read("c", first, 0, 101, 25),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        for name_sorted in [true, false] {
+            let args = Args {
+                name_sorted,
+                metrics_file: Some(path.clone()),
+                ..Default::default()
+            };
+            let mask = detect_with(&records, args);
+            assert_eq!(mask.iter().collect::<Vec<_>>(), [2, 3]);
+            let text = std::fs::read_to_string(&path).unwrap();
+            let row: Vec<_> = text.lines().nth(2).unwrap().split('\t').collect();
+            // UNPAIRED_READS_EXAMINED, READ_PAIRS_EXAMINED
+            assert_eq!(row[1..3], ["1", "2"]);
+        }
+
+        let args = Args {
+            input: vec!["a.bam".into(), "b.bam".into()],
+            name_sorted: true,
+            ..Default::default()
+        };
+        assert!(try_detect_with(&records, args).is_err());
+    }
+
+    #[test]
+    fn test_repeated_read_name_for_same_end() {
+        use sam::alignment::record::Flags;
+        let first = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        let last = Flags::SEGMENTED | Flags::LAST_SEGMENT | Flags::REVERSE_COMPLEMENTED;
+
+        // A bad merge left read "t" in the file twice as the first end
+        let records = vec![
+            mapped_read("t", first, 0, 101, 30),
+            mapped_read("t", first, 0, 101, 30),
+            mapped_read("t", last, 0, 401, 30),
+        ];
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("metrics.txt");
+        let args = Args {
+            metrics_file: Some(path.clone()),
+            ..Default::default()
+        };
+        try_detect_with(&records, args).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let row: Vec<_> = text.lines().nth(2).unwrap().split('\t').collect();
+        // Pairing is unchanged: the copies pair up, the real mate is left over
+        assert_eq!(row[1..3], ["1", "1"]);
+
+        let args = Args {
+            strict: true,
+            ..Default::default()
+        };
+        let err = try_detect_with(&records, args).unwrap_err().to_string();
+        assert!(err.contains("read name t appears twice"), "{}", err);
+
+        // A well-formed pair passes under --strict
+        assert!(
+            try_detect_with(
+                &records[1..],
+                Args {
+                    strict: true,
+                    ..Default::default()
+                }
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_nameless_paired_read_is_a_fragment() {
+        use sam::alignment::record::Flags;
+        let mut nameless = mapped_read("x", Flags::SEGMENTED | Flags::FIRST_SEGMENT, 0, 101, 30);
+        *nameless.name_mut() = None;
+        let records = vec![nameless, mapped_read("f", Flags::empty(), 0, 101, 20)];
+
+        // Grouped with the fragment at its position, where it is the best copy
+        let dups = try_detect_with(&records, Args::default()).unwrap();
+        assert_eq!(dups.iter().collect::<Vec<_>>(), [1]);
+
+        let err = try_detect_with(
+            &records,
+            Args {
+                strict: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "paired read at record 0 has no name");
+    }
+}
@@ -0,0 +1,83 @@
+//! Python bindings (`python` feature)
+//!
+//! Builds the `rmduprs` extension module with maturin (see `pyproject.toml`).
+//! `markdup` runs [`run_markdup`] without holding the GIL, on a thread pool
+//! of its own, and returns the run's counts as a dict; pipeline errors are
+//! raised as `RuntimeError` with the full error chain as the message.
+
+use crate::args::{Args, effective_threads};
+use crate::pipeline::{MarkdupSummary, run_markdup};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::path::PathBuf;
+
+/// Mark (or remove) duplicates in `input`, writing the result to `output`
+///
+/// `threads` of `None` uses one thread per CPU, as the CLI does.
+#[pyfunction]
+#[pyo3(signature = (input, output, remove_duplicates = false, threads = None))]
+fn markdup<'py>(
+    py: Python<'py>,
+    input: PathBuf,
+    output: PathBuf,
+    remove_duplicates: bool,
+    threads: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let defaults = Args::default();
+    let args = Args {
+        input: vec![input.to_string_lossy().into_owned()],
+        output: output.to_string_lossy().into_owned(),
+        remove_duplicates,
+        threads: threads.unwrap_or(defaults.threads),
+        ..defaults
+    };
+    let summary = py
+        .detach(|| -> anyhow::Result<MarkdupSummary> {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(effective_threads(&args))
+                .build()?;
+            pool.install(|| run_markdup(&args))
+        })
+        .map_err(|e| PyRuntimeError::new_err(format!("{:#}", e)))?;
+    summary_dict(py, &summary)
+}
+
+/// The counts of `summary`, with a `libraries` dict of Picard metrics per
+/// library name
+fn summary_dict<'py>(py: Python<'py>, summary: &MarkdupSummary) -> PyResult<Bound<'py, PyDict>> {
+    let libraries = PyDict::new(py);
+    for (name, m) in summary.metrics.libraries() {
+        let lib = PyDict::new(py);
+        lib.set_item("unpaired_reads_examined", m.unpaired_reads_examined)?;
+        lib.set_item("read_pairs_examined", m.read_pairs_examined)?;
+        lib.set_item(
+            "secondary_or_supplementary_rds",
+            m.secondary_or_supplementary_rds,
+        )?;
+        lib.set_item("unmapped_reads", m.unmapped_reads)?;
+        lib.set_item("unpaired_read_duplicates", m.unpaired_read_duplicates)?;
+        lib.set_item("read_pair_duplicates", m.read_pair_duplicates)?;
+        lib.set_item("percent_duplication", m.percent_duplication())?;
+        lib.set_item("estimated_library_size", m.estimated_library_size())?;
+        libraries.set_item(name, lib)?;
+    }
+
+    let dict = PyDict::new(py);
+    dict.set_item("pe", summary.pe)?;
+    dict.set_item("se", summary.se)?;
+    dict.set_item("inter_chromosomal", summary.inter_chromosomal)?;
+    dict.set_item("spilled_mates", summary.spilled_mates)?;
+    dict.set_item("duplicates", summary.duplicates)?;
+    dict.set_item("orphan_duplicates", summary.orphan_duplicates)?;
+    dict.set_item("pe_duplicates", summary.pe_duplicates)?;
+    dict.set_item("se_only_duplicates", summary.se_only_duplicates)?;
+    dict.set_item("libraries", libraries)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn rmduprs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(markdup, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! Duplicate detection by read sequence (`--by-sequence`)
+//!
+//! Unaligned BAM has no 5' positions to group on, so reads are grouped by
+//! their bases instead: a pair by the sequences of both ends (first end,
+//! then second), a single read by its own. With `--sequence-prefix N` only
+//! the first N bases of each end are compared, which also catches copies
+//! that differ by quality trimming at the 3' end. Reads only match within a
+//! library and, with `--umi-tag`, a UMI.
+//!
+//! The keys go through the same external sort as positional detection: each
+//! template becomes a [`Metadata`] whose `pos1` (and `pos2` for a pair) is a
+//! 63-bit hash of the compared bases, so two different sequences could in
+//! principle collide; at 2^63 buckets this is astronomically unlikely. In
+//! every set of identical templates the one with the highest score (sum of
+//! base qualities >= 15) is kept, the first in the file on a tie, and every
+//! other read of the set is a duplicate.
+//!
+//! Mates are paired when they are adjacent, as in uBAM written by
+//! `samtools import` or Picard `FastqToSam`; a paired read whose mate does
+//! not follow it is compared as a single read.
+
+use crate::algorithm::{DupSets, get_score};
+use crate::args::Args;
+use crate::io::{self, MergedChunks};
+use crate::logln;
+use crate::metadata::Metadata;
+use crate::pipeline::{Batching, MarkdupSummary, excludes_qcfail};
+use crate::utils::{Timings, format_duration, strip_mate_suffix};
+use anyhow::Result;
+use noodles::bam;
+use roaring::RoaringBitmap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// FNV-1a hash of the first `prefix` bases (all without a prefix), shifted
+/// into the non-negative range of a position
+pub(crate) fn hash_bases(bases: impl IntoIterator<Item = u8>, prefix: Option<usize>) -> i64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for base in bases.into_iter().take(prefix.unwrap_or(usize::MAX)) {
+        h ^= base as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (h >> 1) as i64
+}
+
+fn sequence_hash(record: &bam::Record, prefix: Option<usize>) -> i64 {
+    hash_bases(record.sequence().iter(), prefix)
+}
+
+/// Whether `a` and `b` are the two ends of one template
+fn are_mates(a: &bam::Record, b: &bam::Record, strip_suffix: bool) -> bool {
+    fn name(r: &bam::Record, strip_suffix: bool) -> Option<&[u8]> {
+        let name = r.name()?.as_ref();
+        Some(if strip_suffix {
+            strip_mate_suffix(name)
+        } else {
+            name
+        })
+    }
+    matches!((name(a, strip_suffix), name(b, strip_suffix)), (Some(x), Some(y)) if x == y)
+}
+
+/// Merge the sorted chunks at `paths` and mark every template of a set of
+/// identical ones but the best
+///
+/// Returns the duplicate records and how many of them are paired and
+/// single reads.
+pub(crate) fn mark_identical(paths: &[PathBuf]) -> Result<(RoaringBitmap, usize, usize)> {
+    let mut dup_mask = RoaringBitmap::new();
+    let (mut pe_dups, mut se_dups) = (0usize, 0usize);
+    let mut mark = |set: &[Metadata]| {
+        let best = set
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, m)| (m.score, std::cmp::Reverse(m.idx1)))
+            .map_or(0, |(i, _)| i);
+        for (_, m) in set.iter().enumerate().filter(|&(i, _)| i != best) {
+            dup_mask.insert(m.idx1 as u32);
+            if m.paired_end == 1 {
+                dup_mask.insert(m.idx2 as u32);
+                pe_dups += 2;
+            } else {
+                se_dups += 1;
+            }
+        }
+    };
+    let same_sequence = |a: &Metadata, b: &Metadata| {
+        (a.lib_id, a.pos1, a.umi, a.paired_end, a.pos2)
+            == (b.lib_id, b.pos1, b.umi, b.paired_end, b.pos2)
+    };
+    let mut set: Vec<Metadata> = Vec::new();
+    for meta in MergedChunks::open(paths)? {
+        let meta = meta?;
+        if set.last().is_some_and(|last| !same_sequence(last, &meta)) {
+            mark(&set);
+            set.clear();
+        }
+        set.push(meta);
+    }
+    mark(&set);
+    Ok((dup_mask, pe_dups, se_dups))
+}
+
+/// First pass and merge of `--by-sequence`: collect the indices of the
+/// duplicate records
+///
+/// `library` and `umi` give the library id and UMI hash of a record;
+/// chunks are written to `tmp_dir`.
+pub(crate) fn find_duplicates<I>(
+    args: &Args,
+    records: I,
+    tmp_dir: &Path,
+    library: &dyn Fn(&bam::Record) -> i32,
+    umi: &dyn Fn(&bam::Record) -> u64,
+    timings: &mut Timings,
+    summary: &mut MarkdupSummary,
+) -> Result<(RoaringBitmap, Option<DupSets>)>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    let find_start = Instant::now();
+    logln!("finding duplicate read sequences in the file...");
+    if let Some(prefix) = args.sequence_prefix {
+        logln!("  comparing the first {} bases of each end", prefix);
+    }
+    let batch_size = Batching::from_args(args).batch_size;
+
+    let prefix = args.sequence_prefix;
+    let template = |first: &bam::Record, idx1: u64, second: Option<(&bam::Record, u64)>| {
+        let lib_id = library(first);
+        let pos1 = sequence_hash(first, prefix);
+        let mut meta = match second {
+            Some((second, idx2)) => {
+                let score = get_score(first) + get_score(second);
+                let pos2 = sequence_hash(second, prefix);
+                Metadata::new_pe(lib_id, 0, pos1, 0, 0, pos2, 0, score, idx1, idx2)
+            }
+            None => Metadata::new_se(lib_id, 0, pos1, 0, get_score(first), idx1),
+        };
+        meta.umi = umi(first);
+        meta
+    };
+
+    let mut chunk = Vec::with_capacity(batch_size.min(crate::args::DEFAULT_BATCH_SIZE));
+    let mut tmp_files: Vec<PathBuf> = Vec::new();
+    let mut sort_dur = Duration::ZERO;
+    let mut save_chunk = |chunk: &mut Vec<Metadata>| -> Result<()> {
+        let sort_start = Instant::now();
+        let path = io::save_chunk_parallel(
+            std::mem::take(chunk),
+            tmp_dir,
+            tmp_files.len(),
+            args.tmp_compression,
+        )?;
+        tmp_files.push(path);
+        sort_dur += sort_start.elapsed();
+        Ok(())
+    };
+
+    let (mut record_count, mut pe_count, mut se_count, mut skipped) = (0u64, 0u64, 0u64, 0u64);
+    let mut pending: Option<(bam::Record, u64)> = None;
+    for (index, result) in records.enumerate() {
+        let record = result?;
+        record_count += 1;
+        let flags = record.flags();
+        if flags.is_secondary()
+            || flags.is_supplementary()
+            || (excludes_qcfail(args) && flags.is_qc_fail())
+        {
+            skipped += 1;
+            continue;
+        }
+        let index = index as u64;
+        let mate = pending.take();
+        match mate {
+            Some((mate, mate_idx))
+                if flags.is_segmented() && are_mates(&mate, &record, args.strip_mate_suffix) =>
+            {
+                let (first, second) =
+                    if flags.is_first_segment() && !mate.flags().is_first_segment() {
+                        ((&record, index), (&mate, mate_idx))
+                    } else {
+                        ((&mate, mate_idx), (&record, index))
+                    };
+                chunk.push(template(first.0, first.1, Some(second)));
+                pe_count += 1;
+            }
+            mate => {
+                if let Some((mate, mate_idx)) = mate {
+                    chunk.push(template(&mate, mate_idx, None));
+                    se_count += 1;
+                }
+                if flags.is_segmented() {
+                    pending = Some((record, index));
+                } else {
+                    chunk.push(template(&record, index, None));
+                    se_count += 1;
+                }
+            }
+        }
+        if chunk.len() >= batch_size {
+            save_chunk(&mut chunk)?;
+        }
+    }
+    if let Some((mate, mate_idx)) = pending {
+        chunk.push(template(&mate, mate_idx, None));
+        se_count += 1;
+    }
+    if !chunk.is_empty() {
+        save_chunk(&mut chunk)?;
+    }
+    let read_sort_dur = find_start.elapsed();
+    timings.sort = sort_dur;
+    timings.read = read_sort_dur.saturating_sub(sort_dur);
+    logln!(
+        "  sorted {} pairs and {} single reads by sequence",
+        pe_count,
+        se_count
+    );
+    logln!(
+        "  skipped {} secondary, supplementary or QC-failed reads",
+        skipped
+    );
+
+    let mut merge_seq = 0;
+    let paths = io::reduce_fanin(
+        tmp_files,
+        tmp_dir,
+        args.max_merge_fanin,
+        args.tmp_compression,
+        &mut merge_seq,
+    )?;
+    let (dup_mask, pe_dups, se_dups) = mark_identical(&paths)?;
+
+    logln!("  found {} duplicates", dup_mask.len());
+    logln!("  (pe={}, se={})", pe_dups, se_dups);
+    *summary = MarkdupSummary {
+        records: record_count,
+        pe: pe_count,
+        se: se_count,
+        duplicates: dup_mask.len(),
+        pe_duplicates: pe_dups,
+        se_only_duplicates: se_dups,
+        ..Default::default()
+    };
+
+    let find_dur = find_start.elapsed();
+    timings.merge = find_dur.saturating_sub(read_sort_dur);
+    let (find_m, find_s) = format_duration(find_dur);
+    logln!(
+        "collected list of duplicate sequences in {} min {} sec",
+        find_m,
+        find_s
+    );
+    Ok((dup_mask, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::algorithm::Compat;
+    use crate::args::Args;
+    use crate::pipeline::collect_duplicates;
+    use noodles::bam;
+    use noodles::sam;
+    use noodles::sam::alignment::io::Write as _;
+    use noodles::sam::alignment::record::Flags;
+    use noodles::sam::alignment::record_buf::{QualityScores, RecordBuf, Sequence};
+
+    /// Unaligned read `name` with `bases`, every base at quality `qual`
+    fn read(name: &str, flags: Flags, bases: &str, qual: u8) -> RecordBuf {
+        RecordBuf::builder()
+            .set_name(name)
+            .set_flags(flags | Flags::UNMAPPED)
+            .set_sequence(Sequence::from(bases.as_bytes().to_vec()))
+            .set_quality_scores(QualityScores::from(vec![qual; bases.len()]))
+            .build()
+    }
+
+    fn pair(name: &str, r1: &str, r2: &str, qual: u8) -> [RecordBuf; 2] {
+        let paired = Flags::SEGMENTED | Flags::MATE_UNMAPPED;
+        [
+            read(name, paired | Flags::FIRST_SEGMENT, r1, qual),
+            read(name, paired | Flags::LAST_SEGMENT, r2, qual),
+        ]
+    }
+
+    fn sequence_duplicates(records: &[RecordBuf], prefix: Option<usize>) -> Vec<u32> {
+        sequence_duplicates_with(
+            records,
+            Args {
+                sequence_prefix: prefix,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn sequence_duplicates_with(records: &[RecordBuf], args: Args) -> Vec<u32> {
+        let header = sam::Header::default();
+        let mut data = Vec::new();
+        let mut writer = bam::io::Writer::from(&mut data);
+        writer.write_header(&header).unwrap();
+        for record in records {
+            writer.write_alignment_record(&header, record).unwrap();
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let args = Args {
+            by_sequence: true,
+            batch_size: Some(3),
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..args
+        };
+        let mut reader = bam::io::Reader::from(&data[..]);
+        let header = reader.read_header().unwrap();
+        collect_duplicates(&args, reader.records(), &header)
+            .unwrap()
+            .0
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn test_by_sequence() {
+        let mut records = Vec::new();
+        records.extend(pair("p1", "ACGTACGT", "TTTTGGGG", 30)); // 0, 1: copy of p2
+        records.extend(pair("p2", "ACGTACGT", "TTTTGGGG", 40)); // 2, 3: best
+        records.extend(pair("p3", "ACGTACGT", "TTTTGGGC", 40)); // 4, 5: second end differs
+        records.push(read("s1", Flags::empty(), "ACGTACGT", 30)); // 6: kept, first of a tie
+        records.push(read("s2", Flags::empty(), "ACGTACGT", 30)); // 7
+        records.push(read("s3", Flags::empty(), "ACGTACCC", 35)); // 8: trimmed differently
+        let lone = Flags::SEGMENTED | Flags::FIRST_SEGMENT;
+        records.push(read("p4", lone, "ACGTACGT", 20)); // 9: mate missing, a single read
+        records.push(read("s4", Flags::SECONDARY, "ACGTACGT", 20)); // 10: never marked
+        records.extend(pair("p5", "TTTTGGGG", "ACGTACGT", 40)); // 11, 12: ends swapped
+
+        assert_eq!(sequence_duplicates(&records, None), [0, 1, 7, 9]);
+        // On the first 6 bases s3 is the best copy, and p3 matches p2
+        assert_eq!(
+            sequence_duplicates(&records, Some(6)),
+            [0, 1, 4, 5, 6, 7, 9]
+        );
+    }
+
+    #[test]
+    fn test_by_sequence_follows_qcfail_policy() {
+        // s1 is the best copy but failed QC
+        let records = [
+            read("s1", Flags::QC_FAIL, "ACGTACGT", 40),
+            read("s2", Flags::empty(), "ACGTACGT", 30),
+            read("s3", Flags::empty(), "ACGTACGT", 20),
+        ];
+        assert_eq!(sequence_duplicates(&records, None), [1, 2]);
+        let exclude = Args {
+            exclude_qcfail: true,
+            ..Default::default()
+        };
+        assert_eq!(sequence_duplicates_with(&records, exclude), [2]);
+        // samtools leaves QC-failed reads out without --include-fails
+        let samtools = Args {
+            compat: Compat::Samtools,
+            ..Default::default()
+        };
+        assert_eq!(sequence_duplicates_with(&records, samtools), [2]);
+    }
+}
@@ -0,0 +1,90 @@
+//! Per-tile duplication counts (`--tile-metrics`)
+//!
+//! Duplicates piling up on a few tiles point at the flowcell (a bubble, an
+//! edge effect, pad hopping on patterned flowcells) rather than at the
+//! library. The write pass counts every read it marks or unmarks under the
+//! tile in its name, read as for optical detection (see [`crate::optical`]);
+//! reads whose names give no tile are left out.
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Reads and duplicates of one tile
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TileCounts {
+    pub reads: u64,
+    pub duplicates: u64,
+    pub optical_duplicates: u64,
+}
+
+/// Counts per tile, in tile order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TileMetrics {
+    pub tiles: BTreeMap<u32, TileCounts>,
+}
+
+impl TileMetrics {
+    /// Count a read on `tile`
+    #[inline]
+    pub fn add(&mut self, tile: u32, duplicate: bool, optical: bool) {
+        let counts = self.tiles.entry(tile).or_default();
+        counts.reads += 1;
+        counts.duplicates += u64::from(duplicate);
+        counts.optical_duplicates += u64::from(optical);
+    }
+
+    /// Write the counts as TSV with columns `tile`, `reads`, `duplicates`,
+    /// `optical_duplicates` and `percent_duplication` (a fraction, as in the
+    /// Picard metrics)
+    pub fn write_tsv<W: Write>(&self, w: &mut W) -> Result<()> {
+        writeln!(
+            w,
+            "tile\treads\tduplicates\toptical_duplicates\tpercent_duplication"
+        )?;
+        for (tile, counts) in &self.tiles {
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}\t{:.6}",
+                tile,
+                counts.reads,
+                counts.duplicates,
+                counts.optical_duplicates,
+                counts.duplicates as f64 / counts.reads as f64
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the TSV to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write_tsv(&mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_metrics_tsv() {
+        let mut tiles = TileMetrics::default();
+        tiles.add(1102, false, false);
+        tiles.add(1101, true, true);
+        tiles.add(1101, false, false);
+        tiles.add(1101, true, false);
+        tiles.add(1101, false, false);
+        let mut out = Vec::new();
+        tiles.write_tsv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tile\treads\tduplicates\toptical_duplicates\tpercent_duplication\n\
+             1101\t4\t2\t1\t0.500000\n\
+             1102\t1\t0\t0\t0.000000\n"
+        );
+    }
+}
@@ -2,8 +2,39 @@
 //!
 //! Common helper functions used throughout the project.
 
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
 use std::time::Duration;
 
+/// Build and runtime details for performance reports (`rmduprs info`)
+///
+/// One `key: value` pair per line: crate version, global allocator,
+/// whether BGZF compression is parallel, the noodles version resolved at
+/// build time, target and profile, and the default thread count.
+pub fn build_info() -> String {
+    // Mirrors the `#[global_allocator]` choice in main.rs
+    let allocator = if cfg!(windows) { "system" } else { "mimalloc" };
+    let profile = if cfg!(debug_assertions) {
+        "debug"
+    } else {
+        "release"
+    };
+    format!(
+        "rmduprs: {}\nallocator: {}\nparallel bgzf: no\nnoodles: {}\ntarget: {}-{}\nprofile: {}\ndefault threads: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        allocator,
+        env!("RMDUPRS_NOODLES_VERSION"),
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        profile,
+        crate::args::num_cpus()
+    )
+}
+
 /// Format a duration into (minutes, seconds) tuple
 ///
 /// Useful for printing elapsed time in human-readable format.
@@ -24,10 +55,212 @@ pub fn format_duration_verbose(dur: Duration) -> String {
     }
 }
 
+/// Wall-clock time of each phase of a run, for `--timing-json`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// First pass over the input, without the time spent in `sort`
+    pub read: Duration,
+    /// Sorting chunks and writing them to the temporary directory
+    pub sort: Duration,
+    /// Pre-merging and merging chunks, and detecting duplicates
+    pub merge: Duration,
+    /// Write pass
+    pub write: Duration,
+    /// Whole run
+    pub total: Duration,
+}
+
+impl Timings {
+    /// The phases as a JSON object of seconds, e.g. `{"read_sec": 1.250, ...}`
+    pub fn to_json(&self) -> String {
+        let phases = [
+            ("read", self.read),
+            ("sort", self.sort),
+            ("merge", self.merge),
+            ("write", self.write),
+            ("total", self.total),
+        ];
+        let fields: Vec<_> = phases
+            .iter()
+            .map(|(name, dur)| format!("\"{}_sec\": {:.3}", name, dur.as_secs_f64()))
+            .collect();
+        format!("{{{}}}\n", fields.join(", "))
+    }
+
+    /// Write the JSON timings to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_json())?;
+        Ok(())
+    }
+}
+
+/// Format a byte count with a binary unit, e.g. `4.0 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Hash a barcode/UMI value into a stable 64-bit key (FNV-1a)
+///
+/// The hash is deterministic across runs and platforms. Zero is reserved for
+/// "no barcode", so an input that happens to hash to zero is remapped to one.
+#[inline]
+pub fn hash_barcode(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    if h == 0 { 1 } else { h }
+}
+
+/// Strip a trailing `/1` or `/2` mate suffix from a read name
+///
+/// Older pipelines append these to the two ends of a pair, so both must be
+/// reduced to the same key before mates can be matched. `.1`/`.2` are left
+/// alone: in SRA-style names such as `SRR001.1` they number the spot, not the
+/// mate, and stripping them would pair reads of different spots.
+#[inline]
+pub fn strip_mate_suffix(name: &[u8]) -> &[u8] {
+    match name {
+        [stem @ .., b'/', b'1' | b'2'] if !stem.is_empty() => stem,
+        _ => name,
+    }
+}
+
+/// Destination of progress messages
+///
+/// Messages go to the log file when one is set and to stderr otherwise; with
+/// `quiet` they are dropped. Errors are not routed through the logger and
+/// always reach stderr.
+#[derive(Default)]
+pub struct Logger {
+    file: Option<Mutex<LineWriter<File>>>,
+    quiet: bool,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+impl Logger {
+    /// Create a logger writing to `path` (truncated first), or to stderr
+    pub fn new(path: Option<&Path>, quiet: bool) -> Result<Self> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(LineWriter::new(
+                File::create(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?,
+            ))),
+            None => None,
+        };
+        Ok(Self { file, quiet })
+    }
+
+    /// Make this the logger used by [`log!`](crate::log) and
+    /// [`logln!`](crate::logln); only the first call has an effect
+    pub fn install(self) {
+        let _ = LOGGER.set(self);
+    }
+
+    /// Write a message as is; a failed write to the log file is ignored
+    pub fn write(&self, args: fmt::Arguments) {
+        if self.quiet {
+            return;
+        }
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = file.write_fmt(args);
+            }
+            None => eprint!("{}", args),
+        }
+    }
+}
+
+/// The installed logger, or one writing to stderr if none was installed
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(Logger::default)
+}
+
+/// Like `eprint!`, but through the installed [`Logger`]
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::utils::logger().write(format_args!($($arg)*))
+    };
+}
+
+/// Like `eprintln!`, but through the installed [`Logger`]
+#[macro_export]
+macro_rules! logln {
+    ($($arg:tt)*) => {
+        $crate::utils::logger().write(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert!(info.starts_with(&format!("rmduprs: {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(info.contains("\nnoodles: 0."));
+        assert!(info.contains("\nallocator: "));
+        assert_eq!(info.lines().count(), 7);
+    }
+
+    #[test]
+    fn test_timings_json() {
+        let timings = Timings {
+            read: Duration::from_millis(1250),
+            sort: Duration::from_millis(300),
+            merge: Duration::from_micros(40),
+            write: Duration::from_secs(2),
+            total: Duration::from_millis(3590),
+        };
+        assert_eq!(
+            timings.to_json(),
+            "{\"read_sec\": 1.250, \"sort_sec\": 0.300, \"merge_sec\": 0.000, \"write_sec\": 2.000, \"total_sec\": 3.590}\n"
+        );
+    }
+
+    #[test]
+    fn test_logger_file_and_quiet() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.log");
+        let logger = Logger::new(Some(&path), false).unwrap();
+        logger.write(format_args!("  collecting... "));
+        logger.write(format_args!("done in {} ms\n", 5));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "  collecting... done in 5 ms\n"
+        );
+
+        let logger = Logger::new(Some(&path), true).unwrap();
+        logger.write(format_args!("hidden\n"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn test_strip_mate_suffix() {
+        assert_eq!(strip_mate_suffix(b"read/1"), b"read");
+        assert_eq!(strip_mate_suffix(b"read/2"), b"read");
+        assert_eq!(strip_mate_suffix(b"read/3"), b"read/3");
+        assert_eq!(strip_mate_suffix(b"abc/1x"), b"abc/1x");
+        assert_eq!(strip_mate_suffix(b"SRR001.1"), b"SRR001.1");
+        assert_eq!(strip_mate_suffix(b"/1"), b"/1");
+        assert_eq!(strip_mate_suffix(b"read"), b"read");
+    }
+
     #[test]
     fn test_format_duration_seconds() {
         let dur = Duration::from_secs(45);
@@ -53,4 +286,18 @@ mod tests {
         let result = format_duration_verbose(dur);
         assert_eq!(result, "2 min 5 sec");
     }
+
+    #[test]
+    fn test_hash_barcode() {
+        assert_eq!(hash_barcode(b"ACGT"), hash_barcode(b"ACGT"));
+        assert_ne!(hash_barcode(b"ACGT"), hash_barcode(b"ACGA"));
+        assert_ne!(hash_barcode(b""), 0);
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(4 << 30), "4.0 GiB");
+        assert_eq!(format_bytes(3 << 19), "1.5 MiB");
+    }
 }
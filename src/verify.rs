@@ -0,0 +1,195 @@
+//! Concordance check of DUPLICATE flags against a reference markdup output
+//!
+//! Both files must contain the same records in the same order (e.g. our
+//! output and Sambamba's output for one coordinate-sorted input); they are
+//! streamed in lockstep and every record whose DUPLICATE flag differs is
+//! counted.
+
+use crate::args::VerifyArgs;
+use crate::logln;
+use anyhow::{Result, bail};
+use noodles::bam;
+use noodles::sam;
+use std::path::Path;
+
+/// A record whose DUPLICATE flag differs between the two files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discordance {
+    /// 0-based record index in file order
+    pub idx: u64,
+    pub name: String,
+    /// `ref:pos` (1-based), or `*` for unplaced records
+    pub position: String,
+    pub ours: bool,
+    pub theirs: bool,
+}
+
+/// Result of comparing two marked BAMs
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub compared: u64,
+    pub discordant: u64,
+    /// The first `max_report` discordant records
+    pub examples: Vec<Discordance>,
+}
+
+impl VerifyReport {
+    /// Percentage of records whose DUPLICATE flags agree
+    pub fn concordance(&self) -> f64 {
+        if self.compared == 0 {
+            100.0
+        } else {
+            100.0 * (self.compared - self.discordant) as f64 / self.compared as f64
+        }
+    }
+}
+
+/// Compare the DUPLICATE flags of `ours` and `theirs` record by record
+///
+/// Fails if the files diverge (different read names at the same index or a
+/// different number of records), since flags can only be compared between
+/// identical record streams.
+pub fn compare_duplicates(ours: &Path, theirs: &Path, max_report: usize) -> Result<VerifyReport> {
+    let mut ours_reader = bam::io::reader::Builder.build_from_path(ours)?;
+    let header = ours_reader.read_header()?;
+    let mut theirs_reader = bam::io::reader::Builder.build_from_path(theirs)?;
+    theirs_reader.read_header()?;
+
+    let mut report = VerifyReport::default();
+    let mut a = bam::Record::default();
+    let mut b = bam::Record::default();
+    loop {
+        let n_a = ours_reader.read_record(&mut a)?;
+        let n_b = theirs_reader.read_record(&mut b)?;
+        match (n_a, n_b) {
+            (0, 0) => break,
+            (0, _) | (_, 0) => bail!(
+                "record counts differ: {} ends after {} records",
+                if n_a == 0 { "--ours" } else { "--theirs" },
+                report.compared
+            ),
+            _ => {}
+        }
+        if a.name() != b.name() {
+            bail!(
+                "files diverge at record {}: read name {:?} vs {:?}",
+                report.compared,
+                a.name().map(|n| n.to_string()).unwrap_or_default(),
+                b.name().map(|n| n.to_string()).unwrap_or_default()
+            );
+        }
+
+        let ours_dup = a.flags().is_duplicate();
+        let theirs_dup = b.flags().is_duplicate();
+        if ours_dup != theirs_dup {
+            if report.examples.len() < max_report {
+                report.examples.push(Discordance {
+                    idx: report.compared,
+                    name: a
+                        .name()
+                        .map(|n| n.to_string())
+                        .unwrap_or_else(|| "*".to_string()),
+                    position: format_position(&header, &a)?,
+                    ours: ours_dup,
+                    theirs: theirs_dup,
+                });
+            }
+            report.discordant += 1;
+        }
+        report.compared += 1;
+    }
+    Ok(report)
+}
+
+/// Run the `verify` subcommand, logging a summary (to stderr or the log file)
+///
+/// Returns whether the files were fully concordant.
+pub fn run_verify(args: &VerifyArgs) -> Result<bool> {
+    let report = compare_duplicates(&args.ours, &args.theirs, args.max_report)?;
+    for d in &report.examples {
+        logln!(
+            "  discordant #{}: {} at {} (ours={}, theirs={})",
+            d.idx,
+            d.name,
+            d.position,
+            if d.ours { "dup" } else { "-" },
+            if d.theirs { "dup" } else { "-" }
+        );
+    }
+    logln!(
+        "compared {} records, {} discordant, concordance {:.4}%",
+        report.compared,
+        report.discordant,
+        report.concordance()
+    );
+    Ok(report.discordant == 0)
+}
+
+fn format_position(header: &sam::Header, record: &bam::Record) -> Result<String> {
+    let ref_id = record.reference_sequence_id().transpose()?;
+    let pos = record.alignment_start().transpose()?;
+    Ok(match (ref_id, pos) {
+        (Some(id), Some(pos)) => {
+            let name = header
+                .reference_sequences()
+                .get_index(id)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_else(|| id.to_string());
+            format!("{}:{}", name, pos)
+        }
+        _ => "*".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Args;
+    use crate::io::write_header;
+    use crate::pipeline::run_markdup;
+    use noodles::bgzf::io::Writer as BgzfWriter;
+
+    fn test_bam() -> String {
+        format!("{}/test/W62subA_chr1C_5M.bam", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_verify_against_self_and_unmarked_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("out.bam");
+        let args = Args {
+            input: vec![test_bam()],
+            output: output.to_string_lossy().into_owned(),
+            tmp_dir: Some(tmp.path().to_path_buf()),
+            ..Default::default()
+        };
+        run_markdup(&args).unwrap();
+
+        let report = compare_duplicates(&output, &output, 5).unwrap();
+        assert_eq!(report.compared, 99_017);
+        assert_eq!(report.discordant, 0);
+        assert_eq!(report.concordance(), 100.0);
+
+        // The input carries no duplicate flags, so every marked read differs
+        let report = compare_duplicates(&output, Path::new(&test_bam()), 5).unwrap();
+        assert_eq!(report.discordant, 374);
+        assert_eq!(report.examples.len(), 5);
+        assert!(report.examples.iter().all(|d| d.ours && !d.theirs));
+    }
+
+    #[test]
+    fn test_verify_rejects_different_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let empty = tmp.path().join("empty.bam");
+        let mut reader = bam::io::reader::Builder
+            .build_from_path(test_bam())
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        let mut writer = BgzfWriter::new(std::fs::File::create(&empty).unwrap());
+        write_header(&mut writer, &header).unwrap();
+        writer.finish().unwrap();
+
+        let err = compare_duplicates(Path::new(&test_bam()), &empty, 5).unwrap_err();
+        assert!(err.to_string().contains("record counts differ"));
+    }
+}
@@ -0,0 +1,663 @@
+//! End-to-end runs of the whole pipeline on `test/golden.bam`
+//!
+//! The fixture is `test/golden.sam` converted to BAM: twelve reads on one
+//! 10 kb reference, one library.
+//!
+//! | idx | read      | position           | outcome                         |
+//! |-----|-----------|--------------------|---------------------------------|
+//! | 0   | pA/1 Q30  | 100 + mate 400 rev | kept, best pair                 |
+//! | 1   | pB/1 Q20  | 100 + mate 400 rev | pe duplicate                    |
+//! | 2   | frag1 Q40 | 100                | orphan: pairs start here        |
+//! | 3   | pA/2      | 400 rev            | kept with its mate              |
+//! | 4   | pB/2      | 400 rev            | pe duplicate with its mate      |
+//! | 5   | pC/1 Q30  | 1000, mate unmapped| kept, best fragment             |
+//! | 6   | pC/2      | unmapped           | never marked                    |
+//! | 7   | frag2 Q20 | 1000               | se_only duplicate of pC/1       |
+//! | 8   | frag3 Q25 | 2000               | se_only duplicate of frag4      |
+//! | 9   | frag4 Q35 | 2000               | kept                            |
+//! | 10  | pD/1      | 3000 + mate 3300   | kept, unique pair               |
+//! | 11  | pD/2      | 3300 rev           | kept                            |
+
+use noodles::sam::alignment::io::Write as _;
+use noodles::{bam, sam};
+use rmduprs::io::record_to_bytes;
+use rmduprs::{Args, DUPLICATE_FLAG, FLAG_OFFSET, duplicate_mask, run_markdup};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const EXPECTED_DUPLICATES: [u32; 5] = [1, 2, 4, 7, 8];
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test")
+        .join(name)
+}
+
+/// Raw bytes of every record of a BAM file
+fn record_bytes(path: &Path) -> Vec<Vec<u8>> {
+    let mut reader = bam::io::reader::Builder.build_from_path(path).unwrap();
+    let header = reader.read_header().unwrap();
+    reader
+        .records()
+        .map(|r| record_to_bytes(&header, &r.unwrap()).unwrap())
+        .collect()
+}
+
+/// Check that `output` holds the fixture's records, byte for byte, with only
+/// the DUPLICATE flag of the expected reads changed
+fn assert_marked(output: &Path) {
+    let input = record_bytes(&fixture("golden.bam"));
+    let output = record_bytes(output);
+    assert_eq!(output.len(), input.len());
+    for (i, (want, got)) in input.iter().zip(&output).enumerate() {
+        let mut want = want.clone();
+        if EXPECTED_DUPLICATES.contains(&(i as u32)) {
+            want[FLAG_OFFSET + 1] |= (DUPLICATE_FLAG >> 8) as u8;
+        }
+        assert_eq!(got, &want, "record {}", i);
+    }
+}
+
+fn run_binary(output: &Path, extra: &[&str]) -> String {
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("-i")
+        .arg(fixture("golden.bam"))
+        .arg("-o")
+        .arg(output)
+        .args(extra)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(result.status.success(), "{}", stderr);
+    stderr
+}
+
+#[test]
+fn test_fixture_bam_matches_sam() {
+    let mut sam_reader = sam::io::reader::Builder::default()
+        .build_from_path(fixture("golden.sam"))
+        .unwrap();
+    let header = sam_reader.read_header().unwrap();
+    let mut data = Vec::new();
+    let mut writer = bam::io::Writer::from(&mut data);
+    for record in sam_reader.record_bufs(&header) {
+        writer
+            .write_alignment_record(&header, &record.unwrap())
+            .unwrap();
+    }
+    drop(writer);
+
+    let expected: Vec<u8> = record_bytes(&fixture("golden.bam")).concat();
+    assert_eq!(
+        data, expected,
+        "test/golden.bam is stale; regenerate it from test/golden.sam"
+    );
+}
+
+#[test]
+fn test_golden_binary() {
+    let tmp = tempfile::tempdir().unwrap();
+    for (i, extra) in [&[][..], &["--single-threaded"], &["--batch-size", "2"]]
+        .into_iter()
+        .enumerate()
+    {
+        let output = tmp.path().join(format!("out{}.bam", i));
+        let stderr = run_binary(&output, extra);
+        assert!(
+            stderr.contains("found 5 duplicates"),
+            "{:?}: {}",
+            extra,
+            stderr
+        );
+        assert!(
+            stderr.contains("(orphan=1, pe=2, se_only=2)"),
+            "{:?}: {}",
+            extra,
+            stderr
+        );
+        assert_marked(&output);
+    }
+}
+
+#[test]
+fn test_golden_from_stdin() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .args(["-i", "-", "-o"])
+        .arg(&output)
+        .arg("--tmp-dir")
+        .arg(tmp.path())
+        .stdin(std::fs::File::open(fixture("golden.bam")).unwrap())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(result.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("of input from standard input"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("found 5 duplicates"), "{}", stderr);
+    assert_marked(&output);
+    // Only the output is left behind
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_golden_from_process_substitution() {
+    // bash hands the binary a /dev/fd/N pipe for <(...), readable only once
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let script = format!(
+        "exec '{}' -i <(cat '{}') -o '{}' --tmp-dir '{}'",
+        env!("CARGO_BIN_EXE_rmduprs"),
+        fixture("golden.bam").display(),
+        output.display(),
+        tmp.path().display()
+    );
+    let result = Command::new("bash").args(["-c", &script]).output().unwrap();
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("of input from /dev/fd/"), "{}", stderr);
+    assert_marked(&output);
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn test_golden_sam_input() {
+    // SAM is detected from its contents, as a file or on standard input, and
+    // marks the same records as the BAM it was converted to
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    for stdin in [false, true] {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_rmduprs"));
+        if stdin {
+            command
+                .args(["-i", "-"])
+                .stdin(std::fs::File::open(fixture("golden.sam")).unwrap());
+        } else {
+            command.arg("-i").arg(fixture("golden.sam"));
+        }
+        let result = command
+            .arg("-o")
+            .arg(&output)
+            .arg("--force")
+            .arg("--tmp-dir")
+            .arg(tmp.path())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8(result.stderr).unwrap();
+        assert!(result.status.success(), "{}", stderr);
+        assert!(stderr.contains("converted 12 SAM records"), "{}", stderr);
+        assert_marked(&output);
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+    }
+
+    let cram = tmp.path().join("in.cram");
+    std::fs::write(&cram, b"CRAM\x03\x00").unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("-i")
+        .arg(&cram)
+        .arg("-o")
+        .arg(tmp.path().join("cram.bam"))
+        .output()
+        .unwrap();
+    assert!(
+        String::from_utf8(result.stderr)
+            .unwrap()
+            .contains("CRAM input is not supported")
+    );
+}
+
+#[test]
+fn test_golden_to_stdout() {
+    let tmp = tempfile::tempdir().unwrap();
+    let file_output = tmp.path().join("out.bam");
+    // The @PG command lines differ, so leave it out of both
+    run_binary(&file_output, &["--no-PG"]);
+
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("-i")
+        .arg(fixture("golden.bam"))
+        .args(["-o", "-", "--no-PG"])
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert_eq!(result.stdout, std::fs::read(&file_output).unwrap());
+}
+
+#[test]
+fn test_golden_program_record() {
+    let tmp = tempfile::tempdir().unwrap();
+    let header = |path: &Path| {
+        bam::io::reader::Builder
+            .build_from_path(path)
+            .unwrap()
+            .read_header()
+            .unwrap()
+    };
+    let program = |header: &sam::Header, id: &str| {
+        let pg = header
+            .programs()
+            .as_ref()
+            .get(id.as_bytes())
+            .cloned()
+            .unwrap();
+        let field = |tag: &[u8; 2]| {
+            pg.other_fields()
+                .iter()
+                .find(|(t, _)| t.as_ref() == tag)
+                .map(|(_, v)| v.to_string())
+        };
+        (field(b"PN"), field(b"PP"), field(b"CL").unwrap())
+    };
+
+    let once = tmp.path().join("once.bam");
+    run_binary(&once, &[]);
+    let (name, previous, command_line) = program(&header(&once), "rmduprs");
+    assert_eq!(name.as_deref(), Some("rmduprs"));
+    assert_eq!(previous, None);
+    assert!(command_line.contains("once.bam"), "{}", command_line);
+
+    // A second run chains to the first under a new id
+    let twice = tmp.path().join("twice.bam");
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .args([
+            "-i".as_ref(),
+            once.as_os_str(),
+            "-o".as_ref(),
+            twice.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    let (_, previous, _) = program(&header(&twice), "rmduprs.1");
+    assert_eq!(previous.as_deref(), Some("rmduprs"));
+    assert_marked(&twice);
+
+    let bare = tmp.path().join("bare.bam");
+    run_binary(&bare, &["--no-PG"]);
+    assert_eq!(header(&bare), header(&fixture("golden.bam")));
+}
+
+#[test]
+fn test_golden_uncompressed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let compressed = tmp.path().join("out.bam");
+    let uncompressed = tmp.path().join("out.u.bam");
+    run_binary(&compressed, &[]);
+    run_binary(&uncompressed, &["-u", "--no-PG"]);
+    assert_marked(&uncompressed);
+    let size = |path: &Path| std::fs::metadata(path).unwrap().len();
+    assert!(size(&uncompressed) > 2 * size(&compressed));
+
+    // Every level decodes to the same records; level 0 matches -u (both
+    // without the @PG line, whose command lines differ)
+    for level in ["0", "1", "9", "12"] {
+        let output = tmp.path().join(format!("out.{}.bam", level));
+        run_binary(&output, &["--compression-level", level, "--no-PG"]);
+        assert_marked(&output);
+    }
+    let level_0 = std::fs::read(tmp.path().join("out.0.bam")).unwrap();
+    assert_eq!(level_0, std::fs::read(&uncompressed).unwrap());
+}
+
+#[test]
+fn test_golden_only_duplicates() {
+    let tmp = tempfile::tempdir().unwrap();
+    let marked = tmp.path().join("marked.bam");
+    let only = tmp.path().join("only.bam");
+    run_binary(&marked, &[]);
+    let stderr = run_binary(&only, &["--only-duplicates"]);
+    assert!(stderr.contains("wrote only the 5 duplicates"), "{}", stderr);
+
+    let duplicates: Vec<_> = record_bytes(&marked)
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| EXPECTED_DUPLICATES.contains(&(*i as u32)))
+        .map(|(_, bytes)| bytes)
+        .collect();
+    assert_eq!(record_bytes(&only), duplicates);
+}
+
+#[test]
+fn test_golden_duplicates_output() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let dups = tmp.path().join("dups.bam");
+    let stderr = run_binary(&output, &["--duplicates-output", dups.to_str().unwrap()]);
+    assert!(stderr.contains("moved 5 duplicates to"), "{}", stderr);
+
+    // The output keeps the rest; the duplicates file holds the marked reads
+    let (mut kept, mut removed) = (Vec::new(), Vec::new());
+    for (i, mut bytes) in record_bytes(&fixture("golden.bam")).into_iter().enumerate() {
+        if EXPECTED_DUPLICATES.contains(&(i as u32)) {
+            bytes[FLAG_OFFSET + 1] |= (DUPLICATE_FLAG >> 8) as u8;
+            removed.push(bytes);
+        } else {
+            kept.push(bytes);
+        }
+    }
+    assert_eq!(record_bytes(&output), kept);
+    assert_eq!(record_bytes(&dups), removed);
+
+    // The duplicates file is never the output, and is not overwritten
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("-i")
+        .arg(fixture("golden.bam"))
+        .arg("-o")
+        .arg(tmp.path().join("out2.bam"))
+        .arg("--duplicates-output")
+        .arg(&dups)
+        .output()
+        .unwrap();
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("use --force"));
+}
+
+#[test]
+fn test_golden_dup_names_out() {
+    let tmp = tempfile::tempdir().unwrap();
+    let names = tmp.path().join("names.txt");
+    // One line per duplicate record, in output order, with or without removal
+    for extra in [&[][..], &["-r"]] {
+        let output = tmp.path().join("out.bam");
+        let args = [
+            &["--force", "--dup-names-out", names.to_str().unwrap()],
+            extra,
+        ]
+        .concat();
+        run_binary(&output, &args);
+        assert_eq!(
+            std::fs::read_to_string(&names).unwrap(),
+            "pB\nfrag1\npB\nfrag2\nfrag3\n",
+            "{:?}",
+            extra
+        );
+    }
+}
+
+#[test]
+fn test_golden_dup_loci_bed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let bed = tmp.path().join("loci.bed");
+    run_binary(&output, &["--dup-loci-bed", bed.to_str().unwrap()]);
+    // pA/pB pairs with orphan frag1, pC/1 with frag2, frag3/frag4; the
+    // unique pD pair has no line
+    assert_eq!(
+        std::fs::read_to_string(&bed).unwrap(),
+        "chr1\t99\t100\t.\t2\t+\t3\t2\n\
+         chr1\t999\t1000\t.\t1\t+\t2\t1\n\
+         chr1\t1999\t2000\t.\t1\t+\t2\t1\n"
+    );
+}
+
+#[test]
+fn test_golden_write_mask() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let mask = tmp.path().join("dups.rmd");
+    run_binary(&output, &["--write-mask", mask.to_str().unwrap()]);
+    let (dups, record_count) = rmduprs::io::read_mask(&mask).unwrap();
+    assert_eq!(dups.iter().collect::<Vec<_>>(), EXPECTED_DUPLICATES);
+    assert_eq!(record_count, 12);
+}
+
+#[test]
+fn test_golden_apply_mask() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mask = tmp.path().join("dups.rmd");
+    let removed = tmp.path().join("removed.bam");
+    run_binary(&removed, &["-r", "--write-mask", mask.to_str().unwrap()]);
+
+    let apply = |output: &Path, mask: &Path, extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+            .arg("apply")
+            .arg("--mask")
+            .arg(mask)
+            .arg("-i")
+            .arg(fixture("golden.bam"))
+            .arg("-o")
+            .arg(output)
+            .args(extra)
+            .output()
+            .unwrap()
+    };
+    // The write pass alone gives the same output, with any write options
+    let marked = tmp.path().join("marked.bam");
+    let result = apply(&marked, &mask, &[]);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("applying 5 duplicates from"), "{}", stderr);
+    assert!(!stderr.contains("finding positions"), "{}", stderr);
+    assert_marked(&marked);
+    let reapplied = tmp.path().join("reapplied.bam");
+    assert!(apply(&reapplied, &mask, &["-r"]).status.success());
+    assert_eq!(record_bytes(&reapplied), record_bytes(&removed));
+
+    // A mask of another input, or one that cannot carry DI tags, is refused
+    let other = tmp.path().join("other.rmd");
+    let (dups, _) = rmduprs::io::read_mask(&mask).unwrap();
+    rmduprs::io::write_mask(&other, &dups, 13).unwrap();
+    let result = apply(&tmp.path().join("bad.bam"), &other, &[]);
+    assert!(!result.status.success());
+    assert!(
+        String::from_utf8_lossy(&result.stderr)
+            .contains("saved for 13 records but the input has 12")
+    );
+    let result = apply(&tmp.path().join("di.bam"), &mask, &["--tag-duplicate-set"]);
+    assert!(!result.status.success());
+}
+
+#[test]
+fn test_golden_write_index() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    run_binary(&output, &["--write-index"]);
+    assert_marked(&output);
+    let index = bam::bai::fs::read(tmp.path().join("out.bam.bai")).unwrap();
+    let reindexed = bam::fs::index(&output).unwrap();
+    let counts = |index: &bam::bai::Index| {
+        use noodles::csi::binning_index::ReferenceSequence as _;
+        let metadata = index.reference_sequences()[0].metadata().unwrap();
+        (
+            metadata.mapped_record_count(),
+            metadata.unmapped_record_count(),
+        )
+    };
+    assert_eq!(counts(&index), counts(&reindexed));
+
+    // Queries through the index find the marked records
+    let mut reader = bam::io::indexed_reader::Builder::default()
+        .set_index(index)
+        .build_from_path(&output)
+        .unwrap();
+    let header = reader.read_header().unwrap();
+    let region = "chr1:1000-2000".parse().unwrap();
+    let flags: Vec<_> = reader
+        .query(&header, &region)
+        .unwrap()
+        .records()
+        .map(|r| r.unwrap().flags().is_duplicate())
+        .collect();
+    assert_eq!(flags, [false, false, true, true, false]);
+
+    // Only a BAM file can be indexed
+    for (name, extra) in [
+        ("out.sam", &[][..]),
+        ("-", &[]),
+        ("out2.bam", &["--name-sorted"]),
+    ] {
+        let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+            .arg("-i")
+            .arg(fixture("golden.bam"))
+            .arg("-o")
+            .arg(if name == "-" {
+                PathBuf::from(name)
+            } else {
+                tmp.path().join(name)
+            })
+            .arg("--write-index")
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(!result.status.success(), "{}", name);
+    }
+    assert!(!tmp.path().join("out.sam").exists());
+}
+
+#[test]
+fn test_golden_lanes_merged() {
+    // Split the fixture into two lanes by read name, keeping mates together,
+    // and give them as repeated -i flags
+    let tmp = tempfile::tempdir().unwrap();
+    let lanes = [tmp.path().join("lane1.bam"), tmp.path().join("lane2.bam")];
+    let mut reader = bam::io::reader::Builder
+        .build_from_path(fixture("golden.bam"))
+        .unwrap();
+    let header = reader.read_header().unwrap();
+    let mut writers: Vec<_> = lanes
+        .iter()
+        .map(|p| bam::io::writer::Builder.build_from_path(p).unwrap())
+        .collect();
+    for writer in &mut writers {
+        writer.write_header(&header).unwrap();
+    }
+    let mut expected = Vec::new();
+    for (i, record) in reader.records().enumerate() {
+        let record = record.unwrap();
+        let name = record.name().unwrap().to_vec();
+        let lane = usize::from(name.starts_with(b"frag"));
+        writers[lane]
+            .write_alignment_record(&header, &record)
+            .unwrap();
+        if EXPECTED_DUPLICATES.contains(&(i as u32)) {
+            expected.push((name, record.flags().bits()));
+        }
+    }
+    for writer in &mut writers {
+        writer.try_finish().unwrap();
+    }
+
+    let output = tmp.path().join("out.bam");
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("-i")
+        .arg(&lanes[0])
+        .arg("-i")
+        .arg(&lanes[1])
+        .arg("-o")
+        .arg(&output)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(result.status.success(), "{}", stderr);
+    assert!(stderr.contains("(orphan=1, pe=2, se_only=2)"), "{}", stderr);
+
+    // One coordinate-sorted output with the same reads marked
+    let mut reader = bam::io::reader::Builder.build_from_path(&output).unwrap();
+    reader.read_header().unwrap();
+    let records: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+    assert_eq!(records.len(), 12);
+    let starts: Vec<_> = records
+        .iter()
+        .map(|r| r.alignment_start().transpose().unwrap())
+        .collect();
+    assert!(starts.iter().flatten().is_sorted());
+    let mut marked: Vec<_> = records
+        .iter()
+        .filter(|r| r.flags().is_duplicate())
+        .map(|r| {
+            (
+                r.name().unwrap().to_vec(),
+                r.flags().bits() & !DUPLICATE_FLAG,
+            )
+        })
+        .collect();
+    marked.sort();
+    expected.sort();
+    assert_eq!(marked, expected);
+}
+
+#[test]
+fn test_golden_library() {
+    let tmp = tempfile::tempdir().unwrap();
+    let args = |output: &Path| Args {
+        input: vec![fixture("golden.bam").to_string_lossy().into_owned()],
+        output: output.to_string_lossy().into_owned(),
+        tmp_dir: Some(tmp.path().to_path_buf()),
+        quiet: true,
+        ..Default::default()
+    };
+
+    let mask = duplicate_mask(&args(&tmp.path().join("unused.bam"))).unwrap();
+    assert_eq!(mask.iter().collect::<Vec<_>>(), EXPECTED_DUPLICATES);
+
+    let output = tmp.path().join("out.bam");
+    let summary = run_markdup(&args(&output)).unwrap();
+    assert_marked(&output);
+    assert_eq!((summary.pe, summary.se, summary.duplicates), (3, 5, 5));
+    assert_eq!(
+        (
+            summary.orphan_duplicates,
+            summary.pe_duplicates,
+            summary.se_only_duplicates
+        ),
+        (1, 2, 2)
+    );
+    let libraries: Vec<_> = summary.metrics.libraries().collect();
+    assert_eq!(libraries.len(), 1);
+    assert_eq!(libraries[0].0, "lib1");
+    assert_eq!(libraries[0].1.read_pair_duplicates, 1);
+    assert_eq!(libraries[0].1.unpaired_read_duplicates, 3);
+}
+
+#[test]
+fn test_golden_remove_duplicates() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    let stderr = run_binary(&output, &["--remove-duplicates"]);
+    assert!(stderr.contains("removed 5 duplicates"), "{}", stderr);
+
+    let kept: Vec<_> = record_bytes(&fixture("golden.bam"))
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !EXPECTED_DUPLICATES.contains(&(*i as u32)))
+        .map(|(_, bytes)| bytes)
+        .collect();
+    assert_eq!(record_bytes(&output), kept);
+}
+
+#[test]
+fn test_golden_verify_log_file() {
+    let tmp = tempfile::tempdir().unwrap();
+    let output = tmp.path().join("out.bam");
+    run_binary(&output, &[]);
+    let log = tmp.path().join("verify.log");
+    let result = Command::new(env!("CARGO_BIN_EXE_rmduprs"))
+        .arg("verify")
+        .arg("--ours")
+        .arg(&output)
+        .arg("--theirs")
+        .arg(fixture("golden.bam"))
+        .arg("--log-file")
+        .arg(&log)
+        .output()
+        .unwrap();
+    // The unmarked fixture differs in the five duplicates
+    assert_eq!(result.status.code(), Some(1));
+    assert!(
+        result.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let report = std::fs::read_to_string(&log).unwrap();
+    assert!(report.contains(", 5 discordant,"), "{}", report);
+    assert_eq!(report.matches("discordant #").count(), 5, "{}", report);
+}